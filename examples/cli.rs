@@ -14,7 +14,7 @@ extern crate rand;
 extern crate rayon;
 extern crate rustlight;
 
-use clap::{Args, Parser, Subcommand, ValueEnum};
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
 
 use log::LevelFilter;
 use log4rs::{
@@ -50,6 +50,371 @@ enum ExtraOptions {
     TextureLight,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum AccelOption {
+    /// Test all primitives (debugging)
+    Naive,
+    /// Binary BVH
+    BVH,
+    /// Wide BVH (4 children)
+    BVH4,
+    /// Wide BVH (8 children)
+    BVH8,
+    /// Embree (need embree feature)
+    Embree,
+}
+impl AccelOption {
+    pub fn parse(self) -> rustlight::accel::AccelType {
+        match self {
+            AccelOption::Naive => rustlight::accel::AccelType::Naive,
+            AccelOption::BVH => rustlight::accel::AccelType::BVH,
+            AccelOption::BVH4 => rustlight::accel::AccelType::BVH4,
+            AccelOption::BVH8 => rustlight::accel::AccelType::BVH8,
+            AccelOption::Embree => rustlight::accel::AccelType::Embree,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum FilterOption {
+    /// Pixel averaging
+    Box,
+    Tent,
+    Gaussian,
+    /// Mitchell-Netravali
+    Mitchell,
+    /// Lanczos sinc (3 lobes)
+    Lanczos,
+}
+impl FilterOption {
+    pub fn parse(self) -> rustlight::filter::Filter {
+        match self {
+            FilterOption::Box => rustlight::filter::Filter::default(),
+            FilterOption::Tent => rustlight::filter::Filter::tent(),
+            FilterOption::Gaussian => rustlight::filter::Filter::gaussian(),
+            FilterOption::Mitchell => rustlight::filter::Filter::mitchell(),
+            FilterOption::Lanczos => rustlight::filter::Filter::lanczos(),
+        }
+    }
+}
+
+/// Hair model added to the scene
+#[derive(Debug, Clone)]
+struct HairOption {
+    filename: String,
+    eumelanin: f32,
+    pheomelanin: f32,
+}
+fn parse_hair(input: &str) -> Result<HairOption, String> {
+    let values = input.split(':').collect::<Vec<_>>();
+    let parse = |v: &str| {
+        v.parse::<f32>()
+            .map_err(|_| format!("wrong melanin concentration: {}", v))
+    };
+    match &values[..] {
+        [filename] => Ok(HairOption {
+            filename: filename.to_string(),
+            eumelanin: 1.3,
+            pheomelanin: 0.0,
+        }),
+        [filename, eumelanin] => Ok(HairOption {
+            filename: filename.to_string(),
+            eumelanin: parse(eumelanin)?,
+            pheomelanin: 0.0,
+        }),
+        [filename, eumelanin, pheomelanin] => Ok(HairOption {
+            filename: filename.to_string(),
+            eumelanin: parse(eumelanin)?,
+            pheomelanin: parse(pheomelanin)?,
+        }),
+        _ => Err("expected file[:eumelanin[:pheomelanin]]".to_string()),
+    }
+}
+
+fn parse_f32(v: &str) -> Result<f32, String> {
+    v.parse::<f32>().map_err(|_| format!("wrong number: {}", v))
+}
+fn parse_radius(v: &str) -> Result<f32, String> {
+    match parse_f32(v)? {
+        r if r > 0.0 => Ok(r),
+        r => Err(format!("the radius needs to be positive: {}", r)),
+    }
+}
+fn parse_alpha(v: &str) -> Result<f32, String> {
+    match parse_f32(v)? {
+        a if a > 0.0 && a < 1.0 => Ok(a),
+        a => Err(format!("alpha needs to be inside ]0, 1[: {}", a)),
+    }
+}
+fn parse_point(v: &str) -> Result<cgmath::Point3<f32>, String> {
+    let values = v.split(',').map(parse_f32).collect::<Result<Vec<_>, _>>()?;
+    match values[..] {
+        [x, y, z] => Ok(cgmath::Point3::new(x, y, z)),
+        _ => Err(format!("expected x,y,z: {}", v)),
+    }
+}
+
+/// Transformation of a light placed at 'from' and aiming 'to' (+z axis)
+fn light_to_world(from: cgmath::Point3<f32>, to: cgmath::Point3<f32>) -> cgmath::Matrix4<f32> {
+    use cgmath::{EuclideanSpace, InnerSpace, Vector3};
+    let frame = rustlight::math::Frame::new((to - from).normalize());
+    cgmath::Matrix4::from_cols(
+        frame.to_world(Vector3::new(1.0, 0.0, 0.0)).extend(0.0),
+        frame.to_world(Vector3::new(0.0, 1.0, 0.0)).extend(0.0),
+        frame.to_world(Vector3::new(0.0, 0.0, 1.0)).extend(0.0),
+        from.to_vec().extend(1.0),
+    )
+}
+
+/// Spot light added to the scene
+#[derive(Debug, Clone)]
+struct SpotOption {
+    from: cgmath::Point3<f32>,
+    to: cgmath::Point3<f32>,
+    angle: f32,
+    delta: f32,
+    intensity: f32,
+}
+fn parse_spot(input: &str) -> Result<SpotOption, String> {
+    let values = input.split(':').collect::<Vec<_>>();
+    let (from, to, angle, delta, intensity) = match &values[..] {
+        [from, to, angle] => (from, to, angle, &"5", &"1"),
+        [from, to, angle, delta] => (from, to, angle, delta, &"1"),
+        [from, to, angle, delta, intensity] => (from, to, angle, delta, intensity),
+        _ => return Err("expected from:to:angle[:delta[:intensity]]".to_string()),
+    };
+    Ok(SpotOption {
+        from: parse_point(from)?,
+        to: parse_point(to)?,
+        angle: parse_f32(angle)?,
+        delta: parse_f32(delta)?,
+        intensity: parse_f32(intensity)?,
+    })
+}
+
+/// IES light added to the scene
+#[derive(Debug, Clone)]
+struct IESOption {
+    filename: String,
+    from: cgmath::Point3<f32>,
+    to: cgmath::Point3<f32>,
+    scale: f32,
+}
+fn parse_ies(input: &str) -> Result<IESOption, String> {
+    let values = input.split(':').collect::<Vec<_>>();
+    let (filename, from, to, scale) = match &values[..] {
+        [filename, from, to] => (filename, from, to, &"1"),
+        [filename, from, to, scale] => (filename, from, to, scale),
+        _ => return Err("expected file:from:to[:scale]".to_string()),
+    };
+    let (from, to) = (parse_point(from)?, parse_point(to)?);
+    if from == to {
+        return Err("the light needs a direction (from == to)".to_string());
+    }
+    Ok(IESOption {
+        filename: filename.to_string(),
+        from,
+        to,
+        scale: parse_f32(scale)?,
+    })
+}
+
+/// Projector light added to the scene
+#[derive(Debug, Clone)]
+struct ProjectorOption {
+    filename: String,
+    from: cgmath::Point3<f32>,
+    to: cgmath::Point3<f32>,
+    fov: f32,
+    scale: f32,
+}
+fn parse_projector(input: &str) -> Result<ProjectorOption, String> {
+    let values = input.split(':').collect::<Vec<_>>();
+    let (filename, from, to, fov, scale) = match &values[..] {
+        [filename, from, to, fov] => (filename, from, to, fov, &"1"),
+        [filename, from, to, fov, scale] => (filename, from, to, fov, scale),
+        _ => return Err("expected file:from:to:fov[:scale]".to_string()),
+    };
+    let (from, to) = (parse_point(from)?, parse_point(to)?);
+    if from == to {
+        return Err("the light needs a direction (from == to)".to_string());
+    }
+    Ok(ProjectorOption {
+        filename: filename.to_string(),
+        from,
+        to,
+        fov: parse_f32(fov)?,
+        scale: parse_f32(scale)?,
+    })
+}
+
+/// Camera projection replacing the one of the scene
+#[derive(Debug, Clone)]
+enum CameraOption {
+    LatLong,
+    Fisheye(f32),
+    Ortho(f32),
+}
+fn parse_camera(input: &str) -> Result<CameraOption, String> {
+    match input.split(':').collect::<Vec<_>>()[..] {
+        ["latlong"] => Ok(CameraOption::LatLong),
+        ["fisheye", fov] => match parse_f32(fov)? {
+            fov if fov > 0.0 && fov <= 360.0 => Ok(CameraOption::Fisheye(fov)),
+            fov => Err(format!("the fov needs to be inside ]0, 360]: {}", fov)),
+        },
+        ["ortho", size] => match parse_f32(size)? {
+            size if size > 0.0 => Ok(CameraOption::Ortho(size)),
+            size => Err(format!("the size needs to be positive: {}", size)),
+        },
+        _ => Err("expected 'latlong', 'fisheye:fov' or 'ortho:half_height'".to_string()),
+    }
+}
+
+/// Crop window given in the command line: x:y:width:height (pixels)
+fn parse_crop(input: &str) -> Result<(cgmath::Point2<u32>, cgmath::Vector2<u32>), String> {
+    let values = input
+        .split(':')
+        .map(|v| v.parse::<u32>().map_err(|_| format!("wrong pixel value: {}", v)))
+        .collect::<Result<Vec<_>, _>>()?;
+    match values[..] {
+        [_, _, w, h] if w == 0 || h == 0 => {
+            Err(format!("the crop window needs a non-zero size: {}", input))
+        }
+        [x, y, w, h] => Ok((cgmath::Point2::new(x, y), cgmath::Vector2::new(w, h))),
+        _ => Err(format!("expected x:y:width:height: {}", input)),
+    }
+}
+
+/// Thin lens given in the command line
+#[derive(Debug, Clone)]
+enum ApertureOption {
+    Disk,
+    Blades(u32),
+    Image(String),
+}
+#[derive(Debug, Clone)]
+struct LensOption {
+    radius: f32,
+    focus_distance: f32,
+    aperture: ApertureOption,
+}
+fn parse_lens(input: &str) -> Result<LensOption, String> {
+    let values = input.split(':').collect::<Vec<_>>();
+    let (radius, focus_distance, aperture) = match &values[..] {
+        [radius, focus_distance] => (radius, focus_distance, "disk"),
+        [radius, focus_distance, aperture] => (radius, focus_distance, *aperture),
+        _ => return Err("expected radius:focus_distance[:aperture]".to_string()),
+    };
+    let (radius, focus_distance) = (parse_f32(radius)?, parse_f32(focus_distance)?);
+    if radius < 0.0 || focus_distance <= 0.0 {
+        return Err("the radius needs to be positive and the focus distance non zero".to_string());
+    }
+    let aperture = match aperture {
+        "disk" => ApertureOption::Disk,
+        _ => match aperture.parse::<u32>() {
+            Ok(blades) if blades >= 3 => ApertureOption::Blades(blades),
+            Ok(blades) => return Err(format!("at least 3 blades are needed: {}", blades)),
+            Err(_) if std::path::Path::new(aperture).exists() => {
+                ApertureOption::Image(aperture.to_string())
+            }
+            Err(_) => return Err(format!("aperture image not found: {}", aperture)),
+        },
+    };
+    Ok(LensOption {
+        radius,
+        focus_distance,
+        aperture,
+    })
+}
+
+fn parse_shutter(input: &str) -> Result<(f32, f32), String> {
+    match input.split(':').collect::<Vec<_>>()[..] {
+        [open, close] => {
+            let (open, close) = (parse_f32(open)?, parse_f32(close)?);
+            if open > close {
+                Err(format!("the shutter opens after closing: {}", input))
+            } else {
+                Ok((open, close))
+            }
+        }
+        _ => Err("expected open:close".to_string()),
+    }
+}
+fn parse_mesh_motion(input: &str) -> Result<(String, cgmath::Point3<f32>), String> {
+    match input.rsplit_once(':') {
+        Some((name, t)) => Ok((name.to_string(), parse_point(t)?)),
+        None => Err("expected name:x,y,z".to_string()),
+    }
+}
+
+/// Sun position: a direction or a time and location on Earth
+#[derive(Debug, Clone)]
+enum SunOption {
+    Direction(cgmath::Vector3<f32>),
+    Location(rustlight::sky::SunLocation),
+}
+fn parse_sun(input: &str) -> Result<SunOption, String> {
+    use cgmath::EuclideanSpace;
+    let values = input.split(':').collect::<Vec<_>>();
+    match &values[..] {
+        [d] => {
+            let d = parse_point(d)?.to_vec();
+            if d.z <= 0.0 {
+                Err(format!(
+                    "the sun need to be above the horizon (+z): {}",
+                    input
+                ))
+            } else {
+                Ok(SunOption::Direction(d))
+            }
+        }
+        [date, hour, timezone, latitude, longitude] => {
+            let date = date
+                .split('-')
+                .map(|v| v.parse::<i32>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| format!("wrong date: {}", date))?;
+            let (year, month, day) = match date[..] {
+                [y, m, d] if (1..=12).contains(&m) && (1..=31).contains(&d) => {
+                    (y, m as u32, d as u32)
+                }
+                _ => return Err(format!("expected year-month-day: {:?}", date)),
+            };
+            Ok(SunOption::Location(rustlight::sky::SunLocation {
+                year,
+                month,
+                day,
+                hour: parse_f32(hour)?,
+                timezone: parse_f32(timezone)?,
+                latitude: parse_f32(latitude)?,
+                longitude: parse_f32(longitude)?,
+            }))
+        }
+        _ => Err("expected x,y,z or year-month-day:hour:timezone:latitude:longitude".to_string()),
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum LightSamplingOption {
+    /// Uniform over the area
+    Area,
+    /// Solid angle of each triangle
+    Triangle,
+    /// Solid angle of rectangles (pairs of triangles)
+    Rectangle,
+}
+impl LightSamplingOption {
+    pub fn parse(self) -> rustlight::geometry::DirectSampling {
+        match self {
+            LightSamplingOption::Area => rustlight::geometry::DirectSampling::Area,
+            LightSamplingOption::Triangle => rustlight::geometry::DirectSampling::SphericalTriangle,
+            LightSamplingOption::Rectangle => {
+                rustlight::geometry::DirectSampling::SphericalRectangle
+            }
+        }
+    }
+}
+
 #[derive(Debug, Args)]
 pub struct PathLength {
     #[arg(long, short = 'm', default_value = "inf")]
@@ -139,6 +504,72 @@ pub struct Cli {
     /// Options
     #[arg(long, short)]
     xtra_options: Vec<ExtraOptions>,
+    /// Acceleration structure (default: Embree if available, BVH otherwise)
+    #[arg(long, value_enum)]
+    accel: Option<AccelOption>,
+    /// Directory to cache the BVHs (skip the construction on the next renders)
+    #[arg(long, value_name = "DIR")]
+    bvh_cache: Option<String>,
+    /// Sampling of the mesh emitters for next event estimation
+    #[arg(long, value_enum, default_value = "area")]
+    light_sampling: LightSamplingOption,
+    /// Only render a region of the image: x:y:width:height (pixels)
+    #[arg(long, value_name = "REGION", value_parser = parse_crop)]
+    crop: Option<(cgmath::Point2<u32>, cgmath::Vector2<u32>)>,
+    /// Save the crop region inside the full size image
+    #[arg(long)]
+    crop_full: bool,
+    /// Pixel reconstruction filter
+    #[arg(long, value_enum, default_value = "box")]
+    filter: FilterOption,
+    /// Camera projection: 'latlong', 'fisheye:fov' or 'ortho:half_height'
+    /// (the thin lens is only supported by the perspective projection)
+    #[arg(long, value_name = "PROJECTION", value_parser = parse_camera, conflicts_with = "lens")]
+    camera: Option<CameraOption>,
+    /// Thin lens camera: radius:focus_distance[:aperture]
+    /// (aperture: 'disk', number of blades or image file)
+    #[arg(long, value_name = "LENS", value_parser = parse_lens)]
+    lens: Option<LensOption>,
+    /// Also save the other buffers of the integrator (output_<name>.ext)
+    #[arg(long)]
+    dump_all: bool,
+    /// Add a hair model (.hair file): file[:eumelanin[:pheomelanin]]
+    #[arg(long, value_name = "HAIR", value_parser = parse_hair)]
+    hair: Option<HairOption>,
+    /// Add a spot light (points as x,y,z): from:to:angle[:delta[:intensity]]
+    #[arg(long, value_name = "SPOT", value_parser = parse_spot)]
+    spot: Vec<SpotOption>,
+    /// Add an IES light (points as x,y,z): file:from:to[:scale]
+    #[arg(long, value_name = "IES", value_parser = parse_ies)]
+    ies: Vec<IESOption>,
+    /// Add a projector light (points as x,y,z): image:from:to:fov[:scale]
+    #[arg(long, value_name = "PROJECTOR", value_parser = parse_projector)]
+    projector: Vec<ProjectorOption>,
+    /// Shutter interval (motion blur): open:close
+    #[arg(long, value_name = "INTERVAL", value_parser = parse_shutter)]
+    shutter: Option<(f32, f32)>,
+    /// Camera translation during the shutter interval: x,y,z
+    #[arg(long, value_name = "VECTOR", value_parser = parse_point, requires = "shutter")]
+    camera_motion: Option<cgmath::Point3<f32>>,
+    /// Mesh translation during the shutter interval: name:x,y,z
+    #[arg(long, value_name = "MOTION", value_parser = parse_mesh_motion, requires = "shutter")]
+    animate: Vec<(String, cgmath::Point3<f32>)>,
+    /// Replace the environment by a Hosek-Wilkie sky and its sun
+    /// (ArHosekSkyModelData_RGB.h from the reference implementation)
+    #[arg(long, value_name = "DATASET", requires = "sun")]
+    sky: Option<String>,
+    /// Sun for the sky (+z up, +y north): x,y,z or year-month-day:hour:timezone:latitude:longitude
+    #[arg(long, value_name = "SUN", value_parser = parse_sun)]
+    sun: Option<SunOption>,
+    /// Atmospheric turbidity of the sky [1, 10]
+    #[arg(long, default_value_t = 3.0)]
+    sky_turbidity: f32,
+    /// Ground albedo of the sky [0, 1]
+    #[arg(long, default_value_t = 0.1)]
+    sky_albedo: f32,
+    /// Intensity scale of the sky and the sun
+    #[arg(long, default_value_t = 1.0)]
+    sky_scale: f32,
 
     #[clap(subcommand)]
     command: Commands,
@@ -184,6 +615,9 @@ enum Commands {
         option_lt: String,
         #[arg(long, short = 'v', default_value = "all")]
         option_vpl: String,
+        /// Relative error of the lightcuts (0: gather all the VPLs)
+        #[arg(long, default_value_t = 0.0)]
+        lightcuts: f32,
     },
     // Volume
     VolPrimitivies {
@@ -193,6 +627,12 @@ enum Commands {
         nb_primitive: usize,
         #[arg(long, short, default_value = "BRE")]
         primitives: String,
+        /// Kernel radius of the photons and beams
+        #[arg(long, default_value_t = 0.001, value_parser = parse_radius)]
+        radius: f32,
+        /// Progressive radius reduction ]0, 1[ (with -a)
+        #[arg(long, value_parser = parse_alpha)]
+        alpha: Option<f32>,
     },
     PlaneSingle {
         #[arg(long, short, default_value_t = 128)]
@@ -203,6 +643,12 @@ enum Commands {
         stratified: bool,
         #[arg(long, short = 'k', default_value_t = 4)]
         samples_smis: usize,
+        /// Maximum number of scattering events (1: single scattering only)
+        #[arg(long, default_value = "1")]
+        max_depth: String,
+        /// Russian roulette depth of the light paths
+        #[arg(long, default_value = "0")]
+        rr_depth: String,
     },
     UncorrelatedPlaneSingle {
         #[arg(long, short, default_value_t = 128)]
@@ -213,6 +659,12 @@ enum Commands {
         stratified: bool,
         #[arg(long, short = 'k', default_value_t = 4)]
         samples_smis: usize,
+        /// Maximum number of scattering events (1: single scattering only)
+        #[arg(long, default_value = "1")]
+        max_depth: String,
+        /// Russian roulette depth of the light paths
+        #[arg(long, default_value = "0")]
+        rr_depth: String,
     },
     PointNormal {
         #[arg(long, short = 'z')]
@@ -357,7 +809,18 @@ fn main() {
             }
         },
     };
-    let mut scene = scene.nb_samples(cli.nbsamples).output_img(&cli.output);
+    let scene = match cli.accel {
+        None => scene,
+        Some(v) => scene.accel(v.parse()),
+    };
+    let scene = match &cli.bvh_cache {
+        None => scene,
+        Some(dir) => scene.bvh_cache(dir),
+    };
+    let mut scene = scene
+        .nb_samples(cli.nbsamples)
+        .output_img(&cli.output)
+        .direct_sampling(cli.light_sampling.parse());
 
     ///////////////// Medium
     {
@@ -405,6 +868,41 @@ fn main() {
             info!(" - sigma_t: {:?}", sigma_t);
         }
     }
+    scene.camera.filter = cli.filter.parse();
+    ///////////////// Camera projection
+    if let Some(projection) = &cli.camera {
+        scene.camera.projection = match projection {
+            CameraOption::LatLong => rustlight::camera::Projection::LatLong,
+            CameraOption::Fisheye(fov) => rustlight::camera::Projection::Fisheye {
+                fov: fov.to_radians(),
+            },
+            CameraOption::Ortho(size) => {
+                let aspect_ratio = scene.camera.img.x as f32 / scene.camera.img.y as f32;
+                rustlight::camera::Projection::Orthographic {
+                    size: cgmath::Vector2::new(size * aspect_ratio, *size),
+                }
+            }
+        };
+    }
+    ///////////////// Depth of field
+    if let Some(lens) = &cli.lens {
+        let aperture = match &lens.aperture {
+            ApertureOption::Disk => rustlight::camera::Aperture::Disk,
+            ApertureOption::Blades(blades) => rustlight::camera::Aperture::Polygon {
+                blades: *blades,
+                rotation: 0.0,
+            },
+            ApertureOption::Image(filename) => rustlight::camera::Aperture::from_bitmap(
+                &rustlight::structure::Bitmap::read(filename),
+            ),
+        };
+        scene.camera.lens = Some(rustlight::camera::ThinLens {
+            radius: lens.radius,
+            focus_distance: lens.focus_distance,
+            aperture,
+        });
+    }
+    scene.camera.check_lens();
     ///////////////// Tweak the image size
     {
         let image_scale = cli.scale_image;
@@ -412,8 +910,116 @@ fn main() {
             info!("Scale the image: {:?}", image_scale);
             assert!(image_scale != 0.0);
             scene.camera.scale_image(image_scale);
+            // The crop window of the scene file follows the image
+            if let Some(crop) = scene.crop.take() {
+                let scale = |v: u32| (v as f32 * image_scale).round() as u32;
+                scene = scene.crop(
+                    cgmath::Point2::new(scale(crop.pos.x), scale(crop.pos.y)),
+                    cgmath::Vector2::new(scale(crop.size.x), scale(crop.size.y)),
+                    crop.cropped_output,
+                );
+            }
+        }
+    }
+    ///////////////// Crop window (after the image scaling)
+    let mut scene = match cli.crop {
+        None => scene,
+        Some((pos, size)) => scene.crop(pos, size, !cli.crop_full),
+    };
+    if cli.crop_full {
+        if let Some(crop) = &mut scene.crop {
+            crop.cropped_output = false;
+        }
+    }
+    ///////////////// Hair model
+    if let Some(hair) = &cli.hair {
+        let curves = rustlight::curve::load_hair(std::path::Path::new(&hair.filename))
+            .expect("error on loading the hair");
+        let mut mesh = rustlight::geometry::Mesh::from_curves(hair.filename.clone(), curves)
+            .expect("error on creating the hair");
+        // Default parameters from PBRT
+        mesh.bsdf = Box::new(rustlight::bsdfs::hair::BSDFHair::new(
+            rustlight::bsdfs::hair::HairAbsorption::Concentration {
+                eumelanin: hair.eumelanin,
+                pheomelanin: hair.pheomelanin,
+            },
+            1.55,
+            0.3,
+            0.3,
+            2.0,
+        ));
+        scene.add_mesh(mesh);
+    }
+    ///////////////// Motion blur
+    if let Some((open, close)) = cli.shutter {
+        use cgmath::{EuclideanSpace, SquareMatrix};
+        scene.camera.shutter_open = open;
+        scene.camera.shutter_close = close;
+        // Linear translation over the shutter interval
+        let translation = |t: cgmath::Point3<f32>| {
+            rustlight::geometry::Motion::new(vec![
+                (open, cgmath::Matrix4::identity()),
+                (close, cgmath::Matrix4::from_translation(t.to_vec())),
+            ])
+        };
+        if let Some(t) = cli.camera_motion {
+            scene.camera.motion = Some(translation(t));
+        }
+        for (name, t) in &cli.animate {
+            // The mesh IDs are shifted by animate_mesh, search by name
+            match scene.meshes.iter().position(|m| &m.name == name) {
+                Some(id) => scene.animate_mesh(id, translation(*t)),
+                None => warn!("No mesh named {}, ignoring its motion", name),
+            }
         }
     }
+    ///////////////// Additional lights
+    for spot in &cli.spot {
+        match rustlight::emitter::SpotEmitter::new(
+            rustlight::structure::Color::value(spot.intensity),
+            spot.from,
+            spot.to,
+            spot.angle,
+            spot.delta,
+        ) {
+            Some(emitter) => scene.add_emitter(Box::new(emitter)),
+            None => warn!("Ignoring the spot light: {:?}", spot),
+        }
+    }
+    for ies in &cli.ies {
+        let profile = rustlight::ies::IESProfile::read(std::path::Path::new(&ies.filename))
+            .expect("error on loading the IES profile");
+        scene.add_emitter(Box::new(rustlight::emitter::IESEmitter::new(
+            profile,
+            rustlight::structure::Color::value(ies.scale),
+            light_to_world(ies.from, ies.to),
+        )));
+    }
+    for projector in &cli.projector {
+        scene.add_emitter(Box::new(rustlight::emitter::ProjectorEmitter::new(
+            rustlight::structure::Bitmap::read(&projector.filename),
+            rustlight::structure::Color::value(projector.scale),
+            projector.fov,
+            light_to_world(projector.from, projector.to),
+        )));
+    }
+    ///////////////// Physical sky
+    if let (Some(dataset), Some(sun)) = (&cli.sky, &cli.sun) {
+        let dataset = rustlight::sky::HosekWilkieDataset::read(std::path::Path::new(dataset))
+            .expect("error on loading the sky dataset");
+        let sun_direction = match sun {
+            SunOption::Direction(d) => *d,
+            SunOption::Location(location) => location.sun_direction(),
+        };
+        info!("Sun direction: {:?}", sun_direction);
+        scene.sunsky(rustlight::sky::Sky::new(
+            &dataset,
+            sun_direction,
+            cli.sky_turbidity,
+            rustlight::structure::Color::value(cli.sky_albedo),
+            cli.sky_scale,
+        ));
+    }
     // ///////////////// Overide light is needed
     if hsv_lights || texture_lights {
         for m in &mut scene.meshes {
@@ -614,12 +1220,16 @@ fn main() {
             nb_vpl,
             option_lt,
             option_vpl,
+            lightcuts,
         } => {
             let get_option = |value: String| match value.as_str() {
                 "all" => rustlight::integrators::explicit::vpl::IntegratorVPLOption::All,
                 "surface" => rustlight::integrators::explicit::vpl::IntegratorVPLOption::Surface,
                 "volume" => rustlight::integrators::explicit::vpl::IntegratorVPLOption::Volume,
-                _ => panic!("Invalid options: [all, surface, volume]"),
+                "spherical" => {
+                    rustlight::integrators::explicit::vpl::IntegratorVPLOption::Spherical
+                }
+                _ => panic!("Invalid options: [all, surface, volume, spherical]"),
             };
             let (_min_depth, max_depth, rr_depth) = path_length.parse();
 
@@ -638,6 +1248,11 @@ fn main() {
                     },
                     option_vpl,
                     option_lt,
+                    lightcuts: if lightcuts <= 0.0 {
+                        None
+                    } else {
+                        Some(lightcuts)
+                    },
                 },
             ))
         }
@@ -646,6 +1261,8 @@ fn main() {
             strategy,
             stratified,
             samples_smis,
+            max_depth,
+            rr_depth,
         } => {
             let strategy = match strategy.as_ref() {
                 "uv" => rustlight::integrators::explicit::plane_single::SinglePlaneStrategy::UV,
@@ -670,11 +1287,18 @@ fn main() {
                     strategy
                 )
             };
+            if let Err(e) = strategy.check_emitters(&scene) {
+                Cli::command()
+                    .error(clap::error::ErrorKind::InvalidValue, e)
+                    .exit();
+            }
             IntegratorType::Primal(Box::new(
                 rustlight::integrators::explicit::uncorrelated_plane_single::IntegratorSinglePlaneUncorrelated {
                     nb_primitive,
                     strategy,
-                    stratified
+                    stratified,
+                    max_depth: match_infinity(max_depth),
+                    rr_depth: match_infinity(rr_depth),
                 },
             ))
         }
@@ -683,6 +1307,8 @@ fn main() {
             strategy,
             stratified,
             samples_smis,
+            max_depth,
+            rr_depth,
         } => {
             let strategy = match strategy.as_ref() {
                 "uv" => rustlight::integrators::explicit::plane_single::SinglePlaneStrategy::UV,
@@ -708,11 +1334,18 @@ fn main() {
                     strategy
                 )
             };
+            if let Err(e) = strategy.check_emitters(&scene) {
+                Cli::command()
+                    .error(clap::error::ErrorKind::InvalidValue, e)
+                    .exit();
+            }
             IntegratorType::Primal(Box::new(
                 rustlight::integrators::explicit::plane_single::IntegratorSinglePlane {
                     nb_primitive,
                     strategy,
                     stratified,
+                    max_depth: match_infinity(max_depth),
+                    rr_depth: match_infinity(rr_depth),
                 },
             ))
         }
@@ -720,6 +1353,8 @@ fn main() {
             path_length,
             nb_primitive,
             primitives,
+            radius,
+            alpha,
         } => {
             let (_min_depth, max_depth, rr_depth) = path_length.parse();
             let primitives = match primitives.as_ref() {
@@ -738,6 +1373,17 @@ fn main() {
                     max_depth,
                     rr_depth,
                     primitives,
+                    radius,
+                    progressive: alpha.map(|alpha| {
+                        rustlight::integrators::explicit::vol_primitives::ProgressiveRadius::new(
+                            alpha,
+                        )
+                        .unwrap_or_else(|e| {
+                            Cli::command()
+                                .error(clap::error::ErrorKind::InvalidValue, e)
+                                .exit()
+                        })
+                    }),
                 },
             ))
         }
@@ -948,4 +1594,7 @@ fn main() {
     // Save the image
     info!("Save final image: {}", cli.output);
     img.save("primal", &cli.output);
+    if cli.dump_all {
+        img.dump_all(&cli.output);
+    }
 }
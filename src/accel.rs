@@ -1,14 +1,67 @@
-use std::cmp::Ordering;
 use std::mem::swap;
+use std::path::PathBuf;
+use std::time::Instant;
 
 use crate::constants::EPSILON;
+use crate::geometry::Mesh;
 use crate::scene::Scene;
 use crate::structure::*;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use cgmath::*;
+use std::sync::Arc;
 
 pub trait Acceleration: Sync + Send {
     fn trace(&self, ray: &Ray) -> Option<Intersection>;
     fn visible(&self, p0: &Point3<f32>, p1: &Point3<f32>) -> bool;
+    /// Visibility at a given time (only differs for animated geometries)
+    fn visible_at(&self, p0: &Point3<f32>, p1: &Point3<f32>, _time: f32) -> bool {
+        self.visible(p0, p1)
+    }
+    /// Visibility from a surface point
+    /// (the origin is offset with the intersection error bounds)
+    fn visible_its(&self, its: &Intersection, p1: &Point3<f32>) -> bool {
+        self.visible_at(&its.spawn_origin(&(p1 - its.p)), p1, its.time)
+    }
+
+    /// Trace a packet of coherent rays (camera rays for example)
+    fn trace_packet(&self, rays: &[Ray]) -> Vec<Option<Intersection>> {
+        rays.iter().map(|r| self.trace(r)).collect()
+    }
+    /// Visibility of a packet of coherent segments (shadow rays for example)
+    fn visible_packet(&self, segments: &[(Point3<f32>, Point3<f32>)], time: f32) -> Vec<bool> {
+        segments
+            .iter()
+            .map(|(p0, p1)| self.visible_at(p0, p1, time))
+            .collect()
+    }
+}
+
+/// Occlusion ray between two points
+/// The far end is moved back by the error bounds of an intersection
+/// point computed at p1 (PBRT) and by a small fraction of the length
+fn shadow_ray(p0: &Point3<f32>, p1: &Point3<f32>, time: f32) -> (Ray, IntersectionUV) {
+    const SHADOW_EPS: f32 = 0.00001;
+    let mut d = p1 - p0;
+    let length = d.magnitude();
+    d /= length;
+    let p1_error = crate::math::gamma(7) * crate::math::abs_vec(&p1.to_vec());
+    let tfar = length * (1.0 - SHADOW_EPS) - crate::math::abs_vec(&d).dot(p1_error);
+    (
+        Ray {
+            o: *p0,
+            d,
+            tnear: EPSILON,
+            tfar,
+            time,
+        },
+        IntersectionUV {
+            t: tfar,
+            p: Point3::new(0.0, 0.0, 0.0),
+            n: Vector3::new(0.0, 0.0, 0.0),
+            u: 0.0,
+            v: 0.0,
+        },
+    )
 }
 
 pub struct NaiveAcceleration<'scene> {
@@ -32,8 +85,8 @@ impl<'a> Acceleration for NaiveAcceleration<'a> {
 
         for m in 0..self.scene.meshes.len() {
             let mesh = &self.scene.meshes[m];
-            for i in 0..mesh.indices.len() {
-                if mesh.intersection_tri(i, &ray.o, &ray.d, &mut its) {
+            for i in 0..mesh.nb_primitives() {
+                if mesh.intersection_primitive(i, &ray.o, &ray.d, &mut its) {
                     id_m = m;
                     id_t = i;
                 }
@@ -50,24 +103,11 @@ impl<'a> Acceleration for NaiveAcceleration<'a> {
         }
     }
     fn visible(&self, p0: &Point3<f32>, p1: &Point3<f32>) -> bool {
-        const SHADOW_EPS: f32 = 0.00001;
-        // Compute ray dir
-        let mut d = p1 - p0;
-        let length = d.magnitude();
-        d /= length;
-
-        let mut its = IntersectionUV {
-            t: length * (1.0 - SHADOW_EPS),
-            p: Point3::new(0.0, 0.0, 0.0),
-            n: Vector3::new(0.0, 0.0, 0.0),
-            u: 0.0,
-            v: 0.0,
-        };
-
+        let (ray, mut its) = shadow_ray(p0, p1, 0.0);
         for m in 0..self.scene.meshes.len() {
             let mesh = &self.scene.meshes[m];
-            for i in 0..mesh.indices.len() {
-                if mesh.intersection_tri(i, &p0, &d, &mut its) {
+            for i in 0..mesh.nb_primitives() {
+                if mesh.intersection_primitive(i, &ray.o, &ray.d, &mut its) {
                     return false;
                 }
             }
@@ -85,6 +125,13 @@ impl BVHNode {
     pub fn is_leaf(&self) -> bool {
         self.count != 0
     }
+    fn empty() -> BVHNode {
+        BVHNode {
+            aabb: AABB::default(),
+            info: 0,
+            count: 0,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -93,148 +140,494 @@ pub struct TriRef {
     id_tri: usize,
 }
 
-pub struct CachedAABB {
+pub struct CachedAABB<T> {
     aabb: AABB,
-    info: TriRef,
+    info: T,
 }
 
 pub struct BVHAccel<'scene> {
     primitives: Vec<TriRef>,
     nodes: Vec<BVHNode>,
-    scene: &'scene Scene,
+    meshes: &'scene [Arc<Mesh>],
 }
 
-fn compute_aabb(aabbs: &Vec<CachedAABB>, start: usize, count: usize) -> AABB {
-    let mut aabb = AABB::default();
-    for i in 0..count {
-        aabb = aabb.union_aabb(&aabbs[i + start].aabb);
+/// Options for the BVH construction
+#[derive(Clone, Debug)]
+pub struct BVHBuildOptions {
+    /// Maximum number of primitives inside a leaf
+    pub leaf_size: usize,
+    /// Maximum depth of the tree (leafs are forced after)
+    pub max_depth: usize,
+    /// Number of bins used to evaluate the SAH
+    pub nb_bins: usize,
+    /// Directory where the BVHs are cached (no cache if None)
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl Default for BVHBuildOptions {
+    fn default() -> Self {
+        BVHBuildOptions {
+            leaf_size: 2,
+            max_depth: 64,
+            nb_bins: 16,
+            cache_dir: None,
+        }
+    }
+}
+
+/// Quality of the constructed BVH
+#[derive(Default, Debug)]
+pub struct BVHBuildStats {
+    pub nb_nodes: usize,
+    pub nb_leafs: usize,
+    pub max_depth: usize,
+    /// SAH cost of the whole tree (relative to the root surface area)
+    pub sah_cost: f32,
+}
+
+// Cost of a traversal step relative to a primitive intersection (same as PBRT)
+const SAH_TRAVERSAL_COST: f32 = 0.125;
+// Under this number of primitives, the subtree is built on the current thread
+const PARALLEL_BUILD_THRESHOLD: usize = 4096;
+
+/// Intermediate representation used during the construction
+enum BuildNode {
+    Leaf {
+        aabb: AABB,
+        first: usize,
+        count: usize,
+    },
+    Inner {
+        aabb: AABB,
+        left: Box<BuildNode>,
+        right: Box<BuildNode>,
+    },
+}
+
+impl BuildNode {
+    fn aabb(&self) -> &AABB {
+        match self {
+            BuildNode::Leaf { aabb, .. } | BuildNode::Inner { aabb, .. } => aabb,
+        }
+    }
+
+    fn compute_stats(&self, root_area: f32, depth: usize, stats: &mut BVHBuildStats) {
+        let area = if root_area > 0.0 {
+            self.aabb().surface_area() / root_area
+        } else {
+            1.0
+        };
+        stats.nb_nodes += 1;
+        stats.max_depth = stats.max_depth.max(depth);
+        match self {
+            BuildNode::Leaf { count, .. } => {
+                stats.nb_leafs += 1;
+                stats.sah_cost += area * *count as f32;
+            }
+            BuildNode::Inner { left, right, .. } => {
+                stats.sah_cost += area * SAH_TRAVERSAL_COST;
+                left.compute_stats(root_area, depth + 1, stats);
+                right.compute_stats(root_area, depth + 1, stats);
+            }
+        }
+    }
+
+    /// Flatten the tree where the two children of a node are contiguous
+    fn flatten(self, id_node: usize, nodes: &mut Vec<BVHNode>) {
+        match self {
+            BuildNode::Leaf { aabb, first, count } => {
+                nodes[id_node] = BVHNode {
+                    aabb,
+                    info: first,
+                    count,
+                };
+            }
+            BuildNode::Inner { aabb, left, right } => {
+                let id_left = nodes.len();
+                nodes[id_node] = BVHNode {
+                    aabb,
+                    info: id_left,
+                    count: 0,
+                };
+                nodes.push(BVHNode::empty());
+                nodes.push(BVHNode::empty());
+                left.flatten(id_left, nodes);
+                right.flatten(id_left + 1, nodes);
+            }
+        }
+    }
+
+    /// Flatten the tree where the children are stored before their parent
+    /// return the ID of the node
+    fn flatten_general(self, nodes: &mut Vec<BVHNodeGeneral>) -> usize {
+        let node = match self {
+            BuildNode::Leaf { aabb, first, count } => BVHNodeGeneral {
+                aabb,
+                first,
+                count,
+                left: None,
+                right: None,
+            },
+            BuildNode::Inner { aabb, left, right } => {
+                let left = left.flatten_general(nodes);
+                let right = right.flatten_general(nodes);
+                BVHNodeGeneral {
+                    aabb,
+                    first: 0,
+                    count: 0,
+                    left: Some(left),
+                    right: Some(right),
+                }
+            }
+        };
+        nodes.push(node);
+        nodes.len() - 1
     }
-    aabb
 }
 
-fn subdivide_node(
-    id_node: usize,
-    aabbs: &mut Vec<CachedAABB>,
-    nodes: &mut Vec<BVHNode>,
+/// Recursive binned SAH construction
+/// The primitives are reordered so that each leaf covers a contiguous range
+fn build_binned<T: Send>(
+    aabbs: &mut [CachedAABB<T>],
+    first: usize,
     depth: usize,
-) {
-    // Decision to transform the node or not
-    let (nb_prim, first_prim) = {
-        if nodes[id_node].count <= 2 {
-            return; // Stop here
-        }
-
-        let nb_prim = nodes[id_node].count;
-        let first_prim = nodes[id_node].info;
-        nodes[id_node].count = 0; // Transform to node
-        nodes[id_node].info = nodes.len(); // Ptr to the next node that we will create
-        (nb_prim, first_prim)
+    options: &BVHBuildOptions,
+) -> BuildNode {
+    let count = aabbs.len();
+    let mut aabb = AABB::default();
+    let mut centroids = AABB::default();
+    for c in aabbs.iter() {
+        aabb = aabb.union_aabb(&c.aabb);
+        centroids = centroids.union_vec(&c.aabb.center());
+    }
+    if count <= 1 || depth >= options.max_depth {
+        return BuildNode::Leaf { aabb, first, count };
+    }
+
+    // Evaluate the SAH for all the bins boundaries
+    let nb_bins = options.nb_bins.max(2);
+    let extent = centroids.size();
+    let bin_index = |c: &CachedAABB<T>, axis: usize| {
+        let b = (c.aabb.center()[axis] - centroids.p_min[axis]) / extent[axis] * nb_bins as f32;
+        (b as usize).min(nb_bins - 1)
     };
+    let mut best: Option<(usize, usize, f32)> = None; // (axis, bin, cost)
+    for axis in 0..3 {
+        if extent[axis] <= 0.0 {
+            continue;
+        }
+        let mut bins_count = vec![0usize; nb_bins];
+        let mut bins_aabb = vec![AABB::default(); nb_bins];
+        for c in aabbs.iter() {
+            let b = bin_index(c, axis);
+            bins_count[b] += 1;
+            bins_aabb[b] = bins_aabb[b].union_aabb(&c.aabb);
+        }
 
-    let offset = {
-        // Use SAH Sweep
-        let mut best_pos = 0;
-        let mut best_cost = std::f32::INFINITY;
-        let mut best_axis = 3;
-        {
-            let mut scores = vec![0.0; nb_prim - 1];
-            for o in 0..3 {
-                aabbs[first_prim..first_prim + nb_prim].sort_by(|a, b| -> Ordering {
-                    if a.aabb.center()[o] < b.aabb.center()[o] {
-                        Ordering::Less
-                    } else {
-                        Ordering::Greater
-                    }
-                });
-                let mut tmp = AABB::default();
-                for id in 0..nb_prim - 1 {
-                    let id_left = nb_prim - id - 1;
-                    tmp = tmp.union_aabb(&aabbs[id_left + first_prim].aabb);
-                    scores[id_left - 1] = tmp.surface_area() * (id + 1) as f32;
-                }
-                tmp = AABB::default();
-                for id in 0..nb_prim - 1 {
-                    tmp = tmp.union_aabb(&aabbs[id + first_prim].aabb);
-                    scores[id] += tmp.surface_area() * (id + 1) as f32;
-                    if scores[id] < best_cost {
-                        best_cost = scores[id];
-                        best_axis = o;
-                        best_pos = id + 1;
-                    }
-                }
+        let mut right_costs = vec![0.0; nb_bins - 1];
+        let mut tmp = AABB::default();
+        let mut n = 0;
+        for b in (1..nb_bins).rev() {
+            tmp = tmp.union_aabb(&bins_aabb[b]);
+            n += bins_count[b];
+            if n != 0 {
+                right_costs[b - 1] = tmp.surface_area() * n as f32;
             }
         }
-        // Sort on the selected axis
-        aabbs[first_prim..first_prim + nb_prim].sort_by(|a, b| -> Ordering {
-            if a.aabb.center()[best_axis] < b.aabb.center()[best_axis] {
-                Ordering::Less
-            } else {
-                Ordering::Greater
+        tmp = AABB::default();
+        n = 0;
+        for b in 0..nb_bins - 1 {
+            tmp = tmp.union_aabb(&bins_aabb[b]);
+            n += bins_count[b];
+            let mut cost = right_costs[b];
+            if n != 0 {
+                cost += tmp.surface_area() * n as f32;
+            }
+            if best.map_or(true, |(_, _, c)| cost < c) {
+                best = Some((axis, b, cost));
             }
-        });
-        if best_pos == nb_prim || best_pos == 0 {
-            ((nb_prim as f32 * 0.5) as usize).max(1)
-        } else {
-            best_pos
         }
+    }
+
+    // Decide to make a leaf or not
+    let area = aabb.surface_area();
+    let split_cost = match best {
+        Some((_, _, cost)) if area > 0.0 => SAH_TRAVERSAL_COST + cost / area,
+        _ => std::f32::INFINITY,
     };
+    if count <= options.leaf_size && split_cost >= count as f32 {
+        return BuildNode::Leaf { aabb, first, count };
+    }
 
-    let left = BVHNode {
-        aabb: compute_aabb(aabbs, first_prim, offset),
-        info: first_prim,
-        count: offset,
+    // Partition the primitives
+    let mut mid = 0;
+    if let Some((axis, bin, _)) = best {
+        for i in 0..count {
+            if bin_index(&aabbs[i], axis) <= bin {
+                aabbs.swap(i, mid);
+                mid += 1;
+            }
+        }
+    }
+    if mid == 0 || mid == count {
+        // All the centroids are at the same position
+        mid = count / 2;
+    }
+
+    let (left, right) = aabbs.split_at_mut(mid);
+    let (left, right) = if count > PARALLEL_BUILD_THRESHOLD {
+        rayon::join(
+            || build_binned(left, first, depth + 1, options),
+            || build_binned(right, first + mid, depth + 1, options),
+        )
+    } else {
+        (
+            build_binned(left, first, depth + 1, options),
+            build_binned(right, first + mid, depth + 1, options),
+        )
     };
-    let right = BVHNode {
-        aabb: compute_aabb(aabbs, first_prim + offset, nb_prim - offset),
-        info: first_prim + offset,
-        count: nb_prim - offset,
+    BuildNode::Inner {
+        aabb,
+        left: Box::new(left),
+        right: Box::new(right),
+    }
+}
+
+/// Compute AABB cached for all the primitives of the meshes
+fn mesh_primitives(meshes: &[Arc<Mesh>]) -> Vec<CachedAABB<TriRef>> {
+    let mut cached_aabbs = Vec::new();
+    for m in 0..meshes.len() {
+        let mesh = &meshes[m];
+        for i in 0..mesh.nb_primitives() {
+            cached_aabbs.push(CachedAABB {
+                aabb: mesh.compute_aabb_primitive(i),
+                info: TriRef {
+                    id_mesh: m,
+                    id_tri: i,
+                },
+            });
+        }
+    }
+    cached_aabbs
+}
+
+/// Node array with the root at the first position
+fn flatten_bvh(root: Option<BuildNode>) -> Vec<BVHNode> {
+    let mut nodes = vec![BVHNode::empty()];
+    if let Some(root) = root {
+        root.flatten(0, &mut nodes);
+    }
+    nodes
+}
+
+/// Inverse of flatten_bvh (used to collapse cached BVHs)
+fn unflatten_bvh(nodes: &[BVHNode], id_node: usize) -> BuildNode {
+    let node = &nodes[id_node];
+    if node.is_leaf() {
+        BuildNode::Leaf {
+            aabb: node.aabb.clone(),
+            first: node.info,
+            count: node.count,
+        }
+    } else {
+        BuildNode::Inner {
+            aabb: node.aabb.clone(),
+            left: Box::new(unflatten_bvh(nodes, node.info)),
+            right: Box::new(unflatten_bvh(nodes, node.info + 1)),
+        }
+    }
+}
+
+/// Hash of the primitives AABB and the build options (FNV-1a)
+/// used to identify cached BVHs
+fn hash_primitives(aabbs: &[CachedAABB<TriRef>], options: &BVHBuildOptions) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    let mut add = |v: u64| {
+        for b in v.to_le_bytes().iter() {
+            hash ^= *b as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
     };
-    let id_left = nodes.len();
-    nodes.push(left);
-    nodes.push(right);
+    add(options.leaf_size as u64);
+    add(options.max_depth as u64);
+    add(options.nb_bins as u64);
+    add(aabbs.len() as u64);
+    for c in aabbs {
+        add(c.info.id_mesh as u64);
+        add(c.info.id_tri as u64);
+        for i in 0..3 {
+            add(c.aabb.p_min[i].to_bits() as u64);
+            add(c.aabb.p_max[i].to_bits() as u64);
+        }
+    }
+    hash
+}
+
+const BVH_CACHE_MAGIC: u32 = 0x4856_4252; // "RBVH"
+const BVH_CACHE_VERSION: u32 = 1;
+/// Sizes (in bytes) of the BVH cache entries
+const BVH_CACHE_HEADER_SIZE: u64 = 4 + 4 + 8 + 8; // with the two counts
+const BVH_CACHE_PRIMITIVE_SIZE: u64 = 8 + 8;
+const BVH_CACHE_NODE_SIZE: u64 = 6 * 4 + 8 + 8;
 
-    // Do the recursive calls
-    subdivide_node(id_left, aabbs, nodes, depth + 1);
-    subdivide_node(id_left + 1, aabbs, nodes, depth + 1);
+fn write_bvh_cache(
+    path: &std::path::Path,
+    primitives: &[TriRef],
+    nodes: &[BVHNode],
+) -> std::io::Result<()> {
+    let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+    f.write_u32::<LittleEndian>(BVH_CACHE_MAGIC)?;
+    f.write_u32::<LittleEndian>(BVH_CACHE_VERSION)?;
+    f.write_u64::<LittleEndian>(primitives.len() as u64)?;
+    for p in primitives {
+        f.write_u64::<LittleEndian>(p.id_mesh as u64)?;
+        f.write_u64::<LittleEndian>(p.id_tri as u64)?;
+    }
+    f.write_u64::<LittleEndian>(nodes.len() as u64)?;
+    for n in nodes {
+        for i in 0..3 {
+            f.write_f32::<LittleEndian>(n.aabb.p_min[i])?;
+        }
+        for i in 0..3 {
+            f.write_f32::<LittleEndian>(n.aabb.p_max[i])?;
+        }
+        f.write_u64::<LittleEndian>(n.info as u64)?;
+        f.write_u64::<LittleEndian>(n.count as u64)?;
+    }
+    Ok(())
 }
 
-impl<'scene> BVHAccel<'scene> {
-    pub fn new(scene: &'scene Scene) -> BVHAccel<'scene> {
-        // Compute AABB cached
-        let mut root_aabb = AABB::default();
-        let mut cached_aabbs = Vec::new();
-        for m in 0..scene.meshes.len() {
-            let mesh = &scene.meshes[m];
-            for i in 0..mesh.indices.len() {
-                cached_aabbs.push(CachedAABB {
-                    aabb: mesh.compute_aabb_tri(i),
-                    info: TriRef {
-                        id_mesh: m,
-                        id_tri: i,
-                    },
-                });
-                root_aabb = root_aabb.union_aabb(&cached_aabbs.last().unwrap().aabb)
+fn read_bvh_cache(path: &std::path::Path) -> std::io::Result<(Vec<TriRef>, Vec<BVHNode>)> {
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string());
+    let f = std::fs::File::open(path)?;
+    let file_size = f.metadata()?.len();
+    let mut f = std::io::BufReader::new(f);
+    if f.read_u32::<LittleEndian>()? != BVH_CACHE_MAGIC
+        || f.read_u32::<LittleEndian>()? != BVH_CACHE_VERSION
+    {
+        return Err(invalid("wrong BVH cache header"));
+    }
+    // Check the counts against the file size before allocating
+    let nb_primitives = f.read_u64::<LittleEndian>()?;
+    if nb_primitives > file_size.saturating_sub(BVH_CACHE_HEADER_SIZE) / BVH_CACHE_PRIMITIVE_SIZE {
+        return Err(invalid("truncated BVH cache"));
+    }
+    let nb_primitives = nb_primitives as usize;
+    let mut primitives = Vec::with_capacity(nb_primitives);
+    for _ in 0..nb_primitives {
+        primitives.push(TriRef {
+            id_mesh: f.read_u64::<LittleEndian>()? as usize,
+            id_tri: f.read_u64::<LittleEndian>()? as usize,
+        });
+    }
+    let nb_nodes = f.read_u64::<LittleEndian>()?;
+    let nodes_size =
+        file_size - BVH_CACHE_HEADER_SIZE - nb_primitives as u64 * BVH_CACHE_PRIMITIVE_SIZE;
+    if nb_nodes.checked_mul(BVH_CACHE_NODE_SIZE) != Some(nodes_size) {
+        return Err(invalid("truncated BVH cache"));
+    }
+    let nb_nodes = nb_nodes as usize;
+    let mut nodes = Vec::with_capacity(nb_nodes);
+    for _ in 0..nb_nodes {
+        let mut aabb = AABB::default();
+        for i in 0..3 {
+            aabb.p_min[i] = f.read_f32::<LittleEndian>()?;
+        }
+        for i in 0..3 {
+            aabb.p_max[i] = f.read_f32::<LittleEndian>()?;
+        }
+        let info = f.read_u64::<LittleEndian>()? as usize;
+        let count = f.read_u64::<LittleEndian>()? as usize;
+        // Check the references to avoid out of bounds accesses
+        if (count == 0 && nb_primitives != 0 && (info <= nodes.len() || info + 1 >= nb_nodes))
+            || (count != 0 && info + count > nb_primitives)
+        {
+            return Err(invalid("corrupted BVH cache"));
+        }
+        nodes.push(BVHNode { aabb, info, count });
+    }
+    if nodes.is_empty() {
+        return Err(invalid("empty BVH cache"));
+    }
+    Ok((primitives, nodes))
+}
+
+/// Binary BVH over all the primitives of the meshes
+/// If a cache directory is given, the BVH is read from (or written to)
+/// a file identified by the hash of the geometry
+fn build_mesh_bvh(meshes: &[Arc<Mesh>], options: &BVHBuildOptions) -> (Vec<TriRef>, Vec<BVHNode>) {
+    let mut cached_aabbs = mesh_primitives(meshes);
+    let cache_file = options.cache_dir.as_ref().map(|dir| {
+        dir.join(format!(
+            "bvh_{:016x}.bin",
+            hash_primitives(&cached_aabbs, options)
+        ))
+    });
+
+    if let Some(path) = &cache_file {
+        if path.exists() {
+            match read_bvh_cache(path) {
+                Ok(v) => {
+                    info!("BVH loaded from cache: {:?}", path);
+                    return v;
+                }
+                Err(e) => warn!("Impossible to read the BVH cache {:?}: {}", path, e),
             }
         }
+    }
 
-        // Construct the root node
-        let mut nodes = Vec::new();
-        let root = BVHNode {
-            aabb: root_aabb,
-            info: 0,
-            count: cached_aabbs.len(),
-        };
-        nodes.push(root);
+    let nodes = flatten_bvh(build_bvh(&mut cached_aabbs, options));
+    let primitives = cached_aabbs.into_iter().map(|c| c.info).collect::<Vec<_>>();
+
+    if let Some(path) = &cache_file {
+        let res = std::fs::create_dir_all(path.parent().unwrap())
+            .and_then(|_| write_bvh_cache(path, &primitives, &nodes));
+        match res {
+            Ok(_) => info!("BVH saved to cache: {:?}", path),
+            Err(e) => warn!("Impossible to write the BVH cache {:?}: {}", path, e),
+        }
+    }
+    (primitives, nodes)
+}
+
+/// Build the BVH hierarchy and report the statistics
+fn build_bvh<T: Send>(
+    aabbs: &mut Vec<CachedAABB<T>>,
+    options: &BVHBuildOptions,
+) -> Option<BuildNode> {
+    if aabbs.is_empty() {
+        return None;
+    }
+
+    let start = Instant::now();
+    let root = build_binned(&mut aabbs[..], 0, 0, options);
+    let elapsed = start.elapsed();
+
+    let mut stats = BVHBuildStats::default();
+    root.compute_stats(root.aabb().surface_area(), 0, &mut stats);
+    info!("BVH stats: ");
+    info!(" - Build time: {:?}", elapsed);
+    info!(" - Number of elements: {}", aabbs.len());
+    info!(
+        " - Number of nodes: {} ({} leafs)",
+        stats.nb_nodes, stats.nb_leafs
+    );
+    info!(" - Max depth: {}", stats.max_depth);
+    info!(" - SAH cost: {}", stats.sah_cost);
+    Some(root)
+}
 
-        // Subdivide recursively the root node
-        subdivide_node(0, &mut cached_aabbs, &mut nodes, 0);
+impl<'scene> BVHAccel<'scene> {
+    pub fn new(scene: &'scene Scene) -> BVHAccel<'scene> {
+        BVHAccel::from_meshes(&scene.meshes, &BVHBuildOptions::default())
+    }
 
-        // Generate list of primitives
-        let primitives = cached_aabbs.iter().map(|c| c.info.clone()).collect();
+    pub fn from_meshes(meshes: &'scene [Arc<Mesh>], options: &BVHBuildOptions) -> BVHAccel<'scene> {
+        let (primitives, nodes) = build_mesh_bvh(meshes, options);
         BVHAccel {
             primitives,
             nodes,
-            scene,
+            meshes,
         }
     }
 }
@@ -246,7 +639,7 @@ impl<'a> BVHAccel<'a> {
             let mut res = None;
             for k in 0..node.count {
                 let e = &self.primitives[node.info + k];
-                if self.scene.meshes[e.id_mesh].intersection_tri(e.id_tri, &ray.o, &ray.d, its) {
+                if self.meshes[e.id_mesh].intersection_primitive(e.id_tri, &ray.o, &ray.d, its) {
                     res = Some(e.clone());
                 }
             }
@@ -286,6 +679,15 @@ impl<'a> BVHAccel<'a> {
             res
         }
     }
+
+    /// Intersect from the root node (if the ray hits the root AABB)
+    pub fn intersect_root(&self, ray: &Ray, its: &mut IntersectionUV) -> Option<TriRef> {
+        if self.primitives.is_empty() || self.nodes[0].aabb.intersect(ray).is_none() {
+            None
+        } else {
+            self.intersect(0, ray, its)
+        }
+    }
 }
 
 impl<'a> Acceleration for BVHAccel<'a> {
@@ -307,39 +709,603 @@ impl<'a> Acceleration for BVHAccel<'a> {
             None
         } else {
             let res = res.unwrap();
-            let mesh = &self.scene.meshes[res.id_mesh];
+            let mesh = &self.meshes[res.id_mesh];
             Some(Intersection::fill_intersection(
                 mesh, res.id_tri, its.u, its.v, ray, its.n, its.t, its.p,
             ))
         }
     }
     fn visible(&self, p0: &Point3<f32>, p1: &Point3<f32>) -> bool {
-        const SHADOW_EPS: f32 = 0.00001;
-        // Compute ray dir
-        let mut d = p1 - p0;
-        let length = d.magnitude();
-        d /= length;
+        let (ray, mut its) = shadow_ray(p0, p1, 0.0);
+        // Segments missing the scene bounds are visible
+        // (e.g. point emitters outside the geometry)
+        self.intersect_root(&ray, &mut its).is_none()
+    }
+}
+
+/**
+ * Wide BVH (BVH4 / BVH8)
+ * Collapsed from the binary BVH. The children bounding boxes are stored
+ * in SoA layout so the box tests are done for all the children at once.
+ */
+pub type BVH4Accel<'scene> = WideBVHAccel<'scene, 4>;
+pub type BVH8Accel<'scene> = WideBVHAccel<'scene, 8>;
+
+struct WideNode<const N: usize> {
+    p_min: [[f32; N]; 3],
+    p_max: [[f32; N]; 3],
+    // Node ID for inner children, first primitive for leafs
+    child: [usize; N],
+    // Number of primitives (0 for inner children)
+    count: [usize; N],
+}
+
+impl<const N: usize> WideNode<N> {
+    /// The unused slots have an inverted AABB (never intersected)
+    fn empty() -> WideNode<N> {
+        WideNode {
+            p_min: [[std::f32::INFINITY; N]; 3],
+            p_max: [[-std::f32::INFINITY; N]; 3],
+            child: [0; N],
+            count: [0; N],
+        }
+    }
+
+    /// Entry distance inside each child AABB (infinity if missed)
+    #[inline]
+    fn intersect_children(
+        &self,
+        o: &Point3<f32>,
+        inv_d: &Vector3<f32>,
+        t_min: f32,
+        t_max: f32,
+    ) -> [f32; N] {
+        let mut t_near = [t_min; N];
+        let mut t_far = [t_max; N];
+        for a in 0..3 {
+            // Near and far planes selected by the ray direction
+            // so the inverted AABBs of the unused slots are always missed
+            let (near, far) = if inv_d[a] >= 0.0 {
+                (&self.p_min[a], &self.p_max[a])
+            } else {
+                (&self.p_max[a], &self.p_min[a])
+            };
+            for i in 0..N {
+                t_near[i] = t_near[i].max((near[i] - o[a]) * inv_d[a]);
+                t_far[i] = t_far[i].min((far[i] - o[a]) * inv_d[a]);
+            }
+        }
+        let mut dists = [std::f32::INFINITY; N];
+        for i in 0..N {
+            if t_near[i] <= t_far[i] {
+                dists[i] = t_near[i];
+            }
+        }
+        dists
+    }
+}
+
+/// Collapse the binary node (by opening the biggest children)
+/// return the ID of the wide node
+fn collapse_node<const N: usize>(node: BuildNode, nodes: &mut Vec<WideNode<N>>) -> usize {
+    let mut children = match node {
+        BuildNode::Leaf { .. } => vec![node],
+        BuildNode::Inner { left, right, .. } => vec![*left, *right],
+    };
+    while children.len() < N {
+        let biggest = children
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| matches!(c, BuildNode::Inner { .. }))
+            .max_by(|(_, a), (_, b)| {
+                a.aabb()
+                    .surface_area()
+                    .partial_cmp(&b.aabb().surface_area())
+                    .unwrap()
+            })
+            .map(|(i, _)| i);
+        match biggest {
+            None => break,
+            Some(i) => {
+                if let BuildNode::Inner { left, right, .. } = children.swap_remove(i) {
+                    children.push(*left);
+                    children.push(*right);
+                }
+            }
+        }
+    }
+
+    let id_node = nodes.len();
+    nodes.push(WideNode::empty());
+    let mut wide = WideNode::empty();
+    for (i, c) in children.into_iter().enumerate() {
+        let aabb = c.aabb().clone();
+        for a in 0..3 {
+            wide.p_min[a][i] = aabb.p_min[a];
+            wide.p_max[a][i] = aabb.p_max[a];
+        }
+        match c {
+            BuildNode::Leaf { first, count, .. } => {
+                wide.child[i] = first;
+                wide.count[i] = count;
+            }
+            BuildNode::Inner { .. } => {
+                wide.child[i] = collapse_node(c, nodes);
+            }
+        }
+    }
+    nodes[id_node] = wide;
+    id_node
+}
+
+/// Order the children hit by the ray (the farthest first)
+#[inline]
+fn order_children<const N: usize>(dists: &[f32; N]) -> ([usize; N], usize) {
+    let mut order = [0; N];
+    let mut nb = 0;
+    for i in 0..N {
+        if dists[i] != std::f32::INFINITY {
+            order[nb] = i;
+            nb += 1;
+        }
+    }
+    order[..nb].sort_unstable_by(|a, b| dists[*b].partial_cmp(&dists[*a]).unwrap());
+    (order, nb)
+}
+
+fn inv_dir(d: &Vector3<f32>) -> Vector3<f32> {
+    Vector3::new(1.0 / d.x, 1.0 / d.y, 1.0 / d.z)
+}
+
+pub struct WideBVHAccel<'scene, const N: usize> {
+    primitives: Vec<TriRef>,
+    nodes: Vec<WideNode<N>>,
+    meshes: &'scene [Arc<Mesh>],
+}
+
+impl<'scene, const N: usize> WideBVHAccel<'scene, N> {
+    /// Only the static meshes are used: the (animated) instances
+    /// need the instance BVH (see build_acceleration)
+    pub fn new(scene: &'scene Scene) -> WideBVHAccel<'scene, N> {
+        if !scene.instances.is_empty() {
+            warn!(
+                "The wide BVH ignores the {} instances (motion blur), use the instance BVH instead",
+                scene.instances.len()
+            );
+        }
+        WideBVHAccel::from_meshes(
+            &scene.meshes,
+            &BVHBuildOptions {
+                leaf_size: 4,
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn from_meshes(
+        meshes: &'scene [Arc<Mesh>],
+        options: &BVHBuildOptions,
+    ) -> WideBVHAccel<'scene, N> {
+        let (primitives, binary_nodes) = build_mesh_bvh(meshes, options);
+        let mut nodes = Vec::new();
+        if !primitives.is_empty() {
+            collapse_node(unflatten_bvh(&binary_nodes, 0), &mut nodes);
+            info!(" - Number of wide nodes (BVH{}): {}", N, nodes.len());
+        }
+        WideBVHAccel {
+            primitives,
+            nodes,
+            meshes,
+        }
+    }
+
+    fn intersect_leaf(
+        &self,
+        first: usize,
+        count: usize,
+        ray: &Ray,
+        its: &mut IntersectionUV,
+        any_hit: bool,
+    ) -> Option<TriRef> {
+        let mut res = None;
+        for e in &self.primitives[first..first + count] {
+            if self.meshes[e.id_mesh].intersection_primitive(e.id_tri, &ray.o, &ray.d, its) {
+                res = Some(e.clone());
+                if any_hit {
+                    break;
+                }
+            }
+        }
+        res
+    }
+
+    /// Stack based traversal
+    /// If any_hit is true, the traversal stops at the first intersection found
+    fn intersect(&self, ray: &Ray, its: &mut IntersectionUV, any_hit: bool) -> Option<TriRef> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let inv_d = inv_dir(&ray.d);
+        // (child, count, entry distance)
+        let mut stack: Vec<(usize, usize, f32)> = Vec::with_capacity(64);
+        stack.push((0, 0, ray.tnear));
+        let mut res = None;
+        while let Some((child, count, t)) = stack.pop() {
+            if t >= its.t {
+                continue;
+            }
+            if count != 0 {
+                if let Some(e) = self.intersect_leaf(child, count, ray, its, any_hit) {
+                    res = Some(e);
+                    if any_hit {
+                        return res;
+                    }
+                }
+                continue;
+            }
+
+            let node = &self.nodes[child];
+            let dists = node.intersect_children(&ray.o, &inv_d, ray.tnear, its.t.min(ray.tfar));
+            let (order, nb) = order_children(&dists);
+            for &i in &order[..nb] {
+                stack.push((node.child[i], node.count[i], dists[i]));
+            }
+        }
+        res
+    }
+
+    /// Packet traversal: a node is visited if one of the active rays hits it
+    fn intersect_packet(
+        &self,
+        rays: &[Ray],
+        its: &mut [IntersectionUV],
+        any_hit: bool,
+    ) -> Vec<Option<TriRef>> {
+        let mut res = vec![None; rays.len()];
+        if self.nodes.is_empty() {
+            return res;
+        }
+        let inv_d = rays.iter().map(|r| inv_dir(&r.d)).collect::<Vec<_>>();
+        let mut done = vec![false; rays.len()];
+        // (child, count)
+        let mut stack: Vec<(usize, usize)> = Vec::with_capacity(64);
+        stack.push((0, 0));
+        while let Some((child, count)) = stack.pop() {
+            if count != 0 {
+                for r in 0..rays.len() {
+                    if done[r] {
+                        continue;
+                    }
+                    if let Some(e) =
+                        self.intersect_leaf(child, count, &rays[r], &mut its[r], any_hit)
+                    {
+                        res[r] = Some(e);
+                        done[r] = any_hit;
+                    }
+                }
+                if any_hit && done.iter().all(|d| *d) {
+                    break;
+                }
+                continue;
+            }
+
+            // Closest entry distance over the active rays
+            let node = &self.nodes[child];
+            let mut dists = [std::f32::INFINITY; N];
+            for r in 0..rays.len() {
+                if done[r] {
+                    continue;
+                }
+                let d = node.intersect_children(
+                    &rays[r].o,
+                    &inv_d[r],
+                    rays[r].tnear,
+                    its[r].t.min(rays[r].tfar),
+                );
+                for i in 0..N {
+                    dists[i] = dists[i].min(d[i]);
+                }
+            }
+            let (order, nb) = order_children(&dists);
+            for &i in &order[..nb] {
+                stack.push((node.child[i], node.count[i]));
+            }
+        }
+        res
+    }
+}
 
+impl<'a, const N: usize> Acceleration for WideBVHAccel<'a, N> {
+    fn trace(&self, ray: &Ray) -> Option<Intersection> {
         let mut its = IntersectionUV {
-            t: length * (1.0 - SHADOW_EPS),
+            t: std::f32::MAX,
             p: Point3::new(0.0, 0.0, 0.0),
             n: Vector3::new(0.0, 0.0, 0.0),
             u: 0.0,
             v: 0.0,
         };
-        let ray = Ray {
-            o: *p0,
-            d,
-            tnear: EPSILON,
-            tfar: length * (1.0 - SHADOW_EPS),
+        let e = self.intersect(ray, &mut its, false)?;
+        let mesh = &self.meshes[e.id_mesh];
+        Some(Intersection::fill_intersection(
+            mesh, e.id_tri, its.u, its.v, ray, its.n, its.t, its.p,
+        ))
+    }
+    fn visible(&self, p0: &Point3<f32>, p1: &Point3<f32>) -> bool {
+        self.visible_at(p0, p1, 0.0)
+    }
+    fn visible_at(&self, p0: &Point3<f32>, p1: &Point3<f32>, time: f32) -> bool {
+        let (ray, mut its) = shadow_ray(p0, p1, time);
+        self.intersect(&ray, &mut its, true).is_none()
+    }
+
+    fn trace_packet(&self, rays: &[Ray]) -> Vec<Option<Intersection>> {
+        let mut its = rays
+            .iter()
+            .map(|_| IntersectionUV {
+                t: std::f32::MAX,
+                p: Point3::new(0.0, 0.0, 0.0),
+                n: Vector3::new(0.0, 0.0, 0.0),
+                u: 0.0,
+                v: 0.0,
+            })
+            .collect::<Vec<_>>();
+        let res = self.intersect_packet(rays, &mut its, false);
+        res.into_iter()
+            .zip(its.iter().zip(rays.iter()))
+            .map(|(e, (its, ray))| {
+                e.map(|e| {
+                    Intersection::fill_intersection(
+                        &self.meshes[e.id_mesh],
+                        e.id_tri,
+                        its.u,
+                        its.v,
+                        ray,
+                        its.n,
+                        its.t,
+                        its.p,
+                    )
+                })
+            })
+            .collect()
+    }
+    fn visible_packet(&self, segments: &[(Point3<f32>, Point3<f32>)], time: f32) -> Vec<bool> {
+        let (rays, mut its): (Vec<_>, Vec<_>) = segments
+            .iter()
+            .map(|(p0, p1)| shadow_ray(p0, p1, time))
+            .unzip();
+        self.intersect_packet(&rays, &mut its, true)
+            .into_iter()
+            .map(|e| e.is_none())
+            .collect()
+    }
+}
+
+/**
+ * Two level BVH (instancing)
+ * The top level BVH is built over the instances and each object
+ * has its own bottom level BVH shared between all its instances.
+ * The non instanced meshes are stored inside a classical BVH.
+ * Animated instances are bounded over their whole motion and
+ * the rays are transformed at their time (motion blur).
+ */
+pub struct InstanceBVHAccel<'scene> {
+    meshes: BVHAccel<'scene>,
+    objects: Vec<BVHAccel<'scene>>,
+    // Top level BVH
+    instances: Vec<usize>,
+    nodes: Vec<BVHNode>,
+    scene: &'scene Scene,
+}
+
+impl<'scene> InstanceBVHAccel<'scene> {
+    pub fn new(scene: &'scene Scene, options: &BVHBuildOptions) -> InstanceBVHAccel<'scene> {
+        // Bottom level BVHs
+        let meshes = BVHAccel::from_meshes(&scene.meshes, options);
+        let objects = scene
+            .objects
+            .iter()
+            .map(|o| BVHAccel::from_meshes(&o.meshes, options))
+            .collect::<Vec<_>>();
+
+        // Top level BVH
+        let mut cached_aabbs = Vec::new();
+        for (id, instance) in scene.instances.iter().enumerate() {
+            cached_aabbs.push(CachedAABB {
+                aabb: instance.compute_aabb(&scene.objects[instance.object]),
+                info: id,
+            });
+        }
+        let nodes = flatten_bvh(build_bvh(&mut cached_aabbs, options));
+        let instances = cached_aabbs.iter().map(|c| c.info).collect::<Vec<_>>();
+
+        info!("Instancing stats: ");
+        info!(" - Number of objects: {}", objects.len());
+        info!(" - Number of instances: {}", instances.len());
+        InstanceBVHAccel {
+            meshes,
+            objects,
+            instances,
+            nodes,
+            scene,
+        }
+    }
+
+    /// Ray expressed in the object space of the instance.
+    /// The direction is normalized so the returned scale
+    /// convert world distances to object distances
+    fn ray_local(&self, id_instance: usize, ray: &Ray) -> (Ray, f32) {
+        let (_, to_local) = self.scene.instances[id_instance].transforms(ray.time);
+        let d = to_local.transform_vector(ray.d);
+        let scale = d.magnitude();
+        (
+            Ray {
+                o: to_local.transform_point(ray.o),
+                d: d / scale,
+                tnear: ray.tnear * scale,
+                tfar: ray.tfar * scale,
+                time: ray.time,
+            },
+            scale,
+        )
+    }
+
+    /// Return the instance and the primitive intersected
+    /// Note that the intersection information (except the distance)
+    /// are expressed in the object space of the instance
+    fn intersect_instances(
+        &self,
+        id_node: usize,
+        ray: &Ray,
+        its: &mut IntersectionUV,
+    ) -> Option<(usize, TriRef)> {
+        let node = &self.nodes[id_node];
+        if node.is_leaf() {
+            let mut res = None;
+            for k in 0..node.count {
+                let id_instance = self.instances[node.info + k];
+                let (ray_local, scale) = self.ray_local(id_instance, ray);
+                let bvh = &self.objects[self.scene.instances[id_instance].object];
+                its.t *= scale;
+                if let Some(e) = bvh.intersect_root(&ray_local, its) {
+                    res = Some((id_instance, e));
+                }
+                its.t /= scale;
+            }
+            res
+        } else {
+            let mut id1 = node.info;
+            let mut id2 = node.info + 1;
+            let mut d1 = self.nodes[id1]
+                .aabb
+                .intersect(ray)
+                .unwrap_or(std::f32::INFINITY);
+            let mut d2 = self.nodes[id2]
+                .aabb
+                .intersect(ray)
+                .unwrap_or(std::f32::INFINITY);
+            if d1 > d2 {
+                swap(&mut d1, &mut d2);
+                swap(&mut id1, &mut id2);
+            }
+            let mut res = None;
+            if d1 < its.t {
+                res = self.intersect_instances(id1, ray, its);
+            }
+            if d2 < its.t {
+                let res2 = self.intersect_instances(id2, ray, its);
+                if res2.is_some() {
+                    res = res2;
+                }
+            }
+            res
+        }
+    }
+
+    fn intersect_all(
+        &self,
+        ray: &Ray,
+        its: &mut IntersectionUV,
+    ) -> Option<(Option<usize>, TriRef)> {
+        let mut res = self.meshes.intersect_root(ray, its).map(|e| (None, e));
+        if !self.instances.is_empty() && self.nodes[0].aabb.intersect(ray).is_some() {
+            if let Some((id_instance, e)) = self.intersect_instances(0, ray, its) {
+                res = Some((Some(id_instance), e));
+            }
+        }
+        res
+    }
+}
+
+impl<'a> Acceleration for InstanceBVHAccel<'a> {
+    fn trace(&self, ray: &Ray) -> Option<Intersection> {
+        let mut its = IntersectionUV {
+            t: std::f32::MAX,
+            p: Point3::new(0.0, 0.0, 0.0),
+            n: Vector3::new(0.0, 0.0, 0.0),
+            u: 0.0,
+            v: 0.0,
         };
+        match self.intersect_all(ray, &mut its)? {
+            (None, e) => {
+                let mesh = &self.scene.meshes[e.id_mesh];
+                Some(Intersection::fill_intersection(
+                    mesh, e.id_tri, its.u, its.v, ray, its.n, its.t, its.p,
+                ))
+            }
+            (Some(id_instance), e) => {
+                let instance = &self.scene.instances[id_instance];
+                let mesh = &self.scene.objects[instance.object].meshes[e.id_mesh];
+                let (ray_local, _) = self.ray_local(id_instance, ray);
+                let (to_world, to_local) = instance.transforms(ray.time);
+                Some(
+                    Intersection::fill_intersection(
+                        mesh, e.id_tri, its.u, its.v, &ray_local, its.n, its.t, its.p,
+                    )
+                    .to_world_space(&to_world, &to_local, ray),
+                )
+            }
+        }
+    }
+    fn visible(&self, p0: &Point3<f32>, p1: &Point3<f32>) -> bool {
+        self.visible_at(p0, p1, 0.0)
+    }
+    fn visible_at(&self, p0: &Point3<f32>, p1: &Point3<f32>, time: f32) -> bool {
+        let (ray, mut its) = shadow_ray(p0, p1, time);
+        self.intersect_all(&ray, &mut its).is_none()
+    }
+}
 
-        // TODO: Unecessary?
-        if self.nodes[0].aabb.intersect(&ray).is_none() {
-            return false;
+/// Acceleration structures selectable at runtime
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AccelType {
+    /// Test all the primitives (for debugging)
+    Naive,
+    BVH,
+    BVH4,
+    BVH8,
+    /// Only available with the embree feature
+    Embree,
+}
+
+impl Default for AccelType {
+    fn default() -> Self {
+        if cfg!(feature = "embree") {
+            AccelType::Embree
+        } else {
+            AccelType::BVH
         }
+    }
+}
 
-        return self.intersect(0, &ray, &mut its).is_none();
+/// Build the acceleration structure for the scene
+/// Note that Embree need to be built by the caller (lifetime of the embree scene)
+pub fn build_acceleration<'scene>(
+    scene: &'scene Scene,
+    accel_type: AccelType,
+    options: &BVHBuildOptions,
+) -> Box<dyn Acceleration + 'scene> {
+    if !scene.instances.is_empty() && accel_type != AccelType::BVH {
+        warn!(
+            "Instances are only supported by the BVH acceleration (asked: {:?}), use the instance BVH instead",
+            accel_type
+        );
+        return Box::new(InstanceBVHAccel::new(scene, options));
+    }
+    match accel_type {
+        AccelType::Naive => Box::new(NaiveAcceleration::new(scene)),
+        AccelType::BVH => {
+            if scene.instances.is_empty() {
+                Box::new(BVHAccel::from_meshes(&scene.meshes, options))
+            } else {
+                Box::new(InstanceBVHAccel::new(scene, options))
+            }
+        }
+        AccelType::BVH4 => Box::new(BVH4Accel::from_meshes(&scene.meshes, options)),
+        AccelType::BVH8 => Box::new(BVH8Accel::from_meshes(&scene.meshes, options)),
+        AccelType::Embree => {
+            warn!("Embree is not available, use BVH instead");
+            Box::new(BVHAccel::from_meshes(&scene.meshes, options))
+        }
     }
 }
 
@@ -347,6 +1313,8 @@ impl<'a> Acceleration for BVHAccel<'a> {
 pub struct EmbreeAcceleration<'scene, 'embree> {
     pub scene: &'scene Scene,
     pub embree_scene_commited: embree::CommittedScene<'embree>,
+    // True if one mesh have an opacity mask
+    has_alpha: bool,
 }
 #[cfg(feature = "embree")]
 impl<'scene, 'embree> EmbreeAcceleration<'scene, 'embree> {
@@ -357,6 +1325,7 @@ impl<'scene, 'embree> EmbreeAcceleration<'scene, 'embree> {
         EmbreeAcceleration {
             scene,
             embree_scene_commited: embree_scene.commit(),
+            has_alpha: scene.meshes.iter().any(|m| m.alpha.is_some()),
         }
     }
 }
@@ -365,15 +1334,34 @@ impl<'scene, 'embree> EmbreeAcceleration<'scene, 'embree> {
 impl<'scene, 'embree> Acceleration for EmbreeAcceleration<'scene, 'embree> {
     fn trace(&self, ray: &Ray) -> Option<Intersection> {
         let mut intersection_ctx = embree::IntersectContext::incoherent();
-        let embree_ray = embree::Ray::segment(
-            Vector3::new(ray.o.x, ray.o.y, ray.o.z),
-            ray.d,
-            ray.tnear,
-            ray.tfar,
-        );
-        let mut ray_hit = embree::RayHit::new(embree_ray);
-        self.embree_scene_commited
-            .intersect(&mut intersection_ctx, &mut ray_hit);
+        let mut tnear = ray.tnear;
+        let ray_hit = loop {
+            let embree_ray = embree::Ray::segment(
+                Vector3::new(ray.o.x, ray.o.y, ray.o.z),
+                ray.d,
+                tnear,
+                ray.tfar,
+            );
+            let mut ray_hit = embree::RayHit::new(embree_ray);
+            self.embree_scene_commited
+                .intersect(&mut intersection_ctx, &mut ray_hit);
+            if !ray_hit.hit.hit() {
+                break ray_hit;
+            }
+
+            // Skip the transparent parts of the alpha masked meshes
+            let mesh = &self.scene.meshes[ray_hit.hit.geomID as usize];
+            if mesh.is_opaque(
+                ray_hit.hit.primID as usize,
+                ray_hit.hit.u,
+                ray_hit.hit.v,
+                &ray.o,
+                &ray.d,
+            ) {
+                break ray_hit;
+            }
+            tnear = crate::math::next_float_up(ray_hit.ray.tfar);
+        };
         if ray_hit.hit.hit() {
             let mut n_g = Vector3::new(ray_hit.hit.Ng_x, ray_hit.hit.Ng_y, ray_hit.hit.Ng_z);
             let n_g_dot = n_g.dot(n_g);
@@ -406,6 +1394,18 @@ impl<'scene, 'embree> Acceleration for EmbreeAcceleration<'scene, 'embree> {
         let mut d = p1 - p0;
         let length = d.magnitude();
         d /= length;
+        if self.has_alpha {
+            // Occlusion queries cannot skip the transparent hits
+            return self
+                .trace(&Ray {
+                    o: *p0,
+                    d,
+                    tnear: 0.0001,
+                    tfar: length - 0.0001,
+                    time: 0.0,
+                })
+                .is_none();
+        }
         // TODO: Do correct self intersection tests...
         let mut embree_ray =
             embree::Ray::segment(Vector3::new(p0.x, p0.y, p0.z), d, 0.0001, length - 0.0001);
@@ -445,101 +1445,52 @@ pub struct BHVAccel<D, T: BVHElement<D>> {
 pub trait BVHElement<D> {
     // Used to build AABB hierachy
     fn aabb(&self) -> AABB;
-    // Used to construct AABB (by sorting elements)
+    // Position of the element (center of the AABB is used for the construction)
     fn position(&self) -> Point3<f32>;
     // Used when collecting the different objects
     fn intersection(&self, r: &Ray) -> Option<D>;
 }
 
-// Implementation from (C++): https://github.com/shiinamiyuki/minpt/blob/master/minpt.cpp
 impl<D, T: BVHElement<D>> BHVAccel<D, T> {
-    // Internal build function
-    // return the node ID (allocate node on the fly)
-    fn build(&mut self, begin: usize, end: usize, depth: u32) -> Option<usize> {
-        if end == begin {
-            return None;
-        }
+    pub fn create(elements: Vec<T>) -> BHVAccel<D, T> {
+        BHVAccel::create_with_options(
+            elements,
+            &BVHBuildOptions {
+                leaf_size: 4,
+                ..Default::default()
+            },
+        )
+    }
 
-        // TODO: Not very optimized ...
-        let mut aabb = AABB::default();
-        for i in begin..end {
-            aabb = aabb.union_aabb(&self.elements[i].aabb());
+    pub fn create_with_options(elements: Vec<T>, options: &BVHBuildOptions) -> BHVAccel<D, T> {
+        let mut cached_aabbs = elements
+            .iter()
+            .enumerate()
+            .map(|(i, e)| CachedAABB {
+                aabb: e.aabb(),
+                info: i,
+            })
+            .collect::<Vec<_>>();
+        let root = build_bvh(&mut cached_aabbs, options);
+        if root.is_none() {
+            warn!("BVH is empty!");
         }
 
-        // If the tree is too deep or not enough element
-        // force to make a leaf
-        // depth >= 20
-        if end - begin <= 4 {
-            // dbg!(aabb.size());
-            self.nodes.push(BVHNodeGeneral {
-                aabb,
-                first: begin,
-                count: end - begin,
-                left: None,
-                right: None,
-            });
-            Some(self.nodes.len() - 1)
-        } else {
-            // For now cut on the biggest axis
-            let aabb_size = aabb.size();
-            let axis = if aabb_size.x > aabb_size.y {
-                if aabb_size.x > aabb_size.z {
-                    0
-                } else {
-                    2
-                }
-            } else if aabb_size.y > aabb_size.z {
-                1
-            } else {
-                2
-            };
+        // Reorder the elements so that the leafs cover contiguous ranges
+        let mut elements = elements.into_iter().map(Some).collect::<Vec<_>>();
+        let elements = cached_aabbs
+            .iter()
+            .map(|c| elements[c.info].take().unwrap())
+            .collect();
 
-            // Split based on largest axis (split inside the middle for now)
-            // or split between triangles
-            // TODO: Implements SAH
-            // let split = (aabb.p_max[axis] + aabb.p_min[axis]) / 2.0;
-            // let split_id = self.triangles[begin..end].iter_mut().partition_in_place(|t| t.middle()[axis] < split ) + begin;
-
-            self.elements[begin..end].sort_unstable_by(|t1, t2| {
-                t1.position()[axis]
-                    .partial_cmp(&t2.position()[axis])
-                    .unwrap()
-            });
-            let split_id = (begin + end) / 2;
-
-            // TODO: Make better
-            let left = self.build(begin, split_id, depth + 1);
-            let right = self.build(split_id, end, depth + 1);
-            self.nodes.push(BVHNodeGeneral {
-                aabb,
-                first: 0, // TODO: Make the node invalid
-                count: 0,
-                left,
-                right,
-            });
-
-            Some(self.nodes.len() - 1)
-        }
-    }
-
-    pub fn create(elements: Vec<T>) -> BHVAccel<D, T> {
-        let mut accel = BHVAccel {
+        let mut nodes = Vec::new();
+        let root = root.map(|r| r.flatten_general(&mut nodes));
+        BHVAccel {
             elements,
-            nodes: Vec::new(),
-            root: None,
+            nodes,
+            root,
             phantom: std::marker::PhantomData,
-        };
-        accel.root = accel.build(0, accel.elements.len(), 0);
-        match accel.root {
-            None => warn!("BVH is empty!"),
-            Some(ref v) => {
-                info!("BVH stats: ");
-                info!(" - Number of elements: {}", accel.elements.len());
-                info!(" - Number of nodes: {}", accel.nodes.len());
-                info!(" - AABB size root: {:?}", accel.nodes[*v].aabb.size());
-            }
-        };
-        accel
+        }
     }
 
     pub fn gather(&self, r: &Ray) -> Vec<(D, usize)> {
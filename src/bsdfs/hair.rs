@@ -0,0 +1,458 @@
+use crate::bsdfs::utils::fresnel_dielectric;
+use crate::bsdfs::*;
+use std;
+use std::f32::consts::PI;
+
+// Number of explicit lobes (R, TT, TRT)
+// the remaining lobes are approximated by a single residual lobe
+const P_MAX: usize = 3;
+const SQRT_PI_OVER_8: f32 = 0.626657069;
+
+/// How the hair absorption is specified
+pub enum HairAbsorption {
+    /// Absorption coefficient inside the hair
+    SigmaA(Color),
+    /// Desired (multiple scattering) color of the hair
+    Reflectance(BSDFColor),
+    /// Melanin concentrations
+    Concentration { eumelanin: f32, pheomelanin: f32 },
+}
+
+/// Hair scattering model from Chiang et al. [2016]
+/// (based on Marschner et al. [2003] and d'Eon et al. [2011])
+/// The implementation follows PBRT-v3.
+/// The local frame have the x axis aligned with the curve tangent
+/// and the offset across the hair (h) is given by the v texture coordinate.
+pub struct BSDFHair {
+    pub absorption: HairAbsorption,
+    pub eta: f32,
+    /// Longitudinal and azimuthal roughness
+    pub beta_m: f32,
+    pub beta_n: f32,
+    /// Cuticle scale angle (degrees)
+    pub alpha: f32,
+    // Precomputed values
+    v: [f32; P_MAX + 1],
+    s: f32,
+    sin_2k_alpha: [f32; 3],
+    cos_2k_alpha: [f32; 3],
+}
+
+fn safe_sqrt(v: f32) -> f32 {
+    v.max(0.0).sqrt()
+}
+fn safe_asin(v: f32) -> f32 {
+    v.max(-1.0).min(1.0).asin()
+}
+
+/// Modified Bessel function of the first kind
+fn i0(x: f32) -> f32 {
+    let mut val = 0.0;
+    let mut x2i = 1.0;
+    let mut ifact = 1.0;
+    let mut i4 = 1.0;
+    for i in 0..10 {
+        if i > 1 {
+            ifact *= i as f32;
+        }
+        val += x2i / (i4 * ifact * ifact);
+        x2i *= x * x;
+        i4 *= 4.0;
+    }
+    val
+}
+
+fn log_i0(x: f32) -> f32 {
+    if x > 12.0 {
+        x + 0.5 * (-(2.0 * PI).ln() + (1.0 / x).ln() + 1.0 / (8.0 * x))
+    } else {
+        i0(x).ln()
+    }
+}
+
+/// Longitudinal scattering function
+fn mp(cos_theta_i: f32, cos_theta_o: f32, sin_theta_i: f32, sin_theta_o: f32, v: f32) -> f32 {
+    let a = cos_theta_i * cos_theta_o / v;
+    let b = sin_theta_i * sin_theta_o / v;
+    if v <= 0.1 {
+        (log_i0(a) - b - 1.0 / v + 0.6931 + (1.0 / (2.0 * v)).ln()).exp()
+    } else {
+        ((-b).exp() * i0(a)) / ((1.0 / v).sinh() * 2.0 * v)
+    }
+}
+
+/// Attenuation for each lobes
+fn ap(cos_theta_o: f32, eta: f32, h: f32, t: Color) -> [Color; P_MAX + 1] {
+    let cos_gamma_o = safe_sqrt(1.0 - h * h);
+    let cos_theta = cos_theta_o * cos_gamma_o;
+    let f = fresnel_dielectric(cos_theta, eta).0;
+
+    let mut ap = [Color::zero(); P_MAX + 1];
+    ap[0] = Color::value(f);
+    ap[1] = t * (1.0 - f) * (1.0 - f);
+    for p in 2..P_MAX {
+        ap[p] = ap[p - 1] * t * f;
+    }
+    // Sum of the remaining lobes
+    let tf = t * f;
+    ap[P_MAX] = ap[P_MAX - 1] * tf / (Color::one() - tf);
+    ap
+}
+
+fn phi(p: usize, gamma_o: f32, gamma_t: f32) -> f32 {
+    let p = p as f32;
+    2.0 * p * gamma_t - 2.0 * gamma_o + p * PI
+}
+
+fn logistic(x: f32, s: f32) -> f32 {
+    let x = x.abs();
+    (-x / s).exp() / (s * (1.0 + (-x / s).exp()).powi(2))
+}
+fn logistic_cdf(x: f32, s: f32) -> f32 {
+    1.0 / (1.0 + (-x / s).exp())
+}
+fn trimmed_logistic(x: f32, s: f32, a: f32, b: f32) -> f32 {
+    logistic(x, s) / (logistic_cdf(b, s) - logistic_cdf(a, s))
+}
+fn sample_trimmed_logistic(u: f32, s: f32, a: f32, b: f32) -> f32 {
+    let k = logistic_cdf(b, s) - logistic_cdf(a, s);
+    let x = -s * (1.0 / (u * k + logistic_cdf(a, s)) - 1.0).ln();
+    x.max(a).min(b)
+}
+
+/// Azimuthal scattering function
+fn np(phi_v: f32, p: usize, s: f32, gamma_o: f32, gamma_t: f32) -> f32 {
+    let mut dphi = phi_v - phi(p, gamma_o, gamma_t);
+    // Remap to [-pi, pi]
+    while dphi > PI {
+        dphi -= 2.0 * PI;
+    }
+    while dphi < -PI {
+        dphi += 2.0 * PI;
+    }
+    trimmed_logistic(dphi, s, -PI, PI)
+}
+
+/// Extract two random numbers from a single one
+/// by taking even and odd bits
+fn demux_float(f: f32) -> (f32, f32) {
+    fn compact_1_by_1(x: u32) -> u32 {
+        let mut x = x & 0x55555555;
+        x = (x ^ (x >> 1)) & 0x33333333;
+        x = (x ^ (x >> 2)) & 0x0f0f0f0f;
+        x = (x ^ (x >> 4)) & 0x00ff00ff;
+        x = (x ^ (x >> 8)) & 0x0000ffff;
+        x
+    }
+    let v = (f as f64 * (1u64 << 32) as f64) as u64;
+    let bits = (compact_1_by_1(v as u32), compact_1_by_1((v >> 1) as u32));
+    (
+        bits.0 as f32 / (1 << 16) as f32,
+        bits.1 as f32 / (1 << 16) as f32,
+    )
+}
+
+impl BSDFHair {
+    pub fn new(absorption: HairAbsorption, eta: f32, beta_m: f32, beta_n: f32, alpha: f32) -> Self {
+        // Longitudinal variance
+        let v0 = (0.726 * beta_m + 0.812 * beta_m * beta_m + 3.7 * beta_m.powi(20)).powi(2);
+        let v = [v0, 0.25 * v0, 4.0 * v0, 4.0 * v0];
+        // Azimuthal logistic scale factor
+        let s =
+            SQRT_PI_OVER_8 * (0.265 * beta_n + 1.194 * beta_n * beta_n + 5.372 * beta_n.powi(22));
+
+        // Cuticle scales tilt
+        let mut sin_2k_alpha = [0.0; 3];
+        let mut cos_2k_alpha = [0.0; 3];
+        sin_2k_alpha[0] = alpha.to_radians().sin();
+        cos_2k_alpha[0] = safe_sqrt(1.0 - sin_2k_alpha[0] * sin_2k_alpha[0]);
+        for i in 1..3 {
+            sin_2k_alpha[i] = 2.0 * cos_2k_alpha[i - 1] * sin_2k_alpha[i - 1];
+            cos_2k_alpha[i] = cos_2k_alpha[i - 1].powi(2) - sin_2k_alpha[i - 1].powi(2);
+        }
+
+        BSDFHair {
+            absorption,
+            eta,
+            beta_m,
+            beta_n,
+            alpha,
+            v,
+            s,
+            sin_2k_alpha,
+            cos_2k_alpha,
+        }
+    }
+
+    fn sigma_a(&self, uv: &Option<Vector2<f32>>) -> Color {
+        match &self.absorption {
+            HairAbsorption::SigmaA(c) => *c,
+            HairAbsorption::Reflectance(c) => {
+                let c = c.color(uv);
+                let b = self.beta_n;
+                let denom = 5.969 - 0.215 * b + 2.532 * b.powi(2) - 10.73 * b.powi(3)
+                    + 5.574 * b.powi(4)
+                    + 0.245 * b.powi(5);
+                let f = |v: f32| (v.ln() / denom).powi(2);
+                Color::new(f(c.r), f(c.g), f(c.b))
+            }
+            HairAbsorption::Concentration {
+                eumelanin,
+                pheomelanin,
+            } => {
+                Color::new(0.419, 0.697, 1.37) * *eumelanin
+                    + Color::new(0.187, 0.4, 1.05) * *pheomelanin
+            }
+        }
+    }
+
+    /// Offset across the hair in [-1, 1]
+    fn h(uv: &Option<Vector2<f32>>) -> f32 {
+        match uv {
+            Some(uv) => -1.0 + 2.0 * uv.y,
+            None => 0.0,
+        }
+    }
+
+    /// Rotate theta_o to account for the cuticle scales
+    fn theta_o_p(&self, p: usize, sin_theta_o: f32, cos_theta_o: f32) -> (f32, f32) {
+        let (sin_theta_op, cos_theta_op) = match p {
+            0 => (
+                sin_theta_o * self.cos_2k_alpha[1] - cos_theta_o * self.sin_2k_alpha[1],
+                cos_theta_o * self.cos_2k_alpha[1] + sin_theta_o * self.sin_2k_alpha[1],
+            ),
+            1 => (
+                sin_theta_o * self.cos_2k_alpha[0] + cos_theta_o * self.sin_2k_alpha[0],
+                cos_theta_o * self.cos_2k_alpha[0] - sin_theta_o * self.sin_2k_alpha[0],
+            ),
+            2 => (
+                sin_theta_o * self.cos_2k_alpha[2] + cos_theta_o * self.sin_2k_alpha[2],
+                cos_theta_o * self.cos_2k_alpha[2] - sin_theta_o * self.sin_2k_alpha[2],
+            ),
+            _ => (sin_theta_o, cos_theta_o),
+        };
+        (sin_theta_op, cos_theta_op.abs())
+    }
+
+    /// Transmittance of a single path inside the hair
+    fn transmittance(&self, uv: &Option<Vector2<f32>>, sin_theta_o: f32, h: f32) -> Color {
+        let sin_theta_t = sin_theta_o / self.eta;
+        let cos_theta_t = safe_sqrt(1.0 - sin_theta_t * sin_theta_t);
+        let cos_theta_o = safe_sqrt(1.0 - sin_theta_o * sin_theta_o);
+        let etap = (self.eta * self.eta - sin_theta_o * sin_theta_o).sqrt() / cos_theta_o;
+        let sin_gamma_t = h / etap;
+        let cos_gamma_t = safe_sqrt(1.0 - sin_gamma_t * sin_gamma_t);
+        (-self.sigma_a(uv) * (2.0 * cos_gamma_t / cos_theta_t)).exp()
+    }
+
+    /// Gamma_t (angle inside the hair)
+    fn gamma_t(&self, sin_theta_o: f32, cos_theta_o: f32, h: f32) -> f32 {
+        let etap = (self.eta * self.eta - sin_theta_o * sin_theta_o).sqrt() / cos_theta_o;
+        safe_asin(h / etap)
+    }
+
+    /// Discrete probabilities to sample each lobe
+    fn ap_pdf(&self, uv: &Option<Vector2<f32>>, cos_theta_o: f32, h: f32) -> [f32; P_MAX + 1] {
+        let sin_theta_o = safe_sqrt(1.0 - cos_theta_o * cos_theta_o);
+        let t = self.transmittance(uv, sin_theta_o, h);
+        let ap = ap(cos_theta_o, self.eta, h, t);
+        let sum_y: f32 = ap.iter().map(|a| a.luminance()).sum();
+        let mut ap_pdf = [0.0; P_MAX + 1];
+        for i in 0..=P_MAX {
+            ap_pdf[i] = ap[i].luminance() / sum_y;
+        }
+        ap_pdf
+    }
+
+    fn pdf_lobes(
+        &self,
+        ap_pdf: &[f32; P_MAX + 1],
+        d_in: &Vector3<f32>,
+        d_out: &Vector3<f32>,
+        h: f32,
+    ) -> f32 {
+        let sin_theta_o = d_in.x;
+        let cos_theta_o = safe_sqrt(1.0 - sin_theta_o * sin_theta_o);
+        let phi_o = d_in.y.atan2(d_in.z);
+        let sin_theta_i = d_out.x;
+        let cos_theta_i = safe_sqrt(1.0 - sin_theta_i * sin_theta_i);
+        let phi_i = d_out.y.atan2(d_out.z);
+
+        let gamma_o = safe_asin(h);
+        let gamma_t = self.gamma_t(sin_theta_o, cos_theta_o, h);
+
+        let dphi = phi_i - phi_o;
+        let mut pdf = 0.0;
+        for p in 0..P_MAX {
+            let (sin_theta_op, cos_theta_op) = self.theta_o_p(p, sin_theta_o, cos_theta_o);
+            pdf += mp(
+                cos_theta_i,
+                cos_theta_op,
+                sin_theta_i,
+                sin_theta_op,
+                self.v[p],
+            ) * ap_pdf[p]
+                * np(dphi, p, self.s, gamma_o, gamma_t);
+        }
+        pdf += mp(
+            cos_theta_i,
+            cos_theta_o,
+            sin_theta_i,
+            sin_theta_o,
+            self.v[P_MAX],
+        ) * ap_pdf[P_MAX]
+            * (1.0 / (2.0 * PI));
+        pdf
+    }
+}
+
+impl BSDF for BSDFHair {
+    fn sample(
+        &self,
+        uv: &Option<Vector2<f32>>,
+        d_in: &Vector3<f32>,
+        sample: Point2<f32>,
+        transport: Transport,
+    ) -> Option<SampledDirection> {
+        let h = BSDFHair::h(uv);
+        let sin_theta_o = d_in.x;
+        let cos_theta_o = safe_sqrt(1.0 - sin_theta_o * sin_theta_o);
+        let phi_o = d_in.y.atan2(d_in.z);
+
+        // Need 4 random numbers
+        let mut u0 = demux_float(sample.x);
+        let u1 = demux_float(sample.y);
+
+        // Select the lobe
+        let ap_pdf = self.ap_pdf(uv, cos_theta_o, h);
+        let mut p = 0;
+        while p < P_MAX {
+            if u0.0 < ap_pdf[p] {
+                break;
+            }
+            u0.0 -= ap_pdf[p];
+            p += 1;
+        }
+
+        // Sample M_p to get theta_i
+        let (sin_theta_op, cos_theta_op) = self.theta_o_p(p, sin_theta_o, cos_theta_o);
+        let u1_0 = u1.0.max(1e-5);
+        let cos_theta = 1.0 + self.v[p] * (u1_0 + (1.0 - u1_0) * (-2.0 / self.v[p]).exp()).ln();
+        let sin_theta = safe_sqrt(1.0 - cos_theta * cos_theta);
+        let cos_phi = (2.0 * PI * u1.1).cos();
+        let sin_theta_i = -cos_theta * sin_theta_op + sin_theta * cos_phi * cos_theta_op;
+        let cos_theta_i = safe_sqrt(1.0 - sin_theta_i * sin_theta_i);
+
+        // Sample N_p to get phi_i
+        let gamma_o = safe_asin(h);
+        let gamma_t = self.gamma_t(sin_theta_o, cos_theta_o, h);
+        let dphi = if p < P_MAX {
+            phi(p, gamma_o, gamma_t) + sample_trimmed_logistic(u0.1, self.s, -PI, PI)
+        } else {
+            2.0 * PI * u0.1
+        };
+        let phi_i = phi_o + dphi;
+        let d_out = Vector3::new(
+            sin_theta_i,
+            cos_theta_i * phi_i.cos(),
+            cos_theta_i * phi_i.sin(),
+        );
+
+        let pdf = self.pdf_lobes(&ap_pdf, d_in, &d_out, h);
+        if pdf == 0.0 {
+            return None;
+        }
+        Some(SampledDirection {
+            weight: self.eval(uv, d_in, &d_out, Domain::SolidAngle, transport) / pdf,
+            d: d_out,
+            pdf: PDF::SolidAngle(pdf),
+            eta: 1.0,
+            event: if p == 0 {
+                BSDFEvent::REFLECTION
+            } else {
+                BSDFEvent::TRANSMISSION
+            },
+            event_type: BSDFType::GLOSSY,
+        })
+    }
+
+    fn pdf(
+        &self,
+        uv: &Option<Vector2<f32>>,
+        d_in: &Vector3<f32>,
+        d_out: &Vector3<f32>,
+        domain: Domain,
+        _: Transport,
+    ) -> PDF {
+        assert!(domain == Domain::SolidAngle);
+        let h = BSDFHair::h(uv);
+        let cos_theta_o = safe_sqrt(1.0 - d_in.x * d_in.x);
+        let ap_pdf = self.ap_pdf(uv, cos_theta_o, h);
+        PDF::SolidAngle(self.pdf_lobes(&ap_pdf, d_in, d_out, h))
+    }
+
+    fn eval(
+        &self,
+        uv: &Option<Vector2<f32>>,
+        d_in: &Vector3<f32>,
+        d_out: &Vector3<f32>,
+        domain: Domain,
+        _: Transport,
+    ) -> Color {
+        assert!(domain == Domain::SolidAngle);
+        let h = BSDFHair::h(uv);
+
+        let sin_theta_o = d_in.x;
+        let cos_theta_o = safe_sqrt(1.0 - sin_theta_o * sin_theta_o);
+        let phi_o = d_in.y.atan2(d_in.z);
+        let sin_theta_i = d_out.x;
+        let cos_theta_i = safe_sqrt(1.0 - sin_theta_i * sin_theta_i);
+        let phi_i = d_out.y.atan2(d_out.z);
+
+        let gamma_o = safe_asin(h);
+        let gamma_t = self.gamma_t(sin_theta_o, cos_theta_o, h);
+        let t = self.transmittance(uv, sin_theta_o, h);
+        let ap = ap(cos_theta_o, self.eta, h, t);
+
+        let dphi = phi_i - phi_o;
+        let mut fsum = Color::zero();
+        for p in 0..P_MAX {
+            let (sin_theta_op, cos_theta_op) = self.theta_o_p(p, sin_theta_o, cos_theta_o);
+            fsum += ap[p]
+                * (mp(
+                    cos_theta_i,
+                    cos_theta_op,
+                    sin_theta_i,
+                    sin_theta_op,
+                    self.v[p],
+                ) * np(dphi, p, self.s, gamma_o, gamma_t));
+        }
+        fsum += ap[P_MAX]
+            * (mp(
+                cos_theta_i,
+                cos_theta_o,
+                sin_theta_i,
+                sin_theta_o,
+                self.v[P_MAX],
+            ) / (2.0 * PI));
+
+        // Note that PBRT divide by |cos(theta_i)| here
+        // we do not as the eval include the cosine factor
+        fsum
+    }
+
+    fn roughness(&self, _uv: &Option<Vector2<f32>>) -> f32 {
+        self.beta_m
+    }
+
+    fn is_twosided(&self) -> bool {
+        false
+    }
+
+    fn bsdf_type(&self) -> BSDFType {
+        BSDFType::GLOSSY
+    }
+    fn bsdf_event(&self) -> BSDFEvent {
+        BSDFEvent::REFLECTION | BSDFEvent::TRANSMISSION
+    }
+}
@@ -202,6 +202,7 @@ pub mod blend;
 pub mod diffuse;
 pub mod distribution;
 pub mod glass;
+pub mod hair;
 pub mod metal;
 pub mod phong;
 pub mod substrate;
@@ -251,6 +252,27 @@ fn bsdf_texture_f32_match_pbrt(
     }
 }
 
+/// Opacity mask of a PBRT shape (None if fully opaque)
+#[cfg(feature = "pbrt")]
+pub fn alpha_pbrt(
+    alpha: &pbrt_rs::parser::BSDFFloat,
+    textures: &HashMap<String, pbrt_rs::Texture>,
+) -> Option<BSDFColor> {
+    match alpha {
+        pbrt_rs::parser::BSDFFloat::Float(v) if *v >= 1.0 => None,
+        pbrt_rs::parser::BSDFFloat::Float(v) => Some(BSDFColor::Constant(Color::value(*v))),
+        pbrt_rs::parser::BSDFFloat::Texture(name) => match textures.get(name) {
+            Some(texture) => Some(BSDFColor::Bitmap {
+                img: Bitmap::read(&texture.filename),
+            }),
+            None => {
+                warn!("Impossible to found an alpha texture with name: {}", name);
+                None
+            }
+        },
+    }
+}
+
 #[cfg(feature = "pbrt")]
 fn distribution_pbrt(
     d: &pbrt_rs::Distribution,
@@ -495,6 +517,16 @@ fn distribution_mts(
     }
 }
 
+/// Extract the opacity of Mitsuba mask BSDF
+#[cfg(feature = "mitsuba")]
+pub fn alpha_mts(bsdf: &mitsuba_rs::BSDF, wk: &std::path::Path) -> Option<BSDFColor> {
+    match bsdf {
+        mitsuba_rs::BSDF::Mask { opacity, .. } => Some(bsdf_texture_match_mts(opacity, wk)),
+        mitsuba_rs::BSDF::TwoSided { bsdf } => alpha_mts(&bsdf, wk),
+        _ => None,
+    }
+}
+
 #[cfg(feature = "mitsuba")]
 pub fn bsdf_mts(bsdf: &mitsuba_rs::BSDF, wk: &std::path::Path) -> Box<dyn BSDF + Sync + Send> {
     let bsdf: Option<Box<dyn BSDF + Sync + Send>> = match bsdf {
@@ -502,6 +534,10 @@ pub fn bsdf_mts(bsdf: &mitsuba_rs::BSDF, wk: &std::path::Path) -> Box<dyn BSDF +
             // Rustlight automatically apply twosided
             Some(bsdf_mts(&bsdf, wk))
         }
+        mitsuba_rs::BSDF::Mask { bsdf, .. } => {
+            // The opacity is handled by the mesh (see alpha_mts)
+            Some(bsdf_mts(&bsdf, wk))
+        }
         mitsuba_rs::BSDF::Diffuse { reflectance } => {
             let diffuse = bsdf_texture_match_mts(reflectance, wk);
             Some(Box::new(BSDFDiffuse { diffuse }))
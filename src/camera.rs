@@ -1,17 +1,111 @@
-use crate::structure::{Color, Ray};
+use crate::filter::Filter;
+use crate::geometry::Motion;
+use crate::math::{concentric_sample_disk, uniform_sample_triangle, Distribution2D};
+use crate::structure::{Bitmap, Color, Ray};
 use cgmath::*;
 use std::f32;
 
+/// Shape of the lens aperture
+pub enum Aperture {
+    Disk,
+    /// Regular polygon with N blades
+    Polygon {
+        blades: u32,
+        rotation: f32,
+    },
+    /// Custom aperture where the luminance is the transmission
+    Bitmap {
+        size: Vector2<u32>,
+        distribution: Distribution2D,
+    },
+}
+
+impl Aperture {
+    pub fn from_bitmap(image: &Bitmap) -> Aperture {
+        Aperture::Bitmap {
+            size: image.size,
+            distribution: Distribution2D::from_bitmap(image),
+        }
+    }
+
+    /// Sample a point on the aperture (inside the [-1, 1]^2 square)
+    /// The aperture transmission is importance sampled
+    pub fn sample(&self, u: Point2<f32>) -> Point2<f32> {
+        match self {
+            Aperture::Disk => concentric_sample_disk(u),
+            Aperture::Polygon { blades, rotation } => {
+                // Select one triangle (center, vertex i, vertex i + 1)
+                let n = *blades as f32;
+                let i = (u.x * n).min(n - 1.0).floor();
+                let u = Point2::new(u.x * n - i, u.y);
+                let vertex = |k: f32| {
+                    let phi = rotation + k * 2.0 * f32::consts::PI / n;
+                    Vector2::new(phi.cos(), phi.sin())
+                };
+                let b = uniform_sample_triangle(u);
+                Point2::from_vec(vertex(i) * b.x + vertex(i + 1.0) * b.y)
+            }
+            Aperture::Bitmap { size, distribution } => {
+                let p = distribution.sample_continuous(u);
+                Point2::new(
+                    2.0 * p.x / size.x as f32 - 1.0,
+                    2.0 * p.y / size.y as f32 - 1.0,
+                )
+            }
+        }
+    }
+}
+
+/// Thin lens model for the depth of field
+pub struct ThinLens {
+    pub radius: f32,
+    pub focus_distance: f32,
+    pub aperture: Aperture,
+}
+
+impl ThinLens {
+    /// Sampled point on the lens (camera space)
+    fn sample(&self, u: Point2<f32>) -> Point3<f32> {
+        let p = self.aperture.sample(u) * self.radius;
+        Point3::new(p.x, p.y, 0.0)
+    }
+}
+
+/// Mapping between the camera space and the image
+pub enum Projection {
+    Perspective {
+        camera_to_sample: Matrix4<f32>,
+        sample_to_camera: Matrix4<f32>,
+        // image rect
+        image_rect_min: Point2<f32>,
+        image_rect_max: Point2<f32>,
+    },
+    /// Half size of the image plane (in camera space)
+    Orthographic { size: Vector2<f32> },
+    /// Equirectangular panorama
+    LatLong,
+    /// Equidistant fisheye, the fov (radians) is the one of the image diagonal
+    Fisheye { fov: f32 },
+}
+
 pub struct Camera {
     pub img: Vector2<u32>,
+    pub projection: Projection,
     // Internally
-    camera_to_sample: Matrix4<f32>,
-    sample_to_camera: Matrix4<f32>,
     to_world: Matrix4<f32>,
     to_local: Matrix4<f32>,
-    // image rect
-    image_rect_min: Point2<f32>,
-    image_rect_max: Point2<f32>,
+    // Sign of the horizontal axis in the image
+    x_sign: f32,
+    /// Pinhole camera if None (only for perspective projection)
+    pub lens: Option<ThinLens>,
+    /// Shutter interval (motion blur if open != close)
+    pub shutter_open: f32,
+    pub shutter_close: f32,
+    /// Animation applied after to_world (only for the rays generated with generate_at,
+    /// the connections to the sensor use the static transformation)
+    pub motion: Option<Motion>,
+    /// Pixel reconstruction filter
+    pub filter: Filter,
 }
 
 pub enum Fov {
@@ -28,10 +122,27 @@ impl Fov {
 }
 
 impl Camera {
-    pub fn new(img: Vector2<u32>, fov: Fov, mat: Matrix4<f32>, flip: bool) -> Camera {
-        let to_world = mat;
-        let to_local = to_world.inverse_transform().unwrap();
+    fn with_projection(
+        img: Vector2<u32>,
+        projection: Projection,
+        mat: Matrix4<f32>,
+        flip: bool,
+    ) -> Camera {
+        Camera {
+            img,
+            projection,
+            to_world: mat,
+            to_local: mat.inverse_transform().unwrap(),
+            x_sign: if flip { -1.0 } else { 1.0 },
+            lens: None,
+            shutter_open: 0.0,
+            shutter_close: 0.0,
+            motion: None,
+            filter: Filter::default(),
+        }
+    }
 
+    pub fn new(img: Vector2<u32>, fov: Fov, mat: Matrix4<f32>, flip: bool) -> Camera {
         // Control the flipping on the horizontal axis
         let x_v = if flip { 1.0 } else { -1.0 };
 
@@ -55,14 +166,46 @@ impl Camera {
         let p1 = sample_to_camera.transform_point(Point3::new(1.0, 1.0, 0.0));
         let image_rect_min = Point2::new(p0.x.min(p1.x), p0.y.min(p1.y)) / p0.z.min(p1.z);
         let image_rect_max = Point2::new(p0.x.max(p1.x), p0.y.max(p1.y)) / p0.z.max(p1.z);
-        Camera {
+        Camera::with_projection(
             img,
-            camera_to_sample,
-            sample_to_camera,
-            to_world,
-            to_local,
-            image_rect_min,
-            image_rect_max,
+            Projection::Perspective {
+                camera_to_sample,
+                sample_to_camera,
+                image_rect_min,
+                image_rect_max,
+            },
+            mat,
+            flip,
+        )
+    }
+
+    /// size: half size of the image plane
+    pub fn orthographic(
+        img: Vector2<u32>,
+        size: Vector2<f32>,
+        mat: Matrix4<f32>,
+        flip: bool,
+    ) -> Camera {
+        Camera::with_projection(img, Projection::Orthographic { size }, mat, flip)
+    }
+
+    pub fn latlong(img: Vector2<u32>, mat: Matrix4<f32>, flip: bool) -> Camera {
+        Camera::with_projection(img, Projection::LatLong, mat, flip)
+    }
+
+    /// fov: field of view (in degree) of the image diagonal
+    pub fn fisheye(img: Vector2<u32>, fov: f32, mat: Matrix4<f32>, flip: bool) -> Camera {
+        assert!(fov > 0.0 && fov <= 360.0);
+        let fov = fov * f32::consts::PI / 180.0;
+        Camera::with_projection(img, Projection::Fisheye { fov }, mat, flip)
+    }
+
+    /// The thin lens is only supported by the perspective projection,
+    /// warn and remove it for the other projections
+    pub fn check_lens(&mut self) {
+        if self.lens.is_some() && !matches!(self.projection, Projection::Perspective { .. }) {
+            warn!("The thin lens is only supported by the perspective projection, ignoring it");
+            self.lens = None;
         }
     }
 
@@ -77,27 +220,223 @@ impl Camera {
         );
     }
 
+    /// Uniform time sample inside the shutter interval
+    pub fn sample_time(&self, u: f32) -> f32 {
+        self.shutter_open + (self.shutter_close - self.shutter_open) * u
+    }
+
+    fn aspect_ratio(&self) -> f32 {
+        self.img.x as f32 / self.img.y as f32
+    }
+
+    // Fisheye image position: the image diagonal is of length 1
+    fn fisheye_scale(&self) -> Vector2<f32> {
+        let aspect_ratio = self.aspect_ratio();
+        let half_diag = (aspect_ratio * aspect_ratio + 1.0).sqrt();
+        Vector2::new(aspect_ratio / half_diag, 1.0 / half_diag)
+    }
+
+    /// Ray going through the pixel at a given time (camera motion)
+    pub fn generate_at(&self, px: Point2<f32>, uv_lens: Point2<f32>, time: f32) -> Ray {
+        let mut ray = self.generate(px, uv_lens).with_time(time);
+        if let Some(motion) = &self.motion {
+            let m = motion.transform(time);
+            ray.o = m.transform_point(ray.o);
+            ray.d = m.transform_vector(ray.d).normalize();
+        }
+        ray
+    }
+
     /// Compute the ray direction going through the pixel passed
-    pub fn generate(&self, px: Point2<f32>) -> Ray {
-        let near_p = self.sample_to_camera.transform_point(Point3::new(
-            px.x / (self.img.x as f32),
-            px.y / (self.img.y as f32),
-            0.0,
-        ));
-        let d = near_p.to_vec().normalize();
+    /// uv_lens is only used by the thin lens camera
+    pub fn generate(&self, px: Point2<f32>, uv_lens: Point2<f32>) -> Ray {
+        let s = Point2::new(px.x / (self.img.x as f32), px.y / (self.img.y as f32));
+        let d = match &self.projection {
+            Projection::Perspective {
+                sample_to_camera, ..
+            } => {
+                let near_p = sample_to_camera.transform_point(Point3::new(s.x, s.y, 0.0));
+                near_p.to_vec().normalize()
+            }
+            Projection::Orthographic { size } => {
+                let o = Point3::new(
+                    self.x_sign * (2.0 * s.x - 1.0) * size.x,
+                    (1.0 - 2.0 * s.y) * size.y,
+                    0.0,
+                );
+                // The camera matrix can contain a scaling
+                let d = self.to_world.transform_vector(Vector3::new(0.0, 0.0, 1.0));
+                return Ray::new(self.to_world.transform_point(o), d.normalize());
+            }
+            Projection::LatLong => {
+                let phi = 2.0 * f32::consts::PI * (s.x - 0.5);
+                let theta = f32::consts::PI * s.y;
+                let sin_theta = theta.sin();
+                Vector3::new(
+                    self.x_sign * sin_theta * phi.sin(),
+                    theta.cos(),
+                    sin_theta * phi.cos(),
+                )
+            }
+            Projection::Fisheye { fov } => {
+                let scale = self.fisheye_scale();
+                let c = Vector2::new((2.0 * s.x - 1.0) * scale.x, (2.0 * s.y - 1.0) * scale.y);
+                let r = c.magnitude();
+                let theta = r * fov * 0.5;
+                let (cos_phi, sin_phi) = if r == 0.0 {
+                    (1.0, 0.0)
+                } else {
+                    (c.x / r, c.y / r)
+                };
+                let sin_theta = theta.sin();
+                Vector3::new(
+                    self.x_sign * sin_theta * cos_phi,
+                    -sin_theta * sin_phi,
+                    theta.cos(),
+                )
+            }
+        };
         // info!("d: {:?}",  self.to_world.transform_vector(d));
 
-        Ray::new(self.position(), self.to_world.transform_vector(d))
+        match (&self.lens, &self.projection) {
+            (Some(lens), Projection::Perspective { .. }) => {
+                // Go through the point on the plane of focus
+                let p_lens = lens.sample(uv_lens);
+                let p_focus = Point3::from_vec(d * (lens.focus_distance / d.z));
+                Ray::new(
+                    self.to_world.transform_point(p_lens),
+                    self.to_world
+                        .transform_vector((p_focus - p_lens).normalize()),
+                )
+            }
+            _ => Ray::new(self.position(), self.to_world.transform_vector(d)),
+        }
     }
 
     /// Method to splat a given sample on the camera
-    pub fn sample_direct(&self, p: &Point3<f32>) -> Option<(Color, Point2<f32>)> {
-        let ref_p = self.to_local.transform_point(*p);
-        if ref_p.z < 0.0 {
-            return None;
-        }
+    /// return the importance, the image position and the sampled position on the lens
+    /// As the lens is sampled proportionally to its transmission,
+    /// the importance is the same as the pinhole camera
+    pub fn sample_direct(
+        &self,
+        p: &Point3<f32>,
+        uv_lens: Point2<f32>,
+    ) -> Option<(Color, Point2<f32>, Point3<f32>)> {
+        let local_p = self.to_local.transform_point(*p);
+        let (importance, screen_pos, p_lens) = match &self.projection {
+            Projection::Perspective {
+                camera_to_sample, ..
+            } => {
+                let (p_lens, ref_p) = match &self.lens {
+                    None => (Point3::new(0.0, 0.0, 0.0), local_p),
+                    Some(lens) => {
+                        // Project the point through the lens on the plane of focus
+                        let p_lens = lens.sample(uv_lens);
+                        let d = local_p - p_lens;
+                        if d.z <= 0.0 {
+                            return None;
+                        }
+                        (p_lens, p_lens + d * (lens.focus_distance / d.z))
+                    }
+                };
+                if ref_p.z < 0.0 {
+                    return None;
+                }
+                let screen_pos = camera_to_sample.transform_point(ref_p);
+                let mut local_d = local_p - p_lens;
+                let inv_dist = 1.0 / local_d.magnitude();
+                local_d *= inv_dist;
+
+                let importance = match &self.lens {
+                    None => self.importance(local_d),
+                    // The image position is already checked
+                    Some(_) => self.importance_cos(local_d.z),
+                };
+                (
+                    importance * inv_dist * inv_dist,
+                    Point2::new(screen_pos.x, screen_pos.y),
+                    p_lens,
+                )
+            }
+            Projection::Orthographic { size } => {
+                // Only one point on the image plane see p
+                if local_p.z < 0.0 {
+                    return None;
+                }
+                let screen_pos = Point2::new(
+                    (self.x_sign * local_p.x / size.x + 1.0) * 0.5,
+                    (1.0 - local_p.y / size.y) * 0.5,
+                );
+                // Area of the image plane (world space)
+                let area = 4.0
+                    * size.x
+                    * size.y
+                    * self.to_world.x.truncate().magnitude()
+                    * self.to_world.y.truncate().magnitude();
+                (
+                    1.0 / area,
+                    screen_pos,
+                    Point3::new(local_p.x, local_p.y, 0.0),
+                )
+            }
+            Projection::LatLong => {
+                let d = local_p.to_vec();
+                let inv_dist = 1.0 / d.magnitude();
+                let d = d * inv_dist;
+                let theta = d.y.min(1.0).max(-1.0).acos();
+                let sin_theta = theta.sin();
+                if sin_theta == 0.0 {
+                    return None;
+                }
+                let phi = (self.x_sign * d.x).atan2(d.z);
+                let screen_pos = Point2::new(
+                    phi * 0.5 * f32::consts::FRAC_1_PI + 0.5,
+                    theta * f32::consts::FRAC_1_PI,
+                );
+                // Pdf of the direction when sampling uniformly the image
+                let importance = 1.0 / (2.0 * f32::consts::PI * f32::consts::PI * sin_theta);
+                (
+                    importance * inv_dist * inv_dist,
+                    screen_pos,
+                    Point3::new(0.0, 0.0, 0.0),
+                )
+            }
+            Projection::Fisheye { fov } => {
+                let d = local_p.to_vec();
+                let inv_dist = 1.0 / d.magnitude();
+                let d = d * inv_dist;
+                let theta = d.z.min(1.0).max(-1.0).acos();
+                if theta > fov * 0.5 {
+                    return None;
+                }
+                let sin_theta = theta.sin();
+                let r = theta / (fov * 0.5);
+                let c = Vector2::new(self.x_sign * d.x, -d.y);
+                let c = if sin_theta == 0.0 {
+                    Vector2::new(0.0, 0.0)
+                } else {
+                    c * (r / c.magnitude())
+                };
+                let scale = self.fisheye_scale();
+                let screen_pos =
+                    Point2::new((c.x / scale.x + 1.0) * 0.5, (c.y / scale.y + 1.0) * 0.5);
+                // Pdf of the direction when sampling uniformly the image
+                // (ratio theta / sin_theta goes to 1 on the optical axis)
+                let theta_max = fov * 0.5;
+                let ratio = if sin_theta == 0.0 {
+                    1.0
+                } else {
+                    theta / sin_theta
+                };
+                let importance = ratio / (4.0 * scale.x * scale.y * theta_max * theta_max);
+                (
+                    importance * inv_dist * inv_dist,
+                    screen_pos,
+                    Point3::new(0.0, 0.0, 0.0),
+                )
+            }
+        };
 
-        let screen_pos = self.camera_to_sample.transform_point(ref_p);
         if screen_pos.x < 0.0 || screen_pos.x > 1.0 || screen_pos.y < 0.0 || screen_pos.y > 1.0 {
             return None;
         }
@@ -105,15 +444,25 @@ impl Camera {
             screen_pos.x * self.img.x as f32,
             screen_pos.y * self.img.y as f32,
         );
-        let mut local_d = ref_p.to_vec();
-        let inv_dist = 1.0 / local_d.magnitude();
-        local_d *= inv_dist;
-
-        let importance = self.importance(local_d);
         if importance == 0.0 {
             None
         } else {
-            Some((Color::value(importance) * inv_dist * inv_dist, screen_pos))
+            Some((
+                Color::value(importance),
+                screen_pos,
+                self.to_world.transform_point(p_lens),
+            ))
+        }
+    }
+
+    fn image_rect(&self) -> (Point2<f32>, Point2<f32>) {
+        match &self.projection {
+            Projection::Perspective {
+                image_rect_min,
+                image_rect_max,
+                ..
+            } => (*image_rect_min, *image_rect_max),
+            _ => unreachable!(),
         }
     }
 
@@ -124,16 +473,24 @@ impl Camera {
         }
         let inv_cos_theta = 1.0 / cos_theta;
         let p = Point2::new(d.x * inv_cos_theta, d.y * inv_cos_theta);
-        if p.x < self.image_rect_min.x
-            || p.x > self.image_rect_max.x
-            || p.y < self.image_rect_min.y
-            || p.x > self.image_rect_max.y
+        let (image_rect_min, image_rect_max) = self.image_rect();
+        if p.x < image_rect_min.x
+            || p.x > image_rect_max.x
+            || p.y < image_rect_min.y
+            || p.x > image_rect_max.y
         {
             return 0.0;
         }
+        self.importance_cos(cos_theta)
+    }
 
-        let size = (self.image_rect_max.x - self.image_rect_min.x)
-            * (self.image_rect_max.y - self.image_rect_min.y);
+    fn importance_cos(&self, cos_theta: f32) -> f32 {
+        if cos_theta <= 0.0 {
+            return 0.0;
+        }
+        let inv_cos_theta = 1.0 / cos_theta;
+        let (image_rect_min, image_rect_max) = self.image_rect();
+        let size = (image_rect_max.x - image_rect_min.x) * (image_rect_max.y - image_rect_min.y);
         (1.0 / size as f32) * inv_cos_theta * inv_cos_theta * inv_cos_theta
     }
 
@@ -143,7 +500,7 @@ impl Camera {
 
     pub fn print_info(&self) {
         let pix = Point2::new(self.img.x as f32 * 0.5 + 0.5, self.img.y as f32 * 0.5 + 0.5);
-        let view_dir = self.generate(pix).d;
+        let view_dir = self.generate(pix, Point2::new(0.5, 0.5)).d;
         info!(" - Position: {:?}", self.position());
         info!(" - View direction: {:?}", view_dir);
     }
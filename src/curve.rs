@@ -0,0 +1,515 @@
+use crate::clamp;
+use crate::math::Frame;
+use crate::structure::*;
+use byteorder::{LittleEndian, ReadBytesExt};
+use cgmath::*;
+use std;
+use std::io::Read;
+
+/// How the curve is seen by the rays
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CurveType {
+    /// Flat ribbon always facing the ray
+    Flat,
+    /// Flat ribbon facing the ray but with a cylinder normal
+    Cylinder,
+    /// Ribbon oriented with the normals given at the extremities
+    Ribbon,
+}
+
+fn lerp<T>(t: f32, a: T, b: T) -> T
+where
+    T: std::ops::Mul<f32, Output = T> + std::ops::Add<Output = T>,
+{
+    a * (1.0 - t) + b * t
+}
+
+/// Split a cubic Bézier curve in two at u = 0.5
+/// the two curves share the middle control point
+fn subdivide_bezier(cp: &[Vector3<f32>; 4]) -> [Vector3<f32>; 7] {
+    [
+        cp[0],
+        (cp[0] + cp[1]) / 2.0,
+        (cp[0] + cp[1] * 2.0 + cp[2]) / 4.0,
+        (cp[0] + cp[1] * 3.0 + cp[2] * 3.0 + cp[3]) / 8.0,
+        (cp[1] + cp[2] * 2.0 + cp[3]) / 4.0,
+        (cp[2] + cp[3]) / 2.0,
+        cp[3],
+    ]
+}
+
+/// Evaluate a cubic Bézier curve (de Casteljau)
+/// return the position and the derivative
+fn eval_bezier(cp: &[Vector3<f32>; 4], u: f32) -> (Vector3<f32>, Vector3<f32>) {
+    let cp1 = [
+        lerp(u, cp[0], cp[1]),
+        lerp(u, cp[1], cp[2]),
+        lerp(u, cp[2], cp[3]),
+    ];
+    let cp2 = [lerp(u, cp1[0], cp1[1]), lerp(u, cp1[1], cp1[2])];
+    let deriv = if (cp2[1] - cp2[0]).magnitude2() > 0.0 {
+        (cp2[1] - cp2[0]) * 3.0
+    } else {
+        // Degenerated case where the first two control points are equal
+        cp[3] - cp[0]
+    };
+    (lerp(u, cp2[0], cp2[1]), deriv)
+}
+
+/// Cubic Bézier segment with a linearly interpolated width
+#[derive(Clone, Debug)]
+pub struct CurveSegment {
+    pub cp: [Vector3<f32>; 4],
+    pub width: [f32; 2],
+    /// Parametric range covered by this segment on the whole strand
+    pub u_min: f32,
+    pub u_max: f32,
+    /// Normals at the segment extremities (only used by ribbons)
+    pub normals: Option<[Vector3<f32>; 2]>,
+}
+
+impl CurveSegment {
+    pub fn eval(&self, u: f32) -> (Vector3<f32>, Vector3<f32>) {
+        eval_bezier(&self.cp, u)
+    }
+
+    pub fn width(&self, u: f32) -> f32 {
+        lerp(u, self.width[0], self.width[1])
+    }
+
+    /// Ribbon normal (spherical interpolation)
+    fn normal(&self, u: f32) -> Vector3<f32> {
+        let n = self.normals.as_ref().unwrap();
+        let cos_angle = clamp(n[0].dot(n[1]), 0.0, 1.0);
+        let angle = cos_angle.acos();
+        if angle < 1e-4 {
+            lerp(u, n[0], n[1]).normalize()
+        } else {
+            let inv_sin_angle = 1.0 / angle.sin();
+            let s0 = ((1.0 - u) * angle).sin() * inv_sin_angle;
+            let s1 = (u * angle).sin() * inv_sin_angle;
+            n[0] * s0 + n[1] * s1
+        }
+    }
+
+    pub fn aabb(&self) -> AABB {
+        let mut aabb = AABB::default();
+        for p in &self.cp {
+            aabb = aabb.union_vec(p);
+        }
+        let half_width = 0.5 * self.width[0].max(self.width[1]);
+        aabb.p_min -= Vector3::new(half_width, half_width, half_width);
+        aabb.p_max += Vector3::new(half_width, half_width, half_width);
+        aabb
+    }
+
+    /// Intersection code based on PBRT-v3 (recursive subdivision)
+    /// Note that the direction need to be normalized
+    pub fn intersect(
+        &self,
+        curve_type: CurveType,
+        p_c: &Point3<f32>,
+        d_c: &Vector3<f32>,
+        its: &mut IntersectionUV,
+    ) -> bool {
+        // Project the control points in the ray coordinate system
+        // where the ray start at the origin and goes along +z
+        let frame = Frame::new(*d_c);
+        let cp = [
+            frame.to_local(self.cp[0] - p_c.to_vec()),
+            frame.to_local(self.cp[1] - p_c.to_vec()),
+            frame.to_local(self.cp[2] - p_c.to_vec()),
+            frame.to_local(self.cp[3] - p_c.to_vec()),
+        ];
+
+        // Compute the number of refinement steps
+        let mut l0 = 0.0_f32;
+        for i in 0..2 {
+            l0 = l0
+                .max((cp[i].x - 2.0 * cp[i + 1].x + cp[i + 2].x).abs())
+                .max((cp[i].y - 2.0 * cp[i + 1].y + cp[i + 2].y).abs())
+                .max((cp[i].z - 2.0 * cp[i + 1].z + cp[i + 2].z).abs());
+        }
+        let eps = self.width[0].max(self.width[1]) * 0.05;
+        let r0 = (std::f32::consts::SQRT_2 * 6.0 * l0 / (8.0 * eps)).log2() * 0.5;
+        let max_depth = if r0 > 0.0 {
+            (r0.ceil() as usize).min(10)
+        } else {
+            0
+        };
+
+        let mut t_max = its.t;
+        let mut hit = None;
+        if !self.recursive_intersect(
+            curve_type, d_c, &cp, 0.0, 1.0, max_depth, &mut t_max, &mut hit,
+        ) {
+            return false;
+        }
+        let (u, v) = hit.unwrap();
+
+        // Compute the surface derivatives in world space
+        let dpdu = self.eval(u).1;
+        let hit_width = self.width(u);
+        let dpdv = match curve_type {
+            CurveType::Ribbon => self.normal(u).cross(dpdu).normalize() * hit_width,
+            CurveType::Flat | CurveType::Cylinder => {
+                let dpdu_plane = frame.to_local(dpdu);
+                let dpdv_plane = frame
+                    .to_world(Vector3::new(-dpdu_plane.y, dpdu_plane.x, 0.0))
+                    .normalize()
+                    * hit_width;
+                if curve_type == CurveType::Cylinder {
+                    // Rotate dpdv around dpdu to give a cylinder appearance
+                    let theta = lerp(v, -90.0_f32, 90.0);
+                    Matrix3::from_axis_angle(dpdu.normalize(), Deg(-theta)) * dpdv_plane
+                } else {
+                    dpdv_plane
+                }
+            }
+        };
+
+        its.t = t_max;
+        its.p = p_c + *d_c * t_max;
+        its.n = dpdu.cross(dpdv).normalize();
+        its.u = u;
+        its.v = v;
+        true
+    }
+
+    fn recursive_intersect(
+        &self,
+        curve_type: CurveType,
+        d_c: &Vector3<f32>,
+        cp: &[Vector3<f32>; 4],
+        u0: f32,
+        u1: f32,
+        depth: usize,
+        t_max: &mut f32,
+        hit: &mut Option<(f32, f32)>,
+    ) -> bool {
+        if depth > 0 {
+            let cp_split = subdivide_bezier(cp);
+            let u = [u0, (u0 + u1) * 0.5, u1];
+            let mut found = false;
+            for seg in 0..2 {
+                let cps = [
+                    cp_split[3 * seg],
+                    cp_split[3 * seg + 1],
+                    cp_split[3 * seg + 2],
+                    cp_split[3 * seg + 3],
+                ];
+                let half_width = 0.5 * self.width(u[seg]).max(self.width(u[seg + 1]));
+
+                // Check the bounding box of the sub-curve against the ray
+                let (mut p_min, mut p_max) = (cps[0], cps[0]);
+                for p in &cps[1..] {
+                    p_min = Vector3::new(p_min.x.min(p.x), p_min.y.min(p.y), p_min.z.min(p.z));
+                    p_max = Vector3::new(p_max.x.max(p.x), p_max.y.max(p.y), p_max.z.max(p.z));
+                }
+                if p_max.x + half_width < 0.0 || p_min.x - half_width > 0.0 {
+                    continue;
+                }
+                if p_max.y + half_width < 0.0 || p_min.y - half_width > 0.0 {
+                    continue;
+                }
+                if p_max.z + half_width < 0.0 || p_min.z - half_width > *t_max {
+                    continue;
+                }
+
+                found |= self.recursive_intersect(
+                    curve_type,
+                    d_c,
+                    &cps,
+                    u[seg],
+                    u[seg + 1],
+                    depth - 1,
+                    t_max,
+                    hit,
+                );
+            }
+            found
+        } else {
+            // Test the ray against the curve start and end perpendicular
+            let edge = (cp[1].y - cp[0].y) * -cp[0].y + cp[0].x * (cp[0].x - cp[1].x);
+            if edge < 0.0 {
+                return false;
+            }
+            let edge = (cp[2].y - cp[3].y) * -cp[3].y + cp[3].x * (cp[3].x - cp[2].x);
+            if edge < 0.0 {
+                return false;
+            }
+
+            // Closest point on the segment
+            let segment_direction = Vector2::new(cp[3].x - cp[0].x, cp[3].y - cp[0].y);
+            let denom = segment_direction.magnitude2();
+            if denom == 0.0 {
+                return false;
+            }
+            let w = Vector2::new(-cp[0].x, -cp[0].y).dot(segment_direction) / denom;
+            let u = clamp(lerp(w, u0, u1), u0, u1);
+
+            let mut hit_width = self.width(u);
+            if curve_type == CurveType::Ribbon {
+                hit_width *= self.normal(u).dot(*d_c).abs();
+            }
+
+            // Distance to the curve center
+            let (pc, dpcdw) = eval_bezier(cp, clamp(w, 0.0, 1.0));
+            let dist_2 = pc.x * pc.x + pc.y * pc.y;
+            if dist_2 > hit_width * hit_width * 0.25 {
+                return false;
+            }
+            // Avoid self intersection
+            // FIXME: Same threshold than triangles
+            if pc.z < 0.00001 || pc.z > *t_max {
+                return false;
+            }
+
+            // v coordinate goes across the curve width
+            let dist = dist_2.sqrt();
+            let edge_func = dpcdw.x * -pc.y + pc.x * dpcdw.y;
+            let v = if edge_func > 0.0 {
+                0.5 + dist / hit_width
+            } else {
+                0.5 - dist / hit_width
+            };
+
+            *t_max = pc.z;
+            *hit = Some((u, v));
+            true
+        }
+    }
+}
+
+/// Set of curves sharing the same type
+pub struct Curves {
+    pub curve_type: CurveType,
+    pub segments: Vec<CurveSegment>,
+}
+
+impl Curves {
+    /// Build the curves from Bézier control points
+    /// this follow PBRT "curve" shape parameters:
+    ///  - cp: 3 * n + 1 control points (n segments)
+    ///  - width: width at the begining and the end of the curve
+    ///  - normals: n + 1 normals (only for ribbons)
+    /// Return None if the parameters are inconsistent
+    pub fn from_bezier(
+        curve_type: CurveType,
+        cp: &[Vector3<f32>],
+        width: (f32, f32),
+        normals: Option<&[Vector3<f32>]>,
+    ) -> Option<Curves> {
+        if cp.len() < 4 || (cp.len() - 1) % 3 != 0 {
+            warn!(
+                "Wrong number of control points for a cubic Bézier curve: {}",
+                cp.len()
+            );
+            return None;
+        }
+        let nb_segments = (cp.len() - 1) / 3;
+        if curve_type == CurveType::Ribbon {
+            match normals {
+                None => {
+                    warn!("Ribbon curves need normals");
+                    return None;
+                }
+                Some(n) if n.len() != nb_segments + 1 => {
+                    warn!(
+                        "Ribbon curves need {} normals ({} given)",
+                        nb_segments + 1,
+                        n.len()
+                    );
+                    return None;
+                }
+                _ => {}
+            }
+        }
+
+        let segments = (0..nb_segments)
+            .map(|i| {
+                let u_min = i as f32 / nb_segments as f32;
+                let u_max = (i + 1) as f32 / nb_segments as f32;
+                CurveSegment {
+                    cp: [cp[3 * i], cp[3 * i + 1], cp[3 * i + 2], cp[3 * i + 3]],
+                    width: [lerp(u_min, width.0, width.1), lerp(u_max, width.0, width.1)],
+                    u_min,
+                    u_max,
+                    normals: match (curve_type, normals) {
+                        (CurveType::Ribbon, Some(n)) => {
+                            Some([n[i].normalize(), n[i + 1].normalize()])
+                        }
+                        _ => None,
+                    },
+                }
+            })
+            .collect();
+
+        Some(Curves {
+            curve_type,
+            segments,
+        })
+    }
+
+    /// Build the curves from strands (polylines) with per-vertex width
+    /// Each strand is converted to Bézier segments (Catmull-Rom spline)
+    /// Return None for ribbons (no normals) or if the widths do not match the strands
+    pub fn from_strands(
+        curve_type: CurveType,
+        strands: &[Vec<Vector3<f32>>],
+        widths: &[Vec<f32>],
+    ) -> Option<Curves> {
+        if curve_type == CurveType::Ribbon {
+            warn!("Ribbon curves cannot be built from strands (no normals)");
+            return None;
+        }
+        if strands.len() != widths.len() {
+            warn!(
+                "Number of strands ({}) and widths ({}) mismatch",
+                strands.len(),
+                widths.len()
+            );
+            return None;
+        }
+
+        let mut segments = vec![];
+        for (points, width) in strands.iter().zip(widths.iter()) {
+            if points.len() != width.len() {
+                warn!(
+                    "Strand with {} points and {} widths, ignoring it",
+                    points.len(),
+                    width.len()
+                );
+                continue;
+            }
+            if points.len() < 2 {
+                continue;
+            }
+            let nb_segments = points.len() - 1;
+            for i in 0..nb_segments {
+                let p_prev = points[if i == 0 { 0 } else { i - 1 }];
+                let p_next = points[(i + 2).min(points.len() - 1)];
+                let (p0, p1) = (points[i], points[i + 1]);
+                segments.push(CurveSegment {
+                    cp: [p0, p0 + (p1 - p_prev) / 6.0, p1 - (p_next - p0) / 6.0, p1],
+                    width: [width[i], width[i + 1]],
+                    u_min: i as f32 / nb_segments as f32,
+                    u_max: (i + 1) as f32 / nb_segments as f32,
+                    normals: None,
+                });
+            }
+        }
+
+        Some(Curves {
+            curve_type,
+            segments,
+        })
+    }
+}
+
+/// Read Cem Yuksel's binary hair format (.hair)
+/// http://www.cemyuksel.com/research/hairmodels/
+/// Transparency and colors informations are ignored
+pub fn load_hair(filename: &std::path::Path) -> std::io::Result<Curves> {
+    info!("Try to load {:?}", filename);
+    let f = std::fs::File::open(filename)?;
+    let file_size = f.metadata()?.len();
+    let mut f = std::io::BufReader::new(f);
+
+    // Header (128 bytes)
+    let mut signature = [0; 4];
+    f.read_exact(&mut signature)?;
+    if &signature != b"HAIR" {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Wrong signature for hair file",
+        ));
+    }
+    let nb_strands = f.read_u32::<LittleEndian>()? as usize;
+    let nb_points = f.read_u32::<LittleEndian>()? as usize;
+    let flags = f.read_u32::<LittleEndian>()?;
+    let default_segments = f.read_u32::<LittleEndian>()? as usize;
+    let default_thickness = f.read_f32::<LittleEndian>()?;
+    let _default_transparency = f.read_f32::<LittleEndian>()?;
+    let mut default_color = [0.0; 3];
+    f.read_f32_into::<LittleEndian>(&mut default_color)?;
+    let mut info = [0; 88];
+    f.read_exact(&mut info)?;
+    info!(" - strands: {}", nb_strands);
+    info!(" - points: {}", nb_points);
+
+    // Check the counts against the file size before allocating
+    // (each strand has at least one point)
+    let mut arrays_size = 0;
+    if flags & 0x1 != 0 {
+        arrays_size += nb_strands as u64 * 2;
+    }
+    if flags & 0x2 != 0 {
+        arrays_size += nb_points as u64 * 3 * 4;
+    }
+    if flags & 0x4 != 0 {
+        arrays_size += nb_points as u64 * 4;
+    }
+    if nb_strands > nb_points || arrays_size > file_size.saturating_sub(128) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Truncated hair file",
+        ));
+    }
+
+    // Number of segments per strands
+    let segments = if flags & 0x1 != 0 {
+        let mut v = vec![0; nb_strands];
+        f.read_u16_into::<LittleEndian>(&mut v)?;
+        v.into_iter().map(|v| v as usize).collect()
+    } else {
+        vec![default_segments; nb_strands]
+    };
+
+    // Position of the points
+    if flags & 0x2 == 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Hair file without points",
+        ));
+    }
+    let mut points = vec![0.0; nb_points * 3];
+    f.read_f32_into::<LittleEndian>(&mut points)?;
+
+    // Thickness per points
+    let thickness = if flags & 0x4 != 0 {
+        let mut v = vec![0.0; nb_points];
+        f.read_f32_into::<LittleEndian>(&mut v)?;
+        v
+    } else {
+        vec![default_thickness; nb_points]
+    };
+
+    // Split the points into strands
+    let mut strands = Vec::with_capacity(nb_strands);
+    let mut widths = Vec::with_capacity(nb_strands);
+    let mut offset = 0;
+    for nb_seg in segments {
+        let nb_strand_points = nb_seg + 1;
+        if offset + nb_strand_points > nb_points {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Hair file strands exceed the number of points",
+            ));
+        }
+        strands.push(
+            (offset..offset + nb_strand_points)
+                .map(|i| Vector3::new(points[3 * i], points[3 * i + 1], points[3 * i + 2]))
+                .collect(),
+        );
+        widths.push(thickness[offset..offset + nb_strand_points].to_vec());
+        offset += nb_strand_points;
+    }
+
+    Curves::from_strands(CurveType::Cylinder, &strands, &widths).ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "Inconsistent strands inside the hair file",
+        )
+    })
+}
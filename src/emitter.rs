@@ -1,8 +1,10 @@
 use crate::constants::ONE_MINUS_EPSILON;
-use crate::geometry::{EmissionType, Mesh};
-use crate::math::{sample_uniform_sphere, Distribution1D, Distribution2D};
+use crate::geometry::{EmissionType, Mesh, ShapeType};
+use crate::ies::IESProfile;
+use crate::math::{sample_uniform_sphere, Distribution1D, Distribution2D, Frame};
 use crate::samplers::Sampler;
 use crate::scene::Scene;
+use crate::sky::Sky;
 use crate::structure::*;
 use cgmath::*;
 use std::{collections::HashMap, sync::Arc};
@@ -29,6 +31,7 @@ pub struct LightSamplingPDF {
     pub n: Vector3<f32>, // normal on the light (geometric)
     pub uv: Option<Vector2<f32>>,
     pub dir: Vector3<f32>, // direction
+    pub primitive_id: Option<usize>,
 }
 
 impl LightSamplingPDF {
@@ -39,10 +42,49 @@ impl LightSamplingPDF {
             n: its.n_g,
             uv: its.uv,
             dir: ray.d,
+            primitive_id: its.primitive_id,
         }
     }
 }
 
+/// Segment on a planar emitter (used by the photon planes)
+pub struct Chord {
+    pub o: Point3<f32>,
+    pub d: Vector3<f32>,
+    pub length: f32,
+    /// Normal of the emitter plane
+    pub n: Vector3<f32>,
+}
+
+/// Segment of a disk passing through p (inside the disk plane)
+/// with an orientation u in [0, 1] (half turn)
+fn disk_chord(
+    center: Point3<f32>,
+    radius: f32,
+    frame: &Frame,
+    p: &Point3<f32>,
+    u: f32,
+) -> Option<Chord> {
+    let alpha = std::f32::consts::PI * u;
+    let d = Vector2::new(alpha.cos(), alpha.sin());
+    let p_local = frame.to_local(p - center);
+    let p_local = Vector2::new(p_local.x, p_local.y);
+    // Solve |p + t d|^2 = r^2
+    let b = p_local.dot(d);
+    let delta = b * b - (p_local.magnitude2() - radius * radius);
+    if delta < 0.0 {
+        return None;
+    }
+    let delta = delta.sqrt();
+    let d = frame.to_world(Vector3::new(d.x, d.y, 0.0));
+    Some(Chord {
+        o: p + d * (-b - delta),
+        d,
+        length: 2.0 * delta,
+        n: frame.to_world(Vector3::new(0.0, 0.0, 1.0)),
+    })
+}
+
 pub trait Emitter: Send + Sync {
     /// Direct sampling & PDF methods
     fn direct_pdf(&self, light_sampling: &LightSamplingPDF) -> PDF;
@@ -70,26 +112,52 @@ pub trait Emitter: Send + Sync {
     fn is_surface(&self) -> bool {
         false
     }
+    /// Lights at infinity (directional, environment) are not inside
+    /// the light tree and are selected with a separate probability
+    fn is_infinite(&self) -> bool {
+        false
+    }
     fn convert_light_proxy(&self, _emitter_id: usize) -> Vec<LightProxy> {
         vec![]
     }
+
+    // For the photon planes
+    /// The emission is constant over a flat surface (per primitive)
+    /// and uniformly sampled by sample_position
+    fn is_planar(&self) -> bool {
+        false
+    }
+    /// Segment of the emitter passing through p, with an orientation u in [0, 1]
+    /// (half turn inside the emitter plane)
+    fn chord(&self, _p: &Point3<f32>, _primitive_id: Option<usize>, _u: f32) -> Option<Chord> {
+        None
+    }
+    /// The emission comes from a single position (sample_position)
+    /// with an intensity given by eval
+    fn is_point(&self) -> bool {
+        false
+    }
+    // By default, the emitter is made of a single primitive
+    fn nb_primitives(&self) -> usize {
+        1
+    }
     fn direct_sample_tri(
         &self,
-        _p: &Point3<f32>,
+        p: &Point3<f32>,
         _primitive_id: usize,
-        _uv: Point2<f32>,
+        uv: Point2<f32>,
     ) -> LightSampling {
-        unimplemented!()
+        self.direct_sample(p, 0.0, uv)
     }
-    fn direct_pdf_tri(&self, _light_sampling: &LightSamplingPDF, _id_primitive: usize) -> PDF {
-        unimplemented!()
+    fn direct_pdf_tri(&self, light_sampling: &LightSamplingPDF, _id_primitive: usize) -> PDF {
+        self.direct_pdf(light_sampling)
     }
     fn sample_position_tri(
         &self,
         _primitive_id: usize,
-        _uv: Point2<f32>,
+        uv: Point2<f32>,
     ) -> (SampledPosition, Color) {
-        unimplemented!()
+        self.sample_position(0.0, uv)
     }
 }
 
@@ -182,6 +250,132 @@ impl Emitter for DirectionalLight {
     ) -> (Vector3<f32>, PDF, Color) {
         (sampled_pos.n, PDF::Discrete(1.0), Color::one())
     }
+    fn is_infinite(&self) -> bool {
+        true
+    }
+    fn is_planar(&self) -> bool {
+        true
+    }
+    fn chord(&self, p: &Point3<f32>, _primitive_id: Option<usize>, u: f32) -> Option<Chord> {
+        let bsphere = self.bsphere.as_ref().unwrap();
+        let center = bsphere.center - self.direction * bsphere.radius;
+        disk_chord(center, bsphere.radius, &Frame::new(self.direction), p, u)
+    }
+}
+
+/// Sun disk emitter (far away, with a small aperture)
+/// Directions are jittered over the disk to get soft shadows,
+/// but as for the directional light, the sun is not visible by the rays.
+pub struct SunEmitter {
+    // Direction toward the sun
+    pub direction: Vector3<f32>,
+    pub radiance: Color,
+    // Cosine of the sun angular radius
+    pub cos_aperture: f32,
+    // World sphere (populated with preprocess)
+    pub bsphere: Option<BoundingSphere>,
+}
+
+impl SunEmitter {
+    pub fn from_sky(sky: &Sky) -> SunEmitter {
+        SunEmitter {
+            direction: sky.sun_direction,
+            radiance: sky.sun_radiance(),
+            cos_aperture: crate::sky::SUN_APERTURE.cos(),
+            bsphere: None,
+        }
+    }
+
+    fn irradiance(&self) -> Color {
+        self.radiance * 2.0 * std::f32::consts::PI * (1.0 - self.cos_aperture)
+    }
+
+    fn sample_disk_direction(&self, uv: Point2<f32>) -> Vector3<f32> {
+        let frame = Frame::new(self.direction);
+        frame.to_world(crate::math::sample_uniform_cone(uv, self.cos_aperture))
+    }
+}
+
+impl Emitter for SunEmitter {
+    fn preprocess(&mut self, scene: &Scene) {
+        self.bsphere = scene.bsphere.clone();
+        self.bsphere.as_mut().unwrap().radius *= 1.1;
+    }
+
+    fn direct_pdf(&self, _: &LightSamplingPDF) -> PDF {
+        PDF::Discrete(1.0) // Treated as a directional light
+    }
+
+    fn direct_sample(&self, v: &Point3<f32>, _: f32, uv: Point2<f32>) -> LightSampling {
+        let bsphere = self.bsphere.as_ref().unwrap();
+        let d = self.sample_disk_direction(uv);
+        // Outside the scene bounding sphere
+        let p = v + 2.0 * bsphere.radius * d;
+        LightSampling {
+            emitter: self,
+            pdf: PDF::Discrete(1.0),
+            p,
+            n: -d,
+            uv: None,
+            primitive_id: None,
+            d,
+            weight: self.irradiance(),
+        }
+    }
+
+    fn sample_position(&self, _s: f32, uv: Point2<f32>) -> (SampledPosition, Color) {
+        let bsphere = self.bsphere.as_ref().unwrap();
+
+        // Sampling a disk facing the sun
+        let p = crate::math::concentric_sample_disk(uv);
+        let area = std::f32::consts::PI * bsphere.radius.powi(2);
+        let frame = Frame::new(self.direction);
+        let poff = frame.to_world(Vector3::new(p.x, p.y, 0.0) * bsphere.radius);
+        let p = bsphere.center + self.direction * bsphere.radius + poff;
+
+        (
+            SampledPosition {
+                p,
+                n: -self.direction,
+                uv: None,
+                pdf: PDF::Area(1.0 / area),
+                primitive_id: None,
+            },
+            self.irradiance() * area,
+        )
+    }
+
+    fn sample_direction(&self, _: &SampledPosition, d: Point2<f32>) -> (Vector3<f32>, PDF, Color) {
+        (
+            -self.sample_disk_direction(d),
+            PDF::Discrete(1.0),
+            Color::one(),
+        )
+    }
+
+    fn flux(&self) -> Color {
+        let area = std::f32::consts::PI * self.bsphere.as_ref().unwrap().radius.powi(2);
+        self.irradiance() * area
+    }
+
+    fn correct_flux(&self) -> f32 {
+        1.0 / (std::f32::consts::PI * self.bsphere.as_ref().unwrap().radius.powi(2))
+    }
+
+    fn eval(&self, _: Vector3<f32>, _: Option<Vector2<f32>>) -> Color {
+        self.irradiance()
+    }
+    fn is_infinite(&self) -> bool {
+        true
+    }
+    fn is_planar(&self) -> bool {
+        true
+    }
+    fn chord(&self, p: &Point3<f32>, _primitive_id: Option<usize>, u: f32) -> Option<Chord> {
+        let bsphere = self.bsphere.as_ref().unwrap();
+        let center = bsphere.center + self.direction * bsphere.radius;
+        disk_chord(center, bsphere.radius, &Frame::new(self.direction), p, u)
+    }
 }
 
 pub struct PointEmitter {
@@ -247,6 +441,20 @@ impl Emitter for PointEmitter {
     fn eval(&self, _: Vector3<f32>, _: Option<Vector2<f32>>) -> Color {
         self.intensity
     }
+    fn is_point(&self) -> bool {
+        true
+    }
+    fn convert_light_proxy(&self, emitter_id: usize) -> Vec<LightProxy> {
+        // Emit in all the directions
+        vec![point_light_proxy(
+            emitter_id,
+            self.position,
+            Vector3::new(0.0, 0.0, 1.0),
+            std::f32::consts::PI,
+            0.0,
+            self.flux().channel_max(),
+        )]
+    }
 }
 
 pub struct PointNormalEmitter {
@@ -295,6 +503,548 @@ impl Emitter for PointNormalEmitter {
     fn is_surface(&self) -> bool {
         true // Because of the cosine.
     }
+    fn convert_light_proxy(&self, emitter_id: usize) -> Vec<LightProxy> {
+        // Cosine emission around the normal
+        vec![point_light_proxy(
+            emitter_id,
+            self.position,
+            self.normal,
+            0.0,
+            std::f32::consts::FRAC_PI_2,
+            self.flux().channel_max(),
+        )]
+    }
+}
+
+/// Light proxy for ATS of lights located at a single point
+fn point_light_proxy(
+    emitter_id: usize,
+    position: Point3<f32>,
+    w: Vector3<f32>,
+    theta_o: f32,
+    theta_e: f32,
+    phi: f32,
+) -> LightProxy {
+    LightProxy {
+        emitter_id,
+        primitive_idx: 0,
+        bounds: LightBounds::from_point(position, w, theta_o, theta_e, phi),
+    }
+}
+
+/// Frame and position of a light from its transformation
+/// The light is facing the +z axis in its local space
+fn light_frame(to_world: &Matrix4<f32>) -> (Point3<f32>, Frame) {
+    let position = to_world.transform_point(Point3::new(0.0, 0.0, 0.0));
+    let n = to_world
+        .transform_vector(Vector3::new(0.0, 0.0, 1.0))
+        .normalize();
+    let t = to_world.transform_vector(Vector3::new(1.0, 0.0, 0.0));
+    (position, Frame::from_tangent(n, t))
+}
+
+/// Point light emitting inside a cone (smooth falloff as PBRT)
+pub struct SpotEmitter {
+    pub intensity: Color,
+    pub position: Point3<f32>,
+    pub frame: Frame,
+    pub cos_total_width: f32,
+    pub cos_falloff_start: f32,
+}
+impl SpotEmitter {
+    /// Angles in degrees: the cone angle and the falloff width inside it
+    /// Return None if the cone angle is outside ]0, 180] or if from == to
+    pub fn new(
+        intensity: Color,
+        from: Point3<f32>,
+        to: Point3<f32>,
+        cone_angle: f32,
+        cone_delta: f32,
+    ) -> Option<SpotEmitter> {
+        if !(cone_angle > 0.0 && cone_angle <= 180.0) {
+            warn!("Invalid spot light cone angle: {}", cone_angle);
+            return None;
+        }
+        let d = to - from;
+        if d.magnitude2() == 0.0 {
+            warn!("Spot light without direction (from == to)");
+            return None;
+        }
+        let cone_delta = crate::clamp(cone_delta, 0.0, cone_angle);
+        Some(SpotEmitter {
+            intensity,
+            position: from,
+            frame: Frame::new(d.normalize()),
+            cos_total_width: cone_angle.to_radians().cos(),
+            cos_falloff_start: (cone_angle - cone_delta).to_radians().cos(),
+        })
+    }
+
+    fn falloff(&self, cos_theta: f32) -> f32 {
+        if cos_theta <= self.cos_total_width {
+            0.0
+        } else if cos_theta >= self.cos_falloff_start {
+            1.0
+        } else {
+            let t = (cos_theta - self.cos_total_width)
+                / (self.cos_falloff_start - self.cos_total_width);
+            t * t * (3.0 - 2.0 * t)
+        }
+    }
+
+    fn cone_solid_angle(&self) -> f32 {
+        2.0 * std::f32::consts::PI * (1.0 - self.cos_total_width)
+    }
+}
+impl Emitter for SpotEmitter {
+    fn direct_pdf(&self, _: &LightSamplingPDF) -> PDF {
+        PDF::Discrete(1.0)
+    }
+
+    fn direct_sample(&self, v: &Point3<f32>, _r: f32, _uv: Point2<f32>) -> LightSampling {
+        let p = self.position;
+        let d = p - v;
+        let dist = d.magnitude();
+        let d = d / dist;
+
+        let falloff = self.falloff(self.frame.to_local(-d).z);
+        LightSampling {
+            emitter: self,
+            pdf: PDF::Discrete(1.0),
+            p,
+            uv: None,
+            primitive_id: None,
+            n: Vector3::new(0.0, 0.0, 0.0),
+            d,
+            weight: self.intensity * falloff / dist.powi(2),
+        }
+    }
+
+    fn sample_position(&self, _s: f32, _uv: Point2<f32>) -> (SampledPosition, Color) {
+        (
+            SampledPosition {
+                p: self.position,
+                n: Vector3::new(0.0, 0.0, 0.0),
+                uv: None,
+                pdf: PDF::Discrete(1.0),
+                primitive_id: None,
+            },
+            self.intensity * self.cone_solid_angle(),
+        )
+    }
+
+    fn sample_direction(&self, _: &SampledPosition, d: Point2<f32>) -> (Vector3<f32>, PDF, Color) {
+        let d_local = crate::math::sample_uniform_cone(d, self.cos_total_width);
+        (
+            self.frame.to_world(d_local),
+            PDF::SolidAngle(1.0 / self.cone_solid_angle()),
+            Color::value(self.falloff(d_local.z)),
+        )
+    }
+
+    fn flux(&self) -> Color {
+        // The smoothstep falloff integrates to half of its width
+        self.intensity
+            * 2.0
+            * std::f32::consts::PI
+            * (1.0 - 0.5 * (self.cos_falloff_start + self.cos_total_width))
+    }
+
+    fn correct_flux(&self) -> f32 {
+        1.0 / self.cone_solid_angle()
+    }
+
+    fn eval(&self, d: Vector3<f32>, _: Option<Vector2<f32>>) -> Color {
+        self.intensity * self.falloff(self.frame.to_local(d).z)
+    }
+
+    fn is_point(&self) -> bool {
+        true
+    }
+
+    fn convert_light_proxy(&self, emitter_id: usize) -> Vec<LightProxy> {
+        let phi = self.intensity.channel_max() * 4.0 * std::f32::consts::PI;
+        vec![point_light_proxy(
+            emitter_id,
+            self.position,
+            self.frame.to_world(Vector3::new(0.0, 0.0, 1.0)),
+            safe_acos(self.cos_total_width),
+            0.0,
+            phi,
+        )]
+    }
+}
+
+/// Point light with a measured intensity profile (IES file)
+pub struct IESEmitter {
+    pub profile: IESProfile,
+    // Scale applied to the candela values
+    pub intensity: Color,
+    pub position: Point3<f32>,
+    pub frame: Frame,
+    // Directional sampling over (phi, theta)
+    distribution: Distribution2D,
+    distribution_size: Vector2<u32>,
+    // Integral of the profile over the sphere
+    profile_flux: f32,
+}
+impl IESEmitter {
+    /// The nadir of the profile is aligned with the +z axis of the transformation
+    pub fn new(profile: IESProfile, intensity: Color, to_world: Matrix4<f32>) -> IESEmitter {
+        use std::f32::consts::PI;
+        let (position, frame) = light_frame(&to_world);
+
+        // Tabulate the profile to build the sampling distribution
+        // the maximum is taken over the cell to never miss a lobe
+        let size = Vector2::new(128, 64);
+        let mut image = Bitmap::new(size);
+        for y in 0..size.y {
+            let theta = |t: f32| (y as f32 + t) * PI / size.y as f32;
+            for x in 0..size.x {
+                let phi = |t: f32| (x as f32 + t) * 2.0 * PI / size.x as f32;
+                let v = [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0), (0.5, 0.5)]
+                    .iter()
+                    .map(|(tx, ty)| profile.eval(theta(*ty), phi(*tx)))
+                    .fold(0.0, f32::max);
+                *image.pixel_mut(Point2::new(x, y)) = Color::value(v * theta(0.5).sin());
+            }
+        }
+        let distribution = Distribution2D::from_bitmap(&image);
+
+        // Numerical integration over the sphere
+        const NB_STEPS: usize = 512;
+        let d_theta = PI / NB_STEPS as f32;
+        let d_phi = 2.0 * PI / (2 * NB_STEPS) as f32;
+        let mut profile_flux = 0.0;
+        for i in 0..NB_STEPS {
+            let theta = (i as f32 + 0.5) * d_theta;
+            for j in 0..2 * NB_STEPS {
+                let phi = (j as f32 + 0.5) * d_phi;
+                profile_flux += profile.eval(theta, phi) * theta.sin() * d_theta * d_phi;
+            }
+        }
+        if profile_flux == 0.0 {
+            warn!("IES profile without any emission");
+        }
+
+        IESEmitter {
+            profile,
+            intensity,
+            position,
+            frame,
+            distribution,
+            distribution_size: size,
+            profile_flux,
+        }
+    }
+
+    fn eval_profile(&self, d: Vector3<f32>) -> f32 {
+        let d = self.frame.to_local(d);
+        let uv = to_spherical_coordinates(d);
+        self.profile.eval(
+            uv.y * std::f32::consts::PI,
+            uv.x * 2.0 * std::f32::consts::PI,
+        )
+    }
+
+    fn pdf_direction(&self, d: Vector3<f32>) -> f32 {
+        let uv = to_spherical_coordinates(self.frame.to_local(d));
+        let pdf = self.distribution.pdf(Point2::new(
+            (uv.x * self.distribution_size.x as f32) as usize,
+            (uv.y * self.distribution_size.y as f32) as usize,
+        ));
+        let sin_theta = (std::f32::consts::PI * uv.y).sin();
+        if sin_theta == 0.0 {
+            0.0
+        } else {
+            pdf / (2.0 * std::f32::consts::PI.powi(2) * sin_theta)
+        }
+    }
+}
+impl Emitter for IESEmitter {
+    fn direct_pdf(&self, _: &LightSamplingPDF) -> PDF {
+        PDF::Discrete(1.0)
+    }
+
+    fn direct_sample(&self, v: &Point3<f32>, _r: f32, _uv: Point2<f32>) -> LightSampling {
+        let p = self.position;
+        let d = p - v;
+        let dist = d.magnitude();
+        let d = d / dist;
+
+        LightSampling {
+            emitter: self,
+            pdf: PDF::Discrete(1.0),
+            p,
+            uv: None,
+            primitive_id: None,
+            n: Vector3::new(0.0, 0.0, 0.0),
+            d,
+            weight: self.intensity * self.eval_profile(-d) / dist.powi(2),
+        }
+    }
+
+    fn sample_position(&self, _s: f32, _uv: Point2<f32>) -> (SampledPosition, Color) {
+        (
+            SampledPosition {
+                p: self.position,
+                n: Vector3::new(0.0, 0.0, 0.0),
+                uv: None,
+                pdf: PDF::Discrete(1.0),
+                primitive_id: None,
+            },
+            self.flux(),
+        )
+    }
+
+    fn sample_direction(&self, _: &SampledPosition, d: Point2<f32>) -> (Vector3<f32>, PDF, Color) {
+        // Return [0, size]
+        let uv = self.distribution.sample_continuous(d);
+        let phi = 2.0 * std::f32::consts::PI * uv.x / self.distribution_size.x as f32;
+        let theta = std::f32::consts::PI * uv.y / self.distribution_size.y as f32;
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        let d = self.frame.to_world(Vector3::new(
+            sin_theta * cos_phi,
+            sin_theta * sin_phi,
+            cos_theta,
+        ));
+
+        let pdf = self.pdf_direction(d);
+        if pdf == 0.0 || self.profile_flux == 0.0 {
+            (d, PDF::SolidAngle(0.0), Color::zero())
+        } else {
+            let weight = self.eval_profile(d) / (pdf * self.profile_flux);
+            (d, PDF::SolidAngle(pdf), Color::value(weight))
+        }
+    }
+
+    fn flux(&self) -> Color {
+        self.intensity * self.profile_flux
+    }
+
+    fn correct_flux(&self) -> f32 {
+        1.0 / self.profile_flux
+    }
+
+    fn eval(&self, d: Vector3<f32>, _: Option<Vector2<f32>>) -> Color {
+        self.intensity * self.eval_profile(d)
+    }
+
+    fn is_point(&self) -> bool {
+        true
+    }
+
+    fn convert_light_proxy(&self, emitter_id: usize) -> Vec<LightProxy> {
+        let phi =
+            self.intensity.channel_max() * self.profile.max_value() * 4.0 * std::f32::consts::PI;
+        vec![point_light_proxy(
+            emitter_id,
+            self.position,
+            self.frame.to_world(Vector3::new(0.0, 0.0, 1.0)),
+            std::f32::consts::PI,
+            0.0,
+            phi,
+        )]
+    }
+}
+
+/// Point light projecting an image (as a slide projector)
+pub struct ProjectorEmitter {
+    pub image: Bitmap,
+    // Scale applied to the image values
+    pub intensity: Color,
+    pub position: Point3<f32>,
+    pub frame: Frame,
+    // Half size of the projected image at distance one
+    screen: Vector2<f32>,
+    cos_total_width: f32,
+    // Directional sampling over the image pixels
+    distribution: Distribution2D,
+    // Integral of the image luminance over the solid angle
+    image_flux: f32,
+    image_flux_color: Color,
+}
+impl ProjectorEmitter {
+    /// The field of view (in degrees) is applied on the shortest image axis
+    /// and the light is projecting along the +z axis of the transformation
+    pub fn new(
+        image: Bitmap,
+        intensity: Color,
+        fov: f32,
+        to_world: Matrix4<f32>,
+    ) -> ProjectorEmitter {
+        let (position, frame) = light_frame(&to_world);
+        let aspect = image.size.x as f32 / image.size.y as f32;
+        let tan_fov = (fov.to_radians() * 0.5).tan();
+        let screen = Vector2::new(tan_fov * aspect.max(1.0), tan_fov * (1.0 / aspect).max(1.0));
+        let cos_total_width = 1.0 / (1.0 + screen.magnitude2()).sqrt();
+
+        // Each pixel is weighted by its solid angle
+        let pixel_area = 4.0 * screen.x * screen.y / (image.size.x * image.size.y) as f32;
+        let mut image_pdf = image.clone();
+        let mut image_flux_color = Color::zero();
+        for y in 0..image.size.y {
+            for x in 0..image.size.x {
+                let p = Point2::new(x as f32 + 0.5, y as f32 + 0.5);
+                let s = ProjectorEmitter::to_screen(&screen, &image.size, p);
+                let jacobian = (1.0 + s.magnitude2()).powf(-1.5);
+                let c = image_pdf.pixel_mut(Point2::new(x, y));
+                *c *= jacobian;
+                image_flux_color += *c * pixel_area;
+            }
+        }
+        let distribution = Distribution2D::from_bitmap(&image_pdf);
+        let image_flux = image_flux_color.luminance();
+        if image_flux == 0.0 {
+            warn!("Projector light with a black image");
+        }
+
+        ProjectorEmitter {
+            image,
+            intensity,
+            position,
+            frame,
+            screen,
+            cos_total_width,
+            distribution,
+            image_flux,
+            image_flux_color,
+        }
+    }
+
+    /// Image position ([0, size]) to the screen position at distance one
+    fn to_screen(screen: &Vector2<f32>, size: &Vector2<u32>, p: Point2<f32>) -> Vector2<f32> {
+        Vector2::new(
+            screen.x * (2.0 * p.x / size.x as f32 - 1.0),
+            screen.y * (1.0 - 2.0 * p.y / size.y as f32),
+        )
+    }
+
+    /// Image uv coordinates ([0, 1]) of a direction
+    fn to_image(&self, d: Vector3<f32>) -> Option<Vector2<f32>> {
+        let d = self.frame.to_local(d);
+        if d.z <= 0.0 {
+            return None;
+        }
+        let s = Vector2::new(d.x / d.z, d.y / d.z);
+        if s.x.abs() >= self.screen.x || s.y.abs() >= self.screen.y {
+            return None;
+        }
+        Some(Vector2::new(
+            (s.x / self.screen.x + 1.0) * 0.5,
+            (1.0 - s.y / self.screen.y) * 0.5,
+        ))
+    }
+
+    fn pdf_direction(&self, d: Vector3<f32>) -> f32 {
+        match self.to_image(d) {
+            None => 0.0,
+            Some(uv) => {
+                let size = self.image.size;
+                let x = ((uv.x * size.x as f32) as usize).min(size.x as usize - 1);
+                let y = ((uv.y * size.y as f32) as usize).min(size.y as usize - 1);
+                let pdf = self.distribution.pdf(Point2::new(x, y));
+
+                // From the image space to solid angle
+                let s = self.frame.to_local(d);
+                let s = Vector2::new(s.x / s.z, s.y / s.z);
+                pdf * (1.0 + s.magnitude2()).powf(1.5) / (4.0 * self.screen.x * self.screen.y)
+            }
+        }
+    }
+
+    fn eval_image(&self, d: Vector3<f32>) -> Color {
+        match self.to_image(d) {
+            None => Color::zero(),
+            Some(uv) => self.image.pixel_uv(uv),
+        }
+    }
+}
+impl Emitter for ProjectorEmitter {
+    fn direct_pdf(&self, _: &LightSamplingPDF) -> PDF {
+        PDF::Discrete(1.0)
+    }
+
+    fn direct_sample(&self, v: &Point3<f32>, _r: f32, _uv: Point2<f32>) -> LightSampling {
+        let p = self.position;
+        let d = p - v;
+        let dist = d.magnitude();
+        let d = d / dist;
+
+        LightSampling {
+            emitter: self,
+            pdf: PDF::Discrete(1.0),
+            p,
+            uv: None,
+            primitive_id: None,
+            n: Vector3::new(0.0, 0.0, 0.0),
+            d,
+            weight: self.intensity * self.eval_image(-d) / dist.powi(2),
+        }
+    }
+
+    fn sample_position(&self, _s: f32, _uv: Point2<f32>) -> (SampledPosition, Color) {
+        (
+            SampledPosition {
+                p: self.position,
+                n: Vector3::new(0.0, 0.0, 0.0),
+                uv: None,
+                pdf: PDF::Discrete(1.0),
+                primitive_id: None,
+            },
+            self.intensity * self.image_flux,
+        )
+    }
+
+    fn sample_direction(&self, _: &SampledPosition, d: Point2<f32>) -> (Vector3<f32>, PDF, Color) {
+        // Return [0, size]
+        let p = self.distribution.sample_continuous(d);
+        let s = ProjectorEmitter::to_screen(&self.screen, &self.image.size, Point2::new(p.x, p.y));
+        let d = self.frame.to_world(Vector3::new(s.x, s.y, 1.0).normalize());
+
+        let pdf = self.pdf_direction(d);
+        if pdf == 0.0 || self.image_flux == 0.0 {
+            (d, PDF::SolidAngle(0.0), Color::zero())
+        } else {
+            let weight = self.eval_image(d) / (pdf * self.image_flux);
+            (d, PDF::SolidAngle(pdf), weight)
+        }
+    }
+
+    fn flux(&self) -> Color {
+        self.intensity * self.image_flux_color
+    }
+
+    fn correct_flux(&self) -> f32 {
+        1.0 / self.image_flux
+    }
+
+    fn eval(&self, d: Vector3<f32>, _: Option<Vector2<f32>>) -> Color {
+        self.intensity * self.eval_image(d)
+    }
+
+    fn is_point(&self) -> bool {
+        true
+    }
+
+    fn convert_light_proxy(&self, emitter_id: usize) -> Vec<LightProxy> {
+        let max_pixel = self
+            .image
+            .colors
+            .iter()
+            .map(|c| c.channel_max())
+            .fold(0.0, f32::max);
+        let phi = self.intensity.channel_max() * max_pixel * 4.0 * std::f32::consts::PI;
+        vec![point_light_proxy(
+            emitter_id,
+            self.position,
+            self.frame.to_world(Vector3::new(0.0, 0.0, 1.0)),
+            safe_acos(self.cos_total_width),
+            0.0,
+            phi,
+        )]
+    }
 }
 
 pub enum EnvironmentLightColor {
@@ -303,6 +1053,12 @@ pub enum EnvironmentLightColor {
         image: Bitmap,
         image_cdf: Distribution2D,
     },
+    /// Analytic sky, sampled through its tabulation (image)
+    Sky {
+        sky: Sky,
+        image: Bitmap,
+        image_cdf: Distribution2D,
+    },
 }
 impl std::fmt::Debug for EnvironmentLightColor {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -314,6 +1070,10 @@ impl std::fmt::Debug for EnvironmentLightColor {
                 "Texture: normalization = {}",
                 image_cdf.marginal.func_int
             )),
+            EnvironmentLightColor::Sky { sky, image_cdf, .. } => f.write_str(&format!(
+                "Sky: sun = {:?}, turbidity = {}, normalization = {}",
+                sky.sun_direction, sky.turbidity, image_cdf.marginal.func_int
+            )),
         }
     }
 }
@@ -337,15 +1097,47 @@ pub fn to_spherical_coordinates(d: Vector3<f32>) -> Vector2<f32> {
     uv
 }
 
+/// Bilinear lookup inside a latitude-longitude image ([0,1]x[0,1])
+/// The image is periodic over phi and clamped over theta
+fn latlong_bilinear(image: &Bitmap, uv: Vector2<f32>) -> Color {
+    let size = Vector2::new(image.size.x as i32, image.size.y as i32);
+    let x = uv.x * size.x as f32 - 0.5;
+    let y = uv.y * size.y as f32 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let pixel = |x: i32, y: i32| -> Color {
+        let x = x.rem_euclid(size.x) as u32;
+        let y = crate::clamp(y, 0, size.y - 1) as u32;
+        image.pixel(Point2::new(x, y))
+    };
+    let (x0, y0) = (x0 as i32, y0 as i32);
+    pixel(x0, y0) * ((1.0 - fx) * (1.0 - fy))
+        + pixel(x0 + 1, y0) * (fx * (1.0 - fy))
+        + pixel(x0, y0 + 1) * ((1.0 - fx) * fy)
+        + pixel(x0 + 1, y0 + 1) * (fx * fy)
+}
+
 impl EnvironmentLightColor {
     // Method to build 2D cdf from the bitmap
     pub fn new_texture(image: Bitmap) -> Self {
-        // Precompute the sin theta inside the bitmap
+        // The bilinear lookup spreads the pixels values over their neighbors.
+        // Each cell takes the maximum of the filtered values (corners, edges and center)
+        // so the PDF is never zero where the lookup is not.
         let mut image_pdf = image.clone();
         for y in 0..image.size.y {
             let w = ((y as f32 + 0.5) * std::f32::consts::PI / image.size.y as f32).sin();
             for x in 0..image.size.x {
-                *image_pdf.pixel_mut(Point2::new(x, y)) *= w;
+                let mut v = 0.0 as f32;
+                for dy in &[0.0, 0.5, 1.0] {
+                    for dx in &[0.0, 0.5, 1.0] {
+                        let uv = Vector2::new(
+                            (x as f32 + dx) / image.size.x as f32,
+                            (y as f32 + dy) / image.size.y as f32,
+                        );
+                        v = v.max(latlong_bilinear(&image, uv).luminance());
+                    }
+                }
+                *image_pdf.pixel_mut(Point2::new(x, y)) = Color::value(v * w);
             }
         }
 
@@ -353,6 +1145,31 @@ impl EnvironmentLightColor {
         EnvironmentLightColor::Texture { image, image_cdf }
     }
 
+    /// Tabulate the sky to importance sample it
+    pub fn new_sky(sky: Sky) -> Self {
+        let size = Vector2::new(512, 256);
+        let mut image = Bitmap::new(size);
+        for y in 0..size.y {
+            let (sin_theta, cos_theta) =
+                ((y as f32 + 0.5) * std::f32::consts::PI / size.y as f32).sin_cos();
+            for x in 0..size.x {
+                let (sin_phi, cos_phi) =
+                    ((x as f32 + 0.5) * 2.0 * std::f32::consts::PI / size.x as f32).sin_cos();
+                let d = Vector3::new(sin_theta * cos_phi, sin_theta * sin_phi, cos_theta);
+                *image.pixel_mut(Point2::new(x, y)) = sky.eval(d);
+            }
+        }
+
+        match EnvironmentLightColor::new_texture(image) {
+            EnvironmentLightColor::Texture { image, image_cdf } => EnvironmentLightColor::Sky {
+                sky,
+                image,
+                image_cdf,
+            },
+            _ => unreachable!(),
+        }
+    }
+
     fn sample_direction(&self, uv: Point2<f32>) -> (Vector3<f32>, Color, f32) {
         match self {
             EnvironmentLightColor::Constant(c) => (
@@ -360,15 +1177,17 @@ impl EnvironmentLightColor {
                 *c,
                 1.0 / (std::f32::consts::PI * 4.0),
             ),
-            EnvironmentLightColor::Texture { image, image_cdf } => {
+            EnvironmentLightColor::Texture { image, image_cdf }
+            | EnvironmentLightColor::Sky {
+                image, image_cdf, ..
+            } => {
                 // Return [0, size]
-                let mut uv = image_cdf.sample_continuous(uv);
-                uv.x = crate::clamp(uv.x, 0.0, image.size.x as f32 - 1.0);
-                uv.y = crate::clamp(uv.y, 0.0, image.size.y as f32 - 1.0);
-
-                // TODO: Do bilinear interpolation
-                let value = image.pixel(Point2::new(uv.x as u32, uv.y as u32));
-                let pdf = image_cdf.pdf(Point2::new(uv.x as usize, uv.y as usize));
+                let uv = image_cdf.sample_continuous(uv);
+                // The cell used for the PDF is the one containing the sample
+                let pdf = image_cdf.pdf(Point2::new(
+                    (uv.x as usize).min(image.size.x as usize - 1),
+                    (uv.y as usize).min(image.size.y as usize - 1),
+                ));
 
                 // Compute spherical coordinates for the direction
                 let (sin_phi, cos_phi) =
@@ -382,7 +1201,7 @@ impl EnvironmentLightColor {
                 } else {
                     (
                         d,
-                        value,
+                        self.eval(d),
                         pdf / (2.0 * std::f32::consts::PI.powi(2) * sin_theta),
                     )
                 }
@@ -394,25 +1213,26 @@ impl EnvironmentLightColor {
         match self {
             EnvironmentLightColor::Constant(c) => *c,
             EnvironmentLightColor::Texture { image, .. } => {
-                let uv = to_spherical_coordinates(d);
-                // TODO: Bilinear interpolation
-                image.pixel_uv(uv)
+                latlong_bilinear(image, to_spherical_coordinates(d))
             }
+            EnvironmentLightColor::Sky { sky, .. } => sky.eval(d),
         }
     }
 
     fn pdf(&self, d: Vector3<f32>) -> f32 {
         match self {
             EnvironmentLightColor::Constant(_) => 1.0 / (std::f32::consts::PI * 4.0),
-            EnvironmentLightColor::Texture { image_cdf, image } => {
+            EnvironmentLightColor::Texture { image_cdf, image }
+            | EnvironmentLightColor::Sky {
+                image_cdf, image, ..
+            } => {
                 // Map the direction to [0,1]x[0,1]
                 let uv = to_spherical_coordinates(d);
                 // Use [0, size] coordinates
                 let pdf = image_cdf.pdf(Point2::new(
-                    (uv.x * image.size.x as f32) as usize,
-                    (uv.y * image.size.y as f32) as usize,
+                    ((uv.x * image.size.x as f32) as usize).min(image.size.x as usize - 1),
+                    ((uv.y * image.size.y as f32) as usize).min(image.size.y as usize - 1),
                 ));
-                // TODO: We do not need to scale / size.y as uv is in [0,1]
                 let sin_theta = (std::f32::consts::PI * uv.y).sin();
                 if sin_theta == 0.0 {
                     0.0
@@ -428,11 +1248,49 @@ impl EnvironmentLightColor {
 pub struct EnvironmentLight {
     pub luminance: EnvironmentLightColor,
     pub bsphere: Option<BoundingSphere>,
+    // Rotation from the environment map space to the world space
+    pub to_world: Matrix3<f32>,
+    pub scale: Color,
+}
+impl EnvironmentLight {
+    pub fn new(luminance: EnvironmentLightColor) -> Self {
+        EnvironmentLight {
+            luminance,
+            bsphere: None,
+            to_world: Matrix3::identity(),
+            scale: Color::one(),
+        }
+    }
+
+    /// Only the rotation of the transformation is kept
+    pub fn with_transform(mut self, to_world: Matrix4<f32>) -> Self {
+        let m = Matrix3::from_cols(
+            to_world.x.truncate().normalize(),
+            to_world.y.truncate().normalize(),
+            to_world.z.truncate().normalize(),
+        );
+        if (m.determinant() - 1.0).abs() > 0.01 {
+            warn!("Environment map transformation is not a rotation: {:?}", m);
+        }
+        self.to_world = m;
+        self
+    }
+
+    pub fn with_scale(mut self, scale: Color) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    fn to_local(&self, d: Vector3<f32>) -> Vector3<f32> {
+        self.to_world.transpose() * d
+    }
 }
-impl EnvironmentLight {}
 
 //  Or something else?
 impl Emitter for EnvironmentLight {
+    fn is_infinite(&self) -> bool {
+        true
+    }
     fn preprocess(&mut self, scene: &Scene) {
         self.bsphere = scene.bsphere.clone();
         self.bsphere.as_mut().unwrap().radius *= 1.1;
@@ -460,8 +1318,12 @@ impl Emitter for EnvironmentLight {
                 primitive_id: None,
             },
             match &self.luminance {
-                EnvironmentLightColor::Constant(c) => *c * inv_pdf * std::f32::consts::PI,
-                EnvironmentLightColor::Texture { image_cdf, .. } => {
+                EnvironmentLightColor::Constant(c) => {
+                    self.scale * *c * inv_pdf * std::f32::consts::PI
+                }
+                // The scale is applied by sample_direction
+                EnvironmentLightColor::Texture { image_cdf, .. }
+                | EnvironmentLightColor::Sky { image_cdf, .. } => {
                     Color::value(inv_pdf / image_cdf.marginal.func_int)
                 }
             },
@@ -469,7 +1331,7 @@ impl Emitter for EnvironmentLight {
     }
     fn direct_pdf(&self, light_sampling: &LightSamplingPDF) -> PDF {
         // dir is toward the light
-        PDF::SolidAngle(self.luminance.pdf(light_sampling.dir))
+        PDF::SolidAngle(self.luminance.pdf(self.to_local(light_sampling.dir)))
     }
     fn direct_sample(&self, v: &Point3<f32>, _r: f32, uv: Point2<f32>) -> LightSampling {
         let bsphere = self.bsphere.as_ref().unwrap();
@@ -477,6 +1339,7 @@ impl Emitter for EnvironmentLight {
         // TODO: Pass the Option<normal> to have better IS (cosine weighted)
         // The envmap is constant so we need to generate a direction over the sphere
         let (d, color, pdf) = self.luminance.sample_direction(uv);
+        let d = self.to_world * d;
 
         let t = bsphere.intersect(&Ray::new(*v, d));
         if t.is_none() {
@@ -506,21 +1369,23 @@ impl Emitter for EnvironmentLight {
             primitive_id: None,
             // d is toward the light source
             d,
-            weight: color / pdf,
+            weight: color * self.scale / pdf,
         }
     }
     fn flux(&self) -> Color {
-        match &self.luminance {
-            EnvironmentLightColor::Constant(c) => {
-                std::f32::consts::PI * self.bsphere.as_ref().unwrap().radius.powi(2) * c
-            }
-            EnvironmentLightColor::Texture { image_cdf, .. } => Color::value(
-                std::f32::consts::PI
+        self.scale
+            * match &self.luminance {
+                EnvironmentLightColor::Constant(c) => {
+                    std::f32::consts::PI * self.bsphere.as_ref().unwrap().radius.powi(2) * c
+                }
+                EnvironmentLightColor::Texture { image_cdf, .. }
+                | EnvironmentLightColor::Sky { image_cdf, .. } => Color::value(
+                    std::f32::consts::PI
                     * self.bsphere.as_ref().unwrap().radius.powi(2)
                     // Avg luminance
                     * image_cdf.marginal.func_int,
-            ),
-        }
+                ),
+            }
     }
 
     fn correct_flux(&self) -> f32 {
@@ -528,7 +1393,7 @@ impl Emitter for EnvironmentLight {
     }
 
     fn eval(&self, d: Vector3<f32>, _: Option<Vector2<f32>>) -> Color {
-        self.luminance.eval(d)
+        self.luminance.eval(self.to_local(d)) * self.scale
     }
 
     fn sample_direction(
@@ -555,45 +1420,130 @@ impl Emitter for EnvironmentLight {
                 let d_out_global = frame.to_world(d_out);
                 (d_out_global, pdf, weight)
             }
-            EnvironmentLightColor::Texture { image_cdf, .. } => {
+            EnvironmentLightColor::Texture { image_cdf, .. }
+            | EnvironmentLightColor::Sky { image_cdf, .. } => {
+                // The light is coming from the sampled direction
                 let (d, color, pdf) = self.luminance.sample_direction(d);
                 (
-                    d,
+                    -(self.to_world * d),
                     PDF::SolidAngle(pdf),
-                    (color * image_cdf.marginal.func_int) / pdf,
+                    (color * self.scale * image_cdf.marginal.func_int) / pdf,
                 )
             }
         }
     }
 }
 
+/// Light sampling record from a position sampled on a mesh
+/// The pdf of the sampled position can be in area or solid angle
+fn mesh_light_sampling<'a>(
+    mesh: &'a Mesh,
+    p: &Point3<f32>,
+    sampled_pos: SampledPosition,
+) -> LightSampling<'a> {
+    // Compute the distance
+    let mut d: Vector3<f32> = sampled_pos.p - p;
+    let dist = d.magnitude();
+    if dist != 0.0 {
+        d /= dist;
+    }
+
+    // Compute Geometry factor
+    let cos_light = sampled_pos.n.dot(-d).max(0.0);
+    let geom = if dist != 0.0 {
+        cos_light / (dist * dist)
+    } else {
+        0.0
+    };
+
+    // PDF & Weight
+    let (pdf, weight) = match sampled_pos.pdf {
+        PDF::SolidAngle(pdf) => {
+            if pdf == 0.0 || geom == 0.0 {
+                (PDF::SolidAngle(0.0), Color::zero())
+            } else {
+                (PDF::SolidAngle(pdf), mesh.emit(&sampled_pos.uv) / pdf)
+            }
+        }
+        _ => {
+            let pdf_area = sampled_pos.pdf.value();
+            let pdf = sampled_pos.pdf.as_solid_angle_geom(geom);
+            let weight = if pdf.is_zero() {
+                Color::zero()
+            } else {
+                mesh.emit(&sampled_pos.uv) * geom / pdf_area
+            };
+            (pdf, weight)
+        }
+    };
+
+    LightSampling {
+        emitter: mesh,
+        pdf,
+        p: sampled_pos.p,
+        n: sampled_pos.n,
+        uv: sampled_pos.uv,
+        primitive_id: sampled_pos.primitive_id,
+        d,
+        weight,
+    }
+}
+
 impl Emitter for Mesh {
     fn direct_pdf(&self, light_sampling: &LightSamplingPDF) -> PDF {
+        // Sphere lights are sampled by their subtended cone
+        if let ShapeType::Sphere(sphere) = &self.shape {
+            if let Some(pdf) = sphere.solid_angle_pdf(&light_sampling.o) {
+                return PDF::SolidAngle(pdf);
+            }
+        }
+
         let cos_light = light_sampling.n.dot(-light_sampling.dir).max(0.0);
         if cos_light == 0.0 {
-            PDF::SolidAngle(0.0)
-        } else {
-            let geom = cos_light / (light_sampling.p - light_sampling.o).magnitude2();
-            PDF::SolidAngle(self.pdf() / geom)
+            return PDF::SolidAngle(0.0);
+        }
+        match self.pdf_direct(
+            &light_sampling.o,
+            light_sampling.primitive_id,
+            light_sampling.uv,
+        ) {
+            PDF::Area(pdf) => {
+                let geom = cos_light / (light_sampling.p - light_sampling.o).magnitude2();
+                PDF::SolidAngle(pdf / geom)
+            }
+            pdf => pdf,
         }
     }
 
     fn direct_pdf_tri(&self, light_sampling: &LightSamplingPDF, id_primitive: usize) -> PDF {
+        if self.shape.is_analytic() {
+            // Only one primitive
+            return self.direct_pdf(light_sampling);
+        }
+
         let cos_light = light_sampling.n.dot(-light_sampling.dir).max(0.0);
         if cos_light == 0.0 {
-            PDF::SolidAngle(0.0)
-        } else {
-            let geom = cos_light / (light_sampling.p - light_sampling.o).magnitude2();
-            PDF::SolidAngle(self.pdf_tri(id_primitive) / geom)
+            return PDF::SolidAngle(0.0);
+        }
+        match self.pdf_direct_tri(&light_sampling.o, id_primitive, light_sampling.uv) {
+            PDF::Area(pdf) => {
+                let geom = cos_light / (light_sampling.p - light_sampling.o).magnitude2();
+                PDF::SolidAngle(pdf / geom)
+            }
+            pdf => pdf,
         }
     }
 
     fn flux(&self) -> Color {
+        if let Some(dist) = &self.emission_distribution {
+            return dist.flux * std::f32::consts::PI;
+        }
         let e = match &self.emission {
             EmissionType::Zero => Color::zero(),
             EmissionType::Color { v } => *v,
-            EmissionType::HSV { scale } => Color::value(*scale), // TODO
-            EmissionType::Texture { scale, .. } => Color::value(*scale), // TODO
+            // Build the emission distribution to get the exact flux
+            EmissionType::HSV { scale } => Color::value(*scale),
+            EmissionType::Texture { scale, .. } => Color::value(*scale),
         };
         self.cdf.as_ref().unwrap().total() * e * std::f32::consts::PI
     }
@@ -612,79 +1562,44 @@ impl Emitter for Mesh {
         primitive_id: usize,
         uv: Point2<f32>,
     ) -> LightSampling {
-        let sampled_pos = self.sample_tri(primitive_id, uv);
-
-        // Compute the distance
-        let mut d: Vector3<f32> = sampled_pos.p - p;
-        let dist = d.magnitude();
-        if dist != 0.0 {
-            d /= dist;
+        if self.shape.is_analytic() {
+            // Only one primitive
+            return self.direct_sample(p, 0.0, uv);
         }
 
-        // Compute Geometry factor
-        let geom = if dist != 0.0 {
-            sampled_pos.n.dot(-d).max(0.0) / (dist * dist)
-        } else {
-            0.0
-        };
-
-        // PDF & Weight
-        let pdf_area = sampled_pos.pdf.value();
-        let pdf = sampled_pos.pdf.as_solid_angle_geom(geom);
-        let weight = if pdf.is_zero() {
-            Color::zero()
-        } else {
-            self.emit(&sampled_pos.uv) * geom / pdf_area
-        };
-
-        LightSampling {
-            emitter: self,
-            pdf,
-            p: sampled_pos.p,
-            n: sampled_pos.n,
-            uv: sampled_pos.uv,
-            primitive_id: None, // Not sampled a particular primitive
-            d,
-            weight,
-        }
+        let sampled_pos = self.sample_direct_tri(p, primitive_id, uv);
+        mesh_light_sampling(self, p, sampled_pos)
     }
 
     fn direct_sample(&self, p: &Point3<f32>, r: f32, uv: Point2<f32>) -> LightSampling {
-        let sampled_pos = self.sample(r, uv);
-
-        // Compute the distance
-        let mut d: Vector3<f32> = sampled_pos.p - p;
-        let dist = d.magnitude();
-        if dist != 0.0 {
-            d /= dist;
+        // Sphere lights are sampled by their subtended cone
+        if let ShapeType::Sphere(sphere) = &self.shape {
+            if let Some(sampled_pos) = sphere.sample_solid_angle(p, uv) {
+                let d = (sampled_pos.p - p).normalize();
+                let pdf = sampled_pos.pdf;
+                let weight = if pdf.is_zero() {
+                    Color::zero()
+                } else {
+                    self.emit(&sampled_pos.uv) / pdf.value()
+                };
+                return LightSampling {
+                    emitter: self,
+                    pdf,
+                    p: sampled_pos.p,
+                    n: sampled_pos.n,
+                    uv: sampled_pos.uv,
+                    primitive_id: sampled_pos.primitive_id,
+                    d,
+                    weight,
+                };
+            }
         }
 
-        // Compute Geometry factor
-        let geom = if dist != 0.0 {
-            sampled_pos.n.dot(-d).max(0.0) / (dist * dist)
-        } else {
-            0.0
-        };
-
-        // PDF & Weight
-        let pdf_area = sampled_pos.pdf.value();
-        let pdf = sampled_pos.pdf.as_solid_angle_geom(geom);
-        let weight = if pdf.is_zero() {
-            Color::zero()
-        } else {
-            self.emit(&sampled_pos.uv) * geom / pdf_area
+        let sampled_pos = match &self.shape {
+            ShapeType::Triangles => self.sample_direct(p, r, uv),
+            _ => self.sample(r, uv),
         };
-
-        LightSampling {
-            emitter: self,
-            pdf,
-            p: sampled_pos.p,
-            n: sampled_pos.n,
-            uv: sampled_pos.uv,
-            primitive_id: sampled_pos.primitive_id,
-            d,
-            weight,
-        }
+        mesh_light_sampling(self, p, sampled_pos)
     }
 
     fn sample_position_tri(
@@ -692,13 +1607,21 @@ impl Emitter for Mesh {
         primitive_id: usize,
         uv: Point2<f32>,
     ) -> (SampledPosition, Color) {
-        let sampled_pos = self.sample_tri(primitive_id, uv);
-        let phi = self.emit(&sampled_pos.uv) * std::f32::consts::PI / sampled_pos.pdf.value();
+        let sampled_pos = self.sample_primitive(primitive_id, uv);
+        let phi = if sampled_pos.pdf.is_zero() {
+            Color::zero()
+        } else {
+            self.emit(&sampled_pos.uv) * std::f32::consts::PI / sampled_pos.pdf.value()
+        };
         (sampled_pos, phi)
     }
     fn sample_position(&self, s: f32, uv: Point2<f32>) -> (SampledPosition, Color) {
         let sampled_pos = self.sample(s, uv);
-        let phi = self.emit(&sampled_pos.uv) * std::f32::consts::PI / sampled_pos.pdf.value();
+        let phi = if sampled_pos.pdf.is_zero() {
+            Color::zero()
+        } else {
+            self.emit(&sampled_pos.uv) * std::f32::consts::PI / sampled_pos.pdf.value()
+        };
         (sampled_pos, phi)
     }
 
@@ -727,7 +1650,93 @@ impl Emitter for Mesh {
     fn is_surface(&self) -> bool {
         true
     }
+    fn is_planar(&self) -> bool {
+        // Textured emitters are sampled proportionally to their power
+        matches!(self.shape, ShapeType::Triangles)
+            && matches!(self.emission, EmissionType::Color { .. })
+    }
+    fn chord(&self, p: &Point3<f32>, primitive_id: Option<usize>, u: f32) -> Option<Chord> {
+        let id = self.indices[primitive_id?];
+        let v = [
+            self.vertices[id.x],
+            self.vertices[id.y],
+            self.vertices[id.z],
+        ];
+        // Same geometric normal as the triangle sampling
+        let n = (v[2] - v[0]).cross(v[1] - v[0]).normalize();
+        let alpha = std::f32::consts::PI * u;
+        let d = Frame::new(n).to_world(Vector3::new(alpha.cos(), alpha.sin(), 0.0));
+
+        // Clip the line p + t d with the three edges
+        let (mut t_min, mut t_max) = (std::f32::NEG_INFINITY, std::f32::INFINITY);
+        for i in 0..3 {
+            let (a, b, c) = (v[i], v[(i + 1) % 3], v[(i + 2) % 3]);
+            // Edge normal (inside the triangle plane) pointing inside
+            let e = b - a;
+            let m = (c - a) - e * ((c - a).dot(e) / e.magnitude2());
+            let dm = d.dot(m);
+            let pm = (p.to_vec() - a).dot(m);
+            if dm == 0.0 {
+                if pm < 0.0 {
+                    return None;
+                }
+            } else if dm > 0.0 {
+                t_min = t_min.max(-pm / dm);
+            } else {
+                t_max = t_max.min(-pm / dm);
+            }
+        }
+        if t_max < t_min {
+            return None;
+        }
+        Some(Chord {
+            o: p + d * t_min,
+            d,
+            length: t_max - t_min,
+            n,
+        })
+    }
+    fn nb_primitives(&self) -> usize {
+        if self.shape.is_analytic() {
+            1
+        } else {
+            self.indices.len()
+        }
+    }
     fn convert_light_proxy(&self, emitter_id: usize) -> Vec<LightProxy> {
+        if self.shape.is_analytic() {
+            // Single proxy for the whole shape
+            let (w, theta_o) = match &self.shape {
+                ShapeType::Disk(d) => (d.frame.to_world(Vector3::new(0.0, 0.0, 1.0)), 0.0),
+                ShapeType::Cylinder(c) => (
+                    c.frame.to_world(Vector3::new(0.0, 0.0, 1.0)),
+                    std::f32::consts::FRAC_PI_2,
+                ),
+                _ => (Vector3::new(0.0, 0.0, 1.0), std::f32::consts::PI),
+            };
+            let theta_e = std::f32::consts::FRAC_PI_2;
+            let uv = self.sample_primitive(0, Point2::new(0.5, 0.5)).uv;
+            let phi = self.emit(&uv).channel_max() * self.cdf.as_ref().unwrap().total();
+            let aabb = self.compute_aabb();
+            return vec![LightProxy {
+                emitter_id,
+                primitive_idx: 0,
+                bounds: LightBounds {
+                    aabb: aabb.clone(),
+                    w,
+                    phi,
+                    theta_o,
+                    theta_e,
+                    cos_theta_o: theta_o.cos(),
+                    cos_theta_e: theta_e.cos(),
+                    two_sided: false,
+                    number_lights: 1,
+                    phi_sqr: phi.powi(2),
+                    bsphere: aabb.to_sphere(),
+                },
+            }];
+        }
+
         self.indices
             .iter()
             .enumerate()
@@ -753,7 +1762,10 @@ impl Emitter for Mesh {
                 let w = n.normalize();
                 let theta_o = 0.0;
                 let theta_e = std::f32::consts::FRAC_PI_2;
-                let phi = self.emit(&uv).channel_max() * n.magnitude() * 0.5;
+                let phi = match &self.emission_distribution {
+                    Some(dist) => dist.flux_triangle(i).channel_max(),
+                    None => self.emit(&uv).channel_max() * n.magnitude() * 0.5,
+                };
                 let aabb = AABB::default().union_vec(&v0).union_vec(&v1).union_vec(&v2);
                 let bounds = LightBounds {
                     aabb: aabb.clone(),
@@ -935,8 +1947,32 @@ impl Default for LightBounds {
     }
 }
 
-const EPSILON_ATS: f32 = 0.0001;
+pub const EPSILON_ATS: f32 = 0.0001;
 impl LightBounds {
+    /// Bounds of a single light located at a point
+    pub fn from_point(
+        position: Point3<f32>,
+        w: Vector3<f32>,
+        theta_o: f32,
+        theta_e: f32,
+        phi: f32,
+    ) -> LightBounds {
+        let aabb = AABB::default().union_vec(&position.to_vec());
+        LightBounds {
+            aabb: aabb.clone(),
+            w,
+            phi,
+            theta_o,
+            theta_e,
+            cos_theta_o: theta_o.cos(),
+            cos_theta_e: theta_e.cos(),
+            two_sided: false,
+            number_lights: 1,
+            phi_sqr: phi.powi(2),
+            bsphere: aabb.to_sphere(),
+        }
+    }
+
     pub fn to_dircone(&self) -> DirectionCone {
         DirectionCone {
             w: self.w,
@@ -1136,6 +2172,8 @@ pub struct LightSamplerATS {
     // To map light query to node
     // to compute PDF
     pub query_to_nodes: HashMap<(usize, usize), usize>,
+    // Lights at infinity (outside the tree)
+    pub infinite_lights: Vec<LightProxy>,
 }
 
 fn max_component(v: Vector3<f32>) -> f32 {
@@ -1288,45 +2326,110 @@ fn build_bvh(
 
 impl LightSamplerATS {
     fn new(emitters: &Vec<Arc<dyn Emitter>>) -> Option<LightSamplerATS> {
-        // For now we do not handle both light type
-        for e in emitters {
-            assert!(e.is_surface());
-        }
+        let infinite_lights = emitters
+            .iter()
+            .enumerate()
+            .filter(|(_, e)| e.is_infinite())
+            .map(|(i, _)| LightProxy {
+                emitter_id: i,
+                primitive_idx: 0,
+                bounds: LightBounds::default(),
+            })
+            .collect::<Vec<_>>();
         let mut lights = emitters
             .iter()
             .enumerate()
-            .map(|(i, e)| e.convert_light_proxy(i))
+            .filter(|(_, e)| !e.is_infinite())
+            .map(|(i, e)| {
+                let proxies = e.convert_light_proxy(i);
+                if proxies.is_empty() {
+                    warn!("Emitter {} is ignored by ATS (no light proxy)", i);
+                }
+                proxies
+            })
             .flatten()
             .collect::<Vec<_>>();
 
-        if lights.is_empty() {
+        if lights.is_empty() && infinite_lights.is_empty() {
             None
         } else {
             let mut nodes = Vec::with_capacity(lights.len());
             let mut query_to_nodes = HashMap::with_capacity(lights.len());
-            let id = build_bvh(&mut nodes, &mut query_to_nodes, 0, &mut lights[..]); // Build nodes recursively
-            dbg!(nodes.len());
-            dbg!(lights.len());
+            let root = if lights.is_empty() {
+                None
+            } else {
+                // Build nodes recursively
+                Some(build_bvh(
+                    &mut nodes,
+                    &mut query_to_nodes,
+                    0,
+                    &mut lights[..],
+                ))
+            };
+            info!(
+                "ATS: {} nodes, {} lights and {} infinite lights",
+                nodes.len(),
+                lights.len(),
+                infinite_lights.len()
+            );
             Some(LightSamplerATS {
-                root: Some(id),
+                root,
                 nodes,
                 lights,
                 query_to_nodes,
+                infinite_lights,
             })
         }
     }
 
+    /// Probability to select one of the infinite lights
+    /// (the tree counts as a single light)
+    fn prob_infinite(&self) -> f32 {
+        let nb_infinite = self.infinite_lights.len() as f32;
+        if self.root.is_some() {
+            nb_infinite / (nb_infinite + 1.0)
+        } else {
+            1.0
+        }
+    }
+
+    /// Select an infinite light if r is below the probability to select them
+    /// Otherwise, r is remapped for the tree traversal
+    fn sample_infinite(&self, r: &mut f32) -> Option<(&LightProxy, f32)> {
+        let prob_infinite = self.prob_infinite();
+        if *r < prob_infinite {
+            let nb_infinite = self.infinite_lights.len();
+            let i = ((*r / prob_infinite) * nb_infinite as f32) as usize;
+            Some((
+                &self.infinite_lights[i.min(nb_infinite - 1)],
+                prob_infinite / nb_infinite as f32,
+            ))
+        } else {
+            *r = ((*r - prob_infinite) / (1.0 - prob_infinite)).min(ONE_MINUS_EPSILON);
+            None
+        }
+    }
+
     fn pdf<F>(&self, id_emitter: usize, id_primitive: usize, importance: F) -> f32
     where
         F: Fn(&LightBounds) -> f32,
     {
-        let mut id = *self
-            .query_to_nodes
-            .get(&(id_emitter, id_primitive))
-            .unwrap();
+        let prob_infinite = self.prob_infinite();
+        if self
+            .infinite_lights
+            .iter()
+            .any(|l| l.emitter_id == id_emitter)
+        {
+            return prob_infinite / self.infinite_lights.len() as f32;
+        }
+
+        let mut id = match self.query_to_nodes.get(&(id_emitter, id_primitive)) {
+            Some(id) => *id,
+            None => return 0.0, // Not inside the tree
+        };
         let mut node = &self.nodes[id];
 
-        let mut pdf = 1.0;
+        let mut pdf = 1.0 - prob_infinite;
         while let Some(id_parent) = node.parent {
             // Update node
             node = &self.nodes[id_parent];
@@ -1362,9 +2465,11 @@ impl LightSamplerATS {
     where
         F: Fn(&LightBounds) -> f32,
     {
-        assert!(self.root.is_some());
+        if let Some(res) = self.sample_infinite(&mut r) {
+            return Some(res);
+        }
 
-        let mut pdf_sel = 1.0;
+        let mut pdf_sel = 1.0 - self.prob_infinite();
         let mut node_index = self.root.unwrap();
         loop {
             let node = &self.nodes[node_index];
@@ -1410,9 +2515,11 @@ impl LightSamplerATS {
         F: Fn(&LightBounds) -> f32,
         F2: Fn(&LightBounds) -> (f32, f32),
     {
-        assert!(self.root.is_some());
+        if let Some(res) = self.sample_infinite(&mut r) {
+            return vec![res];
+        }
 
-        let mut pdf_sel = 1.0;
+        let mut pdf_sel = 1.0 - self.prob_infinite();
         let mut node_index = self.root.unwrap();
         let mut node_selected = Vec::new();
         let mut node_queued: Vec<(usize, f32, f32)> = Vec::new();
@@ -1553,7 +2660,14 @@ impl EmitterSampler {
                     }
                     id_emitter.unwrap()
                 };
-                let id_primitive = id_primitive.unwrap();
+                let id_primitive = match id_primitive.or(light_sampling.primitive_id) {
+                    Some(id) => id,
+                    None if emitter.nb_primitives() == 1 => 0,
+                    None => {
+                        warn!("PDF emitter without the sampled primitive");
+                        return PDF::SolidAngle(0.0);
+                    }
+                };
 
                 // Pdf tri * pdf ATS
                 let f = |bounds: &LightBounds| -> f32 { bounds.importance_ray(ray, max_dist) };
@@ -1590,7 +2704,14 @@ impl EmitterSampler {
                     }
                     id_emitter.unwrap()
                 };
-                let id_primitive = id_primitive.unwrap();
+                let id_primitive = match id_primitive.or(light_sampling.primitive_id) {
+                    Some(id) => id,
+                    None if emitter.nb_primitives() == 1 => 0,
+                    None => {
+                        warn!("PDF emitter without the sampled primitive");
+                        return PDF::SolidAngle(0.0);
+                    }
+                };
 
                 // Pdf tri * pdf ATS
                 let f =
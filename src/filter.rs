@@ -0,0 +1,184 @@
+use std::f32;
+
+/// Shape of the reconstruction filter
+#[derive(Clone, Copy, Debug)]
+pub enum FilterType {
+    Box,
+    Tent,
+    Gaussian {
+        alpha: f32,
+    },
+    /// Mitchell-Netravali with B and C parameters
+    Mitchell {
+        b: f32,
+        c: f32,
+    },
+    /// Windowed sinc
+    Lanczos {
+        tau: f32,
+    },
+}
+
+/// Separable pixel reconstruction filter
+#[derive(Clone, Debug)]
+pub struct Filter {
+    pub kind: FilterType,
+    pub radius: f32,
+    // Integral over the filter support (to normalize the splats)
+    integral: f32,
+}
+
+impl Default for Filter {
+    /// Box filter over one pixel (classical pixel averaging)
+    fn default() -> Self {
+        Filter::new(FilterType::Box, 0.5)
+    }
+}
+
+impl Filter {
+    pub fn new(kind: FilterType, radius: f32) -> Filter {
+        assert!(radius > 0.0);
+        let mut filter = Filter {
+            kind,
+            radius,
+            integral: 1.0,
+        };
+        // Numerical integration (the filter is separable)
+        const NB_STEPS: usize = 512;
+        let dx = 2.0 * radius / NB_STEPS as f32;
+        let integral_1d = (0..NB_STEPS)
+            .map(|i| filter.eval_1d(-radius + (i as f32 + 0.5) * dx) * dx)
+            .sum::<f32>();
+        filter.integral = integral_1d * integral_1d;
+        filter
+    }
+
+    /// Default parameters are the same as Mitsuba
+    pub fn tent() -> Filter {
+        Filter::new(FilterType::Tent, 1.0)
+    }
+    pub fn gaussian() -> Filter {
+        let stddev = 0.5;
+        Filter::new(
+            FilterType::Gaussian {
+                alpha: 1.0 / (2.0 * stddev * stddev),
+            },
+            4.0 * stddev,
+        )
+    }
+    pub fn mitchell() -> Filter {
+        Filter::new(
+            FilterType::Mitchell {
+                b: 1.0 / 3.0,
+                c: 1.0 / 3.0,
+            },
+            2.0,
+        )
+    }
+    pub fn lanczos() -> Filter {
+        Filter::new(FilterType::Lanczos { tau: 3.0 }, 3.0)
+    }
+
+    fn eval_1d(&self, x: f32) -> f32 {
+        let x = x.abs();
+        if x >= self.radius {
+            return 0.0;
+        }
+        match self.kind {
+            FilterType::Box => 1.0,
+            FilterType::Tent => self.radius - x,
+            FilterType::Gaussian { alpha } => {
+                ((-alpha * x * x).exp() - (-alpha * self.radius * self.radius).exp()).max(0.0)
+            }
+            FilterType::Mitchell { b, c } => {
+                let x = 2.0 * x / self.radius;
+                let x2 = x * x;
+                let x3 = x2 * x;
+                if x > 1.0 {
+                    ((-b - 6.0 * c) * x3
+                        + (6.0 * b + 30.0 * c) * x2
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x3
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x2
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                }
+            }
+            FilterType::Lanczos { tau } => sinc(x) * sinc(x / tau),
+        }
+    }
+
+    /// Filter value for an offset to the pixel center
+    pub fn eval(&self, dx: f32, dy: f32) -> f32 {
+        self.eval_1d(dx) * self.eval_1d(dy)
+    }
+
+    /// Filter value that integrates to one (used by the splatting)
+    pub fn eval_normalized(&self, dx: f32, dy: f32) -> f32 {
+        self.eval(dx, dy) / self.integral
+    }
+
+    /// Number of neighboring pixels touched by a sample
+    pub fn margin(&self) -> u32 {
+        (self.radius + 0.5).ceil() as u32
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x < 1e-5 {
+        1.0
+    } else {
+        let x = x * f32::consts::PI;
+        x.sin() / x
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalized_integral() {
+        let filters = [
+            Filter::default(),
+            Filter::tent(),
+            Filter::gaussian(),
+            Filter::mitchell(),
+            Filter::lanczos(),
+        ];
+        for filter in &filters {
+            // Different resolution than the one used by the normalization
+            const N: usize = 300;
+            let dx = 2.0 * filter.radius / N as f32;
+            let mut integral = 0.0;
+            for y in 0..N {
+                for x in 0..N {
+                    let px = -filter.radius + (x as f32 + 0.5) * dx;
+                    let py = -filter.radius + (y as f32 + 0.5) * dx;
+                    integral += filter.eval_normalized(px, py) * dx * dx;
+                }
+            }
+            assert!(
+                (integral - 1.0).abs() < 1e-2,
+                "{:?}: {}",
+                filter.kind,
+                integral
+            );
+        }
+    }
+
+    #[test]
+    fn support() {
+        let filter = Filter::mitchell();
+        assert_eq!(filter.eval(2.0, 0.0), 0.0);
+        assert_eq!(filter.eval(0.0, -2.5), 0.0);
+        assert!(filter.eval(0.0, 0.0) > 0.0);
+        // Symmetric
+        assert_eq!(filter.eval(0.3, -0.7), filter.eval(-0.3, 0.7));
+        assert_eq!(Filter::default().margin(), 1);
+        assert_eq!(Filter::lanczos().margin(), 4);
+    }
+}
@@ -1,6 +1,12 @@
 use crate::bsdfs;
-use crate::constants::EPSILON;
-use crate::math::{uniform_sample_triangle, Distribution1D, Distribution1DConstruct};
+use crate::constants::{EPSILON, ONE_MINUS_EPSILON};
+use crate::curve::Curves;
+use crate::math::{
+    clip_polygon_rect, gamma, polygon_area, sample_convex_polygon, sample_spherical_triangle,
+    spherical_triangle_area, uniform_sample_triangle, Distribution1D, Distribution1DConstruct,
+    Distribution2D, SphericalRectangle,
+};
+use crate::shapes::{Cylinder, Disk, Sphere};
 use crate::structure::*;
 use cgmath::*;
 use std;
@@ -91,11 +97,42 @@ pub fn load_obj(file_name: &std::path::Path) -> Result<Vec<Mesh>, tobj::LoadErro
                 })
             }
         };
+
+        // Load the opacity mask (map_d or d)
+        if let Some(id) = mesh.material_id {
+            let mat = &materials[id];
+            if let Some(tx) = &mat.dissolve_texture {
+                info!(" - alpha texture: {}", tx);
+                let path_texture = wk.join(&tx);
+                tri_mesh.alpha = Some(bsdfs::BSDFColor::Bitmap {
+                    img: Bitmap::read(&path_texture.to_str().unwrap()),
+                });
+                tri_mesh.check_alpha();
+            } else if let Some(d) = mat.dissolve {
+                if d < 1.0 {
+                    tri_mesh.alpha = Some(bsdfs::BSDFColor::Constant(Color::value(d)));
+                }
+            }
+        }
         meshes.push(tri_mesh);
     }
     Ok(meshes)
 }
 
+/// Hash a ray and a primitive id into [0, 1)
+fn hash_float(p: &Point3<f32>, d: &Vector3<f32>, i: usize) -> f32 {
+    let mut h = i as u64;
+    for v in &[p.x, p.y, p.z, d.x, d.y, d.z] {
+        h ^= u64::from(v.to_bits());
+        h = h.wrapping_mul(0xff51afd7ed558ccd);
+        h ^= h >> 33;
+    }
+    // MurmurHash3 finalizer
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    (h >> 40) as f32 / (1u64 << 24) as f32
+}
+
 pub enum EmissionType {
     Zero,
     Color { v: Color },
@@ -103,6 +140,344 @@ pub enum EmissionType {
     Texture { scale: f32, img: Bitmap },
 }
 
+/// Primitives stored inside a mesh
+pub enum ShapeType {
+    /// Triangles defined by the vertices and indices
+    Triangles,
+    /// Cubic Bézier curves (hair, fur)
+    Curves(Curves),
+    /// Analytic shapes (single primitive)
+    Sphere(Sphere),
+    Disk(Disk),
+    Cylinder(Cylinder),
+}
+
+impl ShapeType {
+    pub fn is_analytic(&self) -> bool {
+        match self {
+            ShapeType::Sphere(_) | ShapeType::Disk(_) | ShapeType::Cylinder(_) => true,
+            ShapeType::Triangles | ShapeType::Curves(_) => false,
+        }
+    }
+}
+
+/// Strategy to sample a triangle mesh emitter from a shading point
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DirectSampling {
+    /// Uniform over the area (pdf converted to solid angle)
+    Area,
+    /// Uniform over the solid angle subtended by each triangle (Arvo)
+    SphericalTriangle,
+    /// Uniform over the solid angle subtended by rectangles (Ureña et al.)
+    /// Each consecutive pair of triangles needs to form a rectangle
+    SphericalRectangle,
+}
+
+impl Default for DirectSampling {
+    fn default() -> Self {
+        DirectSampling::Area
+    }
+}
+
+/// Rectangle made by two triangles (orthogonal edges)
+pub struct Rectangle {
+    pub corner: Point3<f32>,
+    pub ex: Vector3<f32>,
+    pub ey: Vector3<f32>,
+}
+
+/// Below this solid angle, area sampling is used (more robust)
+const SOLID_ANGLE_MIN: f32 = 1e-4;
+
+/// Maximum number of cells (per axis) used to sample the emission inside a triangle
+const EMISSION_GRID_MAX: usize = 64;
+
+/// Emission of a triangle used for importance sampling
+enum TriangleEmission {
+    /// Uniform sampling over the triangle area
+    Uniform { area: f32, flux: Color },
+    /// Sampling proportional to the emission over a grid
+    /// aligned with the texels in UV space
+    Grid {
+        origin: Vector2<f32>,
+        cell_size: Vector2<f32>,
+        size: Vector2<usize>,
+        // Average luminance inside each cell
+        luminance: Vec<f32>,
+        // Sum of the luminance weighted by the UV area covered in each cell
+        sum: f32,
+        uv_area: f32,
+        area: f32,
+        cells: Distribution2D,
+        flux: Color,
+    },
+}
+
+impl TriangleEmission {
+    fn flux(&self) -> Color {
+        match self {
+            TriangleEmission::Uniform { flux, .. } | TriangleEmission::Grid { flux, .. } => *flux,
+        }
+    }
+}
+
+/// Emitted power of each triangle of a textured emitter
+/// Used to pick triangles proportionally to their power
+/// and to sample the texture inside each triangle.
+pub struct EmissionDistribution {
+    triangles: Vec<TriangleEmission>,
+    cdf: Distribution1D,
+    /// Integrated emitted radiance over the mesh surface
+    pub flux: Color,
+}
+
+/// Resolution of the emission in UV space
+fn emission_resolution(emission: &EmissionType) -> Option<Vector2<usize>> {
+    match emission {
+        EmissionType::Texture { img, .. } => {
+            Some(Vector2::new(img.size.x as usize, img.size.y as usize))
+        }
+        // Only vary along the u coordinate
+        EmissionType::HSV { .. } => Some(Vector2::new(256, 1)),
+        EmissionType::Zero | EmissionType::Color { .. } => None,
+    }
+}
+
+impl EmissionDistribution {
+    fn new(mesh: &Mesh) -> EmissionDistribution {
+        let res = emission_resolution(&mesh.emission).unwrap();
+        let texel = Vector2::new(1.0 / res.x as f32, 1.0 / res.y as f32);
+        let uvs = mesh.uv.as_ref().unwrap();
+
+        let mut flux = Color::zero();
+        let mut cdf = Distribution1DConstruct::new(mesh.indices.len());
+        let triangles = mesh
+            .indices
+            .iter()
+            .map(|id| {
+                let (v0, v1, v2) = (
+                    mesh.vertices[id.x],
+                    mesh.vertices[id.y],
+                    mesh.vertices[id.z],
+                );
+                let area = (v1 - v0).cross(v2 - v0).magnitude() * 0.5;
+                let tri_uv = [uvs[id.x], uvs[id.y], uvs[id.z]];
+                let uv_area = polygon_area(&tri_uv);
+                let tri = TriangleEmission::new(mesh, &tri_uv, uv_area, area, texel);
+                let (tri, power) = match tri {
+                    Some((tri, power)) => (tri, power),
+                    None => {
+                        // Degenerated UV: constant emission over the triangle
+                        let uv = (tri_uv[0] + tri_uv[1] + tri_uv[2]) / 3.0;
+                        let e = mesh.emit(&Some(uv)) * area;
+                        (TriangleEmission::Uniform { area, flux: e }, e.luminance())
+                    }
+                };
+                flux += tri.flux();
+                cdf.add(power);
+                tri
+            })
+            .collect::<Vec<_>>();
+
+        EmissionDistribution {
+            triangles,
+            cdf: cdf.normalize(),
+            flux,
+        }
+    }
+
+    /// Probability to pick a triangle
+    pub fn pdf_triangle(&self, primitive_id: usize) -> f32 {
+        self.cdf.pdf(primitive_id)
+    }
+
+    /// Pick a triangle proportionally to its emitted power
+    pub fn sample_triangle(&self, s: f32) -> usize {
+        self.cdf.sample_discrete(s)
+    }
+
+    /// Emitted power of a triangle (without the cosine integration)
+    pub fn flux_triangle(&self, primitive_id: usize) -> Color {
+        self.triangles[primitive_id].flux()
+    }
+}
+
+impl TriangleEmission {
+    /// Build the grid over the UV footprint of the triangle
+    /// Return the emission and its power (luminance)
+    fn new(
+        mesh: &Mesh,
+        tri_uv: &[Vector2<f32>; 3],
+        uv_area: f32,
+        area: f32,
+        texel: Vector2<f32>,
+    ) -> Option<(TriangleEmission, f32)> {
+        if uv_area <= 0.0 || area <= 0.0 {
+            return None;
+        }
+
+        // Grid aligned with the texels
+        let uv_min = tri_uv[1..]
+            .iter()
+            .fold(tri_uv[0], |a, b| Vector2::new(a.x.min(b.x), a.y.min(b.y)));
+        let uv_max = tri_uv[1..]
+            .iter()
+            .fold(tri_uv[0], |a, b| Vector2::new(a.x.max(b.x), a.y.max(b.y)));
+        let texel_min = Vector2::new((uv_min.x / texel.x).floor(), (uv_min.y / texel.y).floor());
+        let texel_max = Vector2::new(
+            (uv_max.x / texel.x).ceil().max(texel_min.x + 1.0),
+            (uv_max.y / texel.y).ceil().max(texel_min.y + 1.0),
+        );
+        let nb_texels = texel_max - texel_min;
+        // Number of texels per cell
+        let texels_cell = Vector2::new(
+            (nb_texels.x / EMISSION_GRID_MAX as f32).ceil().max(1.0) as usize,
+            (nb_texels.y / EMISSION_GRID_MAX as f32).ceil().max(1.0) as usize,
+        );
+        let size = Vector2::new(
+            (nb_texels.x as usize + texels_cell.x - 1) / texels_cell.x,
+            (nb_texels.y as usize + texels_cell.y - 1) / texels_cell.y,
+        );
+        let origin = Vector2::new(texel_min.x * texel.x, texel_min.y * texel.y);
+        let cell_size = Vector2::new(
+            texel.x * texels_cell.x as f32,
+            texel.y * texels_cell.y as f32,
+        );
+
+        let mut luminance = vec![0.0; size.x * size.y];
+        let mut weights = Bitmap::new(Vector2::new(size.x as u32, size.y as u32));
+        let mut sum = 0.0;
+        let mut flux = Color::zero();
+        for y in 0..size.y {
+            for x in 0..size.x {
+                let cell_min =
+                    origin + Vector2::new(x as f32 * cell_size.x, y as f32 * cell_size.y);
+                let cell_max = cell_min + cell_size;
+                let covered = polygon_area(&clip_polygon_rect(tri_uv, cell_min, cell_max));
+                if covered <= 0.0 {
+                    continue;
+                }
+
+                // Average emission of the texels inside the cell
+                let mut e = Color::zero();
+                for ty in 0..texels_cell.y {
+                    for tx in 0..texels_cell.x {
+                        let uv = cell_min
+                            + Vector2::new(
+                                (tx as f32 + 0.5) * texel.x,
+                                (ty as f32 + 0.5) * texel.y,
+                            );
+                        e += mesh.emit(&Some(uv));
+                    }
+                }
+                let e = e / (texels_cell.x * texels_cell.y) as f32;
+
+                let l = e.luminance();
+                luminance[y * size.x + x] = l;
+                *weights.pixel_mut(Point2::new(x as u32, y as u32)) = Color::value(l * covered);
+                sum += l * covered;
+                flux += e * covered;
+            }
+        }
+        if sum <= 0.0 {
+            let flux = Color::zero();
+            return Some((TriangleEmission::Uniform { area, flux }, 0.0));
+        }
+
+        // Convert the UV area to the triangle area
+        let scale = area / uv_area;
+        Some((
+            TriangleEmission::Grid {
+                origin,
+                cell_size,
+                size,
+                luminance,
+                sum,
+                uv_area,
+                area,
+                cells: Distribution2D::from_bitmap(&weights),
+                flux: flux * scale,
+            },
+            sum * scale,
+        ))
+    }
+
+    /// Barycentric coordinates and the pdf (area measure) inside the triangle
+    fn sample(&self, tri_uv: &[Vector2<f32>; 3], v: Point2<f32>) -> Option<(Point2<f32>, f32)> {
+        match self {
+            TriangleEmission::Uniform { area, .. } => {
+                Some((uniform_sample_triangle(v), 1.0 / area))
+            }
+            TriangleEmission::Grid {
+                origin,
+                cell_size,
+                size,
+                luminance,
+                sum,
+                uv_area,
+                area,
+                cells,
+                ..
+            } => {
+                // Select a cell and reuse the random numbers inside it
+                let c = cells.sample_continuous(v);
+                let cell = Point2::new(
+                    (c.x as usize).min(size.x - 1),
+                    (c.y as usize).min(size.y - 1),
+                );
+                let u_cell = Point2::new(
+                    crate::clamp(c.x - cell.x as f32, 0.0, ONE_MINUS_EPSILON),
+                    crate::clamp(c.y - cell.y as f32, 0.0, ONE_MINUS_EPSILON),
+                );
+                let cell_min = *origin
+                    + Vector2::new(cell.x as f32 * cell_size.x, cell.y as f32 * cell_size.y);
+                let poly = clip_polygon_rect(tri_uv, cell_min, cell_min + *cell_size);
+                let l = luminance[cell.y * size.x + cell.x];
+                if poly.len() < 3 || l == 0.0 {
+                    return None;
+                }
+                let uv = sample_convex_polygon(&poly, u_cell);
+
+                // Barycentric coordinates (same convention as sample_tri)
+                let e0 = tri_uv[0] - tri_uv[2];
+                let e1 = tri_uv[1] - tri_uv[2];
+                let d = uv - tri_uv[2];
+                let det = e0.x * e1.y - e0.y * e1.x;
+                let b0 = crate::clamp((d.x * e1.y - d.y * e1.x) / det, 0.0, 1.0);
+                let b1 = crate::clamp((e0.x * d.y - e0.y * d.x) / det, 0.0, 1.0 - b0);
+                Some((Point2::new(b0, b1), l / sum * uv_area / area))
+            }
+        }
+    }
+
+    /// Density (area measure) inside the triangle
+    fn pdf(&self, uv: Option<Vector2<f32>>) -> f32 {
+        match self {
+            TriangleEmission::Uniform { area, .. } => 1.0 / area,
+            TriangleEmission::Grid {
+                origin,
+                cell_size,
+                size,
+                luminance,
+                sum,
+                uv_area,
+                area,
+                ..
+            } => {
+                let uv = match uv {
+                    Some(uv) => uv,
+                    None => return 0.0,
+                };
+                let x = ((uv.x - origin.x) / cell_size.x).floor();
+                let y = ((uv.y - origin.y) / cell_size.y).floor();
+                let x = crate::clamp(x, 0.0, (size.x - 1) as f32) as usize;
+                let y = crate::clamp(y, 0.0, (size.y - 1) as f32) as usize;
+                luminance[y * size.x + x] / sum * uv_area / area
+            }
+        }
+    }
+}
+
 /// (Triangle) Mesh information
 pub struct Mesh {
     // Name of the triangle mesh
@@ -112,10 +487,18 @@ pub struct Mesh {
     pub indices: Vec<Vector3<usize>>,
     pub normals: Option<Vec<Vector3<f32>>>,
     pub uv: Option<Vec<Vector2<f32>>>,
+    pub shape: ShapeType,
     // Other informations
     pub bsdf: Box<dyn bsdfs::BSDF>,
+    /// Opacity mask (1: opaque, 0: transparent)
+    pub alpha: Option<bsdfs::BSDFColor>,
     pub emission: EmissionType,
     pub cdf: Option<Distribution1D>,
+    /// Power based sampling for textured emitters
+    pub emission_distribution: Option<EmissionDistribution>,
+    /// Sampling strategy used for next event estimation
+    pub direct_sampling: DirectSampling,
+    pub rectangles: Vec<Rectangle>,
 }
 
 impl Mesh {
@@ -172,15 +555,47 @@ impl Mesh {
                 indices,
                 normals,
                 uv,
+                shape: ShapeType::Triangles,
                 bsdf: Box::new(bsdfs::diffuse::BSDFDiffuse {
                     diffuse: bsdfs::BSDFColor::Constant(Color::zero()),
                 }),
+                alpha: None,
                 emission: EmissionType::Zero,
                 cdf: Some(dist_const.normalize()),
+                emission_distribution: None,
+                direct_sampling: DirectSampling::Area,
+                rectangles: vec![],
             })
         }
     }
 
+    /// Create a mesh made of curves
+    /// Note that curves cannot be used as light sources
+    pub fn from_curves(name: String, curves: Curves) -> Option<Mesh> {
+        if curves.segments.is_empty() {
+            warn!("Empty curves, abording the creating of this mesh");
+            return None;
+        }
+        info!(" - curve segments: {}", curves.segments.len());
+        Some(Mesh {
+            name,
+            vertices: vec![],
+            indices: vec![],
+            normals: None,
+            uv: None,
+            shape: ShapeType::Curves(curves),
+            bsdf: Box::new(bsdfs::diffuse::BSDFDiffuse {
+                diffuse: bsdfs::BSDFColor::Constant(Color::zero()),
+            }),
+            alpha: None,
+            emission: EmissionType::Zero,
+            cdf: None,
+            emission_distribution: None,
+            direct_sampling: DirectSampling::Area,
+            rectangles: vec![],
+        })
+    }
+
     pub fn emit(&self, uv: &Option<Vector2<f32>>) -> Color {
         match &self.emission {
             EmissionType::Zero => Color::zero(),
@@ -220,9 +635,42 @@ impl Mesh {
         self.cdf = Some(dist_const.normalize());
     }
 
+    /// Build the power based sampling for textured emitters
+    /// Need to be called after the mesh is transformed
+    pub fn build_emission_distribution(&mut self) {
+        self.emission_distribution = None;
+        match &self.shape {
+            ShapeType::Triangles => {}
+            _ => return,
+        }
+        if emission_resolution(&self.emission).is_none() {
+            return;
+        }
+        if self.uv.is_none() {
+            warn!(
+                "Textured emitter {} without uv coordinates, use area sampling",
+                self.name
+            );
+            return;
+        }
+        info!("Build emission distribution for {}", self.name);
+        self.emission_distribution = Some(EmissionDistribution::new(self));
+    }
+
     pub fn pdf(&self) -> f32 {
         1.0 / (self.cdf.as_ref().unwrap().total())
     }
+    /// Density (area measure) of sampling a position on the mesh
+    pub fn pdf_position(&self, primitive_id: Option<usize>, uv: Option<Vector2<f32>>) -> f32 {
+        match (&self.emission_distribution, primitive_id) {
+            (Some(dist), Some(i)) => dist.pdf_triangle(i) * dist.triangles[i].pdf(uv),
+            (Some(_), None) => {
+                warn!("Primitive id is missing to compute the emission pdf");
+                0.0
+            }
+            (None, _) => self.pdf(),
+        }
+    }
     pub fn pdf_tri(&self, primitive_id: usize) -> f32 {
         let id = self.indices[primitive_id];
         let v0 = self.vertices[id.x];
@@ -243,8 +691,8 @@ impl Mesh {
         };
 
         let mut id_t = 0;
-        for i in 0..self.indices.len() {
-            if self.intersection_tri(i, &ray.o, &ray.d, &mut its) {
+        for i in 0..self.nb_primitives() {
+            if self.intersection_primitive(i, &ray.o, &ray.d, &mut its) {
                 id_t = i;
             }
         }
@@ -259,14 +707,20 @@ impl Mesh {
     }
 
     pub fn sample_tri(&self, primitive_id: usize, v: Point2<f32>) -> SampledPosition {
+        // Select barycentric coordinate on a triangle
+        let b = uniform_sample_triangle(v);
+        self.sample_tri_barycentric(primitive_id, b)
+    }
+
+    /// Position on the triangle from barycentric coordinates
+    /// (weights of the first and second vertices)
+    /// The pdf is uniform over the triangle area
+    pub fn sample_tri_barycentric(&self, primitive_id: usize, b: Point2<f32>) -> SampledPosition {
         let id = self.indices[primitive_id];
         let v0 = self.vertices[id.x];
         let v1 = self.vertices[id.y];
         let v2 = self.vertices[id.z];
 
-        // Select barycentric coordinate on a triangle
-        let b = uniform_sample_triangle(v);
-
         // Geometry normals
         let pos = v0 * b[0] + v1 * b[1] + v2 * (1.0 as f32 - b[0] - b[1]);
         let n_g = {
@@ -319,7 +773,7 @@ impl Mesh {
                 let n0 = uv[id.x];
                 let n1 = uv[id.y];
                 let n2 = uv[id.z];
-                let uv_0 = (n0 * b[0] + n1 * b[1] + n2 * (1.0 as f32 - b[0] - b[1])).normalize();
+                let uv_0 = n0 * b[0] + n1 * b[1] + n2 * (1.0 as f32 - b[0] - b[1]);
                 Some(uv_0)
             }
             None => None,
@@ -338,15 +792,468 @@ impl Mesh {
 
     // FIXME: reuse random number
     pub fn sample(&self, s: f32, v: Point2<f32>) -> SampledPosition {
+        if let Some(dist) = &self.emission_distribution {
+            // Select a triangle proportionally to its power
+            let primitive_id = dist.sample_triangle(s);
+            let mut res = self.sample_primitive(primitive_id, v);
+            res.pdf = PDF::Area(res.pdf.value() * dist.pdf_triangle(primitive_id));
+            return res;
+        }
+
         // Select a triangle
         let primitive_id = self.cdf.as_ref().unwrap().sample_discrete(s);
         // Sample a point on the triangle
-        let mut res = self.sample_tri(primitive_id, v);
+        let mut res = self.sample_primitive(primitive_id, v);
         // Modify the pdf to show we pick triangle prop to their area
         res.pdf = PDF::Area(1.0 / self.cdf.as_ref().unwrap().total());
         res
     }
 
+    /// Create a mesh made of a single analytic shape
+    pub fn from_shape(name: String, shape: ShapeType) -> Mesh {
+        let area = match &shape {
+            ShapeType::Sphere(s) => s.area(),
+            ShapeType::Disk(d) => d.area(),
+            ShapeType::Cylinder(c) => c.area(),
+            ShapeType::Triangles | ShapeType::Curves(_) => {
+                panic!("Use Mesh::new or Mesh::from_curves for non analytic shapes")
+            }
+        };
+        let mut dist_const = Distribution1DConstruct::new(1);
+        dist_const.add(area);
+        Mesh {
+            name,
+            vertices: vec![],
+            indices: vec![],
+            normals: None,
+            uv: None,
+            shape,
+            bsdf: Box::new(bsdfs::diffuse::BSDFDiffuse {
+                diffuse: bsdfs::BSDFColor::Constant(Color::zero()),
+            }),
+            alpha: None,
+            emission: EmissionType::Zero,
+            cdf: Some(dist_const.normalize()),
+            emission_distribution: None,
+            direct_sampling: DirectSampling::Area,
+            rectangles: vec![],
+        }
+    }
+
+    // Primitive methods (triangles, curves or analytic shapes)
+    pub fn nb_primitives(&self) -> usize {
+        match &self.shape {
+            ShapeType::Triangles => self.indices.len(),
+            ShapeType::Curves(c) => c.segments.len(),
+            ShapeType::Sphere(_) | ShapeType::Disk(_) | ShapeType::Cylinder(_) => 1,
+        }
+    }
+    /// Intersect a primitive and skip the hit if it is masked by the alpha
+    pub fn intersection_primitive(
+        &self,
+        i: usize,
+        p_c: &Point3<f32>,
+        d_c: &Vector3<f32>,
+        its: &mut IntersectionUV,
+    ) -> bool {
+        if self.alpha.is_none() {
+            return self.intersection_primitive_opaque(i, p_c, d_c, its);
+        }
+
+        // Work on a copy so a masked hit does not update the closest intersection
+        let mut its_alpha = its.clone();
+        if self.intersection_primitive_opaque(i, p_c, d_c, &mut its_alpha)
+            && self.is_opaque(i, its_alpha.u, its_alpha.v, p_c, d_c)
+        {
+            *its = its_alpha;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Remove a textured alpha on a triangle mesh without uv coordinates
+    /// (the texture cannot be looked up so the mesh would be fully transparent)
+    pub fn check_alpha(&mut self) {
+        let textured = match &self.alpha {
+            None | Some(bsdfs::BSDFColor::Constant(_)) => false,
+            Some(_) => true,
+        };
+        if textured && self.uv.is_none() && matches!(self.shape, ShapeType::Triangles) {
+            warn!(
+                "Alpha texture without uv coordinates on {}, ignoring the alpha",
+                self.name
+            );
+            self.alpha = None;
+        }
+    }
+
+    /// Alpha test for a hit on the primitive i
+    /// Partial opacity is handled stochastically by hashing the ray
+    /// so the same ray always gives the same answer
+    pub fn is_opaque(
+        &self,
+        i: usize,
+        hit_u: f32,
+        hit_v: f32,
+        p_c: &Point3<f32>,
+        d_c: &Vector3<f32>,
+    ) -> bool {
+        match &self.alpha {
+            None => true,
+            Some(alpha) => {
+                let a = alpha.color(&self.hit_uv(i, hit_u, hit_v)).avg();
+                if a >= 1.0 {
+                    true
+                } else if a <= 0.0 {
+                    false
+                } else {
+                    hash_float(p_c, d_c, i) < a
+                }
+            }
+        }
+    }
+
+    /// Texture coordinates from the hit informations
+    pub fn hit_uv(&self, i: usize, hit_u: f32, hit_v: f32) -> Option<Vector2<f32>> {
+        match &self.shape {
+            ShapeType::Triangles => match &self.uv {
+                Some(uv_data) => {
+                    let index = self.indices[i];
+                    let d0 = &uv_data[index.x];
+                    let d1 = &uv_data[index.y];
+                    let d2 = &uv_data[index.z];
+                    Some(d0 * (1.0 - hit_u - hit_v) + d1 * hit_u + d2 * hit_v)
+                }
+                None => None,
+            },
+            ShapeType::Curves(c) => {
+                let segment = &c.segments[i];
+                Some(Vector2::new(
+                    segment.u_min + (segment.u_max - segment.u_min) * hit_u,
+                    hit_v,
+                ))
+            }
+            ShapeType::Sphere(_) | ShapeType::Disk(_) | ShapeType::Cylinder(_) => {
+                Some(Vector2::new(hit_u, hit_v))
+            }
+        }
+    }
+
+    fn intersection_primitive_opaque(
+        &self,
+        i: usize,
+        p_c: &Point3<f32>,
+        d_c: &Vector3<f32>,
+        its: &mut IntersectionUV,
+    ) -> bool {
+        match &self.shape {
+            ShapeType::Triangles => self.intersection_tri(i, p_c, d_c, its),
+            ShapeType::Curves(c) => c.segments[i].intersect(c.curve_type, p_c, d_c, its),
+            ShapeType::Sphere(s) => s.intersect(p_c, d_c, its),
+            ShapeType::Disk(d) => d.intersect(p_c, d_c, its),
+            ShapeType::Cylinder(c) => c.intersect(p_c, d_c, its),
+        }
+    }
+    pub fn compute_aabb_primitive(&self, i: usize) -> AABB {
+        match &self.shape {
+            ShapeType::Triangles => self.compute_aabb_tri(i),
+            ShapeType::Curves(c) => c.segments[i].aabb(),
+            ShapeType::Sphere(s) => s.aabb(),
+            ShapeType::Disk(d) => d.aabb(),
+            ShapeType::Cylinder(c) => c.aabb(),
+        }
+    }
+    pub fn sample_primitive(&self, i: usize, v: Point2<f32>) -> SampledPosition {
+        match &self.shape {
+            ShapeType::Triangles => match &self.emission_distribution {
+                None => self.sample_tri(i, v),
+                Some(dist) => {
+                    let id = self.indices[i];
+                    let uv = self.uv.as_ref().unwrap();
+                    let tri_uv = [uv[id.x], uv[id.y], uv[id.z]];
+                    match dist.triangles[i].sample(&tri_uv, v) {
+                        Some((b, pdf)) => {
+                            let mut res = self.sample_tri_barycentric(i, b);
+                            res.pdf = PDF::Area(pdf);
+                            res
+                        }
+                        None => {
+                            let mut res = self.sample_tri(i, v);
+                            res.pdf = PDF::Area(0.0);
+                            res
+                        }
+                    }
+                }
+            },
+            ShapeType::Sphere(s) => s.sample(v),
+            ShapeType::Disk(d) => d.sample(v),
+            ShapeType::Cylinder(c) => c.sample(v),
+            ShapeType::Curves(_) => panic!("Impossible to sample curves"),
+        }
+    }
+    pub fn pdf_primitive(&self, i: usize, uv: Option<Vector2<f32>>) -> f32 {
+        match (&self.shape, &self.emission_distribution) {
+            (ShapeType::Triangles, Some(dist)) => dist.triangles[i].pdf(uv),
+            (ShapeType::Triangles, None) => self.pdf_tri(i),
+            _ => self.pdf(),
+        }
+    }
+
+    /// Change how the emitter is sampled from a shading point
+    /// Fallback to spherical triangles if the mesh is not made of rectangles
+    pub fn set_direct_sampling(&mut self, mode: DirectSampling) {
+        self.rectangles = vec![];
+        self.direct_sampling = match &self.shape {
+            ShapeType::Triangles => mode,
+            // Analytic shapes have their own sampling
+            _ => DirectSampling::Area,
+        };
+        if self.direct_sampling == DirectSampling::SphericalRectangle {
+            match self.find_rectangles() {
+                Some(rectangles) => self.rectangles = rectangles,
+                None => {
+                    warn!(
+                        "{} is not made of rectangles, use spherical triangle sampling",
+                        self.name
+                    );
+                    self.direct_sampling = DirectSampling::SphericalTriangle;
+                }
+            }
+        }
+    }
+
+    /// Rectangles made by the consecutive pairs of triangles
+    fn find_rectangles(&self) -> Option<Vec<Rectangle>> {
+        if self.indices.len() % 2 != 0 {
+            return None;
+        }
+        (0..self.indices.len() / 2)
+            .map(|q| {
+                let t0 = self.indices[2 * q];
+                let t1 = self.indices[2 * q + 1];
+                let p0 = [t0.x, t0.y, t0.z]
+                    .iter()
+                    .map(|&i| self.vertices[i])
+                    .collect::<Vec<_>>();
+                let p1 = [t1.x, t1.y, t1.z]
+                    .iter()
+                    .map(|&i| self.vertices[i])
+                    .collect::<Vec<_>>();
+
+                // The corner is the vertex with the right angle
+                (0..3).find_map(|k| {
+                    let corner = p0[k];
+                    let ex = p0[(k + 1) % 3] - corner;
+                    let ey = p0[(k + 2) % 3] - corner;
+                    let l = ex.magnitude().max(ey.magnitude());
+                    let eps = 1e-3 * l;
+                    if l == 0.0 || ex.dot(ey).abs() > eps * l {
+                        return None;
+                    }
+                    // The other triangle needs to be the other half
+                    let expected = [corner + ex, corner + ey, corner + ex + ey];
+                    let same = expected
+                        .iter()
+                        .all(|e| p1.iter().any(|p| (p - e).magnitude() < eps));
+                    if same {
+                        Some(Rectangle {
+                            corner: Point3::from_vec(corner),
+                            ex,
+                            ey,
+                        })
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect()
+    }
+
+    /// Barycentric coordinates of a point on the triangle (same convention as sample_tri)
+    fn barycentric_tri(&self, i: usize, p: Point3<f32>) -> Point2<f32> {
+        let id = self.indices[i];
+        let v0 = Point3::from_vec(self.vertices[id.x]);
+        let v1 = Point3::from_vec(self.vertices[id.y]);
+        let v2 = Point3::from_vec(self.vertices[id.z]);
+        let n = (v1 - v0).cross(v2 - v0);
+        let b0 = (v1 - p).cross(v2 - p).dot(n) / n.magnitude2();
+        let b1 = (v2 - p).cross(v0 - p).dot(n) / n.magnitude2();
+        Point2::new(b0, b1)
+    }
+
+    /// Probability to select a triangle
+    fn pdf_select(&self, i: usize) -> f32 {
+        match &self.emission_distribution {
+            Some(dist) => dist.pdf_triangle(i),
+            None => self.cdf.as_ref().unwrap().pdf(i),
+        }
+    }
+
+    /// Solid angle subtended by the triangle i (None if too small to be sampled reliably)
+    fn solid_angle_tri(&self, i: usize, o: &Point3<f32>) -> Option<f32> {
+        let id = self.indices[i];
+        let solid_angle = spherical_triangle_area(
+            *o,
+            Point3::from_vec(self.vertices[id.x]),
+            Point3::from_vec(self.vertices[id.y]),
+            Point3::from_vec(self.vertices[id.z]),
+        );
+        if solid_angle > SOLID_ANGLE_MIN {
+            Some(solid_angle)
+        } else {
+            None
+        }
+    }
+
+    fn spherical_rectangle(&self, q: usize, o: &Point3<f32>) -> Option<SphericalRectangle> {
+        let r = &self.rectangles[q];
+        SphericalRectangle::new(*o, r.corner, r.ex, r.ey)
+            .filter(|s| s.solid_angle > SOLID_ANGLE_MIN)
+    }
+
+    /// Sample the triangle i uniformly in solid angle from o
+    /// The pdf does not include the selection of the triangle
+    fn sample_tri_solid_angle(
+        &self,
+        i: usize,
+        o: &Point3<f32>,
+        v: Point2<f32>,
+    ) -> Option<SampledPosition> {
+        let solid_angle = self.solid_angle_tri(i, o)?;
+        let id = self.indices[i];
+        let (v0, v1, v2) = (
+            Point3::from_vec(self.vertices[id.x]),
+            Point3::from_vec(self.vertices[id.y]),
+            Point3::from_vec(self.vertices[id.z]),
+        );
+        let d = sample_spherical_triangle(*o, v0, v1, v2, v)?;
+
+        // Intersect the triangle plane
+        let n = (v1 - v0).cross(v2 - v0);
+        let t = (v0 - o).dot(n) / d.dot(n);
+        if !(t > 0.0) {
+            return None;
+        }
+        let b = self.barycentric_tri(i, *o + d * t);
+        let b0 = crate::clamp(b.x, 0.0, 1.0);
+        let b1 = crate::clamp(b.y, 0.0, 1.0 - b0);
+        let mut res = self.sample_tri_barycentric(i, Point2::new(b0, b1));
+        res.pdf = PDF::SolidAngle(1.0 / solid_angle);
+        Some(res)
+    }
+
+    /// Sample the rectangle q uniformly in solid angle from o
+    /// The pdf does not include the selection of the rectangle
+    fn sample_rectangle_solid_angle(
+        &self,
+        q: usize,
+        o: &Point3<f32>,
+        v: Point2<f32>,
+    ) -> Option<SampledPosition> {
+        let rect = self.spherical_rectangle(q, o)?;
+        let p = rect.sample(v);
+
+        // Find the triangle containing the point
+        let b = self.barycentric_tri(2 * q, p);
+        let (i, b) = if b.x >= 0.0 && b.y >= 0.0 && b.x + b.y <= 1.0 {
+            (2 * q, b)
+        } else {
+            (2 * q + 1, self.barycentric_tri(2 * q + 1, p))
+        };
+        let b0 = crate::clamp(b.x, 0.0, 1.0);
+        let b1 = crate::clamp(b.y, 0.0, 1.0 - b0);
+        let mut res = self.sample_tri_barycentric(i, Point2::new(b0, b1));
+        res.pdf = PDF::SolidAngle(1.0 / rect.solid_angle);
+        Some(res)
+    }
+
+    /// Sample a position on the mesh from o
+    /// The pdf is in solid angle if the primitive was sampled by its solid angle
+    pub fn sample_direct(&self, o: &Point3<f32>, s: f32, v: Point2<f32>) -> SampledPosition {
+        let i = match &self.emission_distribution {
+            Some(dist) => dist.sample_triangle(s),
+            None => self.cdf.as_ref().unwrap().sample_discrete(s),
+        };
+        let sampled = match self.direct_sampling {
+            DirectSampling::Area => None,
+            DirectSampling::SphericalTriangle => self
+                .sample_tri_solid_angle(i, o, v)
+                .map(|res| (res, self.pdf_select(i))),
+            DirectSampling::SphericalRectangle => {
+                let q = i / 2;
+                self.sample_rectangle_solid_angle(q, o, v)
+                    .map(|res| (res, self.pdf_select(2 * q) + self.pdf_select(2 * q + 1)))
+            }
+        };
+        match sampled {
+            Some((mut res, pdf_select)) => {
+                res.pdf = PDF::SolidAngle(res.pdf.value() * pdf_select);
+                res
+            }
+            None => {
+                let mut res = self.sample_primitive(i, v);
+                res.pdf = PDF::Area(res.pdf.value() * self.pdf_select(i));
+                res
+            }
+        }
+    }
+
+    /// Density of sample_direct (same measure as the sampling)
+    pub fn pdf_direct(
+        &self,
+        o: &Point3<f32>,
+        primitive_id: Option<usize>,
+        uv: Option<Vector2<f32>>,
+    ) -> PDF {
+        if let Some(i) = primitive_id {
+            match self.direct_sampling {
+                DirectSampling::Area => {}
+                DirectSampling::SphericalTriangle => {
+                    if let Some(solid_angle) = self.solid_angle_tri(i, o) {
+                        return PDF::SolidAngle(self.pdf_select(i) / solid_angle);
+                    }
+                }
+                DirectSampling::SphericalRectangle => {
+                    let q = i / 2;
+                    if let Some(rect) = self.spherical_rectangle(q, o) {
+                        let pdf_select = self.pdf_select(2 * q) + self.pdf_select(2 * q + 1);
+                        return PDF::SolidAngle(pdf_select / rect.solid_angle);
+                    }
+                }
+            }
+        }
+        PDF::Area(self.pdf_position(primitive_id, uv))
+    }
+
+    /// Sample a position on the triangle i from o (primitive already selected)
+    /// Rectangles are sampled as triangles here as each triangle is selected separately
+    pub fn sample_direct_tri(&self, o: &Point3<f32>, i: usize, v: Point2<f32>) -> SampledPosition {
+        let sampled = match self.direct_sampling {
+            DirectSampling::Area => None,
+            DirectSampling::SphericalTriangle | DirectSampling::SphericalRectangle => {
+                self.sample_tri_solid_angle(i, o, v)
+            }
+        };
+        match sampled {
+            Some(res) => res,
+            None => self.sample_primitive(i, v),
+        }
+    }
+
+    /// Density of sample_direct_tri (same measure as the sampling)
+    pub fn pdf_direct_tri(&self, o: &Point3<f32>, i: usize, uv: Option<Vector2<f32>>) -> PDF {
+        match self.direct_sampling {
+            DirectSampling::Area => {}
+            DirectSampling::SphericalTriangle | DirectSampling::SphericalRectangle => {
+                if let Some(solid_angle) = self.solid_angle_tri(i, o) {
+                    return PDF::SolidAngle(1.0 / solid_angle);
+                }
+            }
+        }
+        PDF::Area(self.pdf_primitive(i, uv))
+    }
+
     // Triangle methods
     pub fn middle_tri(&self, i: usize) -> Vector3<f32> {
         let id = self.indices[i];
@@ -362,51 +1269,104 @@ impl Mesh {
         d_c: &Vector3<f32>,
         its: &mut IntersectionUV,
     ) -> bool {
+        // Watertight intersection (Woop et al. 2013), implementation from PBRT-v3
         let id = self.indices[i];
-        let v0 = self.vertices[id.x];
-        let v1 = self.vertices[id.y];
-        let v2 = self.vertices[id.z];
+        let p0 = self.vertices[id.x];
+        let p1 = self.vertices[id.y];
+        let p2 = self.vertices[id.z];
 
-        let e1 = v1 - v0;
-        let e2 = v2 - v0;
-        let n_geo = e1.cross(e2).normalize();
-        let denom = d_c.dot(n_geo);
-        if denom == 0.0 {
+        // Translate the vertices relative to the ray origin
+        // and permute the axis so the ray direction largest component is z
+        let o = p_c.to_vec();
+        let d_abs = crate::math::abs_vec(d_c);
+        let kz = if d_abs.x > d_abs.y {
+            if d_abs.x > d_abs.z {
+                0
+            } else {
+                2
+            }
+        } else if d_abs.y > d_abs.z {
+            1
+        } else {
+            2
+        };
+        let kx = (kz + 1) % 3;
+        let ky = (kx + 1) % 3;
+        let permute = |v: Vector3<f32>| Vector3::new(v[kx], v[ky], v[kz]);
+        let d = permute(*d_c);
+        let mut p0t = permute(p0 - o);
+        let mut p1t = permute(p1 - o);
+        let mut p2t = permute(p2 - o);
+
+        // Shear the vertices so the ray direction is +z
+        let s_x = -d.x / d.z;
+        let s_y = -d.y / d.z;
+        let s_z = 1.0 / d.z;
+        for p in [&mut p0t, &mut p1t, &mut p2t].iter_mut() {
+            p.x += s_x * p.z;
+            p.y += s_y * p.z;
+        }
+
+        // Edge functions (recomputed in double precision if on an edge)
+        let mut e0 = p1t.x * p2t.y - p1t.y * p2t.x;
+        let mut e1 = p2t.x * p0t.y - p2t.y * p0t.x;
+        let mut e2 = p0t.x * p1t.y - p0t.y * p1t.x;
+        if e0 == 0.0 || e1 == 0.0 || e2 == 0.0 {
+            e0 = (p1t.x as f64 * p2t.y as f64 - p1t.y as f64 * p2t.x as f64) as f32;
+            e1 = (p2t.x as f64 * p0t.y as f64 - p2t.y as f64 * p0t.x as f64) as f32;
+            e2 = (p0t.x as f64 * p1t.y as f64 - p0t.y as f64 * p1t.x as f64) as f32;
+        }
+        if (e0 < 0.0 || e1 < 0.0 || e2 < 0.0) && (e0 > 0.0 || e1 > 0.0 || e2 > 0.0) {
             return false;
         }
-        // Distance for intersection
-        let t = -(p_c - v0).dot(n_geo) / denom;
-        if t < 0.0 {
+        let det = e0 + e1 + e2;
+        if det == 0.0 {
             return false;
         }
-        let p = p_c + t * d_c;
-        let det = e1.cross(e2).magnitude();
-        let u0 = e1.cross(p.to_vec() - v0);
-        let v0 = (p.to_vec() - v0).cross(e2);
-        if u0.dot(n_geo) < 0.0 || v0.dot(n_geo) < 0.0 {
+
+        // Scaled distance and check against the current closest intersection
+        p0t.z *= s_z;
+        p1t.z *= s_z;
+        p2t.z *= s_z;
+        let t_scaled = e0 * p0t.z + e1 * p1t.z + e2 * p2t.z;
+        if det < 0.0 && (t_scaled >= 0.0 || t_scaled < its.t * det) {
+            return false;
+        } else if det > 0.0 && (t_scaled <= 0.0 || t_scaled > its.t * det) {
             return false;
         }
-        let v = u0.magnitude() / det;
-        let u = v0.magnitude() / det;
-        if u < 0.0 || v < 0.0 || u > 1.0 || v > 1.0 {
+        let inv_det = 1.0 / det;
+        let b0 = e0 * inv_det;
+        let b1 = e1 * inv_det;
+        let b2 = e2 * inv_det;
+        let t = t_scaled * inv_det;
+
+        // Conservative check that the distance is positive
+        // (replace the constant epsilon to avoid self intersection)
+        let max_zt = p0t.z.abs().max(p1t.z.abs()).max(p2t.z.abs());
+        let max_xt = p0t.x.abs().max(p1t.x.abs()).max(p2t.x.abs());
+        let max_yt = p0t.y.abs().max(p1t.y.abs()).max(p2t.y.abs());
+        let delta_z = gamma(3) * max_zt;
+        let delta_x = gamma(5) * (max_xt + max_zt);
+        let delta_y = gamma(5) * (max_yt + max_zt);
+        let delta_e = 2.0 * (gamma(2) * max_xt * max_yt + delta_y * max_xt + delta_x * max_yt);
+        let max_e = e0.abs().max(e1.abs()).max(e2.abs());
+        let delta_t =
+            3.0 * (gamma(3) * max_e * max_zt + delta_e * max_zt + delta_z * max_e) * inv_det.abs();
+        if t <= delta_t {
             return false;
         }
-        if u + v <= 1.0 {
-            // TODO: Review the condition because
-            //      for now it only return true
-            //      if the itersection is updated
-            if t < its.t && t > 0.00001 {
-                // Avoid self intersection
-                // FIXME: Make this code cleaner
-                its.t = t;
-                its.u = u;
-                its.v = v;
-                its.p = p;
-                its.n = n_geo;
-                return true;
-            }
+
+        let n_geo = (p1 - p0).cross(p2 - p0);
+        let l = n_geo.magnitude2();
+        if l == 0.0 {
+            return false;
         }
-        false
+        its.t = t;
+        its.u = b1;
+        its.v = b2;
+        its.p = Point3::from_vec(p0 * b0 + p1 * b1 + p2 * b2);
+        its.n = n_geo / l.sqrt();
+        true
     }
 
     pub fn is_light(&self) -> bool {
@@ -440,8 +1400,20 @@ impl Mesh {
 
     pub fn compute_aabb(&self) -> AABB {
         let mut aabb = AABB::default();
-        for v in &self.vertices {
-            aabb = aabb.union_vec(v)
+        match &self.shape {
+            ShapeType::Triangles => {
+                for v in &self.vertices {
+                    aabb = aabb.union_vec(v)
+                }
+            }
+            ShapeType::Curves(c) => {
+                for s in &c.segments {
+                    aabb = aabb.union_aabb(&s.aabb())
+                }
+            }
+            ShapeType::Sphere(_) | ShapeType::Disk(_) | ShapeType::Cylinder(_) => {
+                aabb = self.compute_aabb_primitive(0)
+            }
         }
 
         // Make sure the AABB to be non degenerative
@@ -456,3 +1428,273 @@ impl Mesh {
         aabb
     }
 }
+
+/// Group of meshes shared by several instances
+/// The meshes are expressed in the object space
+pub struct InstanceObject {
+    pub name: String,
+    pub meshes: Vec<std::sync::Arc<Mesh>>,
+}
+
+impl InstanceObject {
+    pub fn compute_aabb(&self) -> AABB {
+        let mut aabb = AABB::default();
+        for m in &self.meshes {
+            aabb = aabb.union_aabb(&m.compute_aabb());
+        }
+        aabb
+    }
+}
+
+/// Keyframed transformation (motion blur)
+/// The translation and the scale are linearly interpolated
+/// and the rotation is spherically interpolated.
+pub struct Motion {
+    keyframes: Vec<(f32, Decomposed<Vector3<f32>, Quaternion<f32>>)>,
+}
+
+impl Motion {
+    /// Keyframes (time, matrix), the matrices need to be
+    /// composed of a translation, a rotation and an uniform scaling
+    pub fn new(mut keyframes: Vec<(f32, Matrix4<f32>)>) -> Motion {
+        assert!(!keyframes.is_empty());
+        keyframes.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        let keyframes = keyframes
+            .into_iter()
+            .map(|(t, m)| {
+                let scale = Vector3::new(
+                    m.x.truncate().magnitude(),
+                    m.y.truncate().magnitude(),
+                    m.z.truncate().magnitude(),
+                );
+                if (scale.x - scale.y).abs() > EPSILON || (scale.x - scale.z).abs() > EPSILON {
+                    warn!(
+                        "Non uniform scaling inside the motion keyframes: {:?}",
+                        scale
+                    );
+                }
+                let rot = Matrix3::from_cols(
+                    m.x.truncate() / scale.x,
+                    m.y.truncate() / scale.y,
+                    m.z.truncate() / scale.z,
+                );
+                (
+                    t,
+                    Decomposed {
+                        scale: scale.x,
+                        rot: Quaternion::from(rot),
+                        disp: m.w.truncate(),
+                    },
+                )
+            })
+            .collect();
+        Motion { keyframes }
+    }
+
+    pub fn time_range(&self) -> (f32, f32) {
+        (
+            self.keyframes[0].0,
+            self.keyframes[self.keyframes.len() - 1].0,
+        )
+    }
+
+    /// Transformation at a given time (clamped to the keyframes)
+    pub fn transform(&self, time: f32) -> Matrix4<f32> {
+        let id = self.keyframes.iter().position(|(t, _)| *t > time);
+        let d = match id {
+            Some(0) => self.keyframes[0].1,
+            None => self.keyframes[self.keyframes.len() - 1].1,
+            Some(i) => {
+                let (t0, d0) = &self.keyframes[i - 1];
+                let (t1, d1) = &self.keyframes[i];
+                let a = (time - t0) / (t1 - t0);
+                // Take the shortest path for the rotation
+                let rot1 = if d0.rot.dot(d1.rot) < 0.0 {
+                    -d1.rot
+                } else {
+                    d1.rot
+                };
+                Decomposed {
+                    scale: d0.scale * (1.0 - a) + d1.scale * a,
+                    rot: d0.rot.slerp(rot1, a),
+                    disp: d0.disp.lerp(d1.disp, a),
+                }
+            }
+        };
+        Matrix4::from(d)
+    }
+}
+
+/// Placement of an object inside the scene
+pub struct Instance {
+    /// Index of the object (see Scene::objects)
+    pub object: usize,
+    pub to_world: Matrix4<f32>,
+    pub to_local: Matrix4<f32>,
+    /// Animation applied after to_world
+    pub motion: Option<Motion>,
+}
+
+impl Instance {
+    /// Return None if the transformation is not invertible
+    pub fn new(object: usize, to_world: Matrix4<f32>) -> Option<Instance> {
+        Some(Instance {
+            object,
+            to_world,
+            to_local: to_world.invert()?,
+            motion: None,
+        })
+    }
+
+    pub fn with_motion(mut self, motion: Motion) -> Instance {
+        self.motion = Some(motion);
+        self
+    }
+
+    /// Transformations (to_world, to_local) at a given time
+    pub fn transforms(&self, time: f32) -> (Matrix4<f32>, Matrix4<f32>) {
+        match &self.motion {
+            None => (self.to_world, self.to_local),
+            Some(motion) => {
+                let to_world = motion.transform(time) * self.to_world;
+                // A keyframe with a null scale is degenerate,
+                // keep the static transformation in this case
+                match to_world.invert() {
+                    Some(to_local) => (to_world, to_local),
+                    None => (self.to_world, self.to_local),
+                }
+            }
+        }
+    }
+
+    /// World space AABB from the object AABB
+    /// For animated instances, the AABB is the union of the AABBs
+    /// at regular times between the keyframes
+    pub fn compute_aabb(&self, object: &InstanceObject) -> AABB {
+        const MOTION_SAMPLES: usize = 16;
+        let aabb_local = object.compute_aabb();
+        let times = match &self.motion {
+            None => vec![0.0],
+            Some(motion) => {
+                let mut times = vec![];
+                for k in motion.keyframes.windows(2) {
+                    for i in 0..MOTION_SAMPLES {
+                        times.push(k[0].0 + (k[1].0 - k[0].0) * i as f32 / MOTION_SAMPLES as f32);
+                    }
+                }
+                times.push(motion.time_range().1);
+                times
+            }
+        };
+
+        let mut aabb = AABB::default();
+        for time in times {
+            let (to_world, _) = self.transforms(time);
+            for i in 0..8 {
+                let p = Point3::new(
+                    if i & 1 == 0 {
+                        aabb_local.p_min.x
+                    } else {
+                        aabb_local.p_max.x
+                    },
+                    if i & 2 == 0 {
+                        aabb_local.p_min.y
+                    } else {
+                        aabb_local.p_max.y
+                    },
+                    if i & 4 == 0 {
+                        aabb_local.p_min.z
+                    } else {
+                        aabb_local.p_max.z
+                    },
+                );
+                aabb = aabb.union_vec(&to_world.transform_point(p).to_vec());
+            }
+        }
+        aabb
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::samplers::independent::IndependentSampler;
+    use crate::samplers::Sampler;
+
+    /// Quad [0, 2] x [0, 1] emitting a 4x2 texture
+    fn textured_quad() -> Mesh {
+        let mut mesh = Mesh::new(
+            "quad".to_string(),
+            vec![
+                Vector3::new(0.0, 0.0, 0.0),
+                Vector3::new(2.0, 0.0, 0.0),
+                Vector3::new(2.0, 1.0, 0.0),
+                Vector3::new(0.0, 1.0, 0.0),
+            ],
+            vec![Vector3::new(0, 1, 2), Vector3::new(0, 2, 3)],
+            None,
+            Some(vec![
+                Vector2::new(0.0, 0.0),
+                Vector2::new(1.0, 0.0),
+                Vector2::new(1.0, 1.0),
+                Vector2::new(0.0, 1.0),
+            ]),
+        )
+        .unwrap();
+        let mut img = Bitmap::new(Vector2::new(4, 2));
+        for (i, c) in img.colors.iter_mut().enumerate() {
+            *c = Color::value(1.0 + i as f32);
+        }
+        mesh.emission = EmissionType::Texture { scale: 1.0, img };
+        mesh.build_emission_distribution();
+        assert!(mesh.emission_distribution.is_some());
+        mesh
+    }
+
+    #[test]
+    fn emission_sampling_pdf() {
+        let mesh = textured_quad();
+        let mut sampler = IndependentSampler::from_seed(0);
+        for _ in 0..1000 {
+            let res = mesh.sample(sampler.next(), sampler.next2d());
+            let pdf = mesh.pdf_position(res.primitive_id, res.uv);
+            assert!((res.pdf.value() - pdf).abs() <= 1e-4 * pdf);
+        }
+    }
+
+    #[test]
+    fn emission_pdf_integral() {
+        let mesh = textured_quad();
+        // Midpoint rule over the uv domain (area = 2 * uv area)
+        const N: usize = 256;
+        let mut integral = 0.0;
+        for y in 0..N {
+            for x in 0..N {
+                let uv = Vector2::new((x as f32 + 0.5) / N as f32, (y as f32 + 0.5) / N as f32);
+                let tri = if uv.x >= uv.y { 0 } else { 1 };
+                integral += mesh.pdf_position(Some(tri), Some(uv)) * 2.0 / (N * N) as f32;
+            }
+        }
+        assert!((integral - 1.0).abs() < 1e-3, "integral: {}", integral);
+    }
+
+    #[test]
+    fn emission_sampling_flux() {
+        let mesh = textured_quad();
+        let flux = mesh.emission_distribution.as_ref().unwrap().flux;
+        let mut sampler = IndependentSampler::from_seed(1);
+        const N: usize = 10000;
+        let mut estimate = Color::zero();
+        for _ in 0..N {
+            let res = mesh.sample(sampler.next(), sampler.next2d());
+            estimate += mesh.emit(&res.uv) / (res.pdf.value() * N as f32);
+        }
+        // Importance sampling proportional to the emission (low variance)
+        assert!(
+            (estimate.luminance() - flux.luminance()).abs() < 1e-2 * flux.luminance(),
+            "{:?} != {:?}",
+            estimate,
+            flux
+        );
+    }
+}
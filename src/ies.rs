@@ -0,0 +1,259 @@
+use std;
+use std::io::Read;
+
+/// Photometric profile loaded from an IES file (LM-63)
+/// Only type C photometry is supported: the vertical angle is measured
+/// from the nadir (local +z) and the horizontal angle around it.
+#[derive(Clone, Debug)]
+pub struct IESProfile {
+    // Angles in radians (increasing order)
+    pub vertical_angles: Vec<f32>,
+    pub horizontal_angles: Vec<f32>,
+    // Candela values (already scaled by the multiplier)
+    // indexed by [horizontal][vertical]
+    pub candela: Vec<Vec<f32>>,
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Find the interval [i, i+1] containing v and the interpolation factor
+fn find_interval(angles: &[f32], v: f32) -> (usize, f32) {
+    if angles.len() == 1 {
+        return (0, 0.0);
+    }
+    // NaN are treated as smaller than any value (first interval)
+    let i = match angles
+        .binary_search_by(|probe| probe.partial_cmp(&v).unwrap_or(std::cmp::Ordering::Greater))
+    {
+        Ok(i) => i,
+        Err(i) => i.max(1) - 1,
+    }
+    .min(angles.len() - 2);
+    let delta = angles[i + 1] - angles[i];
+    let t = if delta > 0.0 {
+        crate::clamp((v - angles[i]) / delta, 0.0, 1.0)
+    } else {
+        0.0
+    };
+    (i, t)
+}
+
+impl IESProfile {
+    pub fn read(filename: &std::path::Path) -> std::io::Result<IESProfile> {
+        info!("Try to load {:?}", filename);
+        let mut content = String::new();
+        std::fs::File::open(filename)?.read_to_string(&mut content)?;
+        IESProfile::parse(&content)
+    }
+
+    pub fn parse(content: &str) -> std::io::Result<IESProfile> {
+        // Skip the header and keywords until the TILT line
+        let mut lines = content.lines();
+        let tilt = loop {
+            match lines.next() {
+                None => return Err(invalid_data("IES file without TILT line")),
+                Some(l) => {
+                    let l = l.trim();
+                    if l.starts_with("TILT=") {
+                        break l["TILT=".len()..].trim().to_string();
+                    }
+                }
+            }
+        };
+
+        // The remaining of the file is a list of numbers
+        let values = lines
+            .flat_map(|l| l.split(|c: char| c.is_whitespace() || c == ','))
+            .filter(|v| !v.is_empty())
+            .map(|v| {
+                v.parse::<f32>()
+                    .map_err(|_| invalid_data(&format!("IES wrong number: {}", v)))
+            })
+            .collect::<std::io::Result<Vec<f32>>>()?;
+        let mut values = values.into_iter();
+        let mut next = || {
+            values
+                .next()
+                .ok_or_else(|| invalid_data("IES file is truncated"))
+        };
+
+        match tilt.as_str() {
+            "NONE" => {}
+            "INCLUDE" => {
+                // Lamp to luminaire geometry, then the tilt angles and factors
+                warn!("IES tilt information is ignored");
+                let _geometry = next()?;
+                let nb_tilt = next()? as usize;
+                for _ in 0..2 * nb_tilt {
+                    next()?;
+                }
+            }
+            _ => {
+                return Err(invalid_data(&format!(
+                    "IES external tilt file is not supported: {}",
+                    tilt
+                )))
+            }
+        }
+
+        let _nb_lamps = next()?;
+        let _lumens = next()?;
+        let multiplier = next()?;
+        let nb_vertical = next()? as usize;
+        let nb_horizontal = next()? as usize;
+        let photometric_type = next()? as u32;
+        let _units = next()?;
+        let _width = next()?;
+        let _length = next()?;
+        let _height = next()?;
+        let ballast_factor = next()?;
+        let _ballast_lamp_factor = next()?;
+        let _input_watts = next()?;
+        if photometric_type != 1 {
+            return Err(invalid_data(&format!(
+                "Only IES type C photometry is supported (type: {})",
+                photometric_type
+            )));
+        }
+        if nb_vertical == 0 || nb_horizontal == 0 {
+            return Err(invalid_data("IES file without angles"));
+        }
+
+        let mut read_angles = |n: usize| -> std::io::Result<Vec<f32>> {
+            (0..n).map(|_| Ok(next()?.to_radians())).collect()
+        };
+        let vertical_angles = read_angles(nb_vertical)?;
+        let horizontal_angles = read_angles(nb_horizontal)?;
+        let scale = multiplier * ballast_factor;
+        let candela = (0..nb_horizontal)
+            .map(|_| {
+                (0..nb_vertical)
+                    .map(|_| Ok(next()?.max(0.0) * scale))
+                    .collect::<std::io::Result<Vec<f32>>>()
+            })
+            .collect::<std::io::Result<Vec<_>>>()?;
+
+        info!(
+            " - angles: {} vertical x {} horizontal",
+            nb_vertical, nb_horizontal
+        );
+        Ok(IESProfile {
+            vertical_angles,
+            horizontal_angles,
+            candela,
+        })
+    }
+
+    /// Remap the horizontal angle using the profile symmetries
+    fn horizontal_angle(&self, phi: f32) -> f32 {
+        use std::f32::consts::PI;
+        let last = *self.horizontal_angles.last().unwrap();
+        if last <= 0.0 {
+            // Rotationally symmetric
+            0.0
+        } else if last <= PI * 0.5 + 1e-3 {
+            // Quadrant symmetric
+            let phi = phi % PI;
+            if phi > PI * 0.5 {
+                PI - phi
+            } else {
+                phi
+            }
+        } else if last <= PI + 1e-3 {
+            // Bilateral symmetric
+            if phi > PI {
+                2.0 * PI - phi
+            } else {
+                phi
+            }
+        } else {
+            phi
+        }
+    }
+
+    /// Intensity (candela) for a direction in the luminaire space
+    pub fn eval(&self, theta: f32, phi: f32) -> f32 {
+        let v_min = self.vertical_angles[0];
+        let v_max = *self.vertical_angles.last().unwrap();
+        if theta < v_min || theta > v_max {
+            return 0.0;
+        }
+        let phi = self.horizontal_angle(phi);
+        let (iv, tv) = find_interval(&self.vertical_angles, theta);
+        let (ih, th) = find_interval(&self.horizontal_angles, phi);
+        let lookup = |h: usize, t: f32| -> f32 {
+            let row = &self.candela[h];
+            if iv + 1 < row.len() {
+                row[iv] * (1.0 - t) + row[iv + 1] * t
+            } else {
+                row[iv]
+            }
+        };
+        let v0 = lookup(ih, tv);
+        if ih + 1 < self.candela.len() {
+            v0 * (1.0 - th) + lookup(ih + 1, tv) * th
+        } else {
+            v0
+        }
+    }
+
+    pub fn max_value(&self) -> f32 {
+        self.candela
+            .iter()
+            .flat_map(|r| r.iter())
+            .cloned()
+            .fold(0.0, f32::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Quadrant symmetric profile: 3 vertical x 2 horizontal angles
+    const PROFILE: &str = "IESNA:LM-63-2002
+[TEST] rustlight
+TILT=NONE
+1 1000 2 3 2 1 1 0 0 0
+0.5 1 100
+0 45 90
+0 90
+10 20 0
+30 40 0
+";
+
+    #[test]
+    fn parse() {
+        let ies = IESProfile::parse(PROFILE).unwrap();
+        assert_eq!(ies.vertical_angles.len(), 3);
+        assert_eq!(ies.horizontal_angles.len(), 2);
+        // Multiplier and ballast factor
+        assert_eq!(ies.candela[1], vec![30.0, 40.0, 0.0]);
+        assert_eq!(ies.max_value(), 40.0);
+    }
+
+    #[test]
+    fn eval() {
+        use std::f32::consts::{FRAC_PI_2, FRAC_PI_4, PI};
+        let ies = IESProfile::parse(PROFILE).unwrap();
+        let close = |a: f32, b: f32| (a - b).abs() < 1e-3;
+        assert!(close(ies.eval(0.0, 0.0), 10.0));
+        assert!(close(ies.eval(FRAC_PI_4, FRAC_PI_2), 40.0));
+        // Bilinear interpolation
+        assert!(close(ies.eval(FRAC_PI_4 * 0.5, FRAC_PI_4), 25.0));
+        // Quadrant symmetry
+        assert!(close(ies.eval(0.0, PI), ies.eval(0.0, 0.0)));
+        assert!(close(ies.eval(0.0, PI * 0.75), ies.eval(0.0, FRAC_PI_4)));
+        // Outside the vertical angles
+        assert_eq!(ies.eval(PI * 0.75, 0.0), 0.0);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(IESProfile::parse("IESNA:LM-63-2002\n1 2 3").is_err());
+        assert!(IESProfile::parse(&PROFILE.replace("1 1 0 0 0", "2 1 0 0 0")).is_err());
+        assert!(IESProfile::parse(&PROFILE.replace("30 40 0", "30 40")).is_err());
+    }
+}
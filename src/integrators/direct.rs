@@ -20,13 +20,16 @@ impl Integrator for IntegratorDirect {
 impl IntegratorMC for IntegratorDirect {
     fn compute_pixel(
         &self,
-        (ix, iy): (u32, u32),
+        pix: Point2<f32>,
         accel: &dyn Acceleration,
         scene: &Scene,
         sampler: &mut dyn Sampler,
     ) -> Color {
-        let pix = Point2::new(ix as f32 + sampler.next(), iy as f32 + sampler.next());
-        let ray = scene.camera.generate(pix);
+        let ray = scene.camera.generate_at(
+            pix,
+            sampler.next2d(),
+            scene.camera.sample_time(sampler.next_time()),
+        );
         let mut l_i = Color::zero();
 
         // Do the intersection for the first path
@@ -60,21 +63,28 @@ impl IntegratorMC for IntegratorDirect {
         // Light sampling
         /////////////////////////////////
         // Explict connect to the light source
-        for _ in 0..self.nb_light_samples {
-            let light_record = scene.emitters().sample_light(
-                &its.p,
-                Some(&its.n_s),
-                sampler.next(),
-                sampler.next(),
-                sampler.next2d(),
-            );
-
+        let light_records = (0..self.nb_light_samples)
+            .map(|_| {
+                scene.emitters().sample_light(
+                    &its.p,
+                    Some(&its.n_s),
+                    sampler.next(),
+                    sampler.next(),
+                    sampler.next2d(),
+                )
+            })
+            .filter(|r| !r.pdf.is_zero())
+            .collect::<Vec<_>>();
+        // The shadow rays share the same origin, trace them as a packet
+        let segments = light_records
+            .iter()
+            .map(|r| (its.spawn_origin(&(r.p - its.p)), r.p))
+            .collect::<Vec<_>>();
+        let visibilities = accel.visible_packet(&segments, its.time);
+        for (light_record, visible) in light_records.into_iter().zip(visibilities) {
             // Compute the contribution of direct lighting
             let d_out_local = its.frame.to_local(light_record.d);
-            if light_record.is_valid()
-                && accel.visible(&its.p, &light_record.p)
-                && !its.mesh.bsdf.bsdf_type().is_smooth()
-            {
+            if visible && !its.mesh.bsdf.bsdf_type().is_smooth() {
                 // DBG
                 // {
                 //     let v_t = scene
@@ -132,100 +142,102 @@ impl IntegratorMC for IntegratorDirect {
         // BSDF sampling
         /////////////////////////////////
         // Compute an new direction (diffuse)
-        for _ in 0..self.nb_bsdf_samples {
-            if let Some(sampled_bsdf) =
+        let sampled_bsdfs = (0..self.nb_bsdf_samples)
+            .filter_map(|_| {
                 its.mesh
                     .bsdf
                     .sample(&its.uv, &its.wi, sampler.next2d(), Transport::Importance)
-            {
-                // Generate the new ray and do the intersection
-                let d_out_world = its.frame.to_world(sampled_bsdf.d);
-                let ray = Ray::spawn_ray(&its, d_out_world);
-                match accel.trace(&ray) {
-                    Some(next_its) => {
-                        // Check that we have intersected a light or not
-                        if next_its.mesh.is_light() && next_its.n_g.dot(-ray.d) > 0.0 {
-                            let weight_bsdf = match sampled_bsdf.pdf {
-                                PDF::SolidAngle(bsdf_pdf) => {
-                                    // DBG
-                                    // {
-                                    //     dbg!(bsdf_pdf);
-                                    //     dbg!(its.mesh.bsdf.pdf(&its.uv, &its.wi, &sampled_bsdf.d, Domain::SolidAngle, Transport::Importance));
-                                    // }
-
-                                    let light_pdf = scene
-                                        .emitters()
-                                        .direct_pdf(
-                                            next_its.mesh,
-                                            &LightSamplingPDF::new(&ray, &next_its),
-                                            Some(&its.n_s),
-                                            next_its.primitive_id,
-                                        )
-                                        .value();
+            })
+            .collect::<Vec<_>>();
+        // Generate the new rays and do the intersections as a packet
+        let rays = sampled_bsdfs
+            .iter()
+            .map(|s| Ray::spawn_ray(&its, its.frame.to_world(s.d)))
+            .collect::<Vec<_>>();
+        let hits = accel.trace_packet(&rays);
+        for ((sampled_bsdf, ray), hit) in sampled_bsdfs.into_iter().zip(rays).zip(hits) {
+            match hit {
+                Some(next_its) => {
+                    // Check that we have intersected a light or not
+                    if next_its.mesh.is_light() && next_its.n_g.dot(-ray.d) > 0.0 {
+                        let weight_bsdf = match sampled_bsdf.pdf {
+                            PDF::SolidAngle(bsdf_pdf) => {
+                                // DBG
+                                // {
+                                //     dbg!(bsdf_pdf);
+                                //     dbg!(its.mesh.bsdf.pdf(&its.uv, &its.wi, &sampled_bsdf.d, Domain::SolidAngle, Transport::Importance));
+                                // }
+
+                                let light_pdf = scene
+                                    .emitters()
+                                    .direct_pdf(
+                                        next_its.mesh,
+                                        &LightSamplingPDF::new(&ray, &next_its),
+                                        Some(&its.n_s),
+                                        next_its.primitive_id,
+                                    )
+                                    .value();
+                                mis_weight(bsdf_pdf * weight_nb_bsdf, light_pdf * weight_nb_light)
+                            }
+                            PDF::Discrete(_v) => 1.0,
+                            _ => {
+                                warn!("Wrong PDF values retrieve on an intersected mesh");
+                                continue;
+                            }
+                        };
+
+                        l_i += weight_bsdf
+                            * sampled_bsdf.weight
+                            * next_its.mesh.emit(&next_its.uv)
+                            * weight_nb_bsdf;
+                    }
+                }
+                None => {
+                    if scene.emitter_environment.is_some() {
+                        let envmap = scene.emitter_environment.as_ref().unwrap();
+                        // We have to compute MIS
+                        let weight_bsdf = match sampled_bsdf.pdf {
+                            PDF::SolidAngle(bsdf_pdf) => {
+                                let bsphere = envmap.bsphere.as_ref().unwrap();
+                                let t = bsphere.intersect(&ray);
+                                let t = t.unwrap();
+
+                                let p = ray.o + ray.d * t;
+                                let n = (bsphere.center - p).normalize();
+                                if let PDF::SolidAngle(light_pdf) = scene.emitters().direct_pdf(
+                                    envmap.as_ref(),
+                                    &LightSamplingPDF {
+                                        o: ray.o,
+                                        p,
+                                        n,
+                                        uv: None,
+                                        dir: ray.d,
+                                        primitive_id: None,
+                                    },
+                                    None,
+                                    None,
+                                ) {
                                     mis_weight(
                                         bsdf_pdf * weight_nb_bsdf,
                                         light_pdf * weight_nb_light,
                                     )
+                                } else {
+                                    1.0
                                 }
-                                PDF::Discrete(_v) => 1.0,
-                                _ => {
-                                    warn!("Wrong PDF values retrieve on an intersected mesh");
-                                    continue;
-                                }
-                            };
-
-                            l_i += weight_bsdf
-                                * sampled_bsdf.weight
-                                * next_its.mesh.emit(&next_its.uv)
-                                * weight_nb_bsdf;
-                        }
-                    }
-                    None => {
-                        if scene.emitter_environment.is_some() {
-                            let envmap = scene.emitter_environment.as_ref().unwrap();
-                            // We have to compute MIS
-                            let weight_bsdf = match sampled_bsdf.pdf {
-                                PDF::SolidAngle(bsdf_pdf) => {
-                                    let bsphere = envmap.bsphere.as_ref().unwrap();
-                                    let t = bsphere.intersect(&ray);
-                                    let t = t.unwrap();
-
-                                    let p = ray.o + ray.d * t;
-                                    let n = (bsphere.center - p).normalize();
-                                    if let PDF::SolidAngle(light_pdf) = scene.emitters().direct_pdf(
-                                        envmap.as_ref(),
-                                        &LightSamplingPDF {
-                                            o: ray.o,
-                                            p,
-                                            n,
-                                            uv: None,
-                                            dir: ray.d,
-                                        },
-                                        None,
-                                        None,
-                                    ) {
-                                        mis_weight(
-                                            bsdf_pdf * weight_nb_bsdf,
-                                            light_pdf * weight_nb_light,
-                                        )
-                                    } else {
-                                        1.0
-                                    }
-                                }
-                                PDF::Discrete(_v) => 1.0,
-                                _ => {
-                                    warn!("Wrong PDF values retrieve on an intersected mesh");
-                                    continue;
-                                }
-                            };
-
-                            l_i += weight_bsdf
-                                * sampled_bsdf.weight
-                                * scene.enviroment_luminance(ray.d)
-                                * weight_nb_bsdf;
-                        }
+                            }
+                            PDF::Discrete(_v) => 1.0,
+                            _ => {
+                                warn!("Wrong PDF values retrieve on an intersected mesh");
+                                continue;
+                            }
+                        };
+
+                        l_i += weight_bsdf
+                            * sampled_bsdf.weight
+                            * scene.enviroment_luminance(ray.d)
+                            * weight_nb_bsdf;
                     }
-                };
+                }
             }
         }
 
@@ -38,6 +38,7 @@ impl TechniqueLightTracing {
         path: &Path<'scene>,
         accel: &dyn Acceleration,
         scene: &'scene Scene,
+        sampler: &mut dyn Sampler,
         vertex_id: VertexID,
         bitmap: &mut BufferCollection,
         flux: Color,
@@ -56,11 +57,12 @@ impl TechniqueLightTracing {
                 ..
             } => {
                 if accumulate && self.render_volume {
-                    let pos_sensor = scene.camera.position();
-                    let d = (pos_sensor - pos).normalize();
-                    if accel.visible(pos, &pos_sensor) {
-                        // Splat the contribution
-                        if let Some((importance, uv)) = scene.camera.sample_direct(pos) {
+                    // Splat the contribution
+                    if let Some((importance, uv, pos_sensor)) =
+                        scene.camera.sample_direct(pos, sampler.next2d())
+                    {
+                        let d = (pos_sensor - pos).normalize();
+                        if accel.visible_at(pos, &pos_sensor, path.time) {
                             let m = scene.volume.as_ref().unwrap();
 
                             // Compute BSDF for the splatting
@@ -74,8 +76,9 @@ impl TechniqueLightTracing {
                             };
 
                             // Accumulate the results
-                            bitmap.accumulate_safe(
-                                Point2::new(uv.x as i32, uv.y as i32),
+                            bitmap.accumulate_filtered(
+                                &scene.camera.filter,
+                                uv,
                                 flux * importance * bsdf_value * transmittance,
                                 &"primal".to_owned(),
                             );
@@ -84,16 +87,16 @@ impl TechniqueLightTracing {
                 }
             }
             Vertex::Surface { its, .. } => {
-                if accumulate && self.render_surface {
-                    // Chech the visibility from the point to the sensor
-                    // we also exclude smooth BSDF as there is 0 chance to get
-                    // a succesful connection
-                    let pos_sensor = scene.camera.position();
-                    let d = (pos_sensor - its.p).normalize();
-                    if !its.mesh.bsdf.bsdf_type().is_smooth() && accel.visible(&its.p, &pos_sensor)
+                // Chech the visibility from the point to the sensor
+                // we also exclude smooth BSDF as there is 0 chance to get
+                // a succesful connection
+                if accumulate && self.render_surface && !its.mesh.bsdf.bsdf_type().is_smooth() {
+                    // Splat the contribution
+                    if let Some((importance, uv, pos_sensor)) =
+                        scene.camera.sample_direct(&its.p, sampler.next2d())
                     {
-                        // Splat the contribution
-                        if let Some((importance, uv)) = scene.camera.sample_direct(&its.p) {
+                        let d = (pos_sensor - its.p).normalize();
+                        if accel.visible_its(&its, &pos_sensor) {
                             // Compute BSDF for the splatting
                             let wo_local = its.frame.to_local(d);
                             let wi_global = its.frame.to_world(its.wi);
@@ -117,8 +120,9 @@ impl TechniqueLightTracing {
                             };
 
                             // Accumulate the results
-                            bitmap.accumulate_safe(
-                                Point2::new(uv.x as i32, uv.y as i32),
+                            bitmap.accumulate_filtered(
+                                &scene.camera.filter,
+                                uv,
                                 flux * importance * bsdf_value * correction * transmittance,
                                 &"primal".to_owned(),
                             );
@@ -140,10 +144,11 @@ impl TechniqueLightTracing {
                     let edge = path.edge(edge_out);
                     match edge.pdf_direction {
                         PDF::SolidAngle(_) => {
-                            let pos_sensor = scene.camera.position();
-                            let d = (pos_sensor - pos).normalize();
-                            if accel.visible(pos, &pos_sensor) {
-                                if let Some((importance, uv)) = scene.camera.sample_direct(pos) {
+                            if let Some((importance, uv, pos_sensor)) =
+                                scene.camera.sample_direct(pos, sampler.next2d())
+                            {
+                                let d = (pos_sensor - pos).normalize();
+                                if accel.visible_at(pos, &pos_sensor, path.time) {
                                     let transmittance = if let Some(ref m) = scene.volume {
                                         let mut ray = Ray::new(*pos, d); // TODO: Add offset
                                         ray.tfar = (pos - pos_sensor).magnitude();
@@ -152,8 +157,9 @@ impl TechniqueLightTracing {
                                         Color::one()
                                     };
 
-                                    bitmap.accumulate_safe(
-                                        Point2::new(uv.x as i32, uv.y as i32),
+                                    bitmap.accumulate_filtered(
+                                        &scene.camera.filter,
+                                        uv,
                                         transmittance
                                             * flux
                                             * importance
@@ -182,6 +188,7 @@ impl TechniqueLightTracing {
                             path,
                             accel,
                             scene,
+                            sampler,
                             vertex_next,
                             bitmap,
                             flux * edge.weight * edge.rr_weight,
@@ -198,6 +205,7 @@ impl TechniqueLightTracing {
                             path,
                             accel,
                             scene,
+                            sampler,
                             next_vertex,
                             bitmap,
                             edge.weight * flux * edge.rr_weight,
@@ -270,7 +278,16 @@ impl Integrator for IntegratorLightTracing {
                     let root = path.from_light(scene, s.as_mut());
                     generate(&mut path, root.0, accel, scene, s.as_mut(), &mut technique);
                     // Evaluate the path generated using camera splatting operation
-                    technique.evaluate(0, &path, accel, scene, root.0, &mut my_img, root.1);
+                    technique.evaluate(
+                        0,
+                        &path,
+                        accel,
+                        scene,
+                        s.as_mut(),
+                        root.0,
+                        &mut my_img,
+                        root.1,
+                    );
                 });
 
                 // Scale and add the results
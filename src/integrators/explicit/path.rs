@@ -197,7 +197,7 @@ impl Integrator for IntegratorPathTracing {
 impl IntegratorMC for IntegratorPathTracing {
     fn compute_pixel(
         &self,
-        (ix, iy): (u32, u32),
+        pix: Point2<f32>,
         accel: &dyn Acceleration,
         scene: &Scene,
         sampler: &mut dyn Sampler,
@@ -230,7 +230,7 @@ impl IntegratorMC for IntegratorPathTracing {
         // Call the generator on this technique
         // the generator give back the root nodes
         let mut path = Path::default();
-        let root = path.from_sensor(Point2::new(ix, iy), scene, sampler);
+        let root = path.from_sensor_at(pix, scene, sampler);
         generate(&mut path, root.0, accel, scene, sampler, &mut technique);
         // Evaluate the sampling graph
         technique.evaluate(0, self.min_depth, &path, scene, root.0, &self.strategy)
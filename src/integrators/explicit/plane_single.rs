@@ -2,9 +2,12 @@ use std::f32::consts::PI;
 use std::process::exit;
 
 use crate::accel::*;
-use crate::geometry::Mesh;
+use crate::geometry::{EmissionType, Mesh};
 use crate::integrators::*;
 use crate::math::*;
+use crate::paths::path::*;
+use crate::paths::strategies::*;
+use crate::paths::vertex::*;
 use crate::samplers;
 use crate::structure::AABB;
 use crate::volume::*;
@@ -28,6 +31,17 @@ pub enum PlaneType {
     VT,
     UT,
     UAlphaT,
+    /// Spawned along a light path (multiple scattering)
+    Path,
+    /// (u, alpha)-plane from any planar emitter (chord of the emitter)
+    Emitter {
+        n: Vector3<f32>,
+        primitive_id: Option<usize>,
+    },
+    /// Fan of directions around a point emitter (rotated around the sampled direction)
+    Point {
+        p: Point3<f32>,
+    },
 }
 
 // Helper on the light source
@@ -173,9 +187,18 @@ impl SinglePhotonPlane {
     ) -> Point3<f32> {
         match self.plane_type {
             PlaneType::UV => light.o + light.u * plane_its.t0 + light.v * plane_its.t1,
-            PlaneType::VT | PlaneType::UT | PlaneType::UAlphaT => self.o + self.d0 * plane_its.t0,
+            PlaneType::VT
+            | PlaneType::UT
+            | PlaneType::UAlphaT
+            | PlaneType::Path
+            | PlaneType::Emitter { .. } => self.edge_position(plane_its),
+            PlaneType::Point { p } => p,
         }
     }
+    /// Position on the first edge (the light vertex of all the planes except the (u, v)-planes)
+    pub fn edge_position(&self, plane_its: &PhotonPlaneIts) -> Point3<f32> {
+        self.o + self.d0 * plane_its.t0
+    }
     pub fn contrib(&self, d: &Vector3<f32>) -> Color {
         let jacobian = self.d1.cross(self.d0).dot(*d).abs();
         self.weight / jacobian
@@ -293,6 +316,181 @@ impl SinglePhotonPlane {
                     sample_alpha,
                 }
             }
+            PlaneType::Path | PlaneType::Emitter { .. } | PlaneType::Point { .. } => {
+                panic!("{:?} planes are not generated from a rectangular light", t)
+            }
+        }
+    }
+
+    /// Plane generated from an emitter picked by the emitter sampler:
+    /// a chord of a planar emitter or a fan around a point emitter.
+    /// None if the emitter cannot generate planes (or the sample is invalid)
+    pub fn from_emitter(
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        m: &HomogenousVolume,
+    ) -> Option<SinglePhotonPlane> {
+        let emitter_sampler = scene.emitters();
+        let id_emitter = emitter_sampler.emitters_cdf.sample_discrete(sampler.next());
+        let emitter = &emitter_sampler.emitters[id_emitter];
+        let pdf_emitter = emitter_sampler.emitters_cdf.pdf(id_emitter);
+        if emitter.is_point() {
+            let (pos, _) = emitter.sample_position(sampler.next(), sampler.next2d());
+            let (d, pdf, _) = emitter.sample_direction(&pos, sampler.next2d());
+            if pdf.is_zero() {
+                return None;
+            }
+            // The plane contains the sampled direction and is uniformly rotated around it.
+            // The rotations around any axis sweep the whole space once, so the axis
+            // distribution does not appear in the weight: sampling it with the emitter
+            // only makes the planes go through the emitting directions.
+            let sample_alpha = sampler.next();
+            let alpha = std::f32::consts::PI * sample_alpha;
+            let d1 = Frame::new(d).to_world(Vector3::new(alpha.cos(), alpha.sin(), 0.0));
+            // Radius of the fan (transmittance sampling)
+            let mrec = m.sample(&Ray::new(pos.p, d), sampler.next());
+            let radius = mrec.continued_t;
+            return Some(SinglePhotonPlane {
+                o: pos.p - (d + d1) * radius,
+                d0: d,
+                d1,
+                length0: 2.0 * radius,
+                length1: 2.0 * radius,
+                sample: Point2::new(0.0, 0.0),
+                plane_type: PlaneType::Point { p: pos.p },
+                // f() / p(alpha), the intensity is evaluated at the gathering
+                weight: Color::value(std::f32::consts::PI / pdf_emitter),
+                id_emitter,
+                sample_alpha,
+            });
+        }
+        if !emitter.is_planar() {
+            return None;
+        }
+        let (pos, w_pos) = emitter.sample_position(sampler.next(), sampler.next2d());
+        let (d, _, w_dir) = emitter.sample_direction(&pos, sampler.next2d());
+        let sample_alpha = sampler.next();
+        let chord = emitter.chord(&pos.p, pos.primitive_id, sample_alpha)?;
+        let weight = w_pos * w_dir;
+        if chord.length == 0.0 || weight.is_zero() {
+            return None;
+        }
+
+        // FIXME: As for the rectangular lights, the surfaces are ignored
+        let mrec = m.sample(&Ray::new(pos.p, d), sampler.next());
+        Some(SinglePhotonPlane {
+            o: chord.o,
+            d0: chord.d,
+            d1: d,
+            length0: chord.length,
+            length1: mrec.continued_t,
+            sample: Point2::new(0.0, 0.0),
+            plane_type: PlaneType::Emitter {
+                n: chord.n,
+                primitive_id: pos.primitive_id,
+            },
+            // f() / (p(w_l) * p(e)) where p(e) = |e| * p(x)
+            // Note that the position weight is f() / p(x) without the emitter selection
+            weight: weight / (chord.length * pdf_emitter),
+            id_emitter,
+            sample_alpha,
+        })
+    }
+}
+
+/// Light path tracing for the planes spawned by multiple scattering
+pub struct TechniquePlanePath {
+    pub max_depth: Option<u32>,
+    pub samplings: Vec<Box<dyn SamplingStrategy>>,
+}
+
+impl Technique for TechniquePlanePath {
+    fn expand(&self, _vertex: &Vertex, depth: u32) -> bool {
+        self.max_depth.map_or(true, |max| depth < max)
+    }
+
+    fn strategies(&self, _vertex: &Vertex) -> &Vec<Box<dyn SamplingStrategy>> {
+        &self.samplings
+    }
+}
+
+impl TechniquePlanePath {
+    /// None if only single scattering is requested
+    /// (max_depth counts the scattering events, including the camera one)
+    pub fn new(max_depth: Option<u32>, rr_depth: Option<u32>) -> Option<Self> {
+        if max_depth.map_or(false, |max| max <= 1) {
+            return None;
+        }
+        let samplings: Vec<Box<dyn SamplingStrategy>> = vec![Box::new(
+            crate::paths::strategies::directional::DirectionalSamplingStrategy {
+                transport: Transport::Importance,
+                rr_depth,
+            },
+        )];
+        Some(TechniquePlanePath {
+            // One extra vertex is needed to get the second edge of the planes
+            max_depth: max_depth.map(|max| max + 1),
+            samplings,
+        })
+    }
+
+    /// Shoot a light path and push its planes
+    pub fn generate_planes<'scene>(
+        &mut self,
+        path: &mut Path<'scene>,
+        accel: &'scene dyn Acceleration,
+        scene: &'scene Scene,
+        sampler: &mut dyn Sampler,
+        planes: &mut Vec<SinglePhotonPlane>,
+    ) {
+        path.clear();
+        let root = path.from_light(scene, sampler);
+        generate(path, root.0, accel, scene, sampler, self);
+        self.convert_planes(path, root.0, planes, root.1);
+    }
+
+    // Each vertex (light, surface or volume) followed by a volume vertex
+    // spawns a plane: the first edge is the segment toward the volume vertex
+    // and the second is the direction sampled at this volume vertex.
+    // The camera ray scattering is counted in the gathering.
+    fn convert_planes(
+        &self,
+        path: &Path,
+        vertex_id: VertexID,
+        planes: &mut Vec<SinglePhotonPlane>,
+        flux: Color,
+    ) {
+        let o = path.vertex(vertex_id).position();
+        for (edge_id, next_vertex_id) in path.next_vertices(vertex_id) {
+            let edge = path.edge(edge_id);
+            if !path.vertex(next_vertex_id).on_surface() {
+                for (next_edge_id, _) in path.next_vertices(next_vertex_id) {
+                    let next_edge = path.edge(next_edge_id);
+                    planes.push(SinglePhotonPlane {
+                        o,
+                        d0: edge.d,
+                        d1: next_edge.d,
+                        // The volume vertex is before any surface
+                        length0: edge.dist.unwrap(),
+                        // Short plane (visibility is checked during the gathering)
+                        length1: next_edge.sampled_distance.as_ref().unwrap().continued_t,
+                        sample: Point2::new(0.0, 0.0),
+                        plane_type: PlaneType::Path,
+                        // The edge weight contains sigma_s and the transmittance sampling.
+                        // Note that the phase function is supposed isotropic (weight of 1)
+                        weight: flux * edge.weight * edge.rr_weight * next_edge.rr_weight,
+                        // Not used: the flux already contains the emitter selection
+                        id_emitter: 0,
+                        sample_alpha: 0.0,
+                    });
+                }
+            }
+            self.convert_planes(
+                path,
+                next_vertex_id,
+                planes,
+                flux * edge.weight * edge.rr_weight,
+            );
         }
     }
 }
@@ -315,6 +513,160 @@ pub struct IntegratorSinglePlane {
     pub nb_primitive: usize,
     pub strategy: SinglePlaneStrategy,
     pub stratified: bool,
+    pub max_depth: Option<u32>,
+    pub rr_depth: Option<u32>,
+}
+
+impl SinglePlaneStrategy {
+    /// The strategies relying on the light edges (and the proxy tables)
+    /// need rectangular emitters. The others also work with the planes
+    /// generated by SinglePhotonPlane::from_emitter.
+    pub fn check_emitters(&self, scene: &Scene) -> Result<(), String> {
+        match self {
+            SinglePlaneStrategy::UAlpha
+            | SinglePlaneStrategy::ContinousMIS
+            | SinglePlaneStrategy::SMISAll(_)
+            | SinglePlaneStrategy::SMISJacobian(_) => Ok(()),
+            _ if rectangular_emitters(scene) => Ok(()),
+            _ => Err(
+                "only ualpha, cmis, smis_all and smis_jacobian support non rectangular emitters"
+                    .to_string(),
+            ),
+        }
+    }
+}
+
+/// All the emitters (at least one) are quads with a constant emission
+pub fn rectangular_emitters(scene: &Scene) -> bool {
+    let emitters = scene
+        .meshes
+        .iter()
+        .filter(|m| m.is_light())
+        .collect::<Vec<_>>();
+    !emitters.is_empty()
+        && scene.emitters().emitters.len() == emitters.len()
+        && emitters.iter().all(|e| {
+            e.vertices.len() == 4
+                && e.indices.len() == 2
+                && matches!(e.emission, EmissionType::Color { .. })
+        })
+}
+
+/// The emitter can generate planes with SinglePhotonPlane::from_emitter
+pub fn has_planes(emitter: &dyn crate::emitter::Emitter) -> bool {
+    emitter.is_planar() || emitter.is_point()
+}
+
+/// Contribution of a plane generated by SinglePhotonPlane::from_emitter.
+/// For the chords, the CMIS and SMIS weights are the ones of the rectangular
+/// lights expressed inside the emitter plane (only the chord lengths differ).
+/// The fans around the point emitters have no chord: all the strategies
+/// use the (u, alpha) estimator for them.
+pub fn contrib_emitter(
+    strategy: &SinglePlaneStrategy,
+    stratified: bool,
+    scene: &Scene,
+    plane: &SinglePhotonPlane,
+    ray: &Ray,
+    p_hit: &Point3<f32>,
+    p_light: &Point3<f32>,
+    sampler: &mut dyn Sampler,
+    nb_samples: &mut usize,
+) -> Color {
+    let (n, primitive_id) = match plane.plane_type {
+        PlaneType::Emitter { n, primitive_id } => (n, primitive_id),
+        PlaneType::Point { p } => {
+            // Outside of the fan (transmittance)
+            let d = p_hit - p;
+            let dist = d.magnitude();
+            if dist == 0.0 || dist > plane.length0 * 0.5 {
+                return Color::zero();
+            }
+            // |dt / dalpha| = |d1 . (x - p)| / J, and 1 / dist^2 for the intensity
+            let emitter = &scene.emitters().emitters[plane.id_emitter];
+            return plane.contrib(&ray.d)
+                * emitter.eval(d / dist, None)
+                * (plane.d1.dot(d).abs() / (dist * dist));
+        }
+        _ => panic!("{:?} is not an emitter plane", plane.plane_type),
+    };
+    let jacobian = |d0: Vector3<f32>| d0.cross(plane.d1).dot(ray.d).abs();
+    match strategy {
+        SinglePlaneStrategy::UAlpha => plane.contrib(&ray.d),
+        SinglePlaneStrategy::ContinousMIS => {
+            // The integral of the jacobian over the orientations only depends
+            // on the projection of (d1 x w) on the emitter plane
+            *nb_samples += 1;
+            let c = plane.d1.cross(ray.d);
+            let c = c - n * c.dot(n);
+            plane.weight / ((2.0 / std::f32::consts::PI) * c.magnitude())
+        }
+        SinglePlaneStrategy::SMISAll(n_samples) | SinglePlaneStrategy::SMISJacobian(n_samples) => {
+            let n_samples = *n_samples;
+            assert!(n_samples > 0);
+            let smis_weight = |d0: Vector3<f32>, length0: f32| match strategy {
+                SinglePlaneStrategy::SMISAll(_) => jacobian(d0) * length0,
+                _ => jacobian(d0),
+            };
+
+            // The other chords need to cross the same point on the emitter
+            let emitter = &scene.emitters().emitters[plane.id_emitter];
+            let mut inv_norm = smis_weight(plane.d0, plane.length0);
+            for i in 0..(n_samples - 1) {
+                let new_alpha = if stratified {
+                    (plane.sample_alpha + ((i + 1) as f32 / n_samples as f32)) % 1.0
+                } else {
+                    sampler.next()
+                };
+                // Can fail due to floating point precision (point on the border)
+                if let Some(chord) = emitter.chord(p_light, primitive_id, new_alpha) {
+                    inv_norm += smis_weight(chord.d, chord.length);
+                }
+            }
+            *nb_samples += n_samples - 1;
+
+            let contrib = match strategy {
+                SinglePlaneStrategy::SMISAll(_) => plane.weight * plane.length0,
+                _ => plane.weight,
+            };
+            contrib * n_samples as f32 / inv_norm
+        }
+        // Rejected by check_emitters
+        _ => Color::zero(),
+    }
+}
+
+/// Single scattering from the emitters that cannot generate planes
+pub fn nee_without_planes(
+    accel: &dyn Acceleration,
+    scene: &Scene,
+    ray: &Ray,
+    phase_function: &PhaseFunction,
+    sampler: &mut dyn Sampler,
+) -> Color {
+    let m = scene.volume.as_ref().unwrap();
+    let mrec = m.sample(ray, sampler.next());
+    if mrec.exited {
+        return Color::zero();
+    }
+    let p = ray.o + ray.d * mrec.t;
+    let light_record =
+        scene
+            .emitters()
+            .sample_light(&p, None, sampler.next(), sampler.next(), sampler.next2d());
+    // The other emitters are already estimated by the planes
+    if !light_record.is_valid()
+        || has_planes(light_record.emitter)
+        || !accel.visible(&p, &light_record.p)
+    {
+        return Color::zero();
+    }
+    let transmittance = {
+        let mut ray_tr = Ray::new(p, light_record.d);
+        ray_tr.tfar = (light_record.p - p).dot(light_record.d);
+        m.transmittance(ray_tr)
+    };
+    mrec.w * phase_function.eval(&(-ray.d), &light_record.d) * transmittance * light_record.weight
 }
 
 impl Integrator for IntegratorSinglePlane {
@@ -333,7 +685,32 @@ impl Integrator for IntegratorSinglePlane {
             .iter()
             .filter(|m| m.is_light())
             .collect::<Vec<_>>();
-        let rect_lights = {
+
+        let buffernames = vec![String::from("primal")];
+        if let Err(e) = self.strategy.check_emitters(scene) {
+            error!("{}", e);
+            return BufferCollection::new(Point2::new(0, 0), *scene.camera.size(), &buffernames);
+        }
+        // Otherwise, the planes are built from the chords of any planar emitter
+        // or around the point emitters (sampled with the emitter sampler)
+        let rectangular = rectangular_emitters(scene);
+        if !rectangular {
+            info!("Non rectangular emitters, generate the planes from the emitter sampler");
+        }
+        // Single scattering of the emitters without planes (e.g. environment maps)
+        // is estimated with next event estimation from the camera rays
+        let nee = scene
+            .emitters()
+            .emitters
+            .iter()
+            .any(|e| !has_planes(e.as_ref()));
+        if nee {
+            warn!("Some emitters cannot generate planes, use next event estimation for them");
+        }
+
+        let rect_lights = if !rectangular {
+            vec![]
+        } else {
             emitters
                 .iter()
                 .map(|emitter| {
@@ -385,12 +762,32 @@ impl Integrator for IntegratorSinglePlane {
         };
 
         // Create the planes
+        // Multiple scattering planes are spawned from one light path per iteration
         let m = scene.volume.as_ref().unwrap();
+        let mut technique = TechniquePlanePath::new(self.max_depth, self.rr_depth);
+        if technique.is_some() && self.strategy != SinglePlaneStrategy::UAlpha {
+            // TODO: The path planes are spawned from a segment (and not an area),
+            //  so they do not have the chord orientation the strategies work on.
+            warn!("The multiple scattering planes ignore the strategy, only the single scattering ones use it");
+        }
+        let mut path = Path::default();
         let mut planes = vec![];
         let mut number_plane_gen = 0;
-        while planes.len() < self.nb_primitive {
+        // Avoid looping forever if no plane can be generated
+        let single_planes = rectangular
+            || scene
+                .emitters()
+                .emitters
+                .iter()
+                .any(|e| has_planes(e.as_ref()));
+        while (single_planes || technique.is_some()) && planes.len() < self.nb_primitive {
             let id_emitter = (sampler.next() * rect_lights.len() as f32) as usize;
             match self.strategy {
+                _ if !rectangular => {
+                    if let Some(plane) = SinglePhotonPlane::from_emitter(scene, sampler, m) {
+                        planes.push(plane);
+                    }
+                }
                 SinglePlaneStrategy::UT => planes.push(generate_plane(
                     PlaneType::UT,
                     &rect_lights,
@@ -450,9 +847,17 @@ impl Integrator for IntegratorSinglePlane {
                     ));
                 }
             }
+            if let Some(technique) = technique.as_mut() {
+                technique.generate_planes(&mut path, accel, scene, sampler, &mut planes);
+            }
 
             number_plane_gen += 1;
         }
+        info!(
+            "Number of planes: {} ({} light paths)",
+            planes.len(),
+            number_plane_gen
+        );
 
         let s_size = 20;
         let e_size = 100;
@@ -522,7 +927,6 @@ impl Integrator for IntegratorSinglePlane {
         let bvh_plane = BHVAccel::create(planes);
 
         // Generate the image block to get VPL efficiently
-        let buffernames = vec![String::from("primal")];
         let mut image_blocks = generate_img_blocks(scene, sampler, &buffernames);
         let sample_num = Mutex::new(0.0);
         // Gathering all planes
@@ -550,7 +954,11 @@ impl Integrator for IntegratorSinglePlane {
                                     ix_c as f32 + sampler_ray.next(),
                                     iy_c as f32 + sampler_ray.next(),
                                 );
-                                let mut ray = scene.camera.generate(pix);
+                                let mut ray = scene.camera.generate_at(
+                                    pix,
+                                    sampler_ray.next2d(),
+                                    scene.camera.sample_time(sampler_ray.next_time()),
+                                );
 
                                 // Get the max distance
                                 let max_dist = match accel.trace(&ray) {
@@ -572,9 +980,19 @@ impl Integrator for IntegratorSinglePlane {
                                     // let plane_its = plane_its.unwrap();
 
                                     let p_hit = ray.o + ray.d * plane_its.t_cam;
-                                    let p_light = plane
-                                        .light_position(&rect_lights[plane.id_emitter], &plane_its);
-                                    let rect_light = &rect_lights[plane.id_emitter];
+                                    let (p_light, rect_light) = match plane.plane_type {
+                                        PlaneType::Path | PlaneType::Emitter { .. } => {
+                                            (plane.edge_position(&plane_its), None)
+                                        }
+                                        PlaneType::Point { p } => (p, None),
+                                        _ => {
+                                            let rect_light = &rect_lights[plane.id_emitter];
+                                            (
+                                                plane.light_position(rect_light, &plane_its),
+                                                Some(rect_light),
+                                            )
+                                        }
+                                    };
                                     if accel.visible(&p_hit, &p_light) {
                                         let transmittance = {
                                             let mut ray_tr = Ray::new(ray.o, ray.d);
@@ -584,6 +1002,16 @@ impl Integrator for IntegratorSinglePlane {
                                         let rho = phase_function
                                             .eval(&(-ray.d), &(p_light - p_hit).normalize());
                                         let w: f32 = match self.strategy {
+                                            // Planes from the light paths are not reparameterized
+                                            // (the emitter chords are weighted with the contribution)
+                                            _ if plane.plane_type == PlaneType::Path => 1.0,
+                                            _ if matches!(
+                                                plane.plane_type,
+                                                PlaneType::Emitter { .. } | PlaneType::Point { .. }
+                                            ) =>
+                                            {
+                                                1.0
+                                            }
                                             SinglePlaneStrategy::UT
                                             | SinglePlaneStrategy::UV
                                             | SinglePlaneStrategy::VT
@@ -594,6 +1022,7 @@ impl Integrator for IntegratorSinglePlane {
                                             | SinglePlaneStrategy::ProxySample => 1.0,
                                             SinglePlaneStrategy::Average => 1.0 / 3.0,
                                             SinglePlaneStrategy::DiscreteMIS => {
+                                                let rect_light = rect_light.unwrap();
                                                 // Need to compute all possible shapes
                                                 let d = p_hit - p_light;
                                                 // TODO: Not used
@@ -665,8 +1094,29 @@ impl Integrator for IntegratorSinglePlane {
 
                                         // Compute the plane weighted constribution
                                         let contrib = match self.strategy {
+                                            _ if plane.plane_type == PlaneType::Path => {
+                                                plane.contrib(&ray.d)
+                                            }
+                                            _ if matches!(
+                                                plane.plane_type,
+                                                PlaneType::Emitter { .. } | PlaneType::Point { .. }
+                                            ) =>
+                                            {
+                                                contrib_emitter(
+                                                    &self.strategy,
+                                                    self.stratified,
+                                                    scene,
+                                                    plane,
+                                                    &ray,
+                                                    &p_hit,
+                                                    &p_light,
+                                                    &mut sampler_ecmis,
+                                                    &mut block_sampel_num,
+                                                )
+                                            }
                                             // Deng et al. CMIS
                                             SinglePlaneStrategy::ContinousMIS => {
+                                                let rect_light = rect_light.unwrap();
                                                 // Here we use their integration from
                                                 // Normally, all the jacobian simplifies
                                                 // So it is why we need to have a special estimator
@@ -688,6 +1138,7 @@ impl Integrator for IntegratorSinglePlane {
                                             // SMIS
                                             SinglePlaneStrategy::SMISAll(n_samples)
                                             | SinglePlaneStrategy::SMISJacobian(n_samples) => {
+                                                let rect_light = rect_light.unwrap();
                                                 assert!(n_samples > 0);
 
                                                 // Compute wrap random number for generating fake planes that generate same
@@ -801,6 +1252,7 @@ impl Integrator for IntegratorSinglePlane {
 
                                             // Proxy sample
                                             SinglePlaneStrategy::ProxySample => {
+                                                let rect_light = rect_light.unwrap();
                                                 let mut sample_wrap = {
                                                     let p_l = p_light - rect_light.o;
                                                     Point2::new(
@@ -978,16 +1430,35 @@ impl Integrator for IntegratorSinglePlane {
                                             _ => w * plane.contrib(&ray.d), // Do nothing and just evaluate the plane
                                         };
 
+                                        // The light paths flux already contains
+                                        // the emitter selection probability
+                                        let nb_emitters = match plane.plane_type {
+                                            PlaneType::Path
+                                            | PlaneType::Emitter { .. }
+                                            | PlaneType::Point { .. } => 1.0,
+                                            _ => emitters.len() as f32,
+                                        };
+
                                         // Compute the rest of the term
                                         // and accumulate them
                                         c += rho
                                             * transmittance
                                             * m.sigma_s
                                             * contrib
-                                            * (emitters.len() as f32)
+                                            * nb_emitters
                                             * (1.0 / number_plane_gen as f32);
                                     }
                                 }
+
+                                if nee {
+                                    c += nee_without_planes(
+                                        accel,
+                                        scene,
+                                        &ray,
+                                        &phase_function,
+                                        sampler_ray.as_mut(),
+                                    );
+                                }
                                 im_block.accumulate(
                                     Point2 { x: ix, y: iy },
                                     c,
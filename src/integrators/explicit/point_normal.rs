@@ -2582,19 +2582,22 @@ impl IntegratorPointNormal {
 }
 
 impl IntegratorMC for IntegratorPointNormal {
+    fn antialiasing(&self) -> bool {
+        self.use_aa
+    }
+
     fn compute_pixel(
         &self,
-        (ix, iy): (u32, u32),
+        pix: Point2<f32>,
         accel: &dyn Acceleration,
         scene: &Scene,
         sampler: &mut dyn Sampler,
     ) -> Color {
-        let pix = if self.use_aa {
-            Point2::new(ix as f32 + sampler.next(), iy as f32 + sampler.next())
-        } else {
-            Point2::new(ix as f32 + 0.5, iy as f32 + 0.5)
-        };
-        let ray = scene.camera.generate(pix);
+        let ray = scene.camera.generate_at(
+            pix,
+            sampler.next2d(),
+            scene.camera.sample_time(sampler.next_time()),
+        );
 
         // Get the max distance (to a surface)
         let max_dist = match accel.trace(&ray) {
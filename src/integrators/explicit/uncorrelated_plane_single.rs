@@ -2,6 +2,7 @@ use crate::accel::*;
 use crate::integrators::explicit::plane_single::*;
 use crate::integrators::*;
 use crate::math::*;
+use crate::paths::path::*;
 use crate::volume::*;
 use cgmath::{InnerSpace, Point2};
 
@@ -9,6 +10,8 @@ pub struct IntegratorSinglePlaneUncorrelated {
     pub nb_primitive: usize,
     pub strategy: SinglePlaneStrategy,
     pub stratified: bool,
+    pub max_depth: Option<u32>,
+    pub rr_depth: Option<u32>,
 }
 
 impl Integrator for IntegratorSinglePlaneUncorrelated {
@@ -21,6 +24,11 @@ impl Integrator for IntegratorSinglePlaneUncorrelated {
         if scene.volume.is_none() {
             panic!("Volume integrator need a volume (add -m )");
         }
+        let buffernames = vec![String::from("primal")];
+        if let Err(e) = self.strategy.check_emitters(scene) {
+            error!("{}", e);
+            return BufferCollection::new(Point2::new(0, 0), *scene.camera.size(), &buffernames);
+        }
         // Extract the light source
         let emitters = scene
             .meshes
@@ -28,7 +36,23 @@ impl Integrator for IntegratorSinglePlaneUncorrelated {
             .filter(|m| m.is_light())
             .collect::<Vec<_>>();
 
-        let rect_lights = {
+        // Same as plane-single, otherwise the planes are generated by the emitter sampler
+        let rectangular = rectangular_emitters(scene);
+        if !rectangular {
+            info!("Non rectangular emitters, generate the planes from the emitter sampler");
+        }
+        let nee = scene
+            .emitters()
+            .emitters
+            .iter()
+            .any(|e| !has_planes(e.as_ref()));
+        if nee {
+            warn!("Some emitters cannot generate planes, use next event estimation for them");
+        }
+
+        let rect_lights = if !rectangular {
+            vec![]
+        } else {
             emitters
                 .iter()
                 .map(|emitter| {
@@ -79,8 +103,13 @@ impl Integrator for IntegratorSinglePlaneUncorrelated {
             )
         };
 
+        let multiple_scattering = self.max_depth.map_or(true, |max| max > 1);
+        if multiple_scattering && self.strategy != SinglePlaneStrategy::UAlpha {
+            // TODO: Same as plane-single, the path planes have no chord orientation
+            warn!("The multiple scattering planes ignore the strategy, only the single scattering ones use it");
+        }
+
         // Generate the image block to get VPL efficiently
-        let buffernames = vec![String::from("primal")];
         let mut image_blocks = generate_img_blocks(scene, sampler, &buffernames);
         let m = scene.volume.as_ref().unwrap();
 
@@ -93,6 +122,10 @@ impl Integrator for IntegratorSinglePlaneUncorrelated {
             image_blocks
                 .par_iter_mut()
                 .for_each(|(im_block, sampler_ray)| {
+                    // Multiple scattering planes (one light path per plane)
+                    let mut technique = TechniquePlanePath::new(self.max_depth, self.rr_depth);
+                    let mut path = Path::default();
+                    let mut path_planes = vec![];
                     for ix in 0..im_block.size.x {
                         for iy in 0..im_block.size.y {
                             for _ in 0..scene.nb_samples {
@@ -101,7 +134,11 @@ impl Integrator for IntegratorSinglePlaneUncorrelated {
                                     ix_c as f32 + sampler_ray.next(),
                                     iy_c as f32 + sampler_ray.next(),
                                 );
-                                let mut ray = scene.camera.generate(pix);
+                                let mut ray = scene.camera.generate_at(
+                                    pix,
+                                    sampler_ray.next2d(),
+                                    scene.camera.sample_time(sampler_ray.next_time()),
+                                );
 
                                 // Get the max distance
                                 let max_dist = match accel.trace(&ray) {
@@ -113,6 +150,86 @@ impl Integrator for IntegratorSinglePlaneUncorrelated {
                                 // Now gather all planes
                                 let mut c = Color::value(0.0);
                                 for _id_plane in 0..self.nb_primitive {
+                                    if let Some(technique) = technique.as_mut() {
+                                        path_planes.clear();
+                                        technique.generate_planes(
+                                            &mut path,
+                                            accel,
+                                            scene,
+                                            sampler_ray.as_mut(),
+                                            &mut path_planes,
+                                        );
+                                        for plane in &path_planes {
+                                            let plane_its = match plane.intersection(&ray) {
+                                                Some(v) => v,
+                                                None => continue,
+                                            };
+                                            let p_hit = ray.o + ray.d * plane_its.t_cam;
+                                            let p_light = plane.edge_position(&plane_its);
+                                            if accel.visible(&p_hit, &p_light) {
+                                                let transmittance = {
+                                                    let mut ray_tr = Ray::new(ray.o, ray.d);
+                                                    ray_tr.tfar = plane_its.t_cam;
+                                                    m.transmittance(ray_tr)
+                                                };
+                                                let rho = phase_function.eval(
+                                                    &(-ray.d),
+                                                    &(p_light - p_hit).normalize(),
+                                                );
+                                                c += rho
+                                                    * transmittance
+                                                    * m.sigma_s
+                                                    * plane.contrib(&ray.d)
+                                                    * (1.0 / self.nb_primitive as f32);
+                                            }
+                                        }
+                                    }
+
+                                    if !rectangular {
+                                        let plane = match SinglePhotonPlane::from_emitter(
+                                            scene,
+                                            sampler_ray.as_mut(),
+                                            m,
+                                        ) {
+                                            Some(v) => v,
+                                            None => continue,
+                                        };
+                                        let plane_its = match plane.intersection(&ray) {
+                                            Some(v) => v,
+                                            None => continue,
+                                        };
+                                        let p_hit = ray.o + ray.d * plane_its.t_cam;
+                                        let p_light = match plane.plane_type {
+                                            PlaneType::Point { p } => p,
+                                            _ => plane.edge_position(&plane_its),
+                                        };
+                                        if accel.visible(&p_hit, &p_light) {
+                                            let transmittance = {
+                                                let mut ray_tr = Ray::new(ray.o, ray.d);
+                                                ray_tr.tfar = plane_its.t_cam;
+                                                m.transmittance(ray_tr)
+                                            };
+                                            let rho = phase_function
+                                                .eval(&(-ray.d), &(p_light - p_hit).normalize());
+                                            // The emitter selection is inside the plane weight
+                                            c += rho
+                                                * transmittance
+                                                * m.sigma_s
+                                                * contrib_emitter(
+                                                    &self.strategy,
+                                                    self.stratified,
+                                                    scene,
+                                                    &plane,
+                                                    &ray,
+                                                    &p_hit,
+                                                    &p_light,
+                                                    sampler_ray.as_mut(),
+                                                    &mut 0,
+                                                )
+                                                * (1.0 / self.nb_primitive as f32);
+                                        }
+                                        continue;
+                                    }
                                     let id_emitter =
                                         (sampler_ray.next() * rect_lights.len() as f32) as usize;
                                     let plane = match self.strategy {
@@ -299,6 +416,15 @@ impl Integrator for IntegratorSinglePlaneUncorrelated {
                                     }
                                 }
 
+                                if nee {
+                                    c += nee_without_planes(
+                                        accel,
+                                        scene,
+                                        &ray,
+                                        &phase_function,
+                                        sampler_ray.as_mut(),
+                                    );
+                                }
                                 im_block.accumulate(
                                     Point2 { x: ix, y: iy },
                                     c,
@@ -19,6 +19,36 @@ pub struct IntegratorVolPrimitives {
     pub max_depth: Option<u32>,
     pub rr_depth: Option<u32>,
     pub primitives: VolPrimitivies,
+    /// Kernel radius of the photons and beams (updated each pass if progressive)
+    pub radius: f32,
+    pub progressive: Option<ProgressiveRadius>,
+}
+
+/// Radius reduction between the passes (with IntegratorAverage)
+/// Knaus and Zwicker 2011 (photon points, 2D kernel)
+/// and Jarosz et al. 2011 (photon beams, 1D kernel)
+pub struct ProgressiveRadius {
+    /// Fraction of the photons kept each pass ]0, 1[
+    pub alpha: f32,
+    /// Number of passes already rendered
+    pub pass: usize,
+}
+
+impl ProgressiveRadius {
+    pub fn new(alpha: f32) -> Result<ProgressiveRadius, String> {
+        if alpha > 0.0 && alpha < 1.0 {
+            Ok(ProgressiveRadius { alpha, pass: 0 })
+        } else {
+            Err(format!("alpha needs to be inside ]0, 1[: {}", alpha))
+        }
+    }
+
+    /// Radius of the next pass depending on the kernel dimension
+    fn next_radius(&mut self, radius: f32, dim: i32) -> f32 {
+        let i = self.pass as f32 + 1.0;
+        self.pass += 1;
+        radius * ((i + self.alpha) / (i + 1.0)).powf(1.0 / dim as f32)
+    }
 }
 
 pub struct TechniqueVolPrimitives {
@@ -583,6 +613,10 @@ impl Integrator for IntegratorVolPrimitives {
             VolPrimitivies::Planes => info!("Render with Photon planes"),
             VolPrimitivies::VRL => info!("Render with VRL"),
         }
+        match &self.progressive {
+            None => info!("Kernel radius: {}", self.radius),
+            Some(p) => info!("Kernel radius: {} (pass {})", self.radius, p.pass + 1),
+        }
 
         info!("Generating the light paths...");
         let buffernames = vec![String::from("primal")];
@@ -611,16 +645,39 @@ impl Integrator for IntegratorVolPrimitives {
             generate(&mut path, root.0, accel, scene, sampler, &mut technique);
             match self.primitives {
                 VolPrimitivies::Beams | VolPrimitivies::VRL => {
-                    technique.convert_beams(false, &path, scene, root.0, &mut beams, 0.001, root.1);
+                    technique.convert_beams(
+                        false,
+                        &path,
+                        scene,
+                        root.0,
+                        &mut beams,
+                        self.radius,
+                        root.1,
+                    );
                     still_shoot = beams.len() < self.nb_primitive as usize;
                 }
                 VolPrimitivies::BRE => {
-                    technique.convert_photons(&path, scene, root.0, &mut photons, 0.001, root.1);
+                    technique.convert_photons(
+                        &path,
+                        scene,
+                        root.0,
+                        &mut photons,
+                        self.radius,
+                        root.1,
+                    );
                     still_shoot = photons.len() < self.nb_primitive as usize;
                 }
                 VolPrimitivies::Planes => {
                     // Generate beams from surfaces
-                    technique.convert_beams(true, &path, scene, root.0, &mut beams, 0.001, root.1);
+                    technique.convert_beams(
+                        true,
+                        &path,
+                        scene,
+                        root.0,
+                        &mut beams,
+                        self.radius,
+                        root.1,
+                    );
                     // Generate planes
                     technique.convert_planes(&path, scene, root.0, &mut planes, root.1);
                     still_shoot = planes.len() < self.nb_primitive as usize;
@@ -717,7 +774,11 @@ impl Integrator for IntegratorVolPrimitives {
                                 ix_c as f32 + sampler.next(),
                                 iy_c as f32 + sampler.next(),
                             );
-                            let mut ray = scene.camera.generate(pix);
+                            let mut ray = scene.camera.generate_at(
+                                pix,
+                                sampler.next2d(),
+                                scene.camera.sample_time(sampler.next_time()),
+                            );
 
                             // Get the max distance
                             let max_dist = match accel.trace(&ray) {
@@ -800,6 +861,15 @@ impl Integrator for IntegratorVolPrimitives {
         for (im_block, _) in &image_blocks {
             image.accumulate_bitmap(im_block);
         }
+
+        // Shrink the kernel for the next pass
+        if let Some(p) = &mut self.progressive {
+            let dim = match self.primitives {
+                VolPrimitivies::BRE => 2,
+                _ => 1,
+            };
+            self.radius = p.next_radius(self.radius, dim);
+        }
         image
     }
 }
@@ -1,16 +1,26 @@
-use crate::emitter::Emitter;
+use crate::emitter::{Emitter, LightBounds, EPSILON_ATS};
 use crate::integrators::*;
+use crate::math::Frame;
+use crate::paths::edge::*;
 use crate::paths::path::*;
 use crate::paths::strategies::*;
 use crate::paths::vertex::*;
 use crate::volume::*;
 use cgmath::{EuclideanSpace, InnerSpace, Point2, Point3, Vector3};
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::ops::{Add, AddAssign, Mul, Sub};
 
 #[derive(PartialEq, Clone)]
 pub enum IntegratorVPLOption {
     Volume,
     Surface,
     All,
+    /// All the VPLs, with the surface VPLs gathered as
+    /// virtual spherical lights (Hasan et al. 2009) and the light
+    /// path segments inside the media as virtual ray lights
+    /// (Novak et al. 2012) instead of the volume VPLs
+    Spherical,
 }
 
 pub struct IntegratorVPL {
@@ -20,11 +30,15 @@ pub struct IntegratorVPL {
     pub clamping_factor: Option<f32>,
     pub option_vpl: IntegratorVPLOption,
     pub option_lt: IntegratorVPLOption,
+    /// Relative error bound of the lightcuts (None: gather all the VPLs)
+    pub lightcuts: Option<f32>,
 }
 
 struct VPLSurface<'a> {
     its: Intersection<'a>,
     radiance: Color,
+    /// Radius of the virtual spherical light (0: point VPL)
+    radius: f32,
 }
 struct VPLVolume {
     pos: Point3<f32>,
@@ -33,6 +47,16 @@ struct VPLVolume {
     radiance: Color,
 }
 
+/// Light path segment inside the participating media
+struct VPLRay {
+    o: Point3<f32>,
+    d: Vector3<f32>,
+    /// Distance sampled by the light path
+    length: f32,
+    /// Flux leaving the origin (without the distance sampling weight)
+    radiance: Color,
+}
+
 enum VPLEmitterInfo {
     Position { pos: Point3<f32>, n: Vector3<f32> },
     Infinite { d: Vector3<f32> },
@@ -45,9 +69,450 @@ struct VPLEmitter {
 enum VPL<'a> {
     Surface(VPLSurface<'a>),
     Volume(VPLVolume),
+    Ray(VPLRay),
     Emitter(VPLEmitter),
 }
 
+impl<'a> VPL<'a> {
+    /// Bounds of the VPL emission used by lightcuts
+    /// The intensity assumes diffuse surfaces and isotropic media.
+    /// VPLs at infinity are not clustered (None).
+    fn bounds(&self) -> Option<LightBounds> {
+        use std::f32::consts::{FRAC_1_PI, FRAC_PI_2, PI};
+        match self {
+            VPL::Surface(vpl) => {
+                let n = if vpl.its.wi.z < 0.0 {
+                    -vpl.its.n_s
+                } else {
+                    vpl.its.n_s
+                };
+                Some(LightBounds::from_point(
+                    vpl.its.p,
+                    n,
+                    0.0,
+                    FRAC_PI_2,
+                    vpl.radiance.luminance() * FRAC_1_PI,
+                ))
+            }
+            // Emission over the whole sphere (as the point lights of PBRT):
+            // a null spread would cancel the importance and the error bound
+            VPL::Volume(vpl) => Some(LightBounds::from_point(
+                vpl.pos,
+                Vector3::new(0.0, 0.0, 1.0),
+                PI,
+                FRAC_PI_2,
+                vpl.radiance.luminance() * 0.25 * FRAC_1_PI,
+            )),
+            VPL::Ray(vpl) => {
+                let mut bounds = LightBounds::from_point(
+                    vpl.o,
+                    Vector3::new(0.0, 0.0, 1.0),
+                    PI,
+                    FRAC_PI_2,
+                    vpl.radiance.luminance() * vpl.length * 0.25 * FRAC_1_PI,
+                );
+                let end = vpl.o + vpl.d * vpl.length;
+                bounds.aabb = bounds.aabb.union_vec(&end.to_vec());
+                bounds.bsphere = bounds.aabb.to_sphere();
+                Some(bounds)
+            }
+            VPL::Emitter(vpl) => match vpl.info {
+                VPLEmitterInfo::Position { pos, n } => Some(LightBounds::from_point(
+                    pos,
+                    n,
+                    0.0,
+                    FRAC_PI_2,
+                    vpl.emitted_radiance.luminance() * FRAC_1_PI,
+                )),
+                VPLEmitterInfo::Infinite { .. } => None,
+            },
+        }
+    }
+}
+
+/// Radiance gathered from the VPLs
+#[derive(Clone, Copy, Default)]
+struct VPLContrib {
+    radiance: Color,
+    /// Energy removed by the clamping
+    clamped: Color,
+    /// Estimate of the clamped energy (bias compensation)
+    compensation: Color,
+}
+impl From<Color> for VPLContrib {
+    fn from(radiance: Color) -> Self {
+        VPLContrib {
+            radiance,
+            ..Default::default()
+        }
+    }
+}
+impl AddAssign<VPLContrib> for VPLContrib {
+    fn add_assign(&mut self, other: VPLContrib) {
+        self.radiance += other.radiance;
+        self.clamped += other.clamped;
+        self.compensation += other.compensation;
+    }
+}
+impl Add<VPLContrib> for VPLContrib {
+    type Output = VPLContrib;
+    fn add(mut self, other: VPLContrib) -> VPLContrib {
+        self += other;
+        self
+    }
+}
+impl Sub<VPLContrib> for VPLContrib {
+    type Output = VPLContrib;
+    fn sub(self, other: VPLContrib) -> VPLContrib {
+        VPLContrib {
+            radiance: self.radiance - other.radiance,
+            clamped: self.clamped - other.clamped,
+            compensation: self.compensation - other.compensation,
+        }
+    }
+}
+impl Mul<f32> for VPLContrib {
+    type Output = VPLContrib;
+    fn mul(self, other: f32) -> VPLContrib {
+        VPLContrib {
+            radiance: self.radiance * other,
+            clamped: self.clamped * other,
+            compensation: self.compensation * other,
+        }
+    }
+}
+impl Mul<Color> for VPLContrib {
+    type Output = VPLContrib;
+    fn mul(self, other: Color) -> VPLContrib {
+        VPLContrib {
+            radiance: self.radiance * other,
+            clamped: self.clamped * other,
+            compensation: self.compensation * other,
+        }
+    }
+}
+
+/// Maximum recursion of the bias compensation
+const COMPENSATION_MAX_DEPTH: u32 = 16;
+
+/// Number of directions to integrate a virtual spherical light
+const VSL_SAMPLES: usize = 4;
+/// The radius of a virtual spherical light is the distance
+/// to its k-th nearest surface VPL
+const VSL_NEIGHBORS: usize = 10;
+/// Maximum number of grid rings visited to find the neighbors
+/// (isolated VPLs keep the farthest neighbor found)
+const VSL_MAX_RINGS: i32 = 4;
+
+/// Set the radius of the surface VPLs (k nearest neighbors
+/// found with a uniform grid over the VPL positions)
+fn set_vsl_radius(vpls: &mut [VPL]) {
+    let positions = vpls
+        .iter()
+        .filter_map(|vpl| match vpl {
+            VPL::Surface(vpl) => Some(vpl.its.p),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if positions.len() <= 1 {
+        warn!("Not enough surface VPLs to compute the radius of the virtual spherical lights");
+        return;
+    }
+    let k = VSL_NEIGHBORS.min(positions.len() - 1);
+
+    // VPLs are on surfaces: ~k VPLs per cell with a 2D density
+    let aabb = positions
+        .iter()
+        .fold(AABB::default(), |aabb, p| aabb.union_vec(&p.to_vec()));
+    let res = ((positions.len() as f32 / k as f32).sqrt().ceil() as i32)
+        .max(1)
+        .min(256);
+    let cell_size = (aabb.size().x.max(aabb.size().y).max(aabb.size().z) / res as f32).max(1e-5);
+    let cell = |p: &Point3<f32>| {
+        let c = (p.to_vec() - aabb.p_min) / cell_size;
+        (c.x as i32, c.y as i32, c.z as i32)
+    };
+    let mut grid: HashMap<(i32, i32, i32), Vec<usize>> = HashMap::new();
+    for (i, p) in positions.iter().enumerate() {
+        grid.entry(cell(p)).or_insert_with(Vec::new).push(i);
+    }
+
+    let radius = positions
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            let c = cell(p);
+            // Max heap of the k closest squared distances
+            // (positive floats are ordered like their bits)
+            let mut nearest = BinaryHeap::new();
+            for ring in 0..=(res + 1).min(VSL_MAX_RINGS) {
+                for x in (c.0 - ring)..=(c.0 + ring) {
+                    for y in (c.1 - ring)..=(c.1 + ring) {
+                        for z in (c.2 - ring)..=(c.2 + ring) {
+                            let on_ring = (x - c.0).abs() == ring
+                                || (y - c.1).abs() == ring
+                                || (z - c.2).abs() == ring;
+                            if !on_ring {
+                                continue;
+                            }
+                            for &j in grid.get(&(x, y, z)).into_iter().flatten() {
+                                if i == j {
+                                    continue;
+                                }
+                                let dist2 = (positions[j] - p).magnitude2();
+                                nearest.push(dist2.to_bits());
+                                if nearest.len() > k {
+                                    nearest.pop();
+                                }
+                            }
+                        }
+                    }
+                }
+                // The next rings are at least this distance away
+                let ring_dist = ring as f32 * cell_size;
+                if nearest.len() == k
+                    && f32::from_bits(*nearest.peek().unwrap()) <= ring_dist * ring_dist
+                {
+                    break;
+                }
+            }
+            nearest.peek().map_or(0.0, |d| f32::from_bits(*d).sqrt())
+        })
+        .collect::<Vec<_>>();
+
+    let mut radius = radius.into_iter();
+    for vpl in vpls.iter_mut() {
+        if let VPL::Surface(vpl) = vpl {
+            vpl.radius = radius.next().unwrap();
+        }
+    }
+}
+
+/// Maximum number of clusters inside a cut
+const LIGHTCUTS_MAX_CUT: usize = 1000;
+
+/// Cluster of VPLs inside the light tree
+struct LightcutNode {
+    bounds: LightBounds,
+    /// VPL used to estimate the contribution of the whole cluster
+    representative: usize,
+    representative_phi: f32,
+    children: Option<(usize, usize)>,
+}
+
+/// Cluster of the cut with its error bound
+struct CutEntry {
+    error: f32,
+    node: usize,
+    /// Contribution of the representative VPL
+    contrib: VPLContrib,
+}
+impl PartialEq for CutEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.error == other.error
+    }
+}
+impl Eq for CutEntry {}
+impl PartialOrd for CutEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for CutEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.error
+            .partial_cmp(&other.error)
+            .unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Light tree over the VPLs
+/// (Lightcuts: A Scalable Approach to Illumination, Walter et al. 2005)
+struct LightcutTree {
+    nodes: Vec<LightcutNode>,
+    root: Option<usize>,
+    /// VPLs gathered at every shading point (infinite)
+    unclustered: Vec<usize>,
+}
+
+impl LightcutTree {
+    fn new(vpls: &[VPL], sampler: &mut dyn Sampler) -> LightcutTree {
+        let mut leaves = vec![];
+        let mut unclustered = vec![];
+        for (i, vpl) in vpls.iter().enumerate() {
+            match vpl.bounds() {
+                Some(bounds) => {
+                    if bounds.phi > 0.0 {
+                        leaves.push((i, bounds));
+                    }
+                }
+                None => unclustered.push(i),
+            }
+        }
+
+        let mut tree = LightcutTree {
+            nodes: Vec::with_capacity(2 * leaves.len()),
+            root: None,
+            unclustered,
+        };
+        if !leaves.is_empty() {
+            tree.root = Some(tree.build(&mut leaves, sampler));
+        }
+        info!(
+            "Light tree: {} nodes ({} VPLs not clustered)",
+            tree.nodes.len(),
+            tree.unclustered.len()
+        );
+        tree
+    }
+
+    /// Top-down construction: split at the median of the largest axis
+    fn build(&mut self, leaves: &mut [(usize, LightBounds)], sampler: &mut dyn Sampler) -> usize {
+        if leaves.len() == 1 {
+            let (id, bounds) = leaves[0].clone();
+            self.nodes.push(LightcutNode {
+                representative_phi: bounds.phi,
+                bounds,
+                representative: id,
+                children: None,
+            });
+            return self.nodes.len() - 1;
+        }
+
+        let centroids = leaves.iter().fold(AABB::default(), |aabb, (_, b)| {
+            aabb.union_vec(&b.aabb.center())
+        });
+        let size = centroids.size();
+        let axis = if size.x > size.y && size.x > size.z {
+            0
+        } else if size.y > size.z {
+            1
+        } else {
+            2
+        };
+        leaves.sort_unstable_by(|a, b| {
+            a.1.aabb.center()[axis]
+                .partial_cmp(&b.1.aabb.center()[axis])
+                .unwrap_or(Ordering::Equal)
+        });
+        let (left, right) = leaves.split_at_mut(leaves.len() / 2);
+        let left = self.build(left, sampler);
+        let right = self.build(right, sampler);
+
+        // The representative is picked proportionally to the intensity
+        // so the cluster estimate is unbiased
+        let (node_left, node_right) = (&self.nodes[left], &self.nodes[right]);
+        let bounds = LightBounds::union(&node_left.bounds, &node_right.bounds);
+        let node_repr = if sampler.next() * bounds.phi < node_left.bounds.phi {
+            node_left
+        } else {
+            node_right
+        };
+        let node = LightcutNode {
+            representative: node_repr.representative,
+            representative_phi: node_repr.representative_phi,
+            bounds,
+            children: Some((left, right)),
+        };
+        self.nodes.push(node);
+        self.nodes.len() - 1
+    }
+
+    /// Upper bound of the contribution of the cluster (visibility ignored)
+    /// scale bounds the receiver material and the VPL normalization
+    fn error_bound(
+        &self,
+        node: usize,
+        p: &Point3<f32>,
+        n: Option<&Vector3<f32>>,
+        scale: f32,
+    ) -> f32 {
+        let bounds = &self.nodes[node].bounds;
+        let d2_min = bounds.aabb.dist_squared(p);
+        if d2_min <= 0.0 {
+            return std::f32::INFINITY;
+        }
+        // The importance uses the distance to the center of the cluster
+        let d2_center = (p.to_vec() - bounds.aabb.center())
+            .magnitude2()
+            .max(EPSILON_ATS);
+        scale * bounds.importance_point(p, n) * d2_center / d2_min
+    }
+
+    /// Cluster contribution estimated from its representative
+    fn estimate(&self, node: usize, contrib: VPLContrib) -> VPLContrib {
+        let node = &self.nodes[node];
+        contrib * (node.bounds.phi / node.representative_phi)
+    }
+
+    /// Gather the VPLs with a cut of the light tree. The cut is refined
+    /// (largest error bound first) until every cluster error is below
+    /// the relative error of the total estimate.
+    fn gather<F>(
+        &self,
+        p: &Point3<f32>,
+        n: Option<&Vector3<f32>>,
+        scale: f32,
+        relative_error: f32,
+        mut contrib: F,
+    ) -> VPLContrib
+    where
+        F: FnMut(usize) -> VPLContrib,
+    {
+        let mut l_i = VPLContrib::default();
+        for &i in &self.unclustered {
+            l_i += contrib(i);
+        }
+        let root = match self.root {
+            None => return l_i,
+            Some(root) => root,
+        };
+
+        let c = contrib(self.nodes[root].representative);
+        let mut total = self.estimate(root, c);
+        let mut cut = BinaryHeap::new();
+        cut.push(CutEntry {
+            error: self.error_bound(root, p, n, scale),
+            node: root,
+            contrib: c,
+        });
+        while cut.len() < LIGHTCUTS_MAX_CUT {
+            let entry = match cut.pop() {
+                None => break,
+                Some(entry) => entry,
+            };
+            if entry.error <= relative_error * total.radiance.luminance() {
+                break;
+            }
+            let (left, right) = match self.nodes[entry.node].children {
+                None => continue,
+                Some(children) => children,
+            };
+
+            // Replace the cluster by its children
+            // (the representative is shared with one of them)
+            total = total - self.estimate(entry.node, entry.contrib);
+            for &child in &[left, right] {
+                let repr = self.nodes[child].representative;
+                let c = if repr == self.nodes[entry.node].representative {
+                    entry.contrib
+                } else {
+                    contrib(repr)
+                };
+                total += self.estimate(child, c);
+                if self.nodes[child].children.is_some() {
+                    cut.push(CutEntry {
+                        error: self.error_bound(child, p, n, scale),
+                        node: child,
+                        contrib: c,
+                    });
+                }
+            }
+        }
+        l_i + total
+    }
+}
+
 pub struct TechniqueVPL {
     pub max_depth: Option<u32>,
     pub samplings: Vec<Box<dyn SamplingStrategy>>,
@@ -80,12 +545,16 @@ impl TechniqueVPL {
                     vpls.push(VPL::Surface(VPLSurface {
                         its: its.clone(),
                         radiance: flux,
+                        radius: 0.0,
                     }));
                 }
 
                 // Continue to bounce...
                 for edge in edge_out {
                     let edge = path.edge(*edge);
+                    if *options == IntegratorVPLOption::Spherical {
+                        self.convert_vrl(path, edge, vpls, flux);
+                    }
                     if let Some(vertex_next_id) = edge.vertices.1 {
                         self.convert_vpl(
                             path,
@@ -105,7 +574,9 @@ impl TechniqueVPL {
                 edge_out,
                 ..
             } => {
-                if *options != IntegratorVPLOption::Surface {
+                // Replaced by the virtual ray lights
+                let vrl = *options == IntegratorVPLOption::Spherical;
+                if *options != IntegratorVPLOption::Surface && !vrl {
                     vpls.push(VPL::Volume(VPLVolume {
                         pos: *pos,
                         d_in: *d_in,
@@ -117,6 +588,9 @@ impl TechniqueVPL {
                 // Continue to bounce...
                 for edge in edge_out {
                     let edge = path.edge(*edge);
+                    if *options == IntegratorVPLOption::Spherical {
+                        self.convert_vrl(path, edge, vpls, flux);
+                    }
                     if let Some(vertex_next_id) = edge.vertices.1 {
                         self.convert_vpl(
                             path,
@@ -154,6 +628,9 @@ impl TechniqueVPL {
 
                 if let Some(edge) = edge_out {
                     let edge = path.edge(*edge);
+                    if *options == IntegratorVPLOption::Spherical {
+                        self.convert_vrl(path, edge, vpls, flux);
+                    }
                     if let Some(next_vertex_id) = edge.vertices.1 {
                         self.convert_vpl(
                             path,
@@ -169,6 +646,32 @@ impl TechniqueVPL {
             Vertex::Sensor { .. } => {}
         }
     }
+
+    /// Virtual ray light along an edge inside the participating media
+    fn convert_vrl<'scene>(
+        &self,
+        path: &Path<'scene>,
+        edge: &Edge,
+        vpls: &mut Vec<VPL<'scene>>,
+        flux: Color,
+    ) {
+        if let (Some(mrec), Some(length)) = (&edge.sampled_distance, edge.dist) {
+            // Remove the distance sampling from the edge weight
+            // (a channel without scattering does not contribute)
+            let div = |w: f32, w_dist: f32| if w_dist == 0.0 { 0.0 } else { w / w_dist };
+            let weight = Color::new(
+                div(edge.weight.r, mrec.w.r),
+                div(edge.weight.g, mrec.w.g),
+                div(edge.weight.b, mrec.w.b),
+            );
+            vpls.push(VPL::Ray(VPLRay {
+                o: path.vertex(edge.vertices.0).position(),
+                d: edge.d,
+                length,
+                radiance: flux * weight,
+            }));
+        }
+    }
 }
 
 impl Integrator for IntegratorVPL {
@@ -179,7 +682,12 @@ impl Integrator for IntegratorVPL {
         scene: &Scene,
     ) -> BufferCollection {
         info!("Generating the VPL...");
-        let buffernames = vec![String::from("primal")];
+        let mut buffernames = vec![String::from("primal")];
+        if self.clamping_factor.is_some() {
+            // Energy removed by the clamping and its path traced estimate
+            buffernames.push(String::from("clamped"));
+            buffernames.push(String::from("compensation"));
+        }
         let mut nb_path_shot = 0;
         let mut vpls = vec![];
 
@@ -202,8 +710,18 @@ impl Integrator for IntegratorVPL {
             technique.convert_vpl(&path, scene, root.0, &self.option_vpl, &mut vpls, root.1);
             nb_path_shot += 1;
         }
+        if self.option_vpl == IntegratorVPLOption::Spherical {
+            info!("Computing the radius of the virtual spherical lights...");
+            set_vsl_radius(&mut vpls);
+        }
         let vpls = vpls;
 
+        // Cluster the VPLs for lightcuts
+        let tree = self.lightcuts.map(|_| {
+            info!("Building the light tree...");
+            LightcutTree::new(&vpls, sampler)
+        });
+
         // Generate the image block to get VPL efficiently
         let mut image_blocks = generate_img_blocks(scene, sampler, &buffernames);
 
@@ -224,8 +742,18 @@ impl Integrator for IntegratorVPL {
                                 sampler.as_mut(),
                                 &vpls,
                                 norm_vpl,
+                                tree.as_ref(),
+                            );
+                            let p = Point2 { x: ix, y: iy };
+                            im_block.accumulate(
+                                p,
+                                c.radiance + c.compensation,
+                                &"primal".to_owned(),
                             );
-                            im_block.accumulate(Point2 { x: ix, y: iy }, c, &"primal".to_owned());
+                            if self.clamping_factor.is_some() {
+                                im_block.accumulate(p, c.clamped, &"clamped".to_owned());
+                                im_block.accumulate(p, c.compensation, &"compensation".to_owned());
+                            }
                         }
                     }
                 }
@@ -265,78 +793,206 @@ impl IntegratorVPL {
         }
     }
 
-    fn gathering_surface<'a>(
+    /// Point sampled uniformly along a virtual ray light and its weight
+    ///
+    /// As the segment stops at the distance sampled by the light path,
+    /// the transmittance is divided by the probability to reach the point
+    fn sample_vrl(
         &self,
-        medium: Option<&HomogenousVolume>,
-        accel: &dyn Acceleration,
-        vpls: &[VPL<'a>],
-        norm_vpl: f32,
+        m: &HomogenousVolume,
+        vrl: &VPLRay,
+        sampler: &mut dyn Sampler,
+    ) -> (Point3<f32>, Color) {
+        let t = vrl.length * sampler.next();
+        let trans = (-(m.sigma_t * t)).exp();
+        let weight = vrl.radiance * m.sigma_s * trans * (vrl.length / trans.avg());
+        (vrl.o + vrl.d * t, weight)
+    }
+
+    /// Contribution of a virtual spherical light to a surface point:
+    /// the product of the BSDFs is integrated over the solid angle
+    /// subtended by the sphere (visibility tested with its center)
+    fn contrib_vsl(
+        &self,
+        vpl: &VPLSurface,
         its: &Intersection,
+        sampler: &mut dyn Sampler,
     ) -> Color {
-        let mut l_i = Color::zero();
+        let d = vpl.its.p - its.p;
+        let dist2 = d.magnitude2();
+        let radius2 = vpl.radius * vpl.radius;
+        // Whole sphere of directions if the point is inside the light
+        let cos_max = if dist2 <= radius2 {
+            -1.0
+        } else {
+            (1.0 - radius2 / dist2).max(0.0).sqrt()
+        };
+        let pdf = 1.0 / (2.0 * std::f32::consts::PI * (1.0 - cos_max));
+        let frame = Frame::new(d.normalize());
 
-        // Self emission
-        if its.cos_theta() > 0.0 {
-            l_i += its.mesh.emit(&its.uv);
+        let mut l_i = Color::zero();
+        for _ in 0..VSL_SAMPLES {
+            let w = frame.to_world(crate::math::sample_uniform_cone(sampler.next2d(), cos_max));
+            let emitted_radiance = vpl.its.mesh.bsdf.eval(
+                &vpl.its.uv,
+                &vpl.its.wi,
+                &vpl.its.to_local(&-w),
+                Domain::SolidAngle,
+                Transport::Radiance,
+            );
+            let bsdf_val = its.mesh.bsdf.eval(
+                &its.uv,
+                &its.wi,
+                &its.to_local(&w),
+                Domain::SolidAngle,
+                Transport::Importance,
+            );
+            l_i += emitted_radiance * bsdf_val / pdf;
         }
+        // The flux is spread over the disk of the sphere
+        l_i * vpl.radiance / (std::f32::consts::PI * radius2 * VSL_SAMPLES as f32)
+    }
 
-        for vpl in vpls {
-            match *vpl {
-                VPL::Emitter(ref vpl) => {
-                    match vpl.info {
-                        VPLEmitterInfo::Position { pos, n } => {
-                            if accel.visible(&pos, &its.p) {
-                                let mut d = pos - its.p;
-                                let dist = d.magnitude();
-                                d /= dist;
-
-                                // TODO: Check why this difference...
-                                let emitted_radiance = vpl.emitted_radiance
-                                    * n.dot(-d).max(0.0)
-                                    * std::f32::consts::FRAC_1_PI;
-                                if !its.mesh.bsdf.bsdf_type().is_smooth() {
-                                    let bsdf_val = its.mesh.bsdf.eval(
-                                        &its.uv,
-                                        &its.wi,
-                                        &its.to_local(&d),
-                                        Domain::SolidAngle,
-                                        Transport::Importance,
-                                    );
-                                    let trans = self.transmittance(medium, its.p, pos);
-                                    l_i += trans * norm_vpl * emitted_radiance * bsdf_val
-                                        / (dist * dist);
-                                }
+    /// Contribution of a single VPL to a surface point
+    fn contrib_surface(
+        &self,
+        medium: Option<&HomogenousVolume>,
+        accel: &dyn Acceleration,
+        vpl: &VPL,
+        norm_vpl: f32,
+        its: &Intersection,
+        sampler: &mut dyn Sampler,
+    ) -> VPLContrib {
+        let mut l_i = VPLContrib::default();
+        match *vpl {
+            VPL::Emitter(ref vpl) => {
+                match vpl.info {
+                    VPLEmitterInfo::Position { pos, n } => {
+                        if accel.visible_at(&pos, &its.p, its.time) {
+                            let mut d = pos - its.p;
+                            let dist = d.magnitude();
+                            d /= dist;
+
+                            // TODO: Check why this difference...
+                            let emitted_radiance = vpl.emitted_radiance
+                                * n.dot(-d).max(0.0)
+                                * std::f32::consts::FRAC_1_PI;
+                            if !its.mesh.bsdf.bsdf_type().is_smooth() {
+                                let bsdf_val = its.mesh.bsdf.eval(
+                                    &its.uv,
+                                    &its.wi,
+                                    &its.to_local(&d),
+                                    Domain::SolidAngle,
+                                    Transport::Importance,
+                                );
+                                let trans = self.transmittance(medium, its.p, pos);
+                                l_i = self
+                                    .clamp(trans * norm_vpl * emitted_radiance * bsdf_val, dist);
                             }
                         }
-                        VPLEmitterInfo::Infinite { d } => {
-                            let ray = Ray::spawn_ray(&its, -d);
-                            if accel.trace(&ray).is_none() {
-                                let emitted_radiance = vpl.emitted_radiance;
-                                if !its.mesh.bsdf.bsdf_type().is_smooth() {
-                                    let bsdf_val = its.mesh.bsdf.eval(
-                                        &its.uv,
-                                        &its.wi,
-                                        &its.to_local(&-d),
-                                        Domain::SolidAngle,
-                                        Transport::Importance,
-                                    );
-                                    // TODO: Medium is not supported yet...
-                                    assert!(medium.is_none());
-                                    //let trans = self.transmittance(medium, its.p, pos);
-                                    l_i += norm_vpl * emitted_radiance * bsdf_val;
-                                    // trans *
-                                }
+                    }
+                    VPLEmitterInfo::Infinite { d } => {
+                        let ray = Ray::spawn_ray(&its, -d);
+                        if accel.trace(&ray).is_none() {
+                            let emitted_radiance = vpl.emitted_radiance;
+                            if !its.mesh.bsdf.bsdf_type().is_smooth() {
+                                let bsdf_val = its.mesh.bsdf.eval(
+                                    &its.uv,
+                                    &its.wi,
+                                    &its.to_local(&-d),
+                                    Domain::SolidAngle,
+                                    Transport::Importance,
+                                );
+                                // TODO: Medium is not supported yet...
+                                assert!(medium.is_none());
+                                //let trans = self.transmittance(medium, its.p, pos);
+                                l_i = (norm_vpl * emitted_radiance * bsdf_val).into();
+                                // trans *
                             }
                         }
                     }
                 }
-                VPL::Volume(ref vpl) => {
-                    let mut d = vpl.pos - its.p;
+            }
+            VPL::Volume(ref vpl) => {
+                let mut d = vpl.pos - its.p;
+                let dist = d.magnitude();
+                d /= dist;
+
+                if !its.mesh.bsdf.bsdf_type().is_smooth() {
+                    let emitted_radiance = vpl.phase_function.eval(&vpl.d_in, &d);
+                    let bsdf_val = its.mesh.bsdf.eval(
+                        &its.uv,
+                        &its.wi,
+                        &its.to_local(&d),
+                        Domain::SolidAngle,
+                        Transport::Importance,
+                    );
+                    let trans = self.transmittance(medium, its.p, vpl.pos);
+                    l_i = self.clamp(
+                        trans * norm_vpl * emitted_radiance * bsdf_val * vpl.radiance,
+                        dist,
+                    );
+                }
+            }
+            VPL::Ray(ref vpl) => {
+                let m = medium.unwrap();
+                let (pos, weight) = self.sample_vrl(m, vpl, sampler);
+                if !its.mesh.bsdf.bsdf_type().is_smooth()
+                    && accel.visible_at(&pos, &its.p, its.time)
+                {
+                    let mut d = pos - its.p;
+                    let dist = d.magnitude();
+                    d /= dist;
+
+                    let emitted_radiance = m.phase.eval(&-vpl.d, &-d);
+                    let bsdf_val = its.mesh.bsdf.eval(
+                        &its.uv,
+                        &its.wi,
+                        &its.to_local(&d),
+                        Domain::SolidAngle,
+                        Transport::Importance,
+                    );
+                    let trans = self.transmittance(medium, its.p, pos);
+                    l_i = self.clamp(
+                        trans * norm_vpl * emitted_radiance * bsdf_val * weight,
+                        dist,
+                    );
+                }
+            }
+            VPL::Surface(ref vpl) if vpl.radius > 0.0 => {
+                let visible = accel.visible_at(
+                    &vpl.its.spawn_origin(&(its.p - vpl.its.p)),
+                    &its.p,
+                    its.time,
+                );
+                if visible && !its.mesh.bsdf.bsdf_type().is_smooth() {
+                    // Clamped as a point VPL with the same contribution
+                    let dist2 = (vpl.its.p - its.p).magnitude2();
+                    l_i = self.clamp(
+                        norm_vpl * self.contrib_vsl(vpl, its, sampler) * dist2,
+                        dist2.sqrt(),
+                    );
+                }
+            }
+            VPL::Surface(ref vpl) => {
+                // The time of the shading point is used (not the one of the VPL)
+                if accel.visible_at(
+                    &vpl.its.spawn_origin(&(its.p - vpl.its.p)),
+                    &its.p,
+                    its.time,
+                ) {
+                    let mut d = vpl.its.p - its.p;
                     let dist = d.magnitude();
                     d /= dist;
 
                     if !its.mesh.bsdf.bsdf_type().is_smooth() {
-                        let emitted_radiance = vpl.phase_function.eval(&vpl.d_in, &d);
+                        let emitted_radiance = vpl.its.mesh.bsdf.eval(
+                            &vpl.its.uv,
+                            &vpl.its.wi,
+                            &vpl.its.to_local(&-d),
+                            Domain::SolidAngle,
+                            Transport::Radiance, // TODO: Check this
+                        );
                         let bsdf_val = its.mesh.bsdf.eval(
                             &its.uv,
                             &its.wi,
@@ -344,36 +1000,11 @@ impl IntegratorVPL {
                             Domain::SolidAngle,
                             Transport::Importance,
                         );
-                        let trans = self.transmittance(medium, its.p, vpl.pos);
-                        l_i += trans * norm_vpl * emitted_radiance * bsdf_val * vpl.radiance
-                            / (dist * dist);
-                    }
-                }
-                VPL::Surface(ref vpl) => {
-                    if accel.visible(&vpl.its.p, &its.p) {
-                        let mut d = vpl.its.p - its.p;
-                        let dist = d.magnitude();
-                        d /= dist;
-
-                        if !its.mesh.bsdf.bsdf_type().is_smooth() {
-                            let emitted_radiance = vpl.its.mesh.bsdf.eval(
-                                &vpl.its.uv,
-                                &vpl.its.wi,
-                                &vpl.its.to_local(&-d),
-                                Domain::SolidAngle,
-                                Transport::Radiance, // TODO: Check this
-                            );
-                            let bsdf_val = its.mesh.bsdf.eval(
-                                &its.uv,
-                                &its.wi,
-                                &its.to_local(&d),
-                                Domain::SolidAngle,
-                                Transport::Importance,
-                            );
-                            let trans = self.transmittance(medium, its.p, vpl.its.p);
-                            l_i += trans * norm_vpl * emitted_radiance * bsdf_val * vpl.radiance
-                                / (dist * dist);
-                        }
+                        let trans = self.transmittance(medium, its.p, vpl.its.p);
+                        l_i = self.clamp(
+                            trans * norm_vpl * emitted_radiance * bsdf_val * vpl.radiance,
+                            dist,
+                        );
                     }
                 }
             }
@@ -381,83 +1012,196 @@ impl IntegratorVPL {
         l_i
     }
 
-    fn gathering_volume<'a>(
+    fn gathering_surface<'a>(
         &self,
         medium: Option<&HomogenousVolume>,
         accel: &dyn Acceleration,
         vpls: &[VPL<'a>],
         norm_vpl: f32,
+        its: &Intersection,
+        tree: Option<&LightcutTree>,
+        sampler: &mut dyn Sampler,
+    ) -> VPLContrib {
+        let mut l_i = VPLContrib::default();
+
+        // Self emission
+        if its.cos_theta() > 0.0 {
+            l_i.radiance += its.mesh.emit(&its.uv);
+        }
+
+        match tree {
+            None => {
+                for vpl in vpls {
+                    l_i += self.contrib_surface(medium, accel, vpl, norm_vpl, its, sampler);
+                }
+            }
+            Some(tree) => {
+                l_i += tree.gather(
+                    &its.p,
+                    Some(&its.n_s),
+                    norm_vpl * std::f32::consts::FRAC_1_PI,
+                    self.lightcuts.unwrap(),
+                    |i| self.contrib_surface(medium, accel, &vpls[i], norm_vpl, its, sampler),
+                );
+            }
+        }
+        l_i
+    }
+
+    /// Contribution of a single VPL to a point inside the medium
+    fn contrib_volume(
+        &self,
+        medium: Option<&HomogenousVolume>,
+        accel: &dyn Acceleration,
+        vpl: &VPL,
+        norm_vpl: f32,
         d_cam: Vector3<f32>,
         its_pos: Point3<f32>,
+        time: f32,
         phase: &PhaseFunction,
-    ) -> Color {
-        let mut l_i = Color::zero();
-        for vpl in vpls {
-            match *vpl {
-                VPL::Emitter(ref vpl) => {
-                    match vpl.info {
-                        VPLEmitterInfo::Position { pos, n } => {
-                            if accel.visible(&pos, &its_pos) {
-                                let mut d = pos - its_pos;
-                                let dist = d.magnitude();
-                                d /= dist;
-
-                                let emitted_radiance = vpl.emitted_radiance
-                                    * n.dot(-d).max(0.0)
-                                    * std::f32::consts::FRAC_1_PI;
-                                let phase_val = phase.eval(&d_cam, &d);
-                                let trans = self.transmittance(medium, pos, its_pos);
-                                l_i +=
-                                    trans * norm_vpl * emitted_radiance * phase_val / (dist * dist);
-                            }
+        sampler: &mut dyn Sampler,
+    ) -> VPLContrib {
+        let mut l_i = VPLContrib::default();
+        match *vpl {
+            VPL::Emitter(ref vpl) => {
+                match vpl.info {
+                    VPLEmitterInfo::Position { pos, n } => {
+                        if accel.visible_at(&pos, &its_pos, time) {
+                            let mut d = pos - its_pos;
+                            let dist = d.magnitude();
+                            d /= dist;
+
+                            let emitted_radiance = vpl.emitted_radiance
+                                * n.dot(-d).max(0.0)
+                                * std::f32::consts::FRAC_1_PI;
+                            let phase_val = phase.eval(&d_cam, &d);
+                            let trans = self.transmittance(medium, pos, its_pos);
+                            l_i = self.clamp(trans * norm_vpl * emitted_radiance * phase_val, dist);
                         }
-                        VPLEmitterInfo::Infinite { d } => {
-                            let ray = Ray::new(its_pos, -d);
-                            if accel.trace(&ray).is_none() {
-                                let phase_val = phase.eval(&d_cam, &d);
-                                assert!(medium.is_none());
-                                // let trans = self.transmittance(medium, pos, vpl.pos);
-                                let emitted_radiance = vpl.emitted_radiance;
-                                l_i += norm_vpl * emitted_radiance * phase_val; // trans *
-                            }
+                    }
+                    VPLEmitterInfo::Infinite { d } => {
+                        let ray = Ray::new(its_pos, -d).with_time(time);
+                        if accel.trace(&ray).is_none() {
+                            let phase_val = phase.eval(&d_cam, &d);
+                            assert!(medium.is_none());
+                            // let trans = self.transmittance(medium, pos, vpl.pos);
+                            let emitted_radiance = vpl.emitted_radiance;
+                            l_i = (norm_vpl * emitted_radiance * phase_val).into();
+                            // trans *
                         }
                     }
                 }
-                VPL::Volume(ref vpl) => {
-                    let mut d = vpl.pos - its_pos;
+            }
+            VPL::Volume(ref vpl) => {
+                let mut d = vpl.pos - its_pos;
+                let dist = d.magnitude();
+                d /= dist;
+
+                let emitted_radiance = vpl.phase_function.eval(&vpl.d_in, &d);
+                let phase_val = phase.eval(&d_cam, &d);
+                let trans = self.transmittance(medium, its_pos, vpl.pos);
+                l_i = self.clamp(
+                    trans * norm_vpl * emitted_radiance * phase_val * vpl.radiance,
+                    dist,
+                );
+            }
+            VPL::Ray(ref vpl) => {
+                let m = medium.unwrap();
+                let (pos, weight) = self.sample_vrl(m, vpl, sampler);
+                if accel.visible_at(&pos, &its_pos, time) {
+                    let mut d = pos - its_pos;
                     let dist = d.magnitude();
                     d /= dist;
 
-                    let emitted_radiance = vpl.phase_function.eval(&vpl.d_in, &d);
+                    let emitted_radiance = m.phase.eval(&-vpl.d, &-d);
                     let phase_val = phase.eval(&d_cam, &d);
-                    let trans = self.transmittance(medium, its_pos, vpl.pos);
-                    l_i += trans * norm_vpl * emitted_radiance * phase_val * vpl.radiance
-                        / (dist * dist);
+                    let trans = self.transmittance(medium, its_pos, pos);
+                    l_i = self.clamp(
+                        trans * norm_vpl * emitted_radiance * phase_val * weight,
+                        dist,
+                    );
                 }
-                VPL::Surface(ref vpl) => {
-                    if accel.visible(&vpl.its.p, &its_pos) {
-                        let mut d = vpl.its.p - its_pos;
-                        let dist = d.magnitude();
-                        d /= dist;
+            }
+            VPL::Surface(ref vpl) => {
+                if accel.visible_at(
+                    &vpl.its.spawn_origin(&(its_pos - vpl.its.p)),
+                    &its_pos,
+                    time,
+                ) {
+                    let mut d = vpl.its.p - its_pos;
+                    let dist = d.magnitude();
+                    d /= dist;
 
-                        let emitted_radiance = vpl.its.mesh.bsdf.eval(
-                            &vpl.its.uv,
-                            &vpl.its.wi,
-                            &vpl.its.to_local(&-d),
-                            Domain::SolidAngle,
-                            Transport::Radiance,
-                        );
-                        let phase_val = phase.eval(&d_cam, &d);
-                        let trans = self.transmittance(medium, its_pos, vpl.its.p);
-                        l_i += trans * norm_vpl * emitted_radiance * phase_val * vpl.radiance
-                            / (dist * dist);
-                    }
+                    let emitted_radiance = vpl.its.mesh.bsdf.eval(
+                        &vpl.its.uv,
+                        &vpl.its.wi,
+                        &vpl.its.to_local(&-d),
+                        Domain::SolidAngle,
+                        Transport::Radiance,
+                    );
+                    let phase_val = phase.eval(&d_cam, &d);
+                    let trans = self.transmittance(medium, its_pos, vpl.its.p);
+                    l_i = self.clamp(
+                        trans * norm_vpl * emitted_radiance * phase_val * vpl.radiance,
+                        dist,
+                    );
                 }
             }
         }
         l_i
     }
 
+    fn gathering_volume<'a>(
+        &self,
+        medium: Option<&HomogenousVolume>,
+        accel: &dyn Acceleration,
+        vpls: &[VPL<'a>],
+        norm_vpl: f32,
+        d_cam: Vector3<f32>,
+        its_pos: Point3<f32>,
+        time: f32,
+        phase: &PhaseFunction,
+        tree: Option<&LightcutTree>,
+        sampler: &mut dyn Sampler,
+    ) -> VPLContrib {
+        match tree {
+            None => {
+                let mut l_i = VPLContrib::default();
+                for vpl in vpls {
+                    l_i += self.contrib_volume(
+                        medium, accel, vpl, norm_vpl, d_cam, its_pos, time, phase, sampler,
+                    );
+                }
+                l_i
+            }
+            Some(tree) => tree.gather(
+                &its_pos,
+                None,
+                norm_vpl * 0.25 * std::f32::consts::FRAC_1_PI,
+                self.lightcuts.unwrap(),
+                |i| {
+                    self.contrib_volume(
+                        medium, accel, &vpls[i], norm_vpl, d_cam, its_pos, time, phase, sampler,
+                    )
+                },
+            ),
+        }
+    }
+
+    /// Inverse squared distance clamped by the clamping factor
+    /// (the energy removed by the clamping is kept aside)
+    fn clamp(&self, value: Color, dist: f32) -> VPLContrib {
+        let inv_dist2 = 1.0 / (dist * dist);
+        match self.clamping_factor {
+            Some(b) if inv_dist2 > b => VPLContrib {
+                radiance: value * b,
+                clamped: value * (inv_dist2 - b),
+                compensation: Color::zero(),
+            },
+            _ => (value * inv_dist2).into(),
+        }
+    }
+
     fn compute_vpl_contrib<'a>(
         &self,
         (ix, iy): (u32, u32),
@@ -466,68 +1210,207 @@ impl IntegratorVPL {
         sampler: &mut dyn Sampler,
         vpls: &[VPL<'a>],
         norm_vpl: f32,
-    ) -> Color {
+        tree: Option<&LightcutTree>,
+    ) -> VPLContrib {
         let pix = Point2::new(ix as f32 + sampler.next(), iy as f32 + sampler.next());
-        let ray = scene.camera.generate(pix);
-        let mut l_i = Color::zero();
+        let ray = scene.camera.generate_at(
+            pix,
+            sampler.next2d(),
+            scene.camera.sample_time(sampler.next_time()),
+        );
+        self.radiance(&ray, accel, scene, sampler, vpls, norm_vpl, tree, 0)
+    }
 
-        // Check if we have a intersection with the primary ray
-        let its = match accel.trace(&ray) {
-            Some(x) => x,
-            None => {
-                if let Some(m) = &scene.volume {
-                    // Sample the participating media
-                    let mrec = m.sample(&ray, sampler.next());
-                    assert!(!mrec.exited);
-                    let pos = Point3::from_vec(ray.o.to_vec() + ray.d * mrec.t);
-                    l_i *= self.gathering_volume(
-                        scene.volume.as_ref(),
-                        accel,
-                        vpls,
-                        norm_vpl,
-                        -ray.d,
-                        pos,
-                        &m.phase,
-                    ) * mrec.w;
-                    return l_i;
-                } else {
-                    return match &scene.emitter_environment {
-                        None => l_i,
-                        Some(envmap) => l_i + envmap.eval(ray.d, None),
-                    };
-                }
-            }
+    /// Radiance gathered at the first vertex along the ray
+    ///
+    /// Where the clamping is active, the removed energy is estimated
+    /// by path tracing (bias compensation, Kollig and Keller 2004):
+    /// the next vertex is sampled from the BSDF (or phase function)
+    /// and only the clamped part of the connection is kept (depth > 0).
+    fn radiance<'a>(
+        &self,
+        ray: &Ray,
+        accel: &dyn Acceleration,
+        scene: &'a Scene,
+        sampler: &mut dyn Sampler,
+        vpls: &[VPL<'a>],
+        norm_vpl: f32,
+        tree: Option<&LightcutTree>,
+        depth: u32,
+    ) -> VPLContrib {
+        // Fraction of the connection removed by the clamping
+        let residual = |dist: f32| match (depth, self.clamping_factor) {
+            (0, _) | (_, None) => 1.0,
+            (_, Some(b)) => (1.0 - b * dist * dist).max(0.0),
+        };
+        let compensate = |l_i: &VPLContrib| {
+            self.clamping_factor.is_some()
+                && depth < COMPENSATION_MAX_DEPTH
+                && !l_i.clamped.is_zero()
         };
 
+        // Check if we have a intersection with the primary ray
+        let its = accel.trace(ray);
+
+        // Sample the participating media
+        let mut weight = Color::one();
         if let Some(m) = &scene.volume {
             let mut ray_med = ray.clone();
-            ray_med.tfar = its.dist;
+            if let Some(its) = &its {
+                ray_med.tfar = its.dist;
+            }
             let mrec = m.sample(&ray_med, sampler.next());
             if !mrec.exited {
+                let w = residual(mrec.t);
+                if w == 0.0 {
+                    return VPLContrib::default();
+                }
                 let pos = Point3::from_vec(ray.o.to_vec() + ray.d * mrec.t);
-                l_i += self.gathering_volume(
+                let mut l_i = self.gathering_volume(
                     scene.volume.as_ref(),
                     accel,
                     vpls,
                     norm_vpl,
                     -ray.d,
                     pos,
+                    ray.time,
                     &m.phase,
-                ) * mrec.w;
-                l_i
-            } else {
-                if self.option_lt != IntegratorVPLOption::Volume {
-                    l_i +=
-                        self.gathering_surface(scene.volume.as_ref(), accel, vpls, norm_vpl, &its)
-                            * mrec.w;
+                    tree,
+                    sampler,
+                );
+                if compensate(&l_i) {
+                    let sample_phase = m.phase.sample(&-ray.d, sampler.next2d());
+                    let l_next = self.radiance(
+                        &Ray::new(pos, sample_phase.d).with_time(ray.time),
+                        accel,
+                        scene,
+                        sampler,
+                        vpls,
+                        norm_vpl,
+                        tree,
+                        depth + 1,
+                    );
+                    l_i.compensation +=
+                        sample_phase.weight * (l_next.radiance + l_next.compensation);
                 }
-                l_i
+                return l_i * (mrec.w * w);
             }
-        } else {
-            if self.option_lt != IntegratorVPLOption::Surface {
-                l_i += self.gathering_surface(scene.volume.as_ref(), accel, vpls, norm_vpl, &its);
+            weight = mrec.w;
+        }
+
+        let its = match its {
+            Some(x) => x,
+            None => {
+                return match (&scene.emitter_environment, depth) {
+                    (Some(envmap), 0) => envmap.eval(ray.d, None).into(),
+                    _ => VPLContrib::default(),
+                };
+            }
+        };
+        let w = residual(its.dist);
+        if w == 0.0 || self.option_lt == IntegratorVPLOption::Volume {
+            return VPLContrib::default();
+        }
+
+        let mut l_i = self.gathering_surface(
+            scene.volume.as_ref(),
+            accel,
+            vpls,
+            norm_vpl,
+            &its,
+            tree,
+            sampler,
+        );
+        if compensate(&l_i) {
+            if let Some(sampled_bsdf) =
+                its.mesh
+                    .bsdf
+                    .sample(&its.uv, &its.wi, sampler.next2d(), Transport::Importance)
+            {
+                let l_next = self.radiance(
+                    &Ray::spawn_ray(&its, its.frame.to_world(sampled_bsdf.d)),
+                    accel,
+                    scene,
+                    sampler,
+                    vpls,
+                    norm_vpl,
+                    tree,
+                    depth + 1,
+                );
+                l_i.compensation += sampled_bsdf.weight * (l_next.radiance + l_next.compensation);
             }
-            l_i
         }
+        l_i * (weight * w)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::samplers::independent::IndependentSampler;
+
+    fn volume_vpls() -> Vec<VPL<'static>> {
+        (0..200)
+            .map(|i| {
+                let f = i as f32;
+                VPL::Volume(VPLVolume {
+                    pos: Point3::new((f * 0.37).sin() * 5.0, (f * 0.11).cos() * 3.0, f * 0.01),
+                    d_in: Vector3::new(0.0, 0.0, 1.0),
+                    phase_function: PhaseFunction::Isotropic(),
+                    radiance: Color::value(1.0 + (i % 7) as f32),
+                })
+            })
+            .collect()
+    }
+
+    /// Contribution used for the tests (depends on the VPL)
+    fn contrib(vpls: &[VPL], i: usize) -> VPLContrib {
+        match &vpls[i] {
+            VPL::Volume(vpl) => (vpl.radiance * (1.0 + vpl.pos.x.abs())).into(),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn lightcuts_exact() {
+        let vpls = volume_vpls();
+        let tree = LightcutTree::new(&vpls, &mut IndependentSampler::from_seed(0));
+        let p = Point3::new(1.0, 2.0, 0.5);
+        // Without error, the cut contains all the VPLs
+        let l_i = tree.gather(&p, None, 1.0, 0.0, |i| contrib(&vpls, i));
+        let expected = (0..vpls.len())
+            .map(|i| contrib(&vpls, i).radiance)
+            .fold(Color::zero(), |a, b| a + b);
+        assert!(
+            (l_i.radiance.r - expected.r).abs() < 1e-3 * expected.r,
+            "{:?} != {:?}",
+            l_i.radiance,
+            expected
+        );
+    }
+
+    #[test]
+    fn lightcuts_root() {
+        let vpls = volume_vpls();
+        let tree = LightcutTree::new(&vpls, &mut IndependentSampler::from_seed(0));
+        // Far from the VPLs (the error bound is infinite inside the clusters)
+        let p = Point3::new(100.0, 100.0, 100.0);
+        // With a large error, only the root is used
+        let mut nb_evals = 0;
+        let l_i = tree.gather(&p, None, 1.0, 1e9, |i| {
+            nb_evals += 1;
+            contrib(&vpls, i)
+        });
+        assert_eq!(nb_evals, 1);
+        let root = &tree.nodes[tree.root.unwrap()];
+        let c = contrib(&vpls, root.representative).radiance;
+        let expected = c * (root.bounds.phi / root.representative_phi);
+        assert!((l_i.radiance.r - expected.r).abs() < 1e-4 * expected.r);
+        // The root flux is the sum of the VPL intensities
+        let phi = vpls
+            .iter()
+            .map(|vpl| vpl.bounds().unwrap().phi)
+            .sum::<f32>();
+        assert!((root.bounds.phi - phi).abs() < 1e-3 * phi);
     }
 }
@@ -67,6 +67,8 @@ impl<'a> RayState<'a> {
     pub fn new(
         (x, y): (f32, f32),
         off: Point2<i32>,
+        uv_lens: Point2<f32>,
+        time: f32,
         accel: &'a dyn Acceleration,
         scene: &'a Scene,
     ) -> RayState<'a> {
@@ -79,7 +81,7 @@ impl<'a> RayState<'a> {
             return RayState::Dead;
         }
 
-        let ray = scene.camera.generate(pix);
+        let ray = scene.camera.generate_at(pix, uv_lens, time);
         let its = match accel.trace(&ray) {
             Some(x) => x,
             None => return RayState::Dead,
@@ -224,14 +226,17 @@ impl IntegratorGradientPath {
     ) -> ColorGradient {
         let mut l_i = ColorGradient::default();
         let pix = (ix as f32 + sampler.next(), iy as f32 + sampler.next());
-        let mut main = match RayState::new(pix, Point2::new(0, 0), accel, scene) {
+        // Shift mapping keeps the same lens position and time
+        let uv_lens = sampler.next2d();
+        let time = scene.camera.sample_time(sampler.next_time());
+        let mut main = match RayState::new(pix, Point2::new(0, 0), uv_lens, time, accel, scene) {
             RayState::NotConnected(x) => x,
             _ => return l_i,
         };
         let mut offsets: Vec<RayState> = {
             GRADIENT_ORDER
                 .iter()
-                .map(|e| RayState::new(pix, *e, accel, scene))
+                .map(|e| RayState::new(pix, *e, uv_lens, time, accel, scene))
                 .collect()
         };
 
@@ -264,7 +269,7 @@ impl IntegratorGradientPath {
                     scene
                         .emitters()
                         .sample_light(&main.its.p, None, r_sel_rand, r_rand, uv_rand);
-                let main_light_visible = accel.visible(&main.its.p, &main_light_record.p);
+                let main_light_visible = accel.visible_its(&main.its, &main_light_record.p);
                 let main_emitter_rad = if main_light_visible {
                     main_light_record.weight
                 } else {
@@ -376,7 +381,7 @@ impl IntegratorGradientPath {
                                         .emitters()
                                         .sample_light(&s.its.p, None, r_sel_rand, r_rand, uv_rand);
                                     let shift_light_visible =
-                                        accel.visible(&s.its.p, &shift_light_record.p);
+                                        accel.visible_its(&s.its, &shift_light_record.p);
                                     let shift_emitter_rad = if shift_light_visible {
                                         shift_light_record.weight
                                             * (shift_light_record.pdf.value()
@@ -609,7 +614,7 @@ impl IntegratorGradientPath {
                             let shift_bsdf_rought = !s.its.mesh.bsdf.bsdf_type().is_smooth();
                             if main_bsdf_rought && main_next_bsdf_rought && shift_bsdf_rought {
                                 // In this case, we can do the reconnection
-                                if !accel.visible(&s.its.p, &main.its.p) {
+                                if !accel.visible_its(&s.its, &main.its.p) {
                                     ShiftResult::default()
                                 } else {
                                     // Compute the ratio of geometry factors
@@ -672,6 +677,7 @@ impl IntegratorGradientPath {
                                                         n: main.its.n_g,
                                                         uv: main.its.uv,
                                                         dir: shift_d_out_global,
+                                                        primitive_id: main.its.primitive_id,
                                                     },
                                                     None,
                                                     None,
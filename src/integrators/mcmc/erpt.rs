@@ -41,7 +41,7 @@ impl Integrator for IntegratorERPT {
 
             // State
             let mut state = MCMCState::empty();
-            let c = { self.integrator.compute_pixel((x, y), accel, scene, s) };
+            let c = { self.integrator.compute_pixel_box((x, y), accel, scene, s) };
             state.append(c, Point2::new(x, y));
             state
         };
@@ -160,7 +160,7 @@ impl Integrator for IntegratorERPT {
                                         as u32;
                                     let mut state = MCMCState::empty();
                                     let c = {
-                                        self.integrator.compute_pixel(
+                                        self.integrator.compute_pixel_box(
                                             (ix + im_block.pos.x, iy + im_block.pos.y),
                                             accel,
                                             scene,
@@ -20,7 +20,7 @@ impl Integrator for IntegratorPSSMLT {
         let sample = |s: &mut dyn Sampler| {
             let x = (s.next() * scene.camera.size().x as f32) as u32;
             let y = (s.next() * scene.camera.size().y as f32) as u32;
-            let c = { self.integrator.compute_pixel((x, y), accel, scene, s) };
+            let c = { self.integrator.compute_pixel_box((x, y), accel, scene, s) };
             MCMCState::new(c, Point2::new(x, y))
         };
 
@@ -938,7 +938,7 @@ impl Initialization for IndependentInit {
                 .par_chunks_mut(img_size.y as usize)
                 .for_each(|tiles| {
                     let technique = |p: (u32, u32), s: &mut dyn Sampler| -> Color {
-                        int.compute_pixel((p.0, p.1), accel, scene, s)
+                        int.compute_pixel_box((p.0, p.1), accel, scene, s)
                     };
                     let mut nb_initialized = 0;
                     for tile in &mut tiles[..] {
@@ -1006,7 +1006,7 @@ impl Initialization for MCMCInit {
                 .par_chunks_mut(img_size.y as usize)
                 .map(|entries| {
                     let technique = |p: (u32, u32), s: &mut dyn Sampler| -> Color {
-                        int.compute_pixel((p.0, p.1), accel, scene, s)
+                        int.compute_pixel_box((p.0, p.1), accel, scene, s)
                     };
                     let mut seeds = vec![];
                     for entry in &mut entries[..] {
@@ -1058,7 +1058,7 @@ impl Initialization for MCMCInit {
                 .for_each(|(chain_id, mut dumy_tile)| {
                     let img_mut = MutatorKelemen::default();
                     let technique = |p: (u32, u32), s: &mut dyn Sampler| -> Color {
-                        int.compute_pixel((p.0, p.1), accel, scene, s)
+                        int.compute_pixel_box((p.0, p.1), accel, scene, s)
                     };
 
                     // Pick one with stratified sampling
@@ -1238,7 +1238,7 @@ impl Integrator for StratifiedMCMC {
             // (this will be important if the number of SPP is not 8 multiple)
             let mut state = State::MCMC(0);
             let technique = |p: (u32, u32), s: &mut dyn Sampler| -> Color {
-                int.compute_pixel((p.0, p.1), accel, scene, s)
+                int.compute_pixel_box((p.0, p.1), accel, scene, s)
             };
 
             // For now, it is good assumptions
@@ -1,4 +1,5 @@
 use crate::accel::*;
+use crate::filter::Filter;
 use crate::samplers::*;
 use crate::scene::*;
 use crate::structure::*;
@@ -43,6 +44,9 @@ impl ProgressBar {
 }
 
 //////////////// Helpers
+/// Buffer accumulating the reconstruction filter weights
+const FILTER_WEIGHT: &str = "filter_weight";
+
 /// Image block
 /// for easy paralelisation over the thread
 pub struct BufferCollection {
@@ -174,6 +178,86 @@ impl BufferCollection {
         }
     }
 
+    /// Pixels (inside the buffer) touched by a sample at p (image space)
+    fn filter_footprint(&self, filter: &Filter, p: Point2<f32>) -> (i32, i32, i32, i32) {
+        (
+            ((p.x - 0.5 - filter.radius).ceil() as i32).max(self.pos.x as i32),
+            ((p.x - 0.5 + filter.radius).floor() as i32).min((self.pos.x + self.size.x) as i32 - 1),
+            ((p.y - 0.5 - filter.radius).ceil() as i32).max(self.pos.y as i32),
+            ((p.y - 0.5 + filter.radius).floor() as i32).min((self.pos.y + self.size.y) as i32 - 1),
+        )
+    }
+
+    /// Splat a sample (p in image space) to the neighboring pixels.
+    /// The filter weights are accumulated so the image
+    /// can be normalized afterwards (see normalize_filter)
+    pub fn splat_filtered(&mut self, filter: &Filter, p: Point2<f32>, f: Color, name: &str) {
+        if !f.is_valid() {
+            warn!("Try to splat: {:?}", f);
+            return;
+        }
+        if !self.values.contains_key(FILTER_WEIGHT) {
+            self.register(FILTER_WEIGHT.to_string());
+        }
+        let (x0, x1, y0, y1) = self.filter_footprint(filter, p);
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let w = filter.eval(x as f32 + 0.5 - p.x, y as f32 + 0.5 - p.y);
+                if w != 0.0 {
+                    let pix = Point2::new(x as u32 - self.pos.x, y as u32 - self.pos.y);
+                    self.accumulate(pix, f * w, name);
+                    self.accumulate(pix, Color::value(w), FILTER_WEIGHT);
+                }
+            }
+        }
+    }
+
+    /// Divide the splatted samples by the filter weights
+    pub fn normalize_filter(&mut self, name: &str) {
+        if let Some(weights) = self.values.remove(FILTER_WEIGHT) {
+            let bitmap = self.values.get_mut(name).unwrap();
+            for (c, w) in bitmap.colors.iter_mut().zip(weights.colors.iter()) {
+                if w.r != 0.0 {
+                    c.scale(1.0 / w.r);
+                }
+            }
+        }
+    }
+
+    /// Splat a contribution (p in image space) with a normalized filter
+    /// (light tracing, the image is not normalized by the filter weights)
+    pub fn accumulate_filtered(&mut self, filter: &Filter, p: Point2<f32>, f: Color, name: &str) {
+        if !f.is_valid() {
+            warn!("Try to splat: {:?}", f);
+            return;
+        }
+        let (x0, x1, y0, y1) = self.filter_footprint(filter, p);
+        for y in y0..=y1 {
+            for x in x0..=x1 {
+                let w = filter.eval_normalized(x as f32 + 0.5 - p.x, y as f32 + 0.5 - p.y);
+                if w != 0.0 {
+                    let pix = Point2::new(x as u32 - self.pos.x, y as u32 - self.pos.y);
+                    self.accumulate(pix, f * w, name);
+                }
+            }
+        }
+    }
+
+    /// Extract a region of the buffers (p in image space)
+    pub fn crop(&self, p: Point2<u32>, size: Vector2<u32>) -> BufferCollection {
+        let mut bitmap = BufferCollection::copy(Point2::new(0, 0), size, self);
+        for (key, value) in bitmap.values.iter_mut() {
+            let other = &self.values[key];
+            for y in 0..size.y {
+                for x in 0..size.x {
+                    let p_other = Point2::new(p.x + x - self.pos.x, p.y + y - self.pos.y);
+                    value.colors[(y * size.x + x) as usize] = other.pixel(p_other);
+                }
+            }
+        }
+        bitmap
+    }
+
     pub fn rename(&mut self, current_name: &str, new_name: &str) {
         let data = self.values.remove(current_name).unwrap();
         self.values.insert(new_name.to_string(), data);
@@ -273,12 +357,12 @@ pub enum IntegratorType {
 }
 impl IntegratorType {
     pub fn compute(&mut self, sampler: &mut dyn Sampler, scene: &Scene) -> BufferCollection {
-        info!("Build acceleration data structure...");
+        info!(
+            "Build acceleration data structure ({:?})...",
+            scene.accel_type
+        );
 
-        // Naive Acceleration ...
-        #[cfg(not(feature = "embree"))]
-        let accel = BVHAccel::new(scene);
-        // or Embree ...
+        // Embree scene need to outlive the acceleration structure
         // TODO: Need to found a work around due to the lifetime issue
         #[cfg(feature = "embree")]
         let embree_device = embree::Device::new();
@@ -286,66 +370,115 @@ impl IntegratorType {
         let mut embree_scene = embree::Scene::new(&embree_device);
         #[cfg(feature = "embree")]
         {
-            for m in &scene.meshes {
-                let mut tris = embree::TriangleMesh::unanimated(
-                    &embree_device,
-                    m.indices.len(),
-                    m.vertices.len(),
-                );
-                {
-                    let mut verts = tris.vertex_buffer.map();
-                    let mut tris = tris.index_buffer.map();
-                    for i in 0..m.vertices.len() {
-                        verts[i] = cgmath::Vector4::new(
-                            m.vertices[i].x,
-                            m.vertices[i].y,
-                            m.vertices[i].z,
-                            0.0,
-                        );
+            if scene.accel_type == AccelType::Embree && !scene.instances.is_empty() {
+                warn!("Instances are not supported with Embree, use BVH instead");
+            } else if scene.accel_type == AccelType::Embree {
+                for m in &scene.meshes {
+                    // Note that an empty geometry is still attached
+                    // to keep the geometry ID consistent with the mesh ID
+                    match m.shape {
+                        crate::geometry::ShapeType::Triangles => {}
+                        _ => warn!(
+                            "Only triangles are supported with Embree, ignoring {}",
+                            m.name
+                        ),
                     }
-
-                    for i in 0..m.indices.len() {
-                        tris[i] = cgmath::Vector3::new(
-                            m.indices[i].x as u32,
-                            m.indices[i].y as u32,
-                            m.indices[i].z as u32,
-                        );
+                    let mut tris = embree::TriangleMesh::unanimated(
+                        &embree_device,
+                        m.indices.len(),
+                        m.vertices.len(),
+                    );
+                    {
+                        let mut verts = tris.vertex_buffer.map();
+                        let mut tris = tris.index_buffer.map();
+                        for i in 0..m.vertices.len() {
+                            verts[i] = cgmath::Vector4::new(
+                                m.vertices[i].x,
+                                m.vertices[i].y,
+                                m.vertices[i].z,
+                                0.0,
+                            );
+                        }
+
+                        for i in 0..m.indices.len() {
+                            tris[i] = cgmath::Vector3::new(
+                                m.indices[i].x as u32,
+                                m.indices[i].y as u32,
+                                m.indices[i].z as u32,
+                            );
+                        }
                     }
+                    let mut tri_geom = embree::Geometry::Triangle(tris);
+                    tri_geom.commit();
+                    embree_scene.attach_geometry(tri_geom);
                 }
-                let mut tri_geom = embree::Geometry::Triangle(tris);
-                tri_geom.commit();
-                embree_scene.attach_geometry(tri_geom);
             }
         }
-        #[cfg(feature = "embree")]
-        let accel = EmbreeAcceleration::new(scene, &embree_scene);
+        let accel: Box<dyn Acceleration + '_> = match scene.accel_type {
+            #[cfg(feature = "embree")]
+            AccelType::Embree if scene.instances.is_empty() => {
+                Box::new(EmbreeAcceleration::new(scene, &embree_scene))
+            }
+            accel_type => build_acceleration(scene, accel_type, &scene.accel_options),
+        };
 
         info!("Run Integrator...");
         let start = Instant::now();
 
         let img = match self {
-            IntegratorType::Primal(ref mut v) => v.compute(sampler, &accel, scene),
+            IntegratorType::Primal(ref mut v) => v.compute(sampler, accel.as_ref(), scene),
             IntegratorType::Gradient(ref mut v) => {
-                IntegratorGradient::compute(v.as_mut(), sampler, &accel, scene)
+                IntegratorGradient::compute(v.as_mut(), sampler, accel.as_ref(), scene)
             }
         };
 
         let elapsed = start.elapsed();
         info!("Elapsed Integrator: {} ms", elapsed.as_millis());
 
-        img
+        match &scene.crop {
+            Some(crop) if crop.cropped_output => img.crop(crop.pos, crop.size),
+            _ => img,
+        }
     }
 }
 
 /////////////// Implementation gradients
 pub trait IntegratorMC: Sync + Send {
+    /// Estimate the radiance for an image position
+    /// (the pixel (ix, iy) covers [ix, ix + 1] x [iy, iy + 1])
     fn compute_pixel(
         &self,
-        pix: (u32, u32),
+        pix: Point2<f32>,
         accel: &dyn Acceleration,
         scene: &Scene,
         sampler: &mut dyn Sampler,
     ) -> Color;
+
+    /// Jitter the image position inside the pixel
+    fn antialiasing(&self) -> bool {
+        true
+    }
+
+    /// Image position of a sample inside the pixel (ix, iy)
+    fn sample_pixel(&self, (ix, iy): (u32, u32), sampler: &mut dyn Sampler) -> Point2<f32> {
+        if self.antialiasing() {
+            Point2::new(ix as f32 + sampler.next(), iy as f32 + sampler.next())
+        } else {
+            Point2::new(ix as f32 + 0.5, iy as f32 + 0.5)
+        }
+    }
+
+    /// Box filtered estimate of the pixel (ix, iy) (used by MCMC integrators)
+    fn compute_pixel_box(
+        &self,
+        pix: (u32, u32),
+        accel: &dyn Acceleration,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+    ) -> Color {
+        let pix = self.sample_pixel(pix, sampler);
+        self.compute_pixel(pix, accel, scene, sampler)
+    }
 }
 
 pub fn generate_img_blocks(
@@ -354,16 +487,19 @@ pub fn generate_img_blocks(
     buffernames: &[String],
 ) -> Vec<(BufferCollection, Box<dyn Sampler>)> {
     let mut image_blocks: Vec<(BufferCollection, Box<dyn Sampler>)> = Vec::new();
-    for ix in StepRangeInt::new(0, scene.camera.size().x as usize, 16) {
-        for iy in StepRangeInt::new(0, scene.camera.size().y as usize, 16) {
+    // Only the blocks inside the crop window
+    let (region_pos, region_size) = scene.render_region();
+    let region_max = region_pos + region_size;
+    for ix in StepRangeInt::new(region_pos.x as usize, region_max.x as usize, 16) {
+        for iy in StepRangeInt::new(region_pos.y as usize, region_max.y as usize, 16) {
             let block = BufferCollection::new(
                 Point2 {
                     x: ix as u32,
                     y: iy as u32,
                 },
                 Vector2 {
-                    x: cmp::min(16, scene.camera.size().x - ix as u32),
-                    y: cmp::min(16, scene.camera.size().y - iy as u32),
+                    x: cmp::min(16, region_max.x - ix as u32),
+                    y: cmp::min(16, region_max.y - iy as u32),
                 },
                 buffernames,
             );
@@ -386,17 +522,19 @@ pub fn compute_mc_simple<T: IntegratorMC + Integrator>(
     // Render the image blocks
     let mut image = BufferCollection::new(Point2::new(0, 0), *scene.camera.size(), &buffernames);
 
-    for iy in 0..image.size.y {
-        for ix in 0..image.size.x {
+    let (region_pos, region_size) = scene.render_region();
+    for iy in region_pos.y..(region_pos.y + region_size.y) {
+        for ix in region_pos.x..(region_pos.x + region_size.x) {
             sampler.next_pixel(Point2::new(ix, iy));
             for _ in 0..scene.nb_samples {
-                let c = int.compute_pixel((ix, iy), accel, scene, sampler);
-                image.accumulate(Point2 { x: ix, y: iy }, c, &"primal".to_string());
+                let pix = int.sample_pixel((ix, iy), sampler);
+                let c = int.compute_pixel(pix, accel, scene, sampler);
+                image.splat_filtered(&scene.camera.filter, pix, c, &"primal".to_string());
                 sampler.next_sample();
             }
         }
     }
-    image.scale(1.0 / (scene.nb_samples as f32));
+    image.normalize_filter(&"primal".to_string());
     image
 }
 
@@ -413,27 +551,47 @@ pub fn compute_mc<T: IntegratorMC + Integrator>(
     // Create rendering blocks
     let mut image_blocks = generate_img_blocks(scene, sampler, &buffernames);
 
+    // The samples are splatted outside their blocks
+    // so each block has a margin (the radius of the filter)
+    let filter = &scene.camera.filter;
+    let margin = filter.margin();
+    let film_names = vec!["primal".to_string(), FILTER_WEIGHT.to_string()];
+
     // Render the image blocks
     let progress_bar = Mutex::new(ProgressBar::new(image_blocks.len() as u64));
     let pool = generate_pool(scene);
     pool.install(|| {
         image_blocks.par_iter_mut().for_each(|(im_block, sampler)| {
+            let film_min = Point2::new(
+                im_block.pos.x.saturating_sub(margin),
+                im_block.pos.y.saturating_sub(margin),
+            );
+            let film_max = Point2::new(
+                cmp::min(
+                    im_block.pos.x + im_block.size.x + margin,
+                    scene.camera.size().x,
+                ),
+                cmp::min(
+                    im_block.pos.y + im_block.size.y + margin,
+                    scene.camera.size().y,
+                ),
+            );
+            let mut film = BufferCollection::new(film_min, film_max - film_min, &film_names);
             for iy in 0..im_block.size.y {
                 for ix in 0..im_block.size.x {
                     sampler.next_pixel(Point2::new(ix, iy));
                     for _ in 0..scene.nb_samples {
-                        let c = int.compute_pixel(
+                        let pix = int.sample_pixel(
                             (ix + im_block.pos.x, iy + im_block.pos.y),
-                            accel,
-                            scene,
                             sampler.as_mut(),
                         );
-                        im_block.accumulate(Point2 { x: ix, y: iy }, c, &"primal".to_string());
+                        let c = int.compute_pixel(pix, accel, scene, sampler.as_mut());
+                        film.splat_filtered(filter, pix, c, &"primal".to_string());
                         sampler.next_sample();
                     }
                 }
             }
-            im_block.scale(1.0 / (scene.nb_samples as f32));
+            *im_block = film;
 
             {
                 progress_bar.lock().unwrap().inc();
@@ -442,10 +600,11 @@ pub fn compute_mc<T: IntegratorMC + Integrator>(
     });
 
     // Fill the image
-    let mut image = BufferCollection::new(Point2::new(0, 0), *scene.camera.size(), &buffernames);
+    let mut image = BufferCollection::new(Point2::new(0, 0), *scene.camera.size(), &film_names);
     for (im_block, _) in &image_blocks {
         image.accumulate_bitmap(im_block);
     }
+    image.normalize_filter(&"primal".to_string());
     image
 }
 
@@ -71,14 +71,19 @@ pub mod accel;
 pub mod bsdfs;
 pub mod camera;
 pub mod color;
+pub mod curve;
 pub mod emitter;
+pub mod filter;
 pub mod geometry;
+pub mod ies;
 pub mod integrators;
 pub mod math;
 pub mod paths;
 pub mod samplers;
 pub mod scene;
 pub mod scene_loader;
+pub mod shapes;
+pub mod sky;
 pub mod structure;
 pub mod tools;
 pub mod volume;
@@ -71,6 +71,14 @@ pub fn sample_uniform_sphere(u: Point2<f32>) -> Vector3<f32> {
     Vector3::new(r * phi.cos(), r * phi.sin(), z)
 }
 
+/// Uniform sampling of the cone around +z (pdf: 1 / (2 pi (1 - cos_max)))
+pub fn sample_uniform_cone(u: Point2<f32>, cos_max: f32) -> Vector3<f32> {
+    let z = (1.0 - u.x) + u.x * cos_max;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * std::f32::consts::PI * u.y;
+    Vector3::new(r * phi.cos(), r * phi.sin(), z)
+}
+
 pub fn acos_fast(x: f32) -> f32 {
     // From LTC realtime slides
     let x1 = x.abs();
@@ -334,6 +342,24 @@ impl Frame {
         }
     }
 
+    /// Frame where the x axis is aligned with the tangent (projected)
+    pub fn from_tangent(n: Vector3<f32>, t: Vector3<f32>) -> Frame {
+        let x = t - n * n.dot(t);
+        let l = x.magnitude2();
+        if l == 0.0 {
+            Frame::new(n)
+        } else {
+            let x = x / l.sqrt();
+            Frame {
+                0: Matrix3 {
+                    x,
+                    y: n.cross(x),
+                    z: n,
+                },
+            }
+        }
+    }
+
     pub fn to_world(&self, v: Vector3<f32>) -> Vector3<f32> {
         self.0.x * v.x + self.0.y * v.y + self.0.z * v.z
     }
@@ -357,6 +383,285 @@ pub fn uniform_sample_triangle(u: Point2<f32>) -> Point2<f32> {
     }
 }
 
+/// Clip a convex polygon by an axis aligned rectangle (Sutherland-Hodgman)
+pub fn clip_polygon_rect(
+    poly: &[Vector2<f32>],
+    min: Vector2<f32>,
+    max: Vector2<f32>,
+) -> Vec<Vector2<f32>> {
+    // Each plane is defined by an axis, a bound and a side
+    let planes = [
+        (0, min.x, 1.0),
+        (0, max.x, -1.0),
+        (1, min.y, 1.0),
+        (1, max.y, -1.0),
+    ];
+    let mut res = poly.to_vec();
+    for &(axis, bound, side) in &planes {
+        if res.is_empty() {
+            break;
+        }
+        let dist = |p: &Vector2<f32>| (p[axis] - bound) * side;
+        let input = std::mem::replace(&mut res, vec![]);
+        for i in 0..input.len() {
+            let a = input[i];
+            let b = input[(i + 1) % input.len()];
+            let (da, db) = (dist(&a), dist(&b));
+            if da >= 0.0 {
+                res.push(a);
+            }
+            if (da >= 0.0) != (db >= 0.0) {
+                res.push(a + (b - a) * (da / (da - db)));
+            }
+        }
+    }
+    res
+}
+
+/// Area of a polygon (absolute value)
+pub fn polygon_area(poly: &[Vector2<f32>]) -> f32 {
+    let mut area = 0.0;
+    for i in 0..poly.len() {
+        let a = poly[i];
+        let b = poly[(i + 1) % poly.len()];
+        area += a.x * b.y - a.y * b.x;
+    }
+    (area * 0.5).abs()
+}
+
+/// Uniform sampling inside a convex polygon (fan triangulation)
+pub fn sample_convex_polygon(poly: &[Vector2<f32>], u: Point2<f32>) -> Vector2<f32> {
+    assert!(poly.len() >= 3);
+    let areas = (1..poly.len() - 1)
+        .map(|i| polygon_area(&[poly[0], poly[i], poly[i + 1]]))
+        .collect::<Vec<_>>();
+    let total = areas.iter().sum::<f32>();
+
+    // Select the triangle and reuse the random number
+    let mut v = u.x * total;
+    let mut i = 0;
+    while i < areas.len() - 1 && v >= areas[i] {
+        v -= areas[i];
+        i += 1;
+    }
+    let ux = if areas[i] > 0.0 {
+        (v / areas[i]).min(crate::constants::ONE_MINUS_EPSILON)
+    } else {
+        0.0
+    };
+
+    let b = uniform_sample_triangle(Point2::new(ux, u.y));
+    poly[0] * b.x + poly[i + 1] * b.y + poly[i + 2] * (1.0 - b.x - b.y)
+}
+
+/// Angle between two normalized vectors (numerically stable)
+fn angle_between(v1: Vector3<f32>, v2: Vector3<f32>) -> f32 {
+    if v1.dot(v2) < 0.0 {
+        std::f32::consts::PI - 2.0 * ((v1 + v2).magnitude() * 0.5).min(1.0).asin()
+    } else {
+        2.0 * ((v2 - v1).magnitude() * 0.5).min(1.0).asin()
+    }
+}
+
+/// Solid angle subtended by the triangle (v0, v1, v2) from o (Van Oosterom and Strackee)
+pub fn spherical_triangle_area(
+    o: Point3<f32>,
+    v0: Point3<f32>,
+    v1: Point3<f32>,
+    v2: Point3<f32>,
+) -> f32 {
+    let a = (v0 - o).normalize();
+    let b = (v1 - o).normalize();
+    let c = (v2 - o).normalize();
+    let num = a.dot(b.cross(c)).abs();
+    let den = 1.0 + a.dot(b) + b.dot(c) + c.dot(a);
+    (2.0 * num.atan2(den)).abs()
+}
+
+/// Uniform sampling of the solid angle subtended by the triangle (v0, v1, v2) from o (Arvo)
+/// The pdf is one over the solid angle (see spherical_triangle_area)
+/// None if the spherical triangle is degenerated
+pub fn sample_spherical_triangle(
+    o: Point3<f32>,
+    v0: Point3<f32>,
+    v1: Point3<f32>,
+    v2: Point3<f32>,
+    u: Point2<f32>,
+) -> Option<Vector3<f32>> {
+    use std::f32::consts::PI;
+    let a = (v0 - o).normalize();
+    let b = (v1 - o).normalize();
+    let c = (v2 - o).normalize();
+
+    // Normals of the great circles
+    let n_ab = a.cross(b);
+    let n_bc = b.cross(c);
+    let n_ca = c.cross(a);
+    if n_ab.magnitude2() == 0.0 || n_bc.magnitude2() == 0.0 || n_ca.magnitude2() == 0.0 {
+        return None;
+    }
+    let (n_ab, n_bc, n_ca) = (n_ab.normalize(), n_bc.normalize(), n_ca.normalize());
+
+    // Angles at the vertices and spherical excess
+    let alpha = angle_between(n_ab, -n_ca);
+    let beta = angle_between(n_bc, -n_ab);
+    let gamma = angle_between(n_ca, -n_bc);
+    let area = alpha + beta + gamma - PI;
+    if !(area > 0.0) {
+        return None;
+    }
+
+    // Sample the sub-triangle area to find the vertex c'
+    let (sin_area, cos_area) = (PI + u.x * area).sin_cos();
+    let (sin_alpha, cos_alpha) = alpha.sin_cos();
+    let s = sin_area * cos_alpha - cos_area * sin_alpha;
+    let t = cos_area * cos_alpha + sin_area * sin_alpha;
+    let k1 = t + cos_alpha;
+    let k2 = s - sin_alpha * a.dot(b);
+    let denom = (k2 * s + k1 * t) * sin_alpha;
+    let cos_b = if denom != 0.0 {
+        crate::clamp((k2 + (k2 * t - k1 * s) * cos_alpha) / denom, -1.0, 1.0)
+    } else {
+        1.0
+    };
+    let sin_b = (1.0 - cos_b * cos_b).max(0.0).sqrt();
+    let c_perp = c - a * c.dot(a);
+    let c_perp = if c_perp.magnitude2() > 0.0 {
+        c_perp.normalize()
+    } else {
+        return None;
+    };
+    let c_p = a * cos_b + c_perp * sin_b;
+
+    // Sample along the arc between b and c'
+    let cos_theta = 1.0 - u.y * (1.0 - c_p.dot(b));
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let c_p_perp = c_p - b * c_p.dot(b);
+    let d = if c_p_perp.magnitude2() > 0.0 {
+        b * cos_theta + c_p_perp.normalize() * sin_theta
+    } else {
+        b
+    };
+    Some(d.normalize())
+}
+
+/// Rectangle seen from a point for solid angle sampling (Ureña et al. 2013)
+pub struct SphericalRectangle {
+    o: Point3<f32>,
+    x: Vector3<f32>,
+    y: Vector3<f32>,
+    z: Vector3<f32>,
+    z0: f32,
+    x0: f32,
+    y0: f32,
+    x1: f32,
+    y1: f32,
+    b0: f32,
+    b1: f32,
+    k: f32,
+    /// Solid angle subtended by the rectangle
+    pub solid_angle: f32,
+}
+
+impl SphericalRectangle {
+    /// Rectangle defined by a corner and two orthogonal edges
+    /// None if the rectangle is seen from its plane
+    pub fn new(
+        o: Point3<f32>,
+        corner: Point3<f32>,
+        ex: Vector3<f32>,
+        ey: Vector3<f32>,
+    ) -> Option<SphericalRectangle> {
+        use std::f32::consts::PI;
+        let ex_l = ex.magnitude();
+        let ey_l = ey.magnitude();
+        let x = ex / ex_l;
+        let y = ey / ey_l;
+        let mut z = x.cross(y);
+
+        // Local reference system
+        let d = corner - o;
+        let mut z0 = d.dot(z);
+        if z0 > 0.0 {
+            z = -z;
+            z0 = -z0;
+        }
+        if z0 == 0.0 {
+            return None;
+        }
+        let x0 = d.dot(x);
+        let y0 = d.dot(y);
+        let x1 = x0 + ex_l;
+        let y1 = y0 + ey_l;
+
+        // Normals of the planes containing the edges
+        let v00 = Vector3::new(x0, y0, z0);
+        let v01 = Vector3::new(x0, y1, z0);
+        let v10 = Vector3::new(x1, y0, z0);
+        let v11 = Vector3::new(x1, y1, z0);
+        let n0 = v00.cross(v10).normalize();
+        let n1 = v10.cross(v11).normalize();
+        let n2 = v11.cross(v01).normalize();
+        let n3 = v01.cross(v00).normalize();
+
+        // Internal angles
+        let acos = |v: f32| crate::clamp(v, -1.0, 1.0).acos();
+        let g0 = acos(-n0.dot(n1));
+        let g1 = acos(-n1.dot(n2));
+        let g2 = acos(-n2.dot(n3));
+        let g3 = acos(-n3.dot(n0));
+        let k = 2.0 * PI - g2 - g3;
+        let solid_angle = g0 + g1 - k;
+        if !(solid_angle > 0.0) {
+            return None;
+        }
+
+        Some(SphericalRectangle {
+            o,
+            x,
+            y,
+            z,
+            z0,
+            x0,
+            y0,
+            x1,
+            y1,
+            b0: n0.z,
+            b1: n2.z,
+            k,
+            solid_angle,
+        })
+    }
+
+    /// Point on the rectangle sampled uniformly in solid angle
+    pub fn sample(&self, u: Point2<f32>) -> Point3<f32> {
+        // Compute cu
+        let au = u.x * self.solid_angle + self.k;
+        let fu = (au.cos() * self.b0 - self.b1) / au.sin();
+        let cu = (1.0 / (fu * fu + self.b0 * self.b0).sqrt()).copysign(fu);
+        let cu = crate::clamp(cu, -1.0, 1.0);
+
+        // Compute xu
+        let xu = -(cu * self.z0) / (1.0 - cu * cu).max(1e-12).sqrt();
+        let xu = crate::clamp(xu, self.x0, self.x1);
+
+        // Compute yv
+        let d = (xu * xu + self.z0 * self.z0).sqrt();
+        let h0 = self.y0 / (d * d + self.y0 * self.y0).sqrt();
+        let h1 = self.y1 / (d * d + self.y1 * self.y1).sqrt();
+        let hv = h0 + u.y * (h1 - h0);
+        let hv2 = hv * hv;
+        let yv = if hv2 < 1.0 - 1e-6 {
+            (hv * d) / (1.0 - hv2).sqrt()
+        } else {
+            self.y1
+        };
+        let yv = crate::clamp(yv, self.y0, self.y1);
+
+        self.o + self.x * xu + self.y * yv + self.z * self.z0
+    }
+}
+
 /// Create 1D distribution
 #[derive(Debug)]
 pub struct Distribution1DConstruct {
@@ -566,3 +871,144 @@ pub fn next_float_down(v: f32) -> f32 {
         bits_to_float(ui)
     }
 }
+
+/// Conservative bound on the floating-point error after n operations (PBRT)
+pub fn gamma(n: i32) -> f32 {
+    let e = std::f32::EPSILON * 0.5;
+    (n as f32 * e) / (1.0 - n as f32 * e)
+}
+
+/// Offset the ray origin outside the error bounds of the point (PBRT)
+/// so the spawned ray does not intersect again the surface
+pub fn offset_ray_origin(
+    p: &Point3<f32>,
+    p_error: &Vector3<f32>,
+    n: &Vector3<f32>,
+    w: &Vector3<f32>,
+) -> Point3<f32> {
+    let d = abs_vec(n).dot(*p_error);
+    let mut offset = n * d;
+    if w.dot(*n) < 0.0 {
+        offset = -offset;
+    }
+    let mut po = p + offset;
+    // Round the offset point away from p
+    for i in 0..3 {
+        if offset[i] > 0.0 {
+            po[i] = next_float_up(po[i]);
+        } else if offset[i] < 0.0 {
+            po[i] = next_float_down(po[i]);
+        }
+    }
+    po
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Average of f over a regular grid of [0, 1]^2
+    fn integrate_grid<F: FnMut(Point2<f32>) -> f32>(n: usize, mut f: F) -> f32 {
+        let mut sum = 0.0;
+        for y in 0..n {
+            for x in 0..n {
+                sum += f(Point2::new(
+                    (x as f32 + 0.5) / n as f32,
+                    (y as f32 + 0.5) / n as f32,
+                ));
+            }
+        }
+        sum / (n * n) as f32
+    }
+
+    #[test]
+    fn spherical_triangle_octant() {
+        let o = Point3::new(0.0, 0.0, 0.0);
+        let (v0, v1, v2) = (
+            Point3::new(1.0, 0.0, 0.0),
+            Point3::new(0.0, 1.0, 0.0),
+            Point3::new(0.0, 0.0, 1.0),
+        );
+        let area = spherical_triangle_area(o, v0, v1, v2);
+        assert!((area - std::f32::consts::FRAC_PI_2).abs() < 1e-5);
+
+        // Uniform directions over the octant: E[d] = (1/2, 1/2, 1/2)
+        let mut mean = Vector3::new(0.0, 0.0, 0.0);
+        const N: usize = 128;
+        for y in 0..N {
+            for x in 0..N {
+                let u = Point2::new((x as f32 + 0.5) / N as f32, (y as f32 + 0.5) / N as f32);
+                let d = sample_spherical_triangle(o, v0, v1, v2, u).unwrap();
+                assert!(d.x >= -1e-5 && d.y >= -1e-5 && d.z >= -1e-5);
+                assert!((d.magnitude() - 1.0).abs() < 1e-4);
+                mean += d / (N * N) as f32;
+            }
+        }
+        for i in 0..3 {
+            assert!((mean[i] - 0.5).abs() < 1e-3, "mean: {:?}", mean);
+        }
+    }
+
+    #[test]
+    fn spherical_rectangle_square() {
+        // Square [-1, 1]^2 at the distance 1: 4 asin(1 / 2)
+        let o = Point3::new(0.0, 0.0, 0.0);
+        let corner = Point3::new(-1.0, -1.0, 1.0);
+        let (ex, ey) = (Vector3::new(2.0, 0.0, 0.0), Vector3::new(0.0, 2.0, 0.0));
+        let rect = SphericalRectangle::new(o, corner, ex, ey).unwrap();
+        let expected = 4.0 * 0.5f32.asin();
+        assert!((rect.solid_angle - expected).abs() < 1e-4);
+        let tris = spherical_triangle_area(o, corner, corner + ex, corner + ex + ey)
+            + spherical_triangle_area(o, corner, corner + ex + ey, corner + ey);
+        assert!((rect.solid_angle - tris).abs() < 1e-4);
+
+        // Uniform in solid angle: E[cos] = 1/Ω ∫ cos^2 / r^2 dA
+        let mean_cos = integrate_grid(128, |u| {
+            let p = rect.sample(u);
+            assert!(p.x >= -1.0 - 1e-4 && p.x <= 1.0 + 1e-4);
+            assert!(p.y >= -1.0 - 1e-4 && p.y <= 1.0 + 1e-4);
+            assert!((p.z - 1.0).abs() < 1e-4);
+            p.to_vec().normalize().z
+        });
+        let expected_cos = integrate_grid(512, |u| {
+            let r2 = (2.0 * u.x - 1.0).powi(2) + (2.0 * u.y - 1.0).powi(2) + 1.0;
+            4.0 / (r2 * r2)
+        }) / rect.solid_angle;
+        assert!(
+            (mean_cos - expected_cos).abs() < 1e-3,
+            "{} != {}",
+            mean_cos,
+            expected_cos
+        );
+    }
+
+    #[test]
+    fn distribution_1d() {
+        let mut dist = Distribution1DConstruct::new(4);
+        for v in &[1.0, 0.0, 2.0, 1.0] {
+            dist.add(*v);
+        }
+        let dist = dist.normalize();
+        assert!((dist.total() - 4.0).abs() < 1e-5);
+        let pdfs = (0..4).map(|i| dist.pdf(i)).collect::<Vec<_>>();
+        for (pdf, expected) in pdfs.iter().zip(&[0.25, 0.0, 0.5, 0.25]) {
+            assert!((pdf - expected).abs() < 1e-6);
+        }
+        assert_eq!(dist.sample_discrete(0.1), 0);
+        assert_eq!(dist.sample_discrete(0.3), 2);
+        assert_eq!(dist.sample_discrete(0.8), 3);
+        assert!((dist.sample_continuous(0.5) - 2.5).abs() < 1e-5);
+    }
+
+    #[test]
+    fn offset_ray_origin_outside_error() {
+        let p = Point3::new(1000.0, 1.0, -3.0);
+        let p_error = gamma(7) * abs_vec(&p.to_vec());
+        let n = Vector3::new(0.0, 0.0, 1.0);
+        let po = offset_ray_origin(&p, &p_error, &n, &n);
+        assert!(po.z - p.z > p_error.z);
+        let po = offset_ray_origin(&p, &p_error, &n, &-n);
+        assert!(p.z - po.z > p_error.z);
+        assert!(next_float_up(1.0) > 1.0 && next_float_down(1.0) < 1.0);
+    }
+}
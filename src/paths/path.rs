@@ -15,12 +15,15 @@ pub struct EdgeID(usize);
 pub struct Path<'scene> {
     vertices: Vec<Vertex<'scene>>,
     edges: Vec<Edge>,
+    /// Time of the path inside the camera shutter
+    pub time: f32,
 }
 impl<'scene, 'emitter> Default for Path<'scene> {
     fn default() -> Self {
         Path {
             vertices: vec![],
             edges: vec![],
+            time: 0.0,
         }
     }
 }
@@ -37,6 +40,7 @@ impl<'scene, 'emitter> Path<'scene> {
         scene: &'scene Scene,
         sampler: &mut dyn Sampler,
     ) -> (VertexID, Color) {
+        self.time = scene.camera.sample_time(sampler.next_time());
         let (emitter, sampled_point, flux) = scene.emitters().random_sample_emitter_position(
             sampler.next(),
             sampler.next(),
@@ -58,14 +62,27 @@ impl<'scene, 'emitter> Path<'scene> {
         img_pos: Point2<u32>,
         scene: &Scene,
         sampler: &mut dyn Sampler,
+    ) -> (VertexID, Color) {
+        let uv = Point2::new(
+            img_pos.x as f32 + sampler.next(),
+            img_pos.y as f32 + sampler.next(),
+        );
+        self.from_sensor_at(uv, scene, sampler)
+    }
+    /// Generate a root path from an image position
+    pub fn from_sensor_at(
+        &mut self,
+        uv: Point2<f32>,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
     ) -> (VertexID, Color) {
         // Only generate a path from the sensor
+        let uv_lens = sampler.next2d();
+        self.time = scene.camera.sample_time(sampler.next_time());
         let root = Vertex::Sensor {
-            uv: Point2::new(
-                img_pos.x as f32 + sampler.next(),
-                img_pos.y as f32 + sampler.next(),
-            ),
-            pos: scene.camera.position(),
+            uv,
+            uv_lens,
+            pos: scene.camera.generate_at(uv, uv_lens, self.time).o,
             edge_in: None,
             edge_out: None,
         };
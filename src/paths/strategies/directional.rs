@@ -23,9 +23,9 @@ impl DirectionalSamplingStrategy {
         depth: u32,
     ) -> (Option<EdgeID>, Option<VertexID>) {
         match path.vertex(vertex_id) {
-            Vertex::Sensor { uv, .. } => {
+            Vertex::Sensor { uv, uv_lens, .. } => {
                 // Generate the path from the sensor
-                let ray = scene.camera.generate(*uv);
+                let ray = scene.camera.generate_at(*uv, *uv_lens, path.time);
                 let (edge, new_vertex) = Edge::from_ray(
                     path,
                     &ray,
@@ -136,7 +136,7 @@ impl DirectionalSamplingStrategy {
                 throughput.scale(rr_weight);
 
                 // Generate the new ray and do the intersection
-                let ray = Ray::new(*pos, sampled_phase.d);
+                let ray = Ray::new(*pos, sampled_phase.d).with_time(path.time);
                 let (edge, new_vertex) = Edge::from_ray(
                     path,
                     &ray,
@@ -188,7 +188,7 @@ impl DirectionalSamplingStrategy {
                 // This will generate the edge
                 // if there is a participating media
                 // the edge will be generated properly
-                let ray = Ray::new(*pos, d);
+                let ray = Ray::new(*pos, d).with_time(path.time);
                 let (edge, new_vertex) = Edge::from_ray(
                     path,
                     &ray,
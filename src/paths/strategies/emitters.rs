@@ -31,6 +31,7 @@ impl LightSamplingStrategy {
                             n,
                             uv: None,
                             dir: ray.d,
+                            primitive_id: None,
                         },
                         None,
                         None,
@@ -76,6 +77,7 @@ impl LightSamplingStrategy {
                                 n: *n,
                                 uv: *uv,
                                 dir: ray.d,
+                                primitive_id: *primitive_id,
                             },
                             None,
                             *primitive_id,
@@ -122,7 +124,7 @@ impl SamplingStrategy for LightSamplingStrategy {
                     sampler.next(),
                     sampler.next2d(),
                 );
-                let visible = accel.visible(&its.p, &light_record.p);
+                let visible = accel.visible_its(&its, &light_record.p);
                 if light_record.is_valid() && visible {
                     // We create a new vertex as it is a light
                     let next_vertex = Vertex::Light {
@@ -190,7 +192,7 @@ impl SamplingStrategy for LightSamplingStrategy {
                     sampler.next(),
                     sampler.next2d(),
                 );
-                let visible = accel.visible(&pos, &light_record.p);
+                let visible = accel.visible_at(&pos, &light_record.p, path.time);
                 if light_record.is_valid() && visible {
                     let next_vertex = Vertex::Light {
                         pos: light_record.p,
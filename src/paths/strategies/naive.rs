@@ -24,9 +24,9 @@ impl NaiveSamplingStrategy {
         depth: u32,
     ) -> (Option<EdgeID>, Option<VertexID>) {
         match path.vertex(vertex_id) {
-            Vertex::Sensor { uv, .. } => {
+            Vertex::Sensor { uv, uv_lens, .. } => {
                 // Generate the path from the sensor
-                let ray = scene.camera.generate(*uv);
+                let ray = scene.camera.generate_at(*uv, *uv_lens, path.time);
                 let (edge, new_vertex) = Edge::from_ray(
                     path,
                     &ray,
@@ -144,7 +144,7 @@ impl NaiveSamplingStrategy {
                 throughput.scale(rr_weight);
 
                 // Generate the new ray and do the intersection
-                let ray = Ray::new(*pos, d);
+                let ray = Ray::new(*pos, d).with_time(path.time);
                 let (edge, new_vertex) = Edge::from_ray(
                     path,
                     &ray,
@@ -171,7 +171,7 @@ impl NaiveSamplingStrategy {
 
                 let frame = Frame::new(*n);
                 let d_out_global = frame.to_world(d_out);
-                let ray = Ray::new(*pos, d_out_global);
+                let ray = Ray::new(*pos, d_out_global).with_time(path.time);
                 // FIXME: This might be wrong!
                 let weight = Color::one(); // Perfectly importance sampled
 
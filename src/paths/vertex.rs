@@ -9,6 +9,7 @@ use cgmath::*;
 pub enum Vertex<'scene> {
     Sensor {
         uv: Point2<f32>,
+        uv_lens: Point2<f32>,
         pos: Point3<f32>,
         edge_in: Option<EdgeID>,
         edge_out: Option<EdgeID>,
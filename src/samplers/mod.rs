@@ -3,6 +3,10 @@ use cgmath::Point2;
 pub trait Sampler: Send {
     fn next(&mut self) -> f32;
     fn next2d(&mut self) -> Point2<f32>;
+    /// Time dimension (camera shutter)
+    fn next_time(&mut self) -> f32 {
+        self.next()
+    }
     fn clone_box(&mut self) -> Box<dyn Sampler>;
     fn next_sample(&mut self);
     fn next_pixel(&mut self, p: Point2<u32>);
@@ -1,3 +1,4 @@
+use crate::accel::{AccelType, BVHBuildOptions};
 use crate::camera::Camera;
 use crate::emitter::*;
 use crate::geometry;
@@ -12,6 +13,15 @@ pub enum EmittersState {
     Build(EmitterSampler),
 }
 
+/// Region of the image to render (in pixels)
+#[derive(Clone, Debug)]
+pub struct CropWindow {
+    pub pos: Point2<u32>,
+    pub size: Vector2<u32>,
+    /// Only save the region (otherwise, embedded inside the full image)
+    pub cropped_output: bool,
+}
+
 /// Scene representation
 pub struct Scene {
     /// Main camera
@@ -19,8 +29,17 @@ pub struct Scene {
     pub nb_samples: usize,
     pub nb_threads: Option<usize>,
     pub output_img_path: String,
+    pub crop: Option<CropWindow>,
+    // Acceleration structure configuration
+    pub accel_type: AccelType,
+    pub accel_options: BVHBuildOptions,
+    // Sampling strategy of the mesh emitters
+    pub direct_sampling: geometry::DirectSampling,
     // Geometry information
     pub meshes: Vec<Arc<geometry::Mesh>>,
+    // Instancing (shared objects and their placements)
+    pub objects: Vec<geometry::InstanceObject>,
+    pub instances: Vec<geometry::Instance>,
     pub emitter_environment: Option<Arc<EnvironmentLight>>,
     pub volume: Option<volume::HomogenousVolume>,
     // Internal building
@@ -42,6 +61,101 @@ impl Scene {
         self.nb_samples = n;
         self
     }
+    pub fn accel(mut self, accel_type: AccelType) -> Self {
+        self.accel_type = accel_type;
+        self
+    }
+    /// Only render a region of the image (clamped to the image size)
+    pub fn crop(mut self, pos: Point2<u32>, size: Vector2<u32>, cropped_output: bool) -> Self {
+        let img = *self.camera.size();
+        let pos = Point2::new(
+            pos.x.min(img.x.saturating_sub(1)),
+            pos.y.min(img.y.saturating_sub(1)),
+        );
+        let size = Vector2::new(
+            size.x.min(img.x.saturating_sub(pos.x)),
+            size.y.min(img.y.saturating_sub(pos.y)),
+        );
+        if size.x == 0 || size.y == 0 {
+            warn!("Empty crop window: {:?} (size: {:?})", pos, size);
+        }
+        info!("Crop window: {:?} (size: {:?})", pos, size);
+        self.crop = Some(CropWindow {
+            pos,
+            size,
+            cropped_output,
+        });
+        self
+    }
+
+    /// Region of the image to render (position and size)
+    pub fn render_region(&self) -> (Point2<u32>, Vector2<u32>) {
+        match &self.crop {
+            None => (Point2::new(0, 0), *self.camera.size()),
+            Some(c) => (c.pos, c.size),
+        }
+    }
+
+    /// Strategy used to sample the mesh emitters (next event estimation)
+    pub fn direct_sampling(mut self, mode: geometry::DirectSampling) -> Self {
+        self.direct_sampling = mode;
+        self
+    }
+
+    pub fn bvh_cache(mut self, dir: &str) -> Self {
+        self.accel_options.cache_dir = Some(std::path::PathBuf::from(dir));
+        self
+    }
+
+    /// Animate a mesh with keyframed transforms (motion blur)
+    /// The mesh is moved inside an animated instance,
+    /// so the ids of the following meshes are shifted.
+    pub fn animate_mesh(&mut self, mesh_id: usize, motion: geometry::Motion) {
+        if self.meshes[mesh_id].is_light() {
+            warn!(
+                "Animated emitters are not supported, ignoring the motion of {}",
+                self.meshes[mesh_id].name
+            );
+            return;
+        }
+        let mesh = self.meshes.remove(mesh_id);
+        self.objects.push(geometry::InstanceObject {
+            name: mesh.name.clone(),
+            meshes: vec![mesh],
+        });
+        self.instances.push(
+            geometry::Instance::new(self.objects.len() - 1, Matrix4::identity())
+                .unwrap()
+                .with_motion(motion),
+        );
+    }
+
+    /// Add a mesh that is not part of the scene file (e.g. hair curves)
+    /// Need to be called before building the emitters
+    pub fn add_mesh(&mut self, mesh: geometry::Mesh) {
+        self.meshes.push(Arc::new(mesh));
+    }
+
+    /// Add an emitter that is not attached to a mesh (e.g. spot light)
+    /// Need to be called before building the emitters
+    pub fn add_emitter(&mut self, emitter: Box<dyn Emitter>) {
+        match &mut self.emitters {
+            None => self.emitters = Some(EmittersState::Unbuild(vec![emitter])),
+            Some(EmittersState::Unbuild(emitters)) => emitters.push(emitter),
+            Some(EmittersState::Build(_)) => panic!("Emitters are already build"),
+        }
+    }
+
+    /// Replace the environment by a physical sky and add its sun
+    pub fn sunsky(&mut self, sky: crate::sky::Sky) {
+        if self.emitter_environment.is_some() {
+            warn!("The environment map is replaced by the sky");
+        }
+        self.add_emitter(Box::new(SunEmitter::from_sky(&sky)));
+        self.emitter_environment = Some(Arc::new(EnvironmentLight::new(
+            EnvironmentLightColor::new_sky(sky),
+        )));
+    }
 
     pub fn emitters(&self) -> &EmitterSampler {
         match &self.emitters {
@@ -56,9 +170,29 @@ impl Scene {
         for m in &self.meshes {
             aabb = aabb.union_aabb(&m.compute_aabb());
         }
+        for i in &self.instances {
+            aabb = aabb.union_aabb(&i.compute_aabb(&self.objects[i.object]));
+        }
         aabb = aabb.union_vec(&self.camera.position().to_vec());
         self.bsphere = Some(aabb.to_sphere());
 
+        // Configure the sampling of the mesh emitters
+        // (textured emitters are sampled proportionally to their power)
+        let direct_sampling = self.direct_sampling;
+        for m in &mut self.meshes {
+            if m.is_light() {
+                match Arc::get_mut(m) {
+                    Some(m) => {
+                        if m.emission_distribution.is_none() {
+                            m.build_emission_distribution();
+                        }
+                        m.set_direct_sampling(direct_sampling);
+                    }
+                    None => warn!("Shared emitter {}, use area sampling", m.name),
+                }
+            }
+        }
+
         // Append emission mesh to the emitter list
         let mut emitters: Vec<Arc<dyn Emitter>> = vec![];
         for e in &self.meshes {
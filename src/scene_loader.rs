@@ -1,4 +1,4 @@
-use crate::camera::{Camera, Fov};
+use crate::camera::{Aperture, Camera, Fov, ThinLens};
 use crate::emitter::*;
 use crate::geometry;
 use crate::scene::*;
@@ -73,10 +73,115 @@ fn convert_spectrum_to_color(
     }
 }
 
+/// Tokens of a PBRT file (the quoted strings keep their quotes)
+#[cfg(feature = "pbrt")]
+struct PBRTTokens<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+#[cfg(feature = "pbrt")]
+impl<'a> Iterator for PBRTTokens<'a> {
+    type Item = String;
+    fn next(&mut self) -> Option<String> {
+        loop {
+            match self.chars.next()? {
+                '#' => {
+                    // Comment until the end of the line
+                    while self.chars.next().map_or(false, |c| c != '\n') {}
+                }
+                '"' => {
+                    let mut token = String::from("\"");
+                    for c in &mut self.chars {
+                        token.push(c);
+                        if c == '"' {
+                            break;
+                        }
+                    }
+                    return Some(token);
+                }
+                c @ '[' | c @ ']' => return Some(c.to_string()),
+                c if c.is_whitespace() => {}
+                c => {
+                    let mut token = c.to_string();
+                    while let Some(&c) = self.chars.peek() {
+                        if c.is_whitespace() || c == '"' || c == '[' || c == ']' || c == '#' {
+                            break;
+                        }
+                        token.push(c);
+                        self.chars.next();
+                    }
+                    return Some(token);
+                }
+            }
+        }
+    }
+}
+
+/// Statement of a PBRT file that pbrt_rs does not keep entirely
+/// (camera type and lens, film crop window). Only the float parameters are read.
+#[cfg(feature = "pbrt")]
+struct PBRTStatement {
+    name: String,
+    floats: HashMap<String, Vec<f32>>,
+}
+#[cfg(feature = "pbrt")]
+impl PBRTStatement {
+    /// First statement with this keyword inside the file (the includes are not followed)
+    fn read(filename: &str, keyword: &str) -> Option<PBRTStatement> {
+        let content = std::fs::read_to_string(filename).ok()?;
+        let mut tokens = PBRTTokens {
+            chars: content.chars().peekable(),
+        }
+        .skip_while(|t| t != keyword)
+        .skip(1)
+        .peekable();
+        let name = tokens.next()?.trim_matches('"').to_string();
+        // Parameters: "type name" value or "type name" [values]
+        let mut floats = HashMap::new();
+        while let Some(param) = tokens.next_if(|t| t.starts_with('"')) {
+            let param = param
+                .trim_matches('"')
+                .split_whitespace()
+                .collect::<Vec<_>>();
+            let values = match tokens.next() {
+                Some(t) if t == "[" => tokens.by_ref().take_while(|t| t != "]").collect(),
+                Some(t) => vec![t],
+                None => vec![],
+            };
+            if let ["float", param_name] = param[..] {
+                let values = values
+                    .iter()
+                    .filter_map(|v| v.parse::<f32>().ok())
+                    .collect::<Vec<_>>();
+                floats.insert(param_name.to_string(), values);
+            }
+        }
+        Some(PBRTStatement { name, floats })
+    }
+
+    fn float(&self, name: &str) -> Option<f32> {
+        self.floats.get(name).and_then(|v| v.first().copied())
+    }
+}
+
 #[cfg(feature = "pbrt")]
 pub struct PBRTSceneLoader {}
 #[cfg(feature = "pbrt")]
 impl PBRTSceneLoader {
+    fn convert_bsdf(
+        material_name: &Option<String>,
+        materials: &HashMap<String, pbrt_rs::BSDF>,
+        textures: &HashMap<String, pbrt_rs::Texture>,
+    ) -> Box<dyn bsdfs::BSDF> {
+        if let Some(ref name) = material_name {
+            if let Some(bsdf_name) = materials.get(name) {
+                return bsdfs::bsdf_pbrt(bsdf_name, &textures);
+            }
+        }
+        Box::new(bsdfs::diffuse::BSDFDiffuse {
+            diffuse: bsdfs::BSDFColor::Constant(Color::value(0.5)),
+        })
+    }
+
     fn transform_mesh(
         mut m: pbrt_rs::ShapeInfo,
         materials: &HashMap<String, pbrt_rs::BSDF>,
@@ -85,10 +190,22 @@ impl PBRTSceneLoader {
         use_shading_normal: bool,
     ) -> Option<Mesh> {
         match m.data {
-            pbrt_rs::Shape::Ply { filename, .. } => {
+            pbrt_rs::Shape::Ply {
+                filename, alpha, ..
+            } => {
                 m.data =
                     pbrt_rs::ply::read_ply(std::path::Path::new(&filename), false).to_trimesh();
-                PBRTSceneLoader::transform_mesh(m, materials, textures, matrix, use_shading_normal)
+                let mut mesh = PBRTSceneLoader::transform_mesh(
+                    m,
+                    materials,
+                    textures,
+                    matrix,
+                    use_shading_normal,
+                )?;
+                // The opacity mask is not part of the PLY data
+                mesh.alpha = alpha.as_ref().and_then(|a| bsdfs::alpha_pbrt(a, textures));
+                mesh.check_alpha();
+                Some(mesh)
             }
             pbrt_rs::Shape::TriMesh {
                 uv,
@@ -121,19 +238,7 @@ impl PBRTSceneLoader {
                     .map(|n| mat.transform_point(n.clone()).to_vec())
                     .collect();
 
-                let bsdf = if let Some(ref name) = m.material_name {
-                    if let Some(bsdf_name) = materials.get(name) {
-                        bsdfs::bsdf_pbrt(bsdf_name, &textures)
-                    } else {
-                        Box::new(bsdfs::diffuse::BSDFDiffuse {
-                            diffuse: bsdfs::BSDFColor::Constant(Color::value(0.5)),
-                        })
-                    }
-                } else {
-                    Box::new(bsdfs::diffuse::BSDFDiffuse {
-                        diffuse: bsdfs::BSDFColor::Constant(Color::value(0.5)),
-                    })
-                };
+                let bsdf = PBRTSceneLoader::convert_bsdf(&m.material_name, materials, textures);
                 let mesh = geometry::Mesh::new("noname".to_string(), points, indices, normals, uv);
 
                 if let Some(mut mesh) = mesh {
@@ -148,6 +253,61 @@ impl PBRTSceneLoader {
                     None
                 }
             }
+            pbrt_rs::Shape::Sphere {
+                radius,
+                z_min,
+                z_max,
+                phi_max,
+            } => {
+                if z_min > -radius || z_max < radius || phi_max < 360.0 {
+                    warn!("Partial spheres are not supported, use the full sphere instead");
+                }
+                let mat = matrix * m.matrix;
+                let sphere = crate::shapes::Sphere {
+                    center: mat.transform_point(Point3::new(0.0, 0.0, 0.0)),
+                    radius: mat
+                        .transform_vector(Vector3::new(radius, 0.0, 0.0))
+                        .magnitude(),
+                };
+                let mut mesh = geometry::Mesh::from_shape(
+                    "sphere".to_string(),
+                    geometry::ShapeType::Sphere(sphere),
+                );
+                mesh.bsdf = PBRTSceneLoader::convert_bsdf(&m.material_name, materials, textures);
+                if let Some(emission) = &m.emission {
+                    mesh.emission = geometry::EmissionType::Color {
+                        v: convert_spectrum_to_color(emission, None),
+                    };
+                }
+                Some(mesh)
+            }
+            pbrt_rs::Shape::Disk {
+                height,
+                radius,
+                inner_radius,
+                phi_max,
+            } => {
+                if inner_radius != 0.0 || phi_max < 360.0 {
+                    warn!("Partial disks are not supported, use the full disk instead");
+                }
+                let mat = matrix * m.matrix;
+                let n = mat.transform_vector(Vector3::new(0.0, 0.0, 1.0));
+                let disk = crate::shapes::Disk::new(
+                    mat.transform_point(Point3::new(0.0, 0.0, height)),
+                    if m.reverse_orientation { -n } else { n },
+                    mat.transform_vector(Vector3::new(radius, 0.0, 0.0))
+                        .magnitude(),
+                );
+                let mut mesh =
+                    geometry::Mesh::from_shape("disk".to_string(), geometry::ShapeType::Disk(disk));
+                mesh.bsdf = PBRTSceneLoader::convert_bsdf(&m.material_name, materials, textures);
+                if let Some(emission) = &m.emission {
+                    mesh.emission = geometry::EmissionType::Color {
+                        v: convert_spectrum_to_color(emission, None),
+                    };
+                }
+                Some(mesh)
+            }
             _ => {
                 warn!("All mesh should be converted to trimesh: {:?}", m.data);
                 None
@@ -182,10 +342,15 @@ impl SceneLoader for PBRTSceneLoader {
             .map(|m| m.unwrap())
             .collect::<Vec<_>>();
 
+        // Objects are converted once and shared between their instances
+        // except if they contain emitters (flattened to be sampled by NEE)
+        let mut objects: Vec<geometry::InstanceObject> = vec![];
+        let mut instances = vec![];
+        let mut objects_id: HashMap<String, Option<usize>> = HashMap::new();
         for instance in scene_info.instances {
             let object = scene_info.objects.get(&instance.name).unwrap();
-            meshes.extend(
-                object
+            let id = objects_id.entry(instance.name.clone()).or_insert_with(|| {
+                let object_meshes = object
                     .shapes
                     .clone()
                     .into_iter()
@@ -194,14 +359,60 @@ impl SceneLoader for PBRTSceneLoader {
                             m,
                             &materials,
                             &textures,
-                            instance.matrix,
+                            Matrix4::identity(),
                             use_shading_normal,
                         )
                     })
                     .filter(|x| x.is_some())
-                    .map(|m| m.unwrap()),
-            );
+                    .map(|m| Arc::new(m.unwrap()))
+                    .collect::<Vec<_>>();
+                if object_meshes.iter().any(|m| m.is_light()) {
+                    warn!(
+                        "Object {} contains emitters, its instances are flattened",
+                        instance.name
+                    );
+                    None
+                } else {
+                    objects.push(geometry::InstanceObject {
+                        name: instance.name.clone(),
+                        meshes: object_meshes,
+                    });
+                    Some(objects.len() - 1)
+                }
+            });
+
+            match id {
+                Some(id) => match geometry::Instance::new(*id, instance.matrix) {
+                    Some(i) => instances.push(i),
+                    None => warn!(
+                        "Singular transformation for an instance of {}, ignoring it",
+                        instance.name
+                    ),
+                },
+                None => meshes.extend(
+                    object
+                        .shapes
+                        .clone()
+                        .into_iter()
+                        .map(|m| {
+                            PBRTSceneLoader::transform_mesh(
+                                m,
+                                &materials,
+                                &textures,
+                                instance.matrix,
+                                use_shading_normal,
+                            )
+                        })
+                        .filter(|x| x.is_some())
+                        .map(|m| m.unwrap()),
+                ),
+            }
         }
+        info!(
+            "Instancing: {} objects, {} instances",
+            objects.len(),
+            instances.len()
+        );
 
         // Check if there is other emitter type
         let mut emitters: Vec<Box<dyn Emitter>> = Vec::new();
@@ -243,14 +454,13 @@ impl SceneLoader for PBRTSceneLoader {
                             if have_env {
                                 panic!("Multiple env map is NOT supported");
                             }
-                            emitter_environment = Some(Arc::new(EnvironmentLight {
-                                luminance: EnvironmentLightColor::Constant(Color::new(
+                            emitter_environment = Some(Arc::new(EnvironmentLight::new(
+                                EnvironmentLightColor::Constant(Color::new(
                                     rgb.r * scale.r,
                                     rgb.g * scale.g,
                                     rgb.b * scale.b,
                                 )),
-                                bsphere: None,
-                            }));
+                            )));
                             have_env = true;
                         }
                         pbrt_rs::parser::Spectrum::Texture(name) => {
@@ -258,16 +468,16 @@ impl SceneLoader for PBRTSceneLoader {
                             todo!()
                         }
                         pbrt_rs::parser::Spectrum::Mapname(name) => {
-                            assert!(scale.r == 1.0);
-                            assert!(scale.g == 1.0);
-                            assert!(scale.b == 1.0);
-
+                            if have_env {
+                                panic!("Multiple env map is NOT supported");
+                            }
                             let filename = wk.join(name);
                             let image = Bitmap::read(filename.to_str().unwrap());
-                            emitter_environment = Some(Arc::new(EnvironmentLight {
-                                luminance: EnvironmentLightColor::new_texture(image),
-                                bsphere: None,
-                            }));
+                            emitter_environment = Some(Arc::new(
+                                EnvironmentLight::new(EnvironmentLightColor::new_texture(image))
+                                    .with_scale(Color::new(scale.r, scale.g, scale.b)),
+                            ));
+                            have_env = true;
                         }
                         _ => {
                             warn!("Unsupported luminance field: {:?}", luminance);
@@ -277,7 +487,9 @@ impl SceneLoader for PBRTSceneLoader {
             }
         };
 
-        let camera = {
+        // pbrt_rs does not keep the camera type and its lens
+        let statement = PBRTStatement::read(filename, "Camera");
+        let mut camera = {
             if let Some(camera) = scene_info.cameras.get(0) {
                 match camera {
                     pbrt_rs::Camera::Perspective {
@@ -287,33 +499,127 @@ impl SceneLoader for PBRTSceneLoader {
                     } => {
                         let mat = world_to_camera.inverse_transform().unwrap();
                         info!("camera matrix: {:?}", mat);
-                        info!("camera fov: {:?}", fov);
-                        Camera::new(scene_info.image_size, Fov::Y(*fov), mat, false)
+                        let img = scene_info.image_size;
+                        match statement.as_ref().map(|s| &s.name[..]) {
+                            Some("orthographic") => {
+                                // Default screen window of PBRT
+                                let aspect_ratio = img.x as f32 / img.y as f32;
+                                let size = if aspect_ratio > 1.0 {
+                                    Vector2::new(aspect_ratio, 1.0)
+                                } else {
+                                    Vector2::new(1.0, 1.0 / aspect_ratio)
+                                };
+                                Camera::orthographic(img, size, mat, false)
+                            }
+                            Some("environment") => Camera::latlong(img, mat, false),
+                            _ => {
+                                info!("camera fov: {:?}", fov);
+                                Camera::new(img, Fov::Y(*fov), mat, false)
+                            }
+                        }
                     }
                 }
             } else {
                 panic!("The camera is not set!");
             }
         };
+        if let Some(statement) = statement {
+            if let Some(radius) = statement.float("lensradius").filter(|r| *r > 0.0) {
+                camera.lens = Some(ThinLens {
+                    radius,
+                    // Default value of PBRT
+                    focus_distance: statement.float("focaldistance").unwrap_or(1e6),
+                    aperture: Aperture::Disk,
+                });
+            }
+        }
+        camera.check_lens();
         camera.print_info();
 
         let meshes = meshes.into_iter().map(|v| Arc::new(v)).collect();
 
         info!("image size: {:?}", scene_info.image_size);
-        Ok(Scene {
+        let scene = Scene {
             camera,
             meshes,
+            objects,
+            instances,
             nb_samples: 1,
             nb_threads: None,
             output_img_path: "out.pfm".to_string(),
+            crop: None,
+            accel_type: crate::accel::AccelType::default(),
+            accel_options: crate::accel::BVHBuildOptions::default(),
+            direct_sampling: geometry::DirectSampling::default(),
             emitter_environment,
             volume: None,
             emitters: Some(EmittersState::Unbuild(emitters)),
             bsphere: None,
+        };
+
+        // pbrt_rs does not keep the film crop window (fraction of the image)
+        let crop = PBRTStatement::read(filename, "Film").and_then(|film| {
+            match film.floats.get("cropwindow").map(|v| &v[..]) {
+                None => None,
+                Some(&[x0, x1, y0, y1])
+                    if 0.0 <= x0 && x0 <= x1 && x1 <= 1.0 && 0.0 <= y0 && y0 <= y1 && y1 <= 1.0 =>
+                {
+                    // Same pixel bounds as PBRT
+                    let img = scene.camera.size();
+                    let bound = |v: f32, size: u32| (v * size as f32).ceil() as u32;
+                    let pos = Point2::new(bound(x0, img.x), bound(y0, img.y));
+                    let size = Vector2::new(bound(x1, img.x) - pos.x, bound(y1, img.y) - pos.y);
+                    Some((pos, size))
+                }
+                Some(v) => {
+                    warn!("Invalid crop window {:?}, ignoring it", v);
+                    None
+                }
+            }
+        });
+        Ok(match crop {
+            None => scene,
+            Some((pos, size)) => scene.crop(pos, size, true),
         })
     }
 }
 
+/// Sensor attributes of a Mitsuba file that mitsuba_rs does not keep
+/// (sensor type, lens and film crop). Only the float and integer values are read.
+#[cfg(feature = "mitsuba")]
+struct MTSSensor {
+    name: String,
+    values: HashMap<String, f32>,
+}
+#[cfg(feature = "mitsuba")]
+impl MTSSensor {
+    /// First sensor of the file (the includes are not followed)
+    fn read(filename: &str) -> Option<MTSSensor> {
+        let content = std::fs::read_to_string(filename).ok()?;
+        let content = &content[content.find("<sensor")?..];
+        let content = &content[..content.find("</sensor>").unwrap_or(content.len())];
+        let attribute = |tag: &str, name: &str| {
+            let pattern = format!(" {}=\"", name);
+            let start = tag.find(&pattern)? + pattern.len();
+            let end = tag[start..].find('"')?;
+            Some(tag[start..start + end].to_string())
+        };
+        let name = attribute(&content[..content.find('>')?], "type")?;
+        let mut values = HashMap::new();
+        for tag in content
+            .split('<')
+            .filter(|t| t.starts_with("float") || t.starts_with("integer"))
+        {
+            if let (Some(n), Some(v)) = (attribute(tag, "name"), attribute(tag, "value")) {
+                if let Ok(v) = v.parse::<f32>() {
+                    values.insert(n, v);
+                }
+            }
+        }
+        Some(MTSSensor { name, values })
+    }
+}
+
 #[cfg(feature = "mitsuba")]
 pub struct MTSSceneLoader {}
 #[cfg(feature = "mitsuba")]
@@ -323,18 +629,54 @@ impl SceneLoader for MTSSceneLoader {
         let mut mts = mitsuba_rs::parse(filename)?;
         let wk = std::path::Path::new(filename).parent().unwrap();
 
+        // mitsuba_rs does not keep the sensor type, its lens and the film crop
+        let sensor = MTSSensor::read(filename);
+
         // Load camera
         let camera = {
             assert_eq!(mts.sensors.len(), 1);
             let mts_sensor = mts.sensors.pop().unwrap();
             let img_size = Vector2::new(mts_sensor.film.width, mts_sensor.film.height);
             let mat = mts_sensor.to_world.as_matrix();
-            let fov = match &mts_sensor.fov_axis[..] {
-                "x" => Fov::X(mts_sensor.fov),
-                "y" => Fov::Y(mts_sensor.fov),
-                _ => panic!("Unsupport Fov axis definition: {}", mts_sensor.fov_axis),
+            let mut camera = match sensor.as_ref().map(|s| &s.name[..]) {
+                Some("orthographic") => {
+                    // [-1, 1] on the horizontal axis (scaled by to_world)
+                    let aspect_ratio = img_size.x as f32 / img_size.y as f32;
+                    let size = Vector2::new(1.0, 1.0 / aspect_ratio);
+                    Camera::orthographic(img_size, size, mat, true)
+                }
+                Some("spherical") => Camera::latlong(img_size, mat, true),
+                _ => {
+                    let fov = match &mts_sensor.fov_axis[..] {
+                        "x" => Fov::X(mts_sensor.fov),
+                        "y" => Fov::Y(mts_sensor.fov),
+                        _ => panic!("Unsupport Fov axis definition: {}", mts_sensor.fov_axis),
+                    };
+                    Camera::new(img_size, fov, mat, true)
+                }
             };
-            Camera::new(img_size, fov, mat, true)
+            match &sensor {
+                Some(sensor) if sensor.name == "thinlens" => {
+                    match (
+                        sensor.values.get("apertureRadius"),
+                        sensor.values.get("focusDistance"),
+                    ) {
+                        (Some(&radius), Some(&focus_distance)) => {
+                            camera.lens = Some(ThinLens {
+                                radius,
+                                focus_distance,
+                                aperture: Aperture::Disk,
+                            })
+                        }
+                        _ => {
+                            warn!("Thin lens without apertureRadius or focusDistance, ignoring it")
+                        }
+                    }
+                }
+                _ => {}
+            }
+            camera.check_lens();
+            camera
         };
 
         // Helper to transform the mesh
@@ -409,6 +751,11 @@ impl SceneLoader for MTSSceneLoader {
                                 mesh_mts.normals
                             },
                             uv: mesh_mts.uv,
+                            shape: geometry::ShapeType::Triangles,
+                            alpha: option
+                                .bsdf
+                                .as_ref()
+                                .and_then(|b| crate::bsdfs::alpha_mts(b, wk)),
                             bsdf: match &option.bsdf {
                                 Some(bsdf) => crate::bsdfs::bsdf_mts(bsdf, wk),
                                 None => Box::new(crate::bsdfs::diffuse::BSDFDiffuse {
@@ -429,6 +776,9 @@ impl SceneLoader for MTSSceneLoader {
                                 None => geometry::EmissionType::Zero,
                             },
                             cdf: None,
+                            emission_distribution: None,
+                            direct_sampling: geometry::DirectSampling::Area,
+                            rectangles: vec![],
                         }];
 
                         // Apply transform
@@ -456,6 +806,15 @@ impl SceneLoader for MTSSceneLoader {
                                 m.discard_normals();
                             }
 
+                            // Keep the OBJ opacity (map_d) if there is no mask
+                            if let Some(alpha) = option
+                                .bsdf
+                                .as_ref()
+                                .and_then(|b| crate::bsdfs::alpha_mts(b, wk))
+                            {
+                                m.alpha = Some(alpha);
+                            }
+
                             m.bsdf = match &option.bsdf {
                                 Some(bsdf) => crate::bsdfs::bsdf_mts(bsdf, wk),
                                 None => Box::new(crate::bsdfs::diffuse::BSDFDiffuse {
@@ -508,6 +867,12 @@ impl SceneLoader for MTSSceneLoader {
                                 mesh_mts.normals
                             },
                             uv: mesh_mts.texcoords,
+                            shape: geometry::ShapeType::Triangles,
+                            alpha: shape
+                                .option
+                                .bsdf
+                                .as_ref()
+                                .and_then(|b| crate::bsdfs::alpha_mts(b, wk)),
                             bsdf: match &shape.option.bsdf {
                                 Some(bsdf) => crate::bsdfs::bsdf_mts(bsdf, wk),
                                 None => Box::new(crate::bsdfs::diffuse::BSDFDiffuse {
@@ -528,6 +893,9 @@ impl SceneLoader for MTSSceneLoader {
                                 None => geometry::EmissionType::Zero,
                             },
                             cdf: None,
+                            emission_distribution: None,
+                            direct_sampling: geometry::DirectSampling::Area,
+                            rectangles: vec![],
                         }];
 
                         // Apply transform
@@ -566,6 +934,11 @@ impl SceneLoader for MTSSceneLoader {
                             indices,
                             normals,
                             uv,
+                            shape: geometry::ShapeType::Triangles,
+                            alpha: option
+                                .bsdf
+                                .as_ref()
+                                .and_then(|b| crate::bsdfs::alpha_mts(b, wk)),
                             bsdf: match &option.bsdf {
                                 Some(bsdf) => crate::bsdfs::bsdf_mts(bsdf, wk),
                                 None => Box::new(crate::bsdfs::diffuse::BSDFDiffuse {
@@ -586,6 +959,9 @@ impl SceneLoader for MTSSceneLoader {
                                 None => geometry::EmissionType::Zero,
                             },
                             cdf: None,
+                            emission_distribution: None,
+                            direct_sampling: geometry::DirectSampling::Area,
+                            rectangles: vec![],
                         }];
 
                         // Apply transform
@@ -595,6 +971,94 @@ impl SceneLoader for MTSSceneLoader {
 
                         meshes
                     }
+                    mitsuba_rs::Shape::Sphere { .. }
+                    | mitsuba_rs::Shape::Disk { .. }
+                    | mitsuba_rs::Shape::Cylinder { .. } => {
+                        // Analytic shapes (transformation applied to the shape parameters)
+                        let (name, shape, option) = match s {
+                            mitsuba_rs::Shape::Sphere {
+                                center,
+                                radius,
+                                option,
+                                ..
+                            } => {
+                                let mat = option
+                                    .to_world
+                                    .as_ref()
+                                    .map_or(Matrix4::identity(), |t| t.clone().as_matrix());
+                                let sphere = crate::shapes::Sphere {
+                                    center: mat
+                                        .transform_point(Point3::new(center.x, center.y, center.z)),
+                                    radius: mat
+                                        .transform_vector(Vector3::new(radius, 0.0, 0.0))
+                                        .magnitude(),
+                                };
+                                ("sphere", geometry::ShapeType::Sphere(sphere), option)
+                            }
+                            mitsuba_rs::Shape::Disk { option, .. } => {
+                                let mat = option
+                                    .to_world
+                                    .as_ref()
+                                    .map_or(Matrix4::identity(), |t| t.clone().as_matrix());
+                                let disk = crate::shapes::Disk::new(
+                                    mat.transform_point(Point3::new(0.0, 0.0, 0.0)),
+                                    mat.transform_vector(Vector3::new(0.0, 0.0, 1.0)),
+                                    mat.transform_vector(Vector3::new(1.0, 0.0, 0.0))
+                                        .magnitude(),
+                                );
+                                ("disk", geometry::ShapeType::Disk(disk), option)
+                            }
+                            mitsuba_rs::Shape::Cylinder {
+                                p0,
+                                p1,
+                                radius,
+                                option,
+                                ..
+                            } => {
+                                let mat = option
+                                    .to_world
+                                    .as_ref()
+                                    .map_or(Matrix4::identity(), |t| t.clone().as_matrix());
+                                let p0 = Point3::new(p0.x, p0.y, p0.z);
+                                let p1 = Point3::new(p1.x, p1.y, p1.z);
+                                // Radius scaled by the transformation perpendicular to the axis
+                                let axis = crate::math::Frame::new((p1 - p0).normalize());
+                                let (p0, p1) = (mat.transform_point(p0), mat.transform_point(p1));
+                                let radius = radius
+                                    * mat
+                                        .transform_vector(
+                                            axis.to_world(Vector3::new(1.0, 0.0, 0.0)),
+                                        )
+                                        .magnitude();
+                                let cylinder = crate::shapes::Cylinder::new(p0, p1, radius);
+                                ("cylinder", geometry::ShapeType::Cylinder(cylinder), option)
+                            }
+                            _ => unreachable!(),
+                        };
+
+                        let mut mesh = geometry::Mesh::from_shape(name.to_string(), shape);
+                        mesh.alpha = option
+                            .bsdf
+                            .as_ref()
+                            .and_then(|b| crate::bsdfs::alpha_mts(b, wk));
+                        mesh.bsdf = match &option.bsdf {
+                            Some(bsdf) => crate::bsdfs::bsdf_mts(bsdf, wk),
+                            None => Box::new(crate::bsdfs::diffuse::BSDFDiffuse {
+                                diffuse: crate::bsdfs::BSDFColor::Constant(Color::value(0.8)),
+                            }),
+                        };
+                        if let Some(emitter) = &option.emitter {
+                            let rgb = emitter.radiance.clone().as_rgb().unwrap();
+                            mesh.emission = geometry::EmissionType::Color {
+                                v: Color {
+                                    r: rgb.r,
+                                    g: rgb.g,
+                                    b: rgb.b,
+                                },
+                            };
+                        }
+                        vec![mesh]
+                    }
                     _ => {
                         warn!("Ignoring shape {:?}", s);
                         vec![]
@@ -605,7 +1069,7 @@ impl SceneLoader for MTSSceneLoader {
             .collect();
 
         // Other
-        let emitter_environment = None;
+        let mut emitter_environment = None;
         let mut emitters: Vec<Box<dyn Emitter>> = Vec::new();
         {
             for x in mts.emitters {
@@ -655,6 +1119,51 @@ impl SceneLoader for MTSSceneLoader {
                             normal,
                         }))
                     }
+                    mitsuba_rs::Emitter::Spot {
+                        intensity,
+                        cutoff_angle,
+                        beam_width,
+                        to_world,
+                        ..
+                    } => {
+                        // Mitsuba spot lights are pointing toward +z
+                        let trans = to_world.as_matrix();
+                        let from = trans.transform_point(Point3::new(0.0, 0.0, 0.0));
+                        let to = trans.transform_point(Point3::new(0.0, 0.0, 1.0));
+                        let rgb = intensity.clone().as_rgb().unwrap();
+                        if let Some(spot) = crate::emitter::SpotEmitter::new(
+                            Color::new(rgb.r, rgb.g, rgb.b),
+                            from,
+                            to,
+                            cutoff_angle,
+                            cutoff_angle - beam_width,
+                        ) {
+                            emitters.push(Box::new(spot));
+                        }
+                    }
+                    mitsuba_rs::Emitter::Envmap {
+                        filename,
+                        scale,
+                        to_world,
+                        ..
+                    } => {
+                        if emitter_environment.is_some() {
+                            warn!("Multiple env map is NOT supported, keep the last one");
+                        }
+                        let image = Bitmap::read(wk.join(&filename).to_str().unwrap());
+                        // Mitsuba envmaps are Y-up whereas ours are Z-up
+                        let y_up = Matrix4::from_cols(
+                            Vector4::new(0.0, 0.0, 1.0, 0.0),
+                            Vector4::new(1.0, 0.0, 0.0, 0.0),
+                            Vector4::new(0.0, 1.0, 0.0, 0.0),
+                            Vector4::new(0.0, 0.0, 0.0, 1.0),
+                        );
+                        emitter_environment = Some(Arc::new(
+                            EnvironmentLight::new(EnvironmentLightColor::new_texture(image))
+                                .with_transform(to_world.as_matrix() * y_up)
+                                .with_scale(Color::value(scale)),
+                        ));
+                    }
                     _ => {
                         warn!("Ignoring emitter");
                     }
@@ -662,7 +1171,13 @@ impl SceneLoader for MTSSceneLoader {
             }
         };
 
-        let meshes = meshes.into_iter().map(|v| Arc::new(v)).collect();
+        let meshes = meshes
+            .into_iter()
+            .map(|mut m| {
+                m.check_alpha();
+                Arc::new(m)
+            })
+            .collect();
         let volume = if mts.medium.is_empty() {
             None
         } else {
@@ -712,16 +1227,47 @@ impl SceneLoader for MTSSceneLoader {
             }
         };
 
-        Ok(Scene {
+        let scene = Scene {
             camera,
             meshes,
+            objects: vec![],
+            instances: vec![],
             nb_samples: 1,
             nb_threads: None,
             output_img_path: "out.pfm".to_string(),
+            crop: None,
+            accel_type: crate::accel::AccelType::default(),
+            accel_options: crate::accel::BVHBuildOptions::default(),
+            direct_sampling: geometry::DirectSampling::default(),
             emitter_environment,
             volume,
             emitters: Some(EmittersState::Unbuild(emitters)),
             bsphere: None,
+        };
+
+        // Film crop (in pixels)
+        let crop = sensor.as_ref().and_then(|sensor| {
+            let value = |name: &str| sensor.values.get(name).map(|v| *v as u32);
+            if ["cropOffsetX", "cropOffsetY", "cropWidth", "cropHeight"]
+                .iter()
+                .all(|name| value(name).is_none())
+            {
+                return None;
+            }
+            let img = scene.camera.size();
+            let pos = Point2::new(
+                value("cropOffsetX").unwrap_or(0),
+                value("cropOffsetY").unwrap_or(0),
+            );
+            let size = Vector2::new(
+                value("cropWidth").unwrap_or(img.x),
+                value("cropHeight").unwrap_or(img.y),
+            );
+            Some((pos, size))
+        });
+        Ok(match crop {
+            None => scene,
+            Some((pos, size)) => scene.crop(pos, size, true),
         })
     }
 }
@@ -0,0 +1,328 @@
+use crate::math::{concentric_sample_disk, sample_uniform_sphere, solve_quadratic, Frame};
+use crate::structure::*;
+use cgmath::*;
+use std;
+use std::f32::consts::PI;
+
+// Same threshold than the triangle intersection
+// to avoid self intersection
+const T_MIN: f32 = 0.00001;
+
+/// Return the smallest valid distance between the two roots
+fn select_root(t0: f32, t1: f32, t_max: f32) -> Option<f32> {
+    if t0 > T_MIN && t0 < t_max {
+        Some(t0)
+    } else if t1 > T_MIN && t1 < t_max {
+        Some(t1)
+    } else {
+        None
+    }
+}
+
+/// Azimuthal angle in [0, 2pi]
+fn phi_angle(x: f32, y: f32) -> f32 {
+    let phi = y.atan2(x);
+    if phi < 0.0 {
+        phi + 2.0 * PI
+    } else {
+        phi
+    }
+}
+
+pub struct Sphere {
+    pub center: Point3<f32>,
+    pub radius: f32,
+}
+
+impl Sphere {
+    /// Texture coordinates from a normal (spherical coordinates)
+    fn uv(&self, n: &Vector3<f32>) -> Vector2<f32> {
+        Vector2::new(
+            phi_angle(n.x, n.y) / (2.0 * PI),
+            n.z.max(-1.0).min(1.0).acos() / PI,
+        )
+    }
+
+    pub fn area(&self) -> f32 {
+        4.0 * PI * self.radius * self.radius
+    }
+
+    pub fn aabb(&self) -> AABB {
+        let r = Vector3::new(self.radius, self.radius, self.radius);
+        AABB {
+            p_min: self.center.to_vec() - r,
+            p_max: self.center.to_vec() + r,
+        }
+    }
+
+    pub fn intersect(
+        &self,
+        p_c: &Point3<f32>,
+        d_c: &Vector3<f32>,
+        its: &mut IntersectionUV,
+    ) -> bool {
+        let oc = p_c - self.center;
+        let (t0, t1) = match solve_quadratic(
+            d_c.magnitude2(),
+            2.0 * oc.dot(*d_c),
+            oc.magnitude2() - self.radius * self.radius,
+        ) {
+            Some(v) => v,
+            None => return false,
+        };
+        let t = match select_root(t0, t1, its.t) {
+            Some(t) => t,
+            None => return false,
+        };
+
+        // Reproject the point on the sphere to reduce the error
+        let n = ((*p_c + *d_c * t) - self.center).normalize();
+        let uv = self.uv(&n);
+        its.t = t;
+        its.p = self.center + n * self.radius;
+        its.n = n;
+        its.u = uv.x;
+        its.v = uv.y;
+        true
+    }
+
+    pub fn sample(&self, v: Point2<f32>) -> SampledPosition {
+        let n = sample_uniform_sphere(v);
+        SampledPosition {
+            p: self.center + n * self.radius,
+            n,
+            uv: Some(self.uv(&n)),
+            pdf: PDF::Area(1.0 / self.area()),
+            primitive_id: Some(0),
+        }
+    }
+
+    /// Cosine of the cone subtended by the sphere
+    /// None if the point is inside the sphere
+    fn cos_theta_max(&self, p: &Point3<f32>) -> Option<f32> {
+        let dist2 = (self.center - p).magnitude2();
+        let r2 = self.radius * self.radius;
+        if dist2 <= r2 {
+            None
+        } else {
+            Some((1.0 - r2 / dist2).max(0.0).sqrt())
+        }
+    }
+
+    /// Solid angle pdf of sampling the visible cone from p
+    /// None if the point is inside the sphere (area sampling should be used)
+    pub fn solid_angle_pdf(&self, p: &Point3<f32>) -> Option<f32> {
+        self.cos_theta_max(p)
+            .map(|cos_theta_max| 1.0 / (2.0 * PI * (1.0 - cos_theta_max)))
+    }
+
+    /// Sample uniformly the cone subtended by the sphere (PBRT-v3)
+    /// The returned pdf is in solid angle
+    pub fn sample_solid_angle(&self, p: &Point3<f32>, v: Point2<f32>) -> Option<SampledPosition> {
+        let cos_theta_max = self.cos_theta_max(p)?;
+        let d_c = (self.center - p).magnitude();
+        let frame = Frame::new((self.center - p) / d_c);
+
+        // Sample the direction inside the cone
+        let cos_theta = (1.0 - v.x) + v.x * cos_theta_max;
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = v.y * 2.0 * PI;
+
+        // Compute the angle from the sphere center to the sampled point
+        let d_s = d_c * cos_theta
+            - (self.radius * self.radius - d_c * d_c * sin_theta * sin_theta)
+                .max(0.0)
+                .sqrt();
+        let cos_alpha =
+            (d_c * d_c + self.radius * self.radius - d_s * d_s) / (2.0 * d_c * self.radius);
+        let sin_alpha = (1.0 - cos_alpha * cos_alpha).max(0.0).sqrt();
+
+        let n = -frame.to_world(Vector3::new(
+            sin_alpha * phi.cos(),
+            sin_alpha * phi.sin(),
+            cos_alpha,
+        ));
+        Some(SampledPosition {
+            p: self.center + n * self.radius,
+            n,
+            uv: Some(self.uv(&n)),
+            pdf: PDF::SolidAngle(1.0 / (2.0 * PI * (1.0 - cos_theta_max))),
+            primitive_id: Some(0),
+        })
+    }
+}
+
+/// Disk facing the z axis of its frame
+pub struct Disk {
+    pub center: Point3<f32>,
+    pub frame: Frame,
+    pub radius: f32,
+}
+
+impl Disk {
+    pub fn new(center: Point3<f32>, n: Vector3<f32>, radius: f32) -> Disk {
+        Disk {
+            center,
+            frame: Frame::new(n.normalize()),
+            radius,
+        }
+    }
+
+    fn n(&self) -> Vector3<f32> {
+        self.frame.to_world(Vector3::new(0.0, 0.0, 1.0))
+    }
+
+    pub fn area(&self) -> f32 {
+        PI * self.radius * self.radius
+    }
+
+    pub fn aabb(&self) -> AABB {
+        // Extent of the disk along each axis
+        let n = self.n();
+        let e = Vector3::new(
+            (1.0 - n.x * n.x).max(0.0).sqrt(),
+            (1.0 - n.y * n.y).max(0.0).sqrt(),
+            (1.0 - n.z * n.z).max(0.0).sqrt(),
+        ) * self.radius;
+        AABB {
+            p_min: self.center.to_vec() - e,
+            p_max: self.center.to_vec() + e,
+        }
+    }
+
+    pub fn intersect(
+        &self,
+        p_c: &Point3<f32>,
+        d_c: &Vector3<f32>,
+        its: &mut IntersectionUV,
+    ) -> bool {
+        let o = self.frame.to_local(p_c - self.center);
+        let d = self.frame.to_local(*d_c);
+        if d.z == 0.0 {
+            return false;
+        }
+        let t = -o.z / d.z;
+        if t <= T_MIN || t >= its.t {
+            return false;
+        }
+        let p = o + d * t;
+        let r2 = p.x * p.x + p.y * p.y;
+        if r2 > self.radius * self.radius {
+            return false;
+        }
+
+        its.t = t;
+        its.p = self.center + self.frame.to_world(Vector3::new(p.x, p.y, 0.0));
+        its.n = self.n();
+        its.u = r2.sqrt() / self.radius;
+        its.v = phi_angle(p.x, p.y) / (2.0 * PI);
+        true
+    }
+
+    pub fn sample(&self, v: Point2<f32>) -> SampledPosition {
+        let p = concentric_sample_disk(v) * self.radius;
+        SampledPosition {
+            p: self.center + self.frame.to_world(Vector3::new(p.x, p.y, 0.0)),
+            n: self.n(),
+            uv: Some(Vector2::new(
+                (p.x * p.x + p.y * p.y).sqrt() / self.radius,
+                phi_angle(p.x, p.y) / (2.0 * PI),
+            )),
+            pdf: PDF::Area(1.0 / self.area()),
+            primitive_id: Some(0),
+        }
+    }
+}
+
+/// Open cylinder between two points
+pub struct Cylinder {
+    pub p0: Point3<f32>,
+    pub frame: Frame,
+    pub length: f32,
+    pub radius: f32,
+}
+
+impl Cylinder {
+    pub fn new(p0: Point3<f32>, p1: Point3<f32>, radius: f32) -> Cylinder {
+        let axis = p1 - p0;
+        let length = axis.magnitude();
+        Cylinder {
+            p0,
+            frame: Frame::new(axis / length),
+            length,
+            radius,
+        }
+    }
+
+    pub fn area(&self) -> f32 {
+        2.0 * PI * self.radius * self.length
+    }
+
+    pub fn aabb(&self) -> AABB {
+        let p1 = self.p0 + self.frame.to_world(Vector3::new(0.0, 0.0, self.length));
+        let r = Vector3::new(self.radius, self.radius, self.radius);
+        AABB::default()
+            .union_vec(&(self.p0.to_vec() - r))
+            .union_vec(&(self.p0.to_vec() + r))
+            .union_vec(&(p1.to_vec() - r))
+            .union_vec(&(p1.to_vec() + r))
+    }
+
+    pub fn intersect(
+        &self,
+        p_c: &Point3<f32>,
+        d_c: &Vector3<f32>,
+        its: &mut IntersectionUV,
+    ) -> bool {
+        let o = self.frame.to_local(p_c - self.p0);
+        let d = self.frame.to_local(*d_c);
+        let (t0, t1) = match solve_quadratic(
+            d.x * d.x + d.y * d.y,
+            2.0 * (d.x * o.x + d.y * o.y),
+            o.x * o.x + o.y * o.y - self.radius * self.radius,
+        ) {
+            Some(v) => v,
+            None => return false,
+        };
+
+        // Check the two roots against the cylinder extent
+        let inside = |t: f32| {
+            let z = o.z + d.z * t;
+            z >= 0.0 && z <= self.length
+        };
+        let t = if t0 > T_MIN && t0 < its.t && inside(t0) {
+            t0
+        } else if t1 > T_MIN && t1 < its.t && inside(t1) {
+            t1
+        } else {
+            return false;
+        };
+
+        let p = o + d * t;
+        let n_local = Vector3::new(p.x, p.y, 0.0).normalize();
+        its.t = t;
+        its.p = self.p0
+            + self.frame.to_world(Vector3::new(
+                n_local.x * self.radius,
+                n_local.y * self.radius,
+                p.z,
+            ));
+        its.n = self.frame.to_world(n_local);
+        its.u = phi_angle(p.x, p.y) / (2.0 * PI);
+        its.v = p.z / self.length;
+        true
+    }
+
+    pub fn sample(&self, v: Point2<f32>) -> SampledPosition {
+        let phi = v.y * 2.0 * PI;
+        let z = v.x * self.length;
+        let n = self.frame.to_world(Vector3::new(phi.cos(), phi.sin(), 0.0));
+        SampledPosition {
+            p: self.p0 + self.frame.to_world(Vector3::new(0.0, 0.0, z)) + n * self.radius,
+            n,
+            uv: Some(Vector2::new(v.y, v.x)),
+            pdf: PDF::Area(1.0 / self.area()),
+            primitive_id: Some(0),
+        }
+    }
+}
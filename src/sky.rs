@@ -0,0 +1,423 @@
+use crate::structure::*;
+use cgmath::*;
+use std;
+use std::io::Read;
+
+/// Coefficients of the Hosek-Wilkie sky model (RGB version)
+/// These tables are read from the reference implementation
+/// data file (ArHosekSkyModelData_RGB.h) as they are not bundled.
+#[derive(Clone, Debug)]
+pub struct HosekWilkieDataset {
+    // [albedo (2)][turbidity (10)][control point (6)][parameter (9)]
+    configs: [Vec<f32>; 3],
+    // [albedo (2)][turbidity (10)][control point (6)]
+    radiances: [Vec<f32>; 3],
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Remove C comments (// and /* */)
+fn strip_comments(content: &str) -> String {
+    let mut res = String::with_capacity(content.len());
+    let mut chars = content.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('/', Some('/')) => {
+                while let Some(c) = chars.next() {
+                    if c == '\n' {
+                        res.push('\n');
+                        break;
+                    }
+                }
+            }
+            ('/', Some('*')) => {
+                chars.next();
+                let mut prev = ' ';
+                while let Some(c) = chars.next() {
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+                res.push(' ');
+            }
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+impl HosekWilkieDataset {
+    pub fn read(filename: &std::path::Path) -> std::io::Result<HosekWilkieDataset> {
+        info!("Try to load {:?}", filename);
+        let mut content = String::new();
+        std::fs::File::open(filename)?.read_to_string(&mut content)?;
+        HosekWilkieDataset::parse(&content)
+    }
+
+    /// Parse the C arrays: datasetRGB{1,2,3} and datasetRGBRad{1,2,3}
+    pub fn parse(content: &str) -> std::io::Result<HosekWilkieDataset> {
+        let mut configs = [vec![], vec![], vec![]];
+        let mut radiances = [vec![], vec![], vec![]];
+        for statement in strip_comments(content).split(';') {
+            let (decl, values) = match (statement.find('['), statement.find('{')) {
+                (Some(i), Some(j)) if i < j => (&statement[..i], &statement[j + 1..]),
+                _ => continue,
+            };
+            let name = decl.split_whitespace().last().unwrap_or("");
+            let (storage, expected) = if name.starts_with("datasetRGBRad") {
+                (&mut radiances, 120)
+            } else if name.starts_with("datasetRGB") {
+                (&mut configs, 1080)
+            } else {
+                continue;
+            };
+            let channel = match name.chars().last().and_then(|c| c.to_digit(10)) {
+                Some(c) if c >= 1 && c <= 3 => c as usize - 1,
+                _ => continue,
+            };
+
+            let values = values
+                .split(|c: char| c == ',' || c == '}' || c.is_whitespace())
+                .filter(|v| !v.is_empty())
+                .map(|v| {
+                    v.parse::<f32>()
+                        .map_err(|_| invalid_data(&format!("Sky dataset wrong number: {}", v)))
+                })
+                .collect::<std::io::Result<Vec<f32>>>()?;
+            if values.len() != expected {
+                return Err(invalid_data(&format!(
+                    "Sky dataset {} has {} values (expected {})",
+                    name,
+                    values.len(),
+                    expected
+                )));
+            }
+            storage[channel] = values;
+        }
+
+        if configs.iter().chain(radiances.iter()).any(|v| v.is_empty()) {
+            return Err(invalid_data("Sky dataset is incomplete"));
+        }
+        Ok(HosekWilkieDataset { configs, radiances })
+    }
+}
+
+/// Quintic Bezier interpolation over the solar elevation
+/// for a given albedo and turbidity (stride: number of values per control point)
+fn cook(data: &[f32], stride: usize, offset: usize, elevation: f32, out: &mut [f32], w: f32) {
+    let e = elevation;
+    let ie = 1.0 - e;
+    let weights = [
+        ie.powi(5),
+        5.0 * ie.powi(4) * e,
+        10.0 * ie.powi(3) * e.powi(2),
+        10.0 * ie.powi(2) * e.powi(3),
+        5.0 * ie * e.powi(4),
+        e.powi(5),
+    ];
+    for (i, o) in out.iter_mut().enumerate() {
+        *o += w * weights
+            .iter()
+            .enumerate()
+            .map(|(k, wk)| wk * data[offset + k * stride + i])
+            .sum::<f32>();
+    }
+}
+
+/// Time and location on Earth used to compute the sun position
+#[derive(Clone, Debug)]
+pub struct SunLocation {
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    /// Local time in hours (e.g. 15.5 for 15:30)
+    pub hour: f32,
+    /// Offset to UTC in hours
+    pub timezone: f32,
+    /// In degrees (north positive)
+    pub latitude: f32,
+    /// In degrees (east positive)
+    pub longitude: f32,
+}
+
+impl SunLocation {
+    /// Direction toward the sun (up is +z, north +y and east +x)
+    /// From NOAA approximations (accurate to a fraction of degree)
+    pub fn sun_direction(&self) -> Vector3<f32> {
+        use std::f32::consts::PI;
+        assert!(self.month >= 1 && self.month <= 12);
+        let leap = (self.year % 4 == 0 && self.year % 100 != 0) || self.year % 400 == 0;
+        let days_month = [
+            31,
+            if leap { 29 } else { 28 },
+            31,
+            30,
+            31,
+            30,
+            31,
+            31,
+            30,
+            31,
+            30,
+            31,
+        ];
+        let day_of_year = days_month[..self.month as usize - 1].iter().sum::<u32>() + self.day;
+        let nb_days = if leap { 366.0 } else { 365.0 };
+
+        // Fractional year (radians)
+        let g = 2.0 * PI / nb_days * (day_of_year as f32 - 1.0 + (self.hour - 12.0) / 24.0);
+        let eq_time = 229.18
+            * (0.000075 + 0.001868 * g.cos()
+                - 0.032077 * g.sin()
+                - 0.014615 * (2.0 * g).cos()
+                - 0.040849 * (2.0 * g).sin());
+        let decl = 0.006918 - 0.399912 * g.cos() + 0.070257 * g.sin() - 0.006758 * (2.0 * g).cos()
+            + 0.000907 * (2.0 * g).sin()
+            - 0.002697 * (3.0 * g).cos()
+            + 0.00148 * (3.0 * g).sin();
+
+        // True solar time (minutes) and hour angle
+        let time_offset = eq_time + 4.0 * self.longitude - 60.0 * self.timezone;
+        let solar_time = self.hour * 60.0 + time_offset;
+        let hour_angle = (solar_time / 4.0 - 180.0).to_radians();
+
+        let lat = self.latitude.to_radians();
+        let cos_zenith = crate::clamp(
+            lat.sin() * decl.sin() + lat.cos() * decl.cos() * hour_angle.cos(),
+            -1.0,
+            1.0,
+        );
+        let sin_zenith = (1.0 - cos_zenith * cos_zenith).sqrt();
+        // Azimuth clockwise from the north
+        let azimuth = (-hour_angle.sin() * decl.cos())
+            .atan2(lat.cos() * decl.sin() - lat.sin() * decl.cos() * hour_angle.cos());
+        Vector3::new(
+            sin_zenith * azimuth.sin(),
+            sin_zenith * azimuth.cos(),
+            cos_zenith,
+        )
+    }
+}
+
+/// Angular radius of the sun (radians)
+pub const SUN_APERTURE: f32 = 0.004_654;
+
+/// Hosek-Wilkie analytic sky (up is +z)
+#[derive(Clone, Debug)]
+pub struct Sky {
+    /// Direction toward the sun
+    pub sun_direction: Vector3<f32>,
+    /// Atmospheric turbidity [1, 10]
+    pub turbidity: f32,
+    /// Ground albedo [0, 1]
+    pub albedo: Color,
+    pub scale: f32,
+    // Model parameters for each channel
+    configs: [[f32; 9]; 3],
+    radiances: [f32; 3],
+}
+
+impl Sky {
+    pub fn new(
+        dataset: &HosekWilkieDataset,
+        sun_direction: Vector3<f32>,
+        turbidity: f32,
+        albedo: Color,
+        scale: f32,
+    ) -> Sky {
+        let sun_direction = sun_direction.normalize();
+        if turbidity < 1.0 || turbidity > 10.0 {
+            warn!("Sky turbidity {} is clamped to [1, 10]", turbidity);
+        }
+        let turbidity = crate::clamp(turbidity, 1.0, 10.0);
+        let elevation = crate::clamp(sun_direction.z, 0.0, 1.0).asin();
+        // The control points are placed with a cubic root
+        let elevation = (elevation / std::f32::consts::FRAC_PI_2).powf(1.0 / 3.0);
+
+        let int_turbidity = (turbidity as usize).min(10);
+        let turbidity_rem = turbidity - int_turbidity as f32;
+        let mut configs = [[0.0; 9]; 3];
+        let mut radiances = [0.0; 3];
+        for c in 0..3 {
+            let a = crate::clamp(albedo.get(c as u8), 0.0, 1.0);
+            let mut layers = vec![
+                (0, int_turbidity - 1, (1.0 - a) * (1.0 - turbidity_rem)),
+                (1, int_turbidity - 1, a * (1.0 - turbidity_rem)),
+            ];
+            if int_turbidity < 10 {
+                layers.push((0, int_turbidity, (1.0 - a) * turbidity_rem));
+                layers.push((1, int_turbidity, a * turbidity_rem));
+            }
+            for (id_albedo, id_turbidity, w) in layers {
+                let layer = id_albedo * 10 + id_turbidity;
+                cook(
+                    &dataset.configs[c],
+                    9,
+                    layer * 9 * 6,
+                    elevation,
+                    &mut configs[c],
+                    w,
+                );
+                cook(
+                    &dataset.radiances[c],
+                    1,
+                    layer * 6,
+                    elevation,
+                    &mut radiances[c..c + 1],
+                    w,
+                );
+            }
+        }
+
+        Sky {
+            sun_direction,
+            turbidity,
+            albedo,
+            scale,
+            configs,
+            radiances,
+        }
+    }
+
+    fn radiance_channel(&self, c: usize, cos_theta: f32, gamma: f32) -> f32 {
+        let p = &self.configs[c];
+        let cos_gamma = gamma.cos();
+        let exp_m = (p[4] * gamma).exp();
+        let ray_m = cos_gamma * cos_gamma;
+        let mie_m = (1.0 + ray_m) / (1.0 + p[8] * p[8] - 2.0 * p[8] * cos_gamma).powf(1.5);
+        let zenith = cos_theta.sqrt();
+        let f = (1.0 + p[0] * (p[1] / (cos_theta + 0.01)).exp())
+            * (p[2] + p[3] * exp_m + p[5] * ray_m + p[6] * mie_m + p[7] * zenith);
+        (f * self.radiances[c]).max(0.0)
+    }
+
+    /// Sky radiance (zero below the horizon)
+    pub fn eval(&self, d: Vector3<f32>) -> Color {
+        if d.z <= 0.0 {
+            return Color::zero();
+        }
+        let gamma = crate::clamp(d.dot(self.sun_direction), -1.0, 1.0).acos();
+        let cos_theta = d.z.min(1.0);
+        Color::new(
+            self.radiance_channel(0, cos_theta, gamma),
+            self.radiance_channel(1, cos_theta, gamma),
+            self.radiance_channel(2, cos_theta, gamma),
+        ) * self.scale
+    }
+
+    /// Sun disk radiance attenuated by the atmosphere
+    /// (Rayleigh and aerosol extinction as in Preetham et al.)
+    pub fn sun_radiance(&self) -> Color {
+        if self.sun_direction.z <= 0.0 {
+            return Color::zero();
+        }
+        let theta = self.sun_direction.z.min(1.0).acos();
+        // Relative optical mass (Kasten)
+        let m = 1.0 / (theta.cos() + 0.15 * (93.885 - theta.to_degrees()).powf(-1.253));
+        let beta = 0.046_083_66 * self.turbidity - 0.045_860_26;
+        // Wavelengths (micrometers) and extraterrestrial radiance (W/(m^2 sr nm))
+        let channels = [(0.68, 22_100.0), (0.55, 27_400.0), (0.44, 27_700.0)];
+        let v = channels
+            .iter()
+            .map(|&(lambda, radiance): &(f32, f32)| {
+                let rayleigh = (-0.008_735 * lambda.powf(-4.08) * m).exp();
+                let aerosol = (-beta * lambda.powf(-1.3) * m).exp();
+                radiance * rayleigh * aerosol
+            })
+            .collect::<Vec<_>>();
+        Color::new(v[0], v[1], v[2]) * self.scale
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Dataset where every configuration uses the same parameters
+    fn constant_dataset(params: &[f32; 9], radiance: f32) -> String {
+        let mut content = String::from("// Generated for the tests\n");
+        for c in 1..=3 {
+            let values = (0..120 * 9)
+                .map(|i| params[i % 9].to_string())
+                .collect::<Vec<_>>();
+            content += &format!("double datasetRGB{}[] = {{ {} }};\n", c, values.join(", "));
+            let values = vec![radiance.to_string(); 120];
+            content += &format!(
+                "/* radiance */ double datasetRGBRad{}[] = {{ {} }};\n",
+                c,
+                values.join(", ")
+            );
+        }
+        content
+    }
+
+    #[test]
+    fn parse() {
+        let content = constant_dataset(&[0.0; 9], 1.0);
+        assert!(HosekWilkieDataset::parse(&content).is_ok());
+        // Missing values or arrays
+        let truncated = content.replacen("1, 1 }", "1 }", 1);
+        assert!(HosekWilkieDataset::parse(&truncated).is_err());
+        let missing = content.replace("datasetRGBRad3", "unused");
+        assert!(HosekWilkieDataset::parse(&missing).is_err());
+    }
+
+    #[test]
+    fn constant_sky() {
+        // Only the constant term: f = 1 (the control points sum to one)
+        let params = [0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        let dataset = HosekWilkieDataset::parse(&constant_dataset(&params, 2.0)).unwrap();
+        let sky = Sky::new(
+            &dataset,
+            Vector3::new(0.0, 1.0, 1.0),
+            3.5,
+            Color::value(0.3),
+            0.5,
+        );
+        for d in &[
+            Vector3::new(0.0, 0.0, 1.0),
+            Vector3::new(0.6, 0.0, 0.8),
+            Vector3::new(0.0, 0.7071, 0.7071),
+        ] {
+            let v = sky.eval(*d);
+            assert!(
+                (v.r - 1.0).abs() < 1e-4 && (v.b - 1.0).abs() < 1e-4,
+                "{:?}",
+                v
+            );
+        }
+        assert!(sky.eval(Vector3::new(0.0, 0.0, -1.0)).is_zero());
+    }
+
+    #[test]
+    fn sun_direction() {
+        let mut location = SunLocation {
+            year: 2021,
+            month: 3,
+            day: 20,
+            hour: 12.0,
+            timezone: 0.0,
+            latitude: 0.0,
+            longitude: 0.0,
+        };
+        // Equinox at the equator: the sun is close to the zenith at noon
+        let d = location.sun_direction();
+        assert!(d.z > 0.99 && (d.magnitude() - 1.0).abs() < 1e-4);
+        // and below the horizon at midnight
+        location.hour = 0.0;
+        assert!(location.sun_direction().z < -0.99);
+        // Morning: east, evening: west
+        location.hour = 8.0;
+        assert!(location.sun_direction().x > 0.0);
+        location.hour = 16.0;
+        assert!(location.sun_direction().x < 0.0);
+        // Southern sun at noon in the northern hemisphere
+        location.hour = 12.0;
+        location.latitude = 48.0;
+        let d = location.sun_direction();
+        assert!(d.y < 0.0 && (d.z.asin().to_degrees() - 42.0).abs() < 1.0);
+    }
+}
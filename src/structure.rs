@@ -1,9 +1,9 @@
-use crate::geometry::Mesh;
+use crate::geometry::{Mesh, ShapeType};
 use crate::math::Frame;
 use crate::tools::*;
 use crate::Scale;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use cgmath::{InnerSpace, Point2, Point3, Vector2, Vector3};
+use cgmath::{InnerSpace, Matrix, Matrix4, Point2, Point3, Transform, Vector2, Vector3};
 use core::f32;
 #[cfg(feature = "image")]
 use image::{DynamicImage, GenericImage};
@@ -699,6 +699,8 @@ pub struct Ray {
     pub d: Vector3<f32>,
     pub tnear: f32,
     pub tfar: f32,
+    /// Time inside the camera shutter (motion blur)
+    pub time: f32,
 }
 
 impl Ray {
@@ -711,22 +713,24 @@ impl Ray {
             // tnear: std::f32::EPSILON,
             tnear: crate::constants::EPSILON,
             tfar: std::f32::MAX,
+            time: 0.0,
         }
     }
 
+    pub fn with_time(mut self, time: f32) -> Ray {
+        self.time = time;
+        self
+    }
+
     pub fn spawn_ray(its: &Intersection, d_out: Vector3<f32>) -> Ray {
-        // Compute offset ray
-        // let d = crate::math::abs_vec(&its.n_g).dot(its.p_error);
-        // let mut offset = d * its.n_g;
-        // if d_out.dot(its.n_g) < 0.0 {
-        //     offset = -offset;
-        // }
-        // let o = its.p + offset * 100.0;
+        // The origin is offset with the error bounds
+        // to avoid self intersection
         Ray {
-            o: its.p,
+            o: its.spawn_origin(&d_out),
             d: d_out,
-            tnear: crate::constants::EPSILON, // Small epsilon avoiding self intersection
+            tnear: 0.0,
             tfar: std::f32::MAX,
+            time: its.time,
         }
     }
 }
@@ -921,6 +925,7 @@ impl BoundingSphere {
 }
 
 // Simple intersection primitive
+#[derive(Clone)]
 pub struct IntersectionUV {
     pub t: f32,
     pub p: Point3<f32>,
@@ -950,6 +955,8 @@ pub struct Intersection<'a> {
     pub wi: Vector3<f32>,
     /// The primitive id
     pub primitive_id: Option<usize>,
+    /// Time of the intersected ray
+    pub time: f32,
 }
 
 impl<'a> Intersection<'a> {
@@ -962,6 +969,10 @@ impl<'a> Intersection<'a> {
     pub fn to_world(&self, d: &Vector3<f32>) -> Vector3<f32> {
         self.frame.to_world(*d)
     }
+    /// Origin of a ray leaving the surface in the direction w
+    pub fn spawn_origin(&self, w: &Vector3<f32>) -> Point3<f32> {
+        crate::math::offset_ray_origin(&self.p, &self.p_error, &self.n_g, w)
+    }
     pub fn fill_intersection(
         mesh: &'a crate::geometry::Mesh,
         tri_id: usize,
@@ -972,6 +983,61 @@ impl<'a> Intersection<'a> {
         dist: f32,
         p: Point3<f32>,
     ) -> Intersection<'a> {
+        // Curves: the normal is computed by the intersection routine
+        // and the frame follow the curve tangent (needed by the hair BSDF)
+        if let ShapeType::Curves(curves) = &mesh.shape {
+            let segment = &curves.segments[tri_id];
+            let n_g = if mesh.bsdf.is_twosided() && ray.d.dot(n_g) > 0.0 {
+                -n_g
+            } else {
+                n_g
+            };
+            let frame = Frame::from_tangent(n_g, segment.eval(hit_u).1);
+            let wi = frame.to_local(-ray.d);
+            // Same error bound than PBRT (curve width)
+            let width = segment.width(hit_u);
+            return Intersection {
+                dist,
+                n_g,
+                n_s: n_g,
+                p,
+                p_error: Vector3::new(width, width, width),
+                uv: mesh.hit_uv(tri_id, hit_u, hit_v),
+                mesh,
+                frame,
+                wi,
+                primitive_id: Some(tri_id),
+                time: ray.time,
+            };
+        }
+
+        // Analytic shapes: the normal and the texture coordinates
+        // are computed by the intersection routine
+        if mesh.shape.is_analytic() {
+            let n_g = if mesh.bsdf.is_twosided() && !mesh.is_light() && ray.d.dot(n_g) > 0.0 {
+                -n_g
+            } else {
+                n_g
+            };
+            let frame = Frame::new(n_g);
+            let wi = frame.to_local(-ray.d);
+            // Error bound from PBRT (sphere)
+            let gamma_5 = crate::math::gamma(5);
+            return Intersection {
+                dist,
+                n_g,
+                n_s: n_g,
+                p,
+                p_error: gamma_5 * Vector3::new(p.x.abs(), p.y.abs(), p.z.abs()),
+                uv: Some(Vector2::new(hit_u, hit_v)),
+                mesh,
+                frame,
+                wi,
+                primitive_id: Some(tri_id),
+                time: ray.time,
+            };
+        }
+
         let index = mesh.indices[tri_id];
 
         let n_s = if let Some(normals) = &mesh.normals {
@@ -1013,14 +1079,7 @@ impl<'a> Intersection<'a> {
         };
 
         // UV interpolation
-        let uv = if let Some(uv_data) = &mesh.uv {
-            let d0 = &uv_data[index.x];
-            let d1 = &uv_data[index.y];
-            let d2 = &uv_data[index.z];
-            Some(d0 * (1.0 - hit_u - hit_v) + d1 * hit_u + d2 * hit_v)
-        } else {
-            None
-        };
+        let uv = mesh.hit_uv(tri_id, hit_u, hit_v);
 
         // Compute error position
         // From PBRT
@@ -1033,8 +1092,7 @@ impl<'a> Intersection<'a> {
             let b1 = hit_u;
             let b2 = hit_v;
 
-            let gamma_7 = (7.0 * std::f32::EPSILON * 0.5) / (1.0 - 7.0 * std::f32::EPSILON * 0.5);
-            gamma_7
+            crate::math::gamma(7)
                 * Vector3::new(
                     (p0.x * b0).abs() + (p1.x * b1).abs() + (p2.x * b2).abs(),
                     (p0.y * b0).abs() + (p1.y * b1).abs() + (p2.y * b2).abs(),
@@ -1055,6 +1113,57 @@ impl<'a> Intersection<'a> {
             frame,
             wi,
             primitive_id: Some(tri_id),
+            time: ray.time,
+        }
+    }
+
+    /// Move an intersection computed in the object space to the world space
+    /// (used for instancing). The ray is the world space ray.
+    pub fn to_world_space(
+        self,
+        to_world: &Matrix4<f32>,
+        to_local: &Matrix4<f32>,
+        ray: &Ray,
+    ) -> Intersection<'a> {
+        // Normals are transformed with the inverse transpose
+        let to_world_normal = to_local.transpose();
+        let n_g = to_world_normal.transform_vector(self.n_g).normalize();
+        let n_s = to_world_normal.transform_vector(self.n_s).normalize();
+        // Keep the tangent direction (needed by the hair BSDF)
+        let tangent = to_world.transform_vector(self.frame.to_world(Vector3::new(1.0, 0.0, 0.0)));
+        let frame = Frame::from_tangent(n_s, tangent);
+        let wi = frame.to_local(-ray.d);
+
+        // Error bound from PBRT (transformed point)
+        let p = to_world.transform_point(self.p);
+        let p_error = {
+            let abs_mul = |v: Vector3<f32>| {
+                Vector3::new(
+                    to_world.x.x.abs() * v.x + to_world.y.x.abs() * v.y + to_world.z.x.abs() * v.z,
+                    to_world.x.y.abs() * v.x + to_world.y.y.abs() * v.y + to_world.z.y.abs() * v.z,
+                    to_world.x.z.abs() * v.x + to_world.y.z.abs() * v.y + to_world.z.z.abs() * v.z,
+                )
+            };
+            let gamma_3 = crate::math::gamma(3);
+            let p_abs = Vector3::new(self.p.x.abs(), self.p.y.abs(), self.p.z.abs());
+            abs_mul(self.p_error) * (1.0 + gamma_3)
+                + (abs_mul(p_abs)
+                    + Vector3::new(to_world.w.x.abs(), to_world.w.y.abs(), to_world.w.z.abs()))
+                    * gamma_3
+        };
+
+        Intersection {
+            dist: self.dist,
+            n_g,
+            n_s,
+            p,
+            p_error,
+            uv: self.uv,
+            mesh: self.mesh,
+            frame,
+            wi,
+            primitive_id: self.primitive_id,
+            time: self.time,
         }
     }
 }